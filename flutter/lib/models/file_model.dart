@@ -48,6 +48,14 @@ class FileModel {
   String get sessionID => getSessionID();
   late final FileDialogEventLoop evtLoop;
 
+  // the remote search running, its entries arrive in pages until done
+  int _searchID = 0;
+  final searchResults = RxList<Entry>.empty(growable: true);
+  final searchDone = true.obs;
+  // the text of the last remote range read
+  int _previewID = 0;
+  final filePreview = Rx<FilePreview?>(null);
+
   FileModel(this.parent) {
     getSessionID = () => parent.target?.id ?? "";
     fileFetcher = FileFetcher(getSessionID);
@@ -95,6 +103,59 @@ class FileModel {
     fileFetcher.tryCompleteTask(evt['value'], evt['is_local']);
   }
 
+  Future<void> searchRemoteFiles(String path, String pattern,
+      {bool isRegex = false, bool includeHidden = false}) async {
+    _searchID = JobController.jobID.next();
+    searchResults.clear();
+    searchDone.value = false;
+    await bind.sessionSearchRemoteFiles(
+        id: sessionID,
+        actId: _searchID,
+        path: path,
+        pattern: pattern,
+        isRegex: isRegex,
+        minSize: 0,
+        maxSize: 0,
+        minModifiedTime: 0,
+        maxModifiedTime: 0,
+        includeHidden: includeHidden);
+  }
+
+  void receiveSearchResult(Map<String, dynamic> evt) {
+    try {
+      final fd = FileDirectory.fromJson(jsonDecode(evt['value']));
+      if (fd.id != _searchID) return;
+      fd.format(remoteController.options.value.isWindows);
+      searchResults.addAll(fd.entries);
+      searchDone.value = evt['done'] == "true";
+    } catch (e) {
+      debugPrint("Failed to receiveSearchResult, evt: ${evt.toString()}");
+    }
+  }
+
+  /// Read [length] bytes of the remote file from [offset], or its last [tailLines] lines.
+  Future<void> readRemoteRange(String path,
+      {int offset = 0, int length = 0, int tailLines = 0}) async {
+    _previewID = JobController.jobID.next();
+    await bind.sessionReadRemoteRange(
+        id: sessionID,
+        actId: _previewID,
+        path: path,
+        offset: offset,
+        length: length,
+        tailLines: tailLines);
+  }
+
+  void receiveFilePreview(Map<String, dynamic> evt) {
+    final id = int.tryParse(evt['id'] ?? '') ?? 0;
+    if (id != _previewID) return;
+    filePreview.value = FilePreview(
+        path: evt['path'] ?? '',
+        offset: int.tryParse(evt['offset'] ?? '') ?? 0,
+        data: evt['data'] ?? '',
+        fileSize: int.tryParse(evt['file_size'] ?? '') ?? 0);
+  }
+
   Future<void> postOverrideFileConfirm(Map<String, dynamic> evt) async {
     evtLoop.pushEvent(
         _FileDialogEvent(WeakReference(this), FileDialogType.overwrite, evt));
@@ -962,6 +1023,19 @@ class FileDirectory {
   }
 }
 
+class FilePreview {
+  final String path;
+  final int offset;
+  final String data;
+  final int fileSize;
+
+  FilePreview(
+      {required this.path,
+      required this.offset,
+      required this.data,
+      required this.fileSize});
+}
+
 class Entry {
   int entryType = 4;
   int modifiedTime = 0;
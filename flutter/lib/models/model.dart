@@ -178,6 +178,10 @@ class FfiModel with ChangeNotifier {
         parent.target?.fileModel.jobController.loadLastJob(evt);
       } else if (name == 'update_folder_files') {
         parent.target?.fileModel.jobController.updateFolderFiles(evt);
+      } else if (name == 'search_result') {
+        parent.target?.fileModel.receiveSearchResult(evt);
+      } else if (name == 'file_preview') {
+        parent.target?.fileModel.receiveFilePreview(evt);
       } else if (name == 'add_connection') {
         parent.target?.serverModel.addConnection(evt);
       } else if (name == 'on_client_remove') {
@@ -620,6 +620,7 @@ enum ClientType {
   remote,
   file,
   portForward,
+  exec,
 }
 
 class Client {
@@ -627,6 +628,7 @@ class Client {
   bool authorized = false;
   bool isFileTransfer = false;
   String portForward = "";
+  String execCommand = "";
   String name = "";
   String peerId = ""; // peer user's id,show at app
   bool keyboard = false;
@@ -650,6 +652,7 @@ class Client {
     authorized = json['authorized'];
     isFileTransfer = json['is_file_transfer'];
     portForward = json['port_forward'];
+    execCommand = json['exec_command'] ?? "";
     name = json['name'];
     peerId = json['peer_id'];
     keyboard = json['keyboard'];
@@ -670,6 +673,7 @@ class Client {
     data['is_start'] = authorized;
     data['is_file_transfer'] = isFileTransfer;
     data['port_forward'] = portForward;
+    data['exec_command'] = execCommand;
     data['name'] = name;
     data['peer_id'] = peerId;
     data['keyboard'] = keyboard;
@@ -688,6 +692,8 @@ class Client {
       return ClientType.file;
     } else if (portForward.isNotEmpty) {
       return ClientType.portForward;
+    } else if (execCommand.isNotEmpty) {
+      return ClientType.exec;
     } else {
       return ClientType.remote;
     }
@@ -135,6 +135,7 @@ macro_rules! serde_field_bool {
 pub enum NetworkType {
     Direct,
     ProxySocks,
+    ProxyHttp,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
@@ -153,6 +154,9 @@ pub struct Config {
     key_confirmed: bool,
     #[serde(default)]
     keys_confirmed: HashMap<String, bool>,
+    // base32 secret of the one-time codes asked for after the password, none if empty
+    #[serde(default)]
+    totp_secret: String,
 }
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
@@ -165,6 +169,15 @@ pub struct Socks5Server {
     pub password: String,
 }
 
+impl Socks5Server {
+    /// The address of the proxy if it is an HTTP CONNECT one, set as `http://host:port`.
+    pub fn http_proxy(&self) -> Option<&str> {
+        self.proxy
+            .strip_prefix("http://")
+            .map(|x| x.trim_end_matches('/'))
+    }
+}
+
 // more variable configs
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Config2 {
@@ -414,6 +427,10 @@ impl Config {
         let (password, _, store1) = decrypt_str_or_original(&config.password, PASSWORD_ENC_VERSION);
         config.password = password;
         store |= store1;
+        let (totp_secret, _, store3) =
+            decrypt_str_or_original(&config.totp_secret, PASSWORD_ENC_VERSION);
+        config.totp_secret = totp_secret;
+        store |= store3;
         let mut id_valid = false;
         let (id, encrypted, store2) = decrypt_str_or_original(&config.enc_id, PASSWORD_ENC_VERSION);
         if encrypted {
@@ -451,6 +468,7 @@ impl Config {
     fn store(&self) {
         let mut config = self.clone();
         config.password = encrypt_str_or_original(&config.password, PASSWORD_ENC_VERSION);
+        config.totp_secret = encrypt_str_or_original(&config.totp_secret, PASSWORD_ENC_VERSION);
         config.enc_id = encrypt_str_or_original(&config.id, PASSWORD_ENC_VERSION);
         config.id = "".to_owned();
         Config::store_(&config, "");
@@ -839,6 +857,19 @@ impl Config {
         CONFIG.read().unwrap().password.clone()
     }
 
+    pub fn set_totp_secret(secret: &str) {
+        let mut config = CONFIG.write().unwrap();
+        if secret == config.totp_secret {
+            return;
+        }
+        config.totp_secret = secret.into();
+        config.store();
+    }
+
+    pub fn get_totp_secret() -> String {
+        CONFIG.read().unwrap().totp_secret.clone()
+    }
+
     pub fn set_salt(salt: &str) {
         let mut config = CONFIG.write().unwrap();
         if salt == config.salt {
@@ -873,6 +904,7 @@ impl Config {
     pub fn get_network_type() -> NetworkType {
         match &CONFIG2.read().unwrap().socks {
             None => NetworkType::Direct,
+            Some(socks) if socks.http_proxy().is_some() => NetworkType::ProxyHttp,
             Some(_) => NetworkType::ProxySocks,
         }
     }
@@ -1301,6 +1333,45 @@ impl LanPeers {
     }
 }
 
+/// Public keys of the controlling sides let in without a password, like authorized_keys of ssh.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AuthorizedKeys {
+    #[serde(default)]
+    pub keys: Vec<AuthorizedKey>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuthorizedKey {
+    // base64 of the sign public key
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub comment: String,
+}
+
+impl AuthorizedKeys {
+    pub fn load() -> AuthorizedKeys {
+        let _lock = CONFIG.read().unwrap();
+        match confy::load_path(Config::file_("_authorized_keys")) {
+            Ok(keys) => keys,
+            Err(err) => {
+                log::error!("Failed to load authorized keys: {}", err);
+                Default::default()
+            }
+        }
+    }
+
+    pub fn store(&self) {
+        if let Err(err) = store_path(Config::file_("_authorized_keys"), self) {
+            log::error!("Failed to store authorized keys: {}", err);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&AuthorizedKey> {
+        self.keys.iter().find(|x| x.key == key)
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct HwCodecConfig {
     #[serde(default)]
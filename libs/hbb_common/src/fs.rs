@@ -1,18 +1,25 @@
+use std::collections::{HashMap, VecDeque};
 #[cfg(windows)]
 use std::os::windows::prelude::*;
-use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use serde_derive::{Deserialize, Serialize};
 use tokio::{fs::File, io::*};
 
-use crate::{bail, get_version_number, message_proto::*, ResultType, Stream};
+use crate::{bail, get_version_number, message_proto::*, tcp::Channel, ResultType, Stream};
+use sodiumoxide::crypto::hash::sha256;
 // https://doc.rust-lang.org/std/os/windows/fs/trait.MetadataExt.html
 use crate::{
     compress::{compress, decompress},
     config::{Config, COMPRESS_LEVEL},
 };
 
+mod archive;
+use archive::{ArchiveEncoder, ArchiveExtractor};
+mod search;
+pub use search::{handle_search_jobs, new_search_result, remove_search_job, SearchJob};
+
 pub fn read_dir(path: &Path, include_hidden: bool) -> ResultType<FileDirectory> {
     let mut dir = FileDirectory {
         path: get_string(path),
@@ -86,18 +93,243 @@ pub fn read_dir(path: &Path, include_hidden: bool) -> ResultType<FileDirectory>
                     .unwrap_or(0)
             })
             .unwrap_or(0);
-        dir.entries.push(FileEntry {
+        let mut entry = FileEntry {
             name: get_file_name(&p),
             entry_type,
             is_hidden,
             size,
             modified_time,
             ..Default::default()
-        });
+        };
+        set_entry_meta(&mut entry, &p, &meta);
+        dir.entries.push(entry);
     }
     Ok(dir)
 }
 
+// unix mode, owner and link target of the entry
+fn set_entry_meta(entry: &mut FileEntry, path: &Path, meta: &std::fs::Metadata) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        entry.mode = meta.mode() & 0o7777;
+        entry.uid = Some(meta.uid());
+        entry.gid = Some(meta.gid());
+    }
+    if meta.file_type().is_symlink() {
+        if let Ok(target) = std::fs::read_link(path) {
+            entry.link_target = get_string(&target);
+        }
+    }
+}
+
+#[inline]
+fn is_link(entry: &FileEntry) -> bool {
+    matches!(
+        entry.entry_type.enum_value(),
+        Ok(FileType::FileLink) | Ok(FileType::DirLink)
+    ) && !entry.link_target.is_empty()
+}
+
+// Whether the link `name` to `target`, both relative to the destination, stays inside it.
+fn is_link_target_inside(name: &str, target: &str) -> bool {
+    let mut depth = Path::new(name)
+        .parent()
+        .map(|p| {
+            p.components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .count()
+        })
+        .unwrap_or(0);
+    for c in Path::new(target).components() {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+// The path of the received entry `name` under `base`, never outside of it, neither
+// through `..` nor through a link, the ones created by the job included.
+fn entry_path(base: &Path, name: &str) -> ResultType<PathBuf> {
+    let mut path = base.to_path_buf();
+    let components: Vec<_> = Path::new(name).components().collect();
+    for (i, c) in components.iter().enumerate() {
+        match c {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => continue,
+            _ => bail!("Invalid path: {}", name),
+        }
+        if i + 1 < components.len() {
+            if let Ok(meta) = std::fs::symlink_metadata(&path) {
+                if meta.file_type().is_symlink() {
+                    bail!("Invalid path: {}", name);
+                }
+            }
+        }
+    }
+    Ok(path)
+}
+
+// Move the content of the directory `from` into `to`, merging the directories both have,
+// the files `to` has are replaced only if `overwrite`.
+fn move_into(from: &Path, to: &Path, overwrite: bool) -> ResultType<()> {
+    match std::fs::symlink_metadata(to) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => bail!("{:?} is not a directory", to),
+        Err(_) => {
+            if let Some(p) = to.parent() {
+                std::fs::create_dir_all(p)?;
+            }
+            std::fs::rename(from, to)?;
+            return Ok(());
+        }
+    }
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let path = to.join(entry.file_name());
+        let is_dir = std::fs::symlink_metadata(&path)
+            .map(|m| m.is_dir())
+            .unwrap_or(false);
+        if is_dir && entry.file_type()?.is_dir() {
+            move_into(&entry.path(), &path, overwrite)?;
+            // the metadata restored on the extracted directory, the moves changed its mtime
+            let meta = std::fs::metadata(entry.path())?;
+            std::fs::set_permissions(&path, meta.permissions()).ok();
+            filetime::set_file_mtime(
+                &path,
+                filetime::FileTime::from_last_modification_time(&meta),
+            )
+            .ok();
+        } else if overwrite || std::fs::symlink_metadata(&path).is_err() {
+            std::fs::rename(entry.path(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+// What `move_into` would replace in `to`.
+fn replaced_by_move(from: &Path, to: &Path) -> ResultType<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    match std::fs::symlink_metadata(to) {
+        Ok(meta) if meta.is_dir() => {}
+        _ => return Ok(paths),
+    }
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let path = to.join(entry.file_name());
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() && entry.file_type()?.is_dir() => {
+                paths.append(&mut replaced_by_move(&entry.path(), &path)?);
+            }
+            Ok(_) => paths.push(path),
+            Err(_) => {}
+        }
+    }
+    Ok(paths)
+}
+
+// Restore the metadata of a written file, or create the link, `path` being the
+// final path of `entry`.
+fn restore_entry_meta(entry: &FileEntry, path: &Path) {
+    if is_link(entry) {
+        if !is_link_target_inside(&entry.name, &entry.link_target) {
+            log::warn!(
+                "link {:?} to {} leads out of the destination, skipped",
+                path,
+                entry.link_target
+            );
+            return;
+        }
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.is_dir() {
+                log::warn!("{:?} is a directory, not replaced by link", path);
+                return;
+            }
+            std::fs::remove_file(path).ok();
+        }
+        if let Err(err) = create_link(entry, path) {
+            log::error!("Failed to create link {:?}: {}", path, err);
+            return;
+        }
+    } else {
+        filetime::set_file_mtime(
+            path,
+            filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
+        )
+        .ok();
+        #[cfg(unix)]
+        if entry.mode != 0 {
+            use std::os::unix::fs::PermissionsExt;
+            // setuid and setgid are never restored
+            let mode = entry.mode & 0o1777;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).ok();
+        }
+    }
+    restore_owner(entry, path);
+}
+
+// The entry of the file a link to a file under `base` points to, the entry itself if not a link.
+fn follow_link(base: &Path, mut entry: FileEntry) -> Option<FileEntry> {
+    if !is_link(&entry) {
+        return Some(entry);
+    }
+    if entry.entry_type.enum_value() != Ok(FileType::FileLink) {
+        return None;
+    }
+    let path = base.join(&entry.name);
+    let meta = std::fs::metadata(&path).ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    entry.entry_type = FileType::File.into();
+    entry.size = meta.len();
+    entry.modified_time = meta
+        .modified()
+        .map(|x| {
+            x.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or(0)
+        })
+        .unwrap_or(0);
+    entry.link_target.clear();
+    set_entry_meta(&mut entry, &path, &meta);
+    Some(entry)
+}
+
+// Fails if `path` exists.
+fn create_link(entry: &FileEntry, path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(&entry.link_target, path);
+    #[cfg(windows)]
+    return if entry.entry_type.enum_value() == Ok(FileType::DirLink) {
+        std::os::windows::fs::symlink_dir(&entry.link_target, path)
+    } else {
+        std::os::windows::fs::symlink_file(&entry.link_target, path)
+    };
+    #[cfg(not(any(unix, windows)))]
+    return Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "links are not supported",
+    ));
+}
+
+#[allow(unused_variables)]
+fn restore_owner(entry: &FileEntry, path: &Path) {
+    #[cfg(unix)]
+    if let (Some(uid), Some(gid)) = (entry.uid, entry.gid) {
+        // only root is able to give files away
+        if unsafe { libc::geteuid() } == 0 {
+            if let Ok(p) = std::ffi::CString::new(get_string(path)) {
+                unsafe { libc::lchown(p.as_ptr(), uid, gid) };
+            }
+        }
+    }
+}
+
 #[inline]
 pub fn get_file_name(p: &Path) -> String {
     p.file_name()
@@ -125,11 +357,11 @@ fn read_dir_recursive(
     path: &PathBuf,
     prefix: &Path,
     include_hidden: bool,
+    include_dirs: bool,
 ) -> ResultType<Vec<FileEntry>> {
     let mut files = Vec::new();
     if path.is_dir() {
-        // to-do: symbol link handling, cp the link rather than the content
-        // to-do: file mode, for unix
+        // links are copied rather than their content
         let fd = read_dir(path, include_hidden)?;
         for entry in fd.entries.iter() {
             match entry.entry_type.enum_value() {
@@ -138,11 +370,22 @@ fn read_dir_recursive(
                     entry.name = get_string(&prefix.join(entry.name));
                     files.push(entry);
                 }
+                Ok(FileType::FileLink) | Ok(FileType::DirLink) if is_link(entry) => {
+                    let mut entry = entry.clone();
+                    entry.name = get_string(&prefix.join(entry.name));
+                    files.push(entry);
+                }
                 Ok(FileType::Dir) => {
+                    if include_dirs {
+                        let mut entry = entry.clone();
+                        entry.name = get_string(&prefix.join(entry.name));
+                        files.push(entry);
+                    }
                     if let Ok(mut tmp) = read_dir_recursive(
                         &path.join(&entry.name),
                         &prefix.join(&entry.name),
                         include_hidden,
+                        include_dirs,
                     ) {
                         for entry in tmp.drain(0..) {
                             files.push(entry);
@@ -154,26 +397,23 @@ fn read_dir_recursive(
         }
         Ok(files)
     } else if path.is_file() {
-        let (size, modified_time) = if let Ok(meta) = std::fs::metadata(path) {
-            (
-                meta.len(),
-                meta.modified()
-                    .map(|x| {
-                        x.duration_since(std::time::SystemTime::UNIX_EPOCH)
-                            .map(|x| x.as_secs())
-                            .unwrap_or(0)
-                    })
-                    .unwrap_or(0),
-            )
-        } else {
-            (0, 0)
-        };
-        files.push(FileEntry {
+        let mut entry = FileEntry {
             entry_type: FileType::File.into(),
-            size,
-            modified_time,
             ..Default::default()
-        });
+        };
+        if let Ok(meta) = std::fs::metadata(path) {
+            entry.size = meta.len();
+            entry.modified_time = meta
+                .modified()
+                .map(|x| {
+                    x.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                        .map(|x| x.as_secs())
+                        .unwrap_or(0)
+                })
+                .unwrap_or(0);
+            set_entry_meta(&mut entry, path, &meta);
+        }
+        files.push(entry);
         Ok(files)
     } else {
         bail!("Not exists");
@@ -181,7 +421,7 @@ fn read_dir_recursive(
 }
 
 pub fn get_recursive_files(path: &str, include_hidden: bool) -> ResultType<Vec<FileEntry>> {
-    read_dir_recursive(&get_path(path), &get_path(""), include_hidden)
+    read_dir_recursive(&get_path(path), &get_path(""), include_hidden, false)
 }
 
 #[inline]
@@ -194,6 +434,28 @@ pub fn can_enable_overwrite_detection(version: i64) -> bool {
     version >= get_version_number("1.1.10")
 }
 
+const BUF_SIZE: usize = 128 * 1024;
+// upper bound of a FileReadRange
+const MAX_READ_RANGE: u32 = 1024 * 1024;
+// files smaller than this are always sent as a whole
+const DELTA_MIN_FILE_SIZE: u64 = 1024 * 1024;
+const DELTA_MIN_BLOCK_SIZE: u32 = 2 * 1024;
+const DELTA_MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+// upper bound of the bytes covered by one copy instruction
+const DELTA_MAX_COPY_SIZE: u64 = 16 * 1024 * 1024;
+const DELTA_STRONG_LEN: usize = 16;
+// option key of the global transfer rate limit, in KB/s, empty or 0 means unlimited
+pub const OPTION_FILE_TRANSFER_RATE_LIMIT: &str = "file-transfer-rate-limit";
+const RATE_MIN_LIMIT: u32 = 32 * 1024;
+// latency increase (ms) above the base delay that is treated as congestion
+const RATE_DELAY_THRESHOLD: u32 = 100;
+const RATE_BURST: f64 = 0.1;
+// option key of the number of files transferred at once by a job
+pub const OPTION_FILE_TRANSFER_PARALLEL: &str = "file-transfer-parallel";
+const MAX_PARALLEL: u32 = 16;
+// toggle option asking for directories to be downloaded as one archive
+pub const OPTION_FILE_TRANSFER_ARCHIVE: &str = "file-transfer-archive";
+
 #[derive(Default)]
 pub struct TransferJob {
     pub id: i32,
@@ -205,6 +467,8 @@ pub struct TransferJob {
     pub file_num: i32,
     pub files: Vec<FileEntry>,
 
+    // the directories of the job, their metadata is restored once the job is done
+    dirs: Vec<FileEntry>,
     file: Option<File>,
     total_size: u64,
     finished_size: u64,
@@ -215,6 +479,53 @@ pub struct TransferJob {
     file_skipped: bool,
     file_is_waiting: bool,
     default_overwrite_strategy: Option<bool>,
+    // the peer (sender) is able to stream only the changed blocks
+    peer_support_delta: bool,
+    // block checksums of the peer's copy, used once the waiting file is confirmed
+    peer_checksums: Option<FileTransferChecksums>,
+    delta: Option<DeltaReader>,
+    delta_bases: Vec<DeltaBasis>,
+    // sha256 of the data of the current file read or written so far
+    hasher: Option<sha256::State>,
+    // the peer (receiver) verifies the sha256 of the files
+    peer_verify_hash: bool,
+    // waiting for the receiver to verify the last file
+    verify_waiting: bool,
+    // files asked to be sent again after a digest mismatch, at most once each
+    retransmitted: Vec<i32>,
+    retransmitting: Option<i32>,
+    // number of files read or written at once
+    parallel: u32,
+    // the other files in flight, the current one is held by the fields above
+    slots: VecDeque<FileSlot>,
+    // the directory is transferred as one archive
+    is_archive: bool,
+    archive: Option<ArchiveEncoder>,
+    extractor: Option<ArchiveExtractor>,
+    // the files an extracted archive would replace, it waits aside for `confirm_archive`
+    archive_conflicts: Vec<PathBuf>,
+}
+
+// A file in flight, parked while another file of the job is read or written.
+#[derive(Default)]
+struct FileSlot {
+    file_num: i32,
+    file: Option<File>,
+    file_confirmed: bool,
+    file_is_waiting: bool,
+    peer_checksums: Option<FileTransferChecksums>,
+    delta: Option<DeltaReader>,
+    hasher: Option<sha256::State>,
+}
+
+// local copy which the sender's copy instructions refer to
+struct DeltaBasis {
+    file_num: i32,
+    path: PathBuf,
+    block_size: u32,
+    // a partial download renamed aside, removed once the file is done
+    is_partial: bool,
+    file: Option<File>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -233,6 +544,58 @@ pub struct TransferJobMeta {
     pub is_remote: bool,
 }
 
+/// The part of [`FileEntry`] needed to write a file, passed to the connection manager.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct FileEntryMeta {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub entry_type: i32,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub modified_time: u64,
+    #[serde(default)]
+    pub mode: u32,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    #[serde(default)]
+    pub link_target: String,
+}
+
+impl From<FileEntry> for FileEntryMeta {
+    fn from(entry: FileEntry) -> Self {
+        Self {
+            name: entry.name,
+            entry_type: entry.entry_type.value(),
+            size: entry.size,
+            modified_time: entry.modified_time,
+            mode: entry.mode,
+            uid: entry.uid,
+            gid: entry.gid,
+            link_target: entry.link_target,
+        }
+    }
+}
+
+impl From<FileEntryMeta> for FileEntry {
+    fn from(meta: FileEntryMeta) -> Self {
+        Self {
+            name: meta.name,
+            entry_type: protobuf::EnumOrUnknown::from_i32(meta.entry_type),
+            size: meta.size,
+            modified_time: meta.modified_time,
+            mode: meta.mode,
+            uid: meta.uid,
+            gid: meta.gid,
+            link_target: meta.link_target,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct RemoveJobMeta {
     #[serde(default)]
@@ -303,7 +666,10 @@ impl TransferJob {
         enable_overwrite_detection: bool,
     ) -> ResultType<Self> {
         log::info!("new read {}", path);
-        let files = get_recursive_files(&path, show_hidden)?;
+        let (dirs, files): (Vec<_>, Vec<_>) =
+            read_dir_recursive(&get_path(&path), &get_path(""), show_hidden, true)?
+                .into_iter()
+                .partition(|x| x.entry_type.enum_value() == Ok(FileType::Dir));
         let total_size = files.iter().map(|x| x.size).sum();
         Ok(Self {
             id,
@@ -313,12 +679,56 @@ impl TransferJob {
             show_hidden,
             is_remote,
             files,
+            dirs,
             total_size,
             enable_overwrite_detection,
             ..Default::default()
         })
     }
 
+    /// For a peer which does not recreate links, see `Features::file_links`: a link to a file
+    /// is read as the file it points to and links to directories are left out, as before
+    /// links were sent.
+    pub fn follow_links(&mut self) {
+        let path = self.path.clone();
+        self.files = std::mem::take(&mut self.files)
+            .into_iter()
+            .filter_map(|entry| follow_link(&path, entry))
+            .collect();
+        self.total_size = self.files.iter().map(|x| x.size).sum();
+    }
+
+    /// Read the directory `path` as one archive, the job has a single entry whose size
+    /// is the size of all the files.
+    pub fn new_archive_read(
+        id: i32,
+        remote: String,
+        path: String,
+        show_hidden: bool,
+        is_remote: bool,
+    ) -> ResultType<Self> {
+        log::info!("new archive read {}", path);
+        let entries = read_dir_recursive(&get_path(&path), &get_path(""), show_hidden, true)?;
+        let total_size = entries.iter().map(|x| x.size).sum();
+        let files = vec![FileEntry {
+            entry_type: FileType::Dir.into(),
+            size: total_size,
+            ..Default::default()
+        }];
+        Ok(Self {
+            id,
+            remote,
+            path: get_path(&path),
+            show_hidden,
+            is_remote,
+            files,
+            total_size,
+            is_archive: true,
+            archive: Some(ArchiveEncoder::new(get_path(&path), entries)),
+            ..Default::default()
+        })
+    }
+
     #[inline]
     pub fn files(&self) -> &Vec<FileEntry> {
         &self.files
@@ -329,6 +739,16 @@ impl TransferJob {
         self.files = files;
     }
 
+    #[inline]
+    pub fn dirs(&self) -> &Vec<FileEntry> {
+        &self.dirs
+    }
+
+    #[inline]
+    pub fn set_dirs(&mut self, dirs: Vec<FileEntry>) {
+        self.dirs = dirs;
+    }
+
     #[inline]
     pub fn id(&self) -> i32 {
         self.id
@@ -354,22 +774,60 @@ impl TransferJob {
         self.file_num
     }
 
-    pub fn modify_time(&self) {
+    /// Move the finished file in place and restore its mtime, mode and owner,
+    /// links are created instead. Called once the job is done, the directories
+    /// are restored last, the deepest first.
+    pub fn modify_time(&mut self) {
+        // files sent in parallel are finished as soon as they are verified,
+        // the extracted ones as soon as they are complete
+        if !self.is_archive && (self.parallel <= 1 || self.file.is_some()) {
+            self.finish_file();
+        }
+        for entry in std::mem::take(&mut self.dirs).iter().rev() {
+            if let Ok(path) = entry_path(&self.path, &entry.name) {
+                // not through a link put in place of the directory
+                if let Ok(meta) = std::fs::symlink_metadata(&path) {
+                    if meta.is_dir() {
+                        restore_entry_meta(entry, &path);
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish_file(&mut self) {
+        self.close_delta_basis(false);
         let file_num = self.file_num as usize;
         if file_num < self.files.len() {
             let entry = &self.files[file_num];
-            let path = self.join(&entry.name);
+            let path = match entry_path(&self.path, &entry.name) {
+                Ok(path) => path,
+                Err(_) => return,
+            };
             let download_path = format!("{}.download", get_string(&path));
-            std::fs::rename(download_path, &path).ok();
-            filetime::set_file_mtime(
-                &path,
-                filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
-            )
-            .ok();
+            if is_link(entry) {
+                std::fs::remove_file(download_path).ok();
+            } else {
+                std::fs::rename(download_path, &path).ok();
+            }
+            restore_entry_meta(entry, &path);
+        }
+    }
+
+    pub fn remove_download_file(&mut self) {
+        if let Some(mut extractor) = self.extractor.take() {
+            extractor.abort();
+            std::fs::remove_dir_all(archive_download_path(&self.path)).ok();
+        }
+        self.remove_current_download_file();
+        while let Some(slot) = self.slots.pop_front() {
+            self.load_slot(slot);
+            self.file.take();
+            self.remove_current_download_file();
         }
     }
 
-    pub fn remove_download_file(&self) {
+    fn remove_current_download_file(&mut self) {
         let file_num = self.file_num as usize;
         if file_num < self.files.len() {
             let entry = &self.files[file_num];
@@ -377,9 +835,195 @@ impl TransferJob {
             let download_path = format!("{}.download", get_string(&path));
             std::fs::remove_file(download_path).ok();
         }
+        self.close_delta_basis(true);
+    }
+
+    /// Release the basis of the current file. A partial download used as basis is
+    /// put back if `restore` is set, so that a later transfer can still resume from it.
+    fn close_delta_basis(&mut self, restore: bool) {
+        if let Some(i) = self
+            .delta_bases
+            .iter()
+            .position(|b| b.file_num == self.file_num)
+        {
+            let basis = self.delta_bases.remove(i);
+            drop(basis.file);
+            if basis.is_partial {
+                if restore {
+                    let download_path = basis.path.with_extension("");
+                    std::fs::rename(&basis.path, download_path).ok();
+                } else {
+                    std::fs::remove_file(&basis.path).ok();
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub fn set_peer_support_delta(&mut self, support_delta: bool) {
+        self.peer_support_delta = support_delta;
+    }
+
+    /// Compute the block checksums of the local copy of file `file_num`, so that the
+    /// peer only sends the blocks that differ. A partial download left by an interrupted
+    /// transfer is used when the file itself does not exist.
+    pub async fn gen_checksums(&mut self, file_num: i32) -> Option<FileTransferChecksums> {
+        if !self.peer_support_delta {
+            return None;
+        }
+        let entry = self.files.get(file_num as usize)?;
+        let path = self.join(&entry.name);
+        let download_path = get_path(&format!("{}.download", get_string(&path)));
+        let (mut basis, is_partial) = if path.is_file() {
+            (path, false)
+        } else if download_path.is_file() {
+            (download_path, true)
+        } else {
+            return None;
+        };
+        let len = std::fs::metadata(&basis).ok()?.len();
+        if len < DELTA_MIN_FILE_SIZE {
+            return None;
+        }
+        if is_partial {
+            let tmp = get_path(&format!("{}.basis", get_string(&basis)));
+            std::fs::rename(&basis, &tmp).ok()?;
+            basis = tmp;
+        }
+        let block_size = delta_block_size(len);
+        let p = basis.clone();
+        let blocks =
+            match tokio::task::spawn_blocking(move || block_checksums(&p, block_size)).await {
+                Ok(Ok(blocks)) => blocks,
+                res => {
+                    if let Ok(Err(err)) = res {
+                        log::error!("Failed to read block checksums of {:?}: {}", basis, err);
+                    }
+                    if is_partial {
+                        std::fs::rename(&basis, basis.with_extension("")).ok();
+                    }
+                    return None;
+                }
+            };
+        log::info!(
+            "id: {}, file_num: {}, {} block checksums of {:?}",
+            self.id,
+            file_num,
+            blocks.len(),
+            basis
+        );
+        self.delta_bases.push(DeltaBasis {
+            file_num,
+            path: basis,
+            block_size,
+            is_partial,
+            file: None,
+        });
+        Some(FileTransferChecksums {
+            id: self.id,
+            file_num,
+            block_size,
+            blocks,
+            ..Default::default()
+        })
+    }
+
+    /// Confirmation for writing file `file_num`, carrying the block checksums of the
+    /// local copy if delta transfer is possible.
+    pub async fn gen_write_confirm(&mut self, file_num: i32) -> FileTransferSendConfirmRequest {
+        let union = match self.gen_checksums(file_num).await {
+            Some(checksums) => file_transfer_send_confirm_request::Union::Checksums(checksums),
+            None => file_transfer_send_confirm_request::Union::OffsetBlk(0),
+        };
+        FileTransferSendConfirmRequest {
+            id: self.id,
+            file_num,
+            union: Some(union),
+            verify_hash: true,
+            ..Default::default()
+        }
+    }
+
+    async fn write_basis_blocks(&mut self, blk_id: u32, copy_blocks: u32) -> ResultType<u64> {
+        let file_num = self.file_num;
+        let basis = match self.delta_bases.iter_mut().find(|b| b.file_num == file_num) {
+            Some(basis) => basis,
+            None => bail!("No basis file"),
+        };
+        if basis.file.is_none() {
+            basis.file = Some(File::open(&basis.path).await?);
+        }
+        let size = copy_blocks as u64 * basis.block_size as u64;
+        let src = basis.file.as_mut().unwrap();
+        src.seek(SeekFrom::Start(blk_id as u64 * basis.block_size as u64))
+            .await?;
+        let dst = self.file.as_mut().unwrap();
+        let mut buf = vec![0; BUF_SIZE];
+        let mut remaining = size;
+        while remaining > 0 {
+            let n = std::cmp::min(remaining, BUF_SIZE as u64) as usize;
+            src.read_exact(&mut buf[..n]).await?;
+            dst.write_all(&buf[..n]).await?;
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&buf[..n]);
+            }
+            remaining -= n as u64;
+        }
+        Ok(size)
+    }
+
+    #[inline]
+    pub fn retransmitting(&self) -> Option<i32> {
+        self.retransmitting
+    }
+
+    // Check the sha256 sent by the peer with the end of the current file.
+    async fn verify_file(&mut self, sha256: &[u8]) -> ResultType<Option<FileTransferVerified>> {
+        let file_num = self.file_num;
+        if let Some(file) = self.file.as_mut() {
+            file.flush().await?;
+        }
+        let digest = self.hasher.take().map(|h| h.finalize());
+        if digest.as_ref().map(|d| &d.0[..]) == Some(sha256) {
+            if self.retransmitting == Some(file_num) {
+                self.retransmitting = None;
+            }
+            if file_num as usize + 1 == self.files.len() {
+                return Ok(Some(FileTransferVerified {
+                    id: self.id,
+                    file_num,
+                    ok: true,
+                    ..Default::default()
+                }));
+            }
+            return Ok(None);
+        }
+        log::error!("id: {}, file_num: {}, sha256 mismatch", self.id, file_num);
+        self.file.take();
+        self.remove_current_download_file();
+        // retransmitting requires the sender to wait for confirmation before each file
+        if self.enable_overwrite_detection && !self.retransmitted.contains(&file_num) {
+            self.retransmitted.push(file_num);
+            self.retransmitting = Some(file_num);
+            self.finished_size = self
+                .finished_size
+                .saturating_sub(self.files[file_num as usize].size);
+            return Ok(Some(FileTransferVerified {
+                id: self.id,
+                file_num,
+                retransmit: true,
+                ..Default::default()
+            }));
+        }
+        bail!("File digest mismatch");
     }
 
-    pub async fn write(&mut self, block: FileTransferBlock) -> ResultType<()> {
+    /// Write a block sent by the peer. At the end of each file the sha256 of the written
+    /// data is checked, the returned message should be sent back to the peer.
+    pub async fn write(
+        &mut self,
+        block: FileTransferBlock,
+    ) -> ResultType<Option<FileTransferVerified>> {
         if block.id != self.id {
             bail!("Wrong id");
         }
@@ -387,54 +1031,250 @@ impl TransferJob {
         if file_num >= self.files.len() {
             bail!("Wrong file number");
         }
+        if self.is_archive {
+            return self.write_archive(block).await;
+        }
         if file_num != self.file_num as usize || self.file.is_none() {
-            self.modify_time();
-            if let Some(file) = self.file.as_mut() {
+            if self.parallel > 1 {
+                // park the current file, the block may belong to another one in flight
+                if self.file.is_some() {
+                    let slot = self.take_slot();
+                    self.slots.push_back(slot);
+                }
+                if let Some(i) = self.slots.iter().position(|s| s.file_num == block.file_num) {
+                    if let Some(slot) = self.slots.remove(i) {
+                        self.load_slot(slot);
+                    }
+                }
+            } else if let Some(file) = self.file.take() {
+                // finish the previous file, the basis of the new one may be prepared already
                 file.sync_all().await?;
+                drop(file);
+                self.finish_file();
             }
+        }
+        if self.file.is_none() {
             self.file_num = block.file_num;
             let entry = &self.files[file_num];
-            let path = self.join(&entry.name);
+            let path = entry_path(&self.path, &entry.name)?;
             if let Some(p) = path.parent() {
                 std::fs::create_dir_all(p).ok();
             }
             let path = format!("{}.download", get_string(&path));
+            // a link sent under this name is replaced rather than written through
+            if let Ok(meta) = std::fs::symlink_metadata(&path) {
+                if meta.file_type().is_symlink() {
+                    std::fs::remove_file(&path).ok();
+                }
+            }
             self.file = Some(File::create(&path).await?);
+            self.hasher = Some(sha256::State::new());
         }
-        if block.compressed {
+        if block.copy_blocks > 0 {
+            self.finished_size += self
+                .write_basis_blocks(block.blk_id, block.copy_blocks)
+                .await?;
+        } else if block.compressed {
             let tmp = decompress(&block.data);
             self.file.as_mut().unwrap().write_all(&tmp).await?;
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&tmp);
+            }
             self.finished_size += tmp.len() as u64;
         } else {
             self.file.as_mut().unwrap().write_all(&block.data).await?;
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&block.data);
+            }
             self.finished_size += block.data.len() as u64;
         }
         self.transferred += block.data.len() as u64;
-        Ok(())
+        if !block.sha256.is_empty() {
+            let res = self.verify_file(&block.sha256).await?;
+            if self.parallel > 1 {
+                if let Some(file) = self.file.take() {
+                    file.sync_all().await?;
+                    drop(file);
+                    self.finish_file();
+                }
+            }
+            return Ok(res);
+        }
+        Ok(None)
     }
 
-    #[inline]
-    pub fn join(&self, name: &str) -> PathBuf {
-        if name.is_empty() {
-            self.path.clone()
+    async fn write_archive(
+        &mut self,
+        block: FileTransferBlock,
+    ) -> ResultType<Option<FileTransferVerified>> {
+        let base = archive_download_path(&self.path);
+        let extractor = self.extractor.get_or_insert_with(|| {
+            // leftovers of an interrupted transfer
+            std::fs::remove_dir_all(&base).ok();
+            ArchiveExtractor::new(base)
+        });
+        let hasher = self.hasher.get_or_insert_with(sha256::State::new);
+        if block.compressed {
+            let tmp = decompress(&block.data);
+            hasher.update(&tmp);
+            extractor.write(&tmp).await?;
         } else {
-            self.path.join(name)
+            hasher.update(&block.data);
+            extractor.write(&block.data).await?;
+        }
+        self.finished_size = extractor.content();
+        self.transferred += block.data.len() as u64;
+        if !block.sha256.is_empty() {
+            // extracted aside, only moved into place once the digest is checked
+            let res = extractor.finish();
+            self.extractor = None;
+            let tmp = archive_download_path(&self.path);
+            let digest = self.hasher.take().map(|h| h.finalize());
+            if res.is_err() || digest.as_ref().map(|d| &d.0[..]) != Some(&block.sha256[..]) {
+                std::fs::remove_dir_all(&tmp).ok();
+                res?;
+                bail!("File digest mismatch");
+            }
+            // the existing files are handled as by the digests of files sent one by one
+            let conflicts = if self.enable_overwrite_detection {
+                replaced_by_move(&tmp, &self.path)?
+            } else {
+                Vec::new()
+            };
+            match self.default_overwrite_strategy {
+                None if !conflicts.is_empty() => {
+                    log::info!(
+                        "id: {}, archive would replace {} files, waiting for confirm",
+                        self.id,
+                        conflicts.len()
+                    );
+                    self.archive_conflicts = conflicts;
+                    self.set_file_is_waiting(true);
+                }
+                strategy => self.move_archive(strategy.unwrap_or(true))?,
+            }
         }
+        Ok(None)
     }
 
-    pub async fn read(&mut self, stream: &mut Stream) -> ResultType<Option<FileTransferBlock>> {
-        let file_num = self.file_num as usize;
-        if file_num >= self.files.len() {
-            self.file.take();
-            return Ok(None);
+    fn move_archive(&mut self, overwrite: bool) -> ResultType<()> {
+        let tmp = archive_download_path(&self.path);
+        let res = move_into(&tmp, &self.path, overwrite);
+        std::fs::remove_dir_all(&tmp).ok();
+        res
+    }
+
+    /// The first of the files which the received archive would replace, if it waits for
+    /// `confirm_archive`.
+    #[inline]
+    pub fn archive_conflict(&self) -> Option<String> {
+        self.archive_conflicts.first().map(|p| get_string(p))
+    }
+
+    /// Move the received archive into place, replacing the existing files or keeping them.
+    pub fn confirm_archive(&mut self, overwrite: bool) -> ResultType<()> {
+        if self.archive_conflicts.is_empty() {
+            return Ok(());
         }
-        let name = &self.files[file_num].name;
-        if self.file.is_none() {
+        self.archive_conflicts.clear();
+        self.set_file_is_waiting(false);
+        self.move_archive(overwrite)
+    }
+
+    /// Whether the directory is transferred as one archive. The receiver learns it from
+    /// the `FileDirectory` replied by the sender.
+    #[inline]
+    pub fn set_archive(&mut self, is_archive: bool) {
+        self.is_archive = is_archive;
+    }
+
+    #[inline]
+    pub fn is_archive(&self) -> bool {
+        self.is_archive
+    }
+
+    #[inline]
+    pub fn join(&self, name: &str) -> PathBuf {
+        if name.is_empty() {
+            self.path.clone()
+        } else {
+            self.path.join(name)
+        }
+    }
+
+    /// Read the next block to send. With several files in flight the files take turns,
+    /// one block each.
+    pub async fn read(&mut self, stream: &mut Stream) -> ResultType<Option<FileTransferBlock>> {
+        if self.archive.is_some() {
+            return self.read_archive().await;
+        }
+        if self.parallel <= 1 && self.slots.is_empty() {
+            return self.read_file(stream).await;
+        }
+        // between the calls all the files in flight are parked, and `file_num` is
+        // the next file to start
+        let files = self.files.len();
+        for _ in 0..=self.slots.len() {
+            let next = self.file_num;
+            let start = (next as usize) < files
+                && self.slots.len() < self.parallel as usize
+                // the last file goes alone, so that its verification ends the job
+                && (next as usize + 1 < files || self.slots.is_empty());
+            let file_num = if start {
+                next
+            } else if let Some(slot) = self.slots.pop_front() {
+                let file_num = slot.file_num;
+                self.load_slot(slot);
+                file_num
+            } else {
+                break;
+            };
+            let res = self.read_file(stream).await;
+            let slot = self.take_slot();
+            if slot.file_num == file_num {
+                self.slots.push_back(slot);
+            }
+            self.file_num = if start { next + 1 } else { next };
+            match res {
+                Ok(None) => {}
+                res => return res,
+            }
+        }
+        Ok(None)
+    }
+
+    async fn read_file(&mut self, stream: &mut Stream) -> ResultType<Option<FileTransferBlock>> {
+        let file_num = self.file_num as usize;
+        if file_num >= self.files.len() {
+            self.file.take();
+            return Ok(None);
+        }
+        if is_link(&self.files[file_num]) {
+            // an existing file is replaced by the link only once confirmed
+            if self.enable_overwrite_detection && !self.file_confirmed() {
+                if !self.file_is_waiting() {
+                    self.send_current_digest(stream).await?;
+                    self.set_file_is_waiting(true);
+                }
+                return Ok(None);
+            }
+            // only the entry is sent, the receiver creates the link
+            self.hasher = Some(sha256::State::new());
+            return Ok(Some(FileTransferBlock {
+                id: self.id,
+                file_num: file_num as _,
+                sha256: self.finish_read_file().into(),
+                ..Default::default()
+            }));
+        }
+        let name = &self.files[file_num].name;
+        if self.file.is_none() {
             match File::open(self.join(name)).await {
                 Ok(file) => {
                     self.file = Some(file);
                     self.file_confirmed = false;
                     self.file_is_waiting = false;
+                    self.hasher = Some(sha256::State::new());
                 }
                 Err(err) => {
                     self.file_num += 1;
@@ -451,7 +1291,10 @@ impl TransferJob {
             }
             return Ok(None);
         }
-        const BUF_SIZE: usize = 128 * 1024;
+        if self.delta.is_some() {
+            let compressible = !is_compressed_file(name);
+            return self.read_delta(file_num, compressible).await;
+        }
         let mut buf: Vec<u8> = vec![0; BUF_SIZE];
         let mut compressed = false;
         let mut offset: usize = 0;
@@ -473,12 +1316,13 @@ impl TransferJob {
             }
         }
         unsafe { buf.set_len(offset) };
+        let mut sha256 = Vec::new();
         if offset == 0 {
-            self.file_num += 1;
-            self.file = None;
-            self.file_confirmed = false;
-            self.file_is_waiting = false;
+            sha256 = self.finish_read_file();
         } else {
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&buf);
+            }
             self.finished_size += offset as u64;
             if !is_compressed_file(name) {
                 let tmp = compress(&buf, COMPRESS_LEVEL);
@@ -494,27 +1338,137 @@ impl TransferJob {
             file_num: file_num as _,
             data: buf.into(),
             compressed,
+            sha256: sha256.into(),
             ..Default::default()
         }))
     }
 
+    async fn read_archive(&mut self) -> ResultType<Option<FileTransferBlock>> {
+        let file_num = self.file_num;
+        if file_num as usize >= self.files.len() {
+            return Ok(None);
+        }
+        let archive = self.archive.as_mut().unwrap();
+        let mut buf = Vec::with_capacity(BUF_SIZE);
+        if let Err(err) = archive.read(&mut buf, BUF_SIZE).await {
+            self.file_num += 1;
+            return Err(err);
+        }
+        self.finished_size = archive.content();
+        let hasher = self.hasher.get_or_insert_with(sha256::State::new);
+        let mut block = FileTransferBlock {
+            id: self.id,
+            file_num,
+            ..Default::default()
+        };
+        if buf.is_empty() {
+            block.sha256 = self.finish_read_file().into();
+            return Ok(Some(block));
+        }
+        hasher.update(&buf);
+        let tmp = compress(&buf, COMPRESS_LEVEL);
+        if tmp.len() < buf.len() {
+            buf = tmp;
+            block.compressed = true;
+        }
+        self.transferred += buf.len() as u64;
+        block.data = buf.into();
+        Ok(Some(block))
+    }
+
+    // Move on to the next file, returns the sha256 of the finished one.
+    fn finish_read_file(&mut self) -> Vec<u8> {
+        if self.peer_verify_hash && self.file_num as usize + 1 == self.files.len() {
+            self.verify_waiting = true;
+        }
+        self.file_num += 1;
+        self.file = None;
+        self.delta = None;
+        self.file_confirmed = false;
+        self.file_is_waiting = false;
+        self.hasher
+            .take()
+            .map(|h| h.finalize().0.to_vec())
+            .unwrap_or_default()
+    }
+
+    async fn read_delta(
+        &mut self,
+        file_num: usize,
+        compressible: bool,
+    ) -> ResultType<Option<FileTransferBlock>> {
+        let delta = self.delta.as_mut().unwrap();
+        let hasher = self.hasher.get_or_insert_with(sha256::State::new);
+        let op = match delta.next_op(self.file.as_mut().unwrap(), hasher).await {
+            Ok(op) => op,
+            Err(err) => {
+                self.file_num += 1;
+                self.file = None;
+                self.delta = None;
+                self.file_confirmed = false;
+                self.file_is_waiting = false;
+                return Err(err);
+            }
+        };
+        let mut block = FileTransferBlock {
+            id: self.id,
+            file_num: file_num as _,
+            ..Default::default()
+        };
+        match op {
+            DeltaOp::Literal(mut buf) => {
+                self.finished_size += buf.len() as u64;
+                if compressible {
+                    let tmp = compress(&buf, COMPRESS_LEVEL);
+                    if tmp.len() < buf.len() {
+                        buf = tmp;
+                        block.compressed = true;
+                    }
+                }
+                self.transferred += buf.len() as u64;
+                block.data = buf.into();
+            }
+            DeltaOp::Copy {
+                blk_id,
+                copy_blocks,
+                size,
+            } => {
+                self.finished_size += size;
+                block.blk_id = blk_id;
+                block.copy_blocks = copy_blocks;
+            }
+            DeltaOp::End => {
+                block.sha256 = self.finish_read_file().into();
+            }
+        }
+        Ok(Some(block))
+    }
+
     async fn send_current_digest(&mut self, stream: &mut Stream) -> ResultType<()> {
         let mut msg = Message::new();
         let mut resp = FileResponse::new();
-        let meta = self.file.as_ref().unwrap().metadata().await?;
-        let last_modified = meta
-            .modified()?
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs();
+        let (last_modified, file_size) = match self.file.as_ref() {
+            Some(file) => {
+                let meta = file.metadata().await?;
+                let last_modified = meta
+                    .modified()?
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs();
+                (last_modified, meta.len())
+            }
+            // a link, which has no content
+            None => (self.files[self.file_num as usize].modified_time, 0),
+        };
         resp.set_digest(FileTransferDigest {
             id: self.id,
             file_num: self.file_num,
             last_modified,
-            file_size: meta.len(),
+            file_size,
+            support_delta: true,
             ..Default::default()
         });
         msg.set_file_response(resp);
-        stream.send(&msg).await?;
+        stream.send_on(Channel::File, &msg).await?;
         log::info!(
             "id: {}, file_num:{}, digest message is sent. waiting for confirm. msg: {:?}",
             self.id,
@@ -574,7 +1528,9 @@ impl TransferJob {
     #[inline]
     pub fn job_completed(&self) -> bool {
         // has no error, Condition 2
-        !self.enable_overwrite_detection || (!self.file_confirmed && !self.file_is_waiting)
+        self.slots.is_empty()
+            && (!self.enable_overwrite_detection
+                || (!self.file_confirmed && !self.file_is_waiting && !self.verify_waiting))
     }
 
     /// Get job error message, useful for getting status when job had finished
@@ -585,9 +1541,85 @@ impl TransferJob {
         None
     }
 
+    /// Handle the digest replied by the receiver for the waiting file. The block checksums
+    /// of the peer's copy are kept, they are used if the file is confirmed to be overwritten.
+    #[inline]
+    pub fn set_peer_digest(&mut self, digest: &FileTransferDigest) {
+        let checksums = digest.checksums.clone().into_option();
+        if self.file_num == digest.file_num {
+            self.peer_checksums = checksums;
+        } else {
+            self.with_slot(digest.file_num, |job| job.peer_checksums = checksums);
+        }
+        if digest.verify_hash {
+            self.peer_verify_hash = true;
+        }
+    }
+
+    /// Handle the verification result of the receiver, a file may be asked to be sent
+    /// once more.
+    pub fn verified(&mut self, v: &FileTransferVerified) {
+        let is_last = v.file_num as usize + 1 == self.files.len();
+        if v.ok {
+            if is_last {
+                self.verify_waiting = false;
+            }
+            return;
+        }
+        if is_last || self.parallel <= 1 {
+            self.verify_waiting = false;
+        }
+        if !v.retransmit || !self.enable_overwrite_detection {
+            return;
+        }
+        let file_num = v.file_num as usize;
+        if file_num >= self.files.len() || v.file_num > self.file_num {
+            return;
+        }
+        log::info!("id: {}, send file {} again", self.id, v.file_num);
+        self.finished_size = self.finished_size.saturating_sub(self.files[file_num].size);
+        if self.parallel > 1 {
+            // the other files in flight go on
+            if !self.slots.iter().any(|s| s.file_num == v.file_num) {
+                self.slots.push_front(FileSlot {
+                    file_num: v.file_num,
+                    ..Default::default()
+                });
+            }
+            return;
+        }
+        self.file_num = v.file_num;
+        self.file = None;
+        self.delta = None;
+        self.peer_checksums = None;
+        self.hasher = None;
+        self.file_confirmed = false;
+        self.file_is_waiting = false;
+    }
+
+    fn start_delta(&mut self, checksums: FileTransferChecksums) {
+        if checksums.file_num != self.file_num || checksums.blocks.is_empty() {
+            return;
+        }
+        if checksums.block_size < DELTA_MIN_BLOCK_SIZE
+            || checksums.block_size > DELTA_MAX_BLOCK_SIZE
+        {
+            log::warn!("invalid delta block size {}", checksums.block_size);
+            return;
+        }
+        log::info!(
+            "id: {}, file_num: {}, delta transfer against {} blocks",
+            self.id,
+            self.file_num,
+            checksums.blocks.len()
+        );
+        self.delta = Some(DeltaReader::new(checksums));
+    }
+
     pub fn set_file_skipped(&mut self) -> bool {
         log::debug!("skip file {} in job {}", self.file_num, self.id);
         self.file.take();
+        self.peer_checksums = None;
         self.set_file_confirmed(false);
         self.set_file_is_waiting(false);
         self.file_num += 1;
@@ -596,24 +1628,92 @@ impl TransferJob {
     }
 
     pub fn confirm(&mut self, r: &FileTransferSendConfirmRequest) -> bool {
-        if self.file_num() != r.file_num {
+        if r.verify_hash {
+            self.peer_verify_hash = true;
+        }
+        if self.file_num() == r.file_num {
+            self.confirm_file(r);
+        } else if self
+            .with_slot(r.file_num, |job| job.confirm_file(r))
+            .is_none()
+        {
             log::info!("file num truncated, ignoring");
-        } else {
-            match r.union {
-                Some(file_transfer_send_confirm_request::Union::Skip(s)) => {
-                    if s {
-                        self.set_file_skipped();
-                    } else {
-                        self.set_file_confirmed(true);
-                    }
-                }
-                Some(file_transfer_send_confirm_request::Union::OffsetBlk(_offset)) => {
+        }
+        true
+    }
+
+    fn confirm_file(&mut self, r: &FileTransferSendConfirmRequest) {
+        match r.union {
+            Some(file_transfer_send_confirm_request::Union::Skip(s)) => {
+                if s {
+                    self.set_file_skipped();
+                } else {
                     self.set_file_confirmed(true);
                 }
-                _ => {}
             }
+            Some(file_transfer_send_confirm_request::Union::OffsetBlk(_offset)) => {
+                self.set_file_confirmed(true);
+                if let Some(checksums) = self.peer_checksums.take() {
+                    self.start_delta(checksums);
+                }
+            }
+            Some(file_transfer_send_confirm_request::Union::Checksums(ref checksums)) => {
+                self.set_file_confirmed(true);
+                self.peer_checksums = None;
+                self.start_delta(checksums.clone());
+            }
+            _ => {}
         }
-        true
+    }
+
+    /// Number of files read or written at once, capped by `MAX_PARALLEL`.
+    /// Both sides of the job must agree on it.
+    #[inline]
+    pub fn set_parallel(&mut self, parallel: u32) {
+        self.parallel = parallel.min(MAX_PARALLEL);
+    }
+
+    #[inline]
+    pub fn parallel(&self) -> u32 {
+        self.parallel
+    }
+
+    fn take_slot(&mut self) -> FileSlot {
+        FileSlot {
+            file_num: self.file_num,
+            file: self.file.take(),
+            file_confirmed: std::mem::take(&mut self.file_confirmed),
+            file_is_waiting: std::mem::take(&mut self.file_is_waiting),
+            peer_checksums: self.peer_checksums.take(),
+            delta: self.delta.take(),
+            hasher: self.hasher.take(),
+        }
+    }
+
+    fn load_slot(&mut self, slot: FileSlot) {
+        self.file_num = slot.file_num;
+        self.file = slot.file;
+        self.file_confirmed = slot.file_confirmed;
+        self.file_is_waiting = slot.file_is_waiting;
+        self.peer_checksums = slot.peer_checksums;
+        self.delta = slot.delta;
+        self.hasher = slot.hasher;
+    }
+
+    // Run `f` on the parked file `file_num` as if it were the current one.
+    fn with_slot<R>(&mut self, file_num: i32, f: impl FnOnce(&mut Self) -> R) -> Option<R> {
+        let i = self.slots.iter().position(|s| s.file_num == file_num)?;
+        let slot = self.slots.remove(i)?;
+        let current = self.take_slot();
+        self.load_slot(slot);
+        let res = f(self);
+        let slot = self.take_slot();
+        // a skipped file is not in flight any more
+        if slot.file_num == file_num {
+            self.slots.insert(i, slot);
+        }
+        self.load_slot(current);
+        Some(res)
     }
 
     #[inline]
@@ -644,12 +1744,28 @@ pub fn new_error<T: std::string::ToString>(id: i32, err: T, file_num: i32) -> Me
 }
 
 #[inline]
-pub fn new_dir(id: i32, path: String, files: Vec<FileEntry>) -> Message {
+pub fn new_dir(id: i32, path: String, files: Vec<FileEntry>, dirs: Vec<FileEntry>) -> Message {
     let mut resp = FileResponse::new();
     resp.set_dir(FileDirectory {
         id,
         path,
         entries: files,
+        dirs,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_response(resp);
+    msg_out
+}
+
+#[inline]
+pub fn new_archive_dir(id: i32, path: String, files: Vec<FileEntry>) -> Message {
+    let mut resp = FileResponse::new();
+    resp.set_dir(FileDirectory {
+        id,
+        path,
+        entries: files,
+        archive: true,
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -666,6 +1782,15 @@ pub fn new_block(block: FileTransferBlock) -> Message {
     msg_out
 }
 
+#[inline]
+pub fn new_verified(v: FileTransferVerified) -> Message {
+    let mut msg_out = Message::new();
+    let mut action = FileAction::new();
+    action.set_verified(v);
+    msg_out.set_file_action(action);
+    msg_out
+}
+
 #[inline]
 pub fn new_send_confirm(r: FileTransferSendConfirmRequest) -> Message {
     let mut msg_out = Message::new();
@@ -676,13 +1801,22 @@ pub fn new_send_confirm(r: FileTransferSendConfirmRequest) -> Message {
 }
 
 #[inline]
-pub fn new_receive(id: i32, path: String, file_num: i32, files: Vec<FileEntry>) -> Message {
+pub fn new_receive(
+    id: i32,
+    path: String,
+    file_num: i32,
+    files: Vec<FileEntry>,
+    dirs: Vec<FileEntry>,
+    parallel: u32,
+) -> Message {
     let mut action = FileAction::new();
     action.set_receive(FileTransferReceiveRequest {
         id,
         path,
         files,
         file_num,
+        parallel,
+        dirs,
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -691,7 +1825,14 @@ pub fn new_receive(id: i32, path: String, file_num: i32, files: Vec<FileEntry>)
 }
 
 #[inline]
-pub fn new_send(id: i32, path: String, file_num: i32, include_hidden: bool) -> Message {
+pub fn new_send(
+    id: i32,
+    path: String,
+    file_num: i32,
+    include_hidden: bool,
+    parallel: u32,
+    archive: bool,
+) -> Message {
     log::info!("new send: {},id : {}", path, id);
     let mut action = FileAction::new();
     action.set_send(FileTransferSendRequest {
@@ -699,6 +1840,8 @@ pub fn new_send(id: i32, path: String, file_num: i32, include_hidden: bool) -> M
         path,
         include_hidden,
         file_num,
+        parallel,
+        archive,
         ..Default::default()
     });
     let mut msg_out = Message::new();
@@ -729,34 +1872,176 @@ pub fn get_job(id: i32, jobs: &mut [TransferJob]) -> Option<&mut TransferJob> {
     jobs.iter_mut().find(|x| x.id() == id)
 }
 
+/// Paces the blocks sent by [`handle_read_jobs`].
+///
+/// The rate is capped by the configured and the session limits, and is lowered
+/// further while `TestDelay` reports rising latency, so that the file transfer
+/// leaves room on the network path for the rest of the session. The blocks go on
+/// their own [`Channel::File`], which only orders them behind the queued messages
+/// of the peer connection, it does not bound how much the path is loaded.
+pub struct TransferScheduler {
+    // all limits in bytes per second, 0 means unlimited
+    config_limit: u32,
+    session_limit: u32,
+    congestion_limit: u32,
+    base_delay: Option<u32>,
+    tokens: f64,
+    last_refill: Instant,
+    sent: usize,
+    sent_since: Instant,
+}
+
+impl Default for TransferScheduler {
+    fn default() -> Self {
+        Self::new(parse_rate_limit(&Config::get_option(
+            OPTION_FILE_TRANSFER_RATE_LIMIT,
+        )))
+    }
+}
+
+impl TransferScheduler {
+    /// `limit` is in KB/s, 0 means unlimited.
+    pub fn new(limit: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            config_limit: limit.saturating_mul(1024),
+            session_limit: 0,
+            congestion_limit: 0,
+            base_delay: None,
+            tokens: 0.,
+            last_refill: now,
+            sent: 0,
+            sent_since: now,
+        }
+    }
+
+    /// `limit` is in KB/s, 0 means unlimited.
+    #[inline]
+    pub fn set_session_limit(&mut self, limit: u32) {
+        self.session_limit = limit.saturating_mul(1024);
+    }
+
+    /// Current limit in bytes per second, 0 means unlimited.
+    pub fn limit(&self) -> u32 {
+        [self.config_limit, self.session_limit, self.congestion_limit]
+            .iter()
+            .copied()
+            .filter(|x| *x > 0)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Feed the latest network delay in ms.
+    pub fn update_delay(&mut self, delay: u32) {
+        let elapsed = self.sent_since.elapsed().as_secs_f64();
+        let rate = if elapsed > 0. {
+            (self.sent as f64 / elapsed) as u32
+        } else {
+            0
+        };
+        self.sent = 0;
+        self.sent_since = Instant::now();
+        let base = match self.base_delay {
+            Some(base) if base <= delay => base,
+            _ => {
+                self.base_delay = Some(delay);
+                delay
+            }
+        };
+        if delay > base + RATE_DELAY_THRESHOLD.max(base) {
+            if rate > 0 {
+                let limit = (rate / 2).max(RATE_MIN_LIMIT);
+                if self.congestion_limit == 0 || limit < self.congestion_limit {
+                    log::debug!(
+                        "file transfer backs off to {} B/s, delay {}ms",
+                        limit,
+                        delay
+                    );
+                    self.congestion_limit = limit;
+                }
+            }
+        } else if self.congestion_limit > 0 {
+            let limit = self
+                .congestion_limit
+                .saturating_add(self.congestion_limit / 4);
+            // the cap is no longer what holds the transfer back
+            if (rate as u64) * 2 < limit as u64 {
+                self.congestion_limit = 0;
+            } else {
+                self.congestion_limit = limit;
+            }
+        }
+    }
+
+    pub fn can_send(&mut self) -> bool {
+        let limit = self.limit();
+        if limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * limit as f64).min(limit as f64 * RATE_BURST);
+        self.tokens > 0.
+    }
+
+    #[inline]
+    pub fn on_sent(&mut self, size: usize) {
+        self.sent += size;
+        if self.limit() > 0 {
+            self.tokens -= size as f64;
+        }
+    }
+}
+
+#[inline]
+pub fn parse_rate_limit(v: &str) -> u32 {
+    v.trim().parse().unwrap_or(0)
+}
+
+/// Send the next blocks of the read jobs. Returns the ids of the jobs which failed to read a
+/// file or finished, with the error sent to the peer if any.
 pub async fn handle_read_jobs(
     jobs: &mut Vec<TransferJob>,
     stream: &mut crate::Stream,
-) -> ResultType<()> {
+    scheduler: &mut TransferScheduler,
+) -> ResultType<Vec<(i32, Option<String>)>> {
+    let mut res = Vec::new();
     let mut finished = Vec::new();
     for job in jobs.iter_mut() {
         if job.is_last_job {
             continue;
         }
+        if !scheduler.can_send() {
+            break;
+        }
         match job.read(stream).await {
             Err(err) => {
+                res.push((job.id(), Some(err.to_string())));
                 stream
-                    .send(&new_error(job.id(), err, job.file_num()))
+                    .send_on(Channel::File, &new_error(job.id(), err, job.file_num()))
                     .await?;
             }
             Ok(Some(block)) => {
-                stream.send(&new_block(block)).await?;
+                scheduler.on_sent(block.data.len());
+                stream.send_on(Channel::File, &new_block(block)).await?;
             }
             Ok(None) => {
                 if job.job_completed() {
                     finished.push(job.id());
                     let err = job.job_error();
+                    res.push((job.id(), err.clone()));
                     if err.is_some() {
                         stream
-                            .send(&new_error(job.id(), err.unwrap(), job.file_num()))
+                            .send_on(
+                                Channel::File,
+                                &new_error(job.id(), err.unwrap(), job.file_num()),
+                            )
                             .await?;
                     } else {
-                        stream.send(&new_done(job.id(), job.file_num())).await?;
+                        stream
+                            .send_on(Channel::File, &new_done(job.id(), job.file_num()))
+                            .await?;
                     }
                 } else {
                     // waiting confirmation.
@@ -767,7 +2052,7 @@ pub async fn handle_read_jobs(
     for id in finished {
         remove_job(id, jobs);
     }
-    Ok(())
+    Ok(res)
 }
 
 pub fn remove_all_empty_dir(path: &PathBuf) -> ResultType<()> {
@@ -799,6 +2084,278 @@ pub fn create_dir(dir: &str) -> ResultType<()> {
     Ok(())
 }
 
+fn get_entry(path: &Path) -> ResultType<FileEntry> {
+    let meta = std::fs::symlink_metadata(path)?;
+    let entry_type = if meta.file_type().is_symlink() {
+        if path.is_dir() {
+            FileType::DirLink
+        } else {
+            FileType::FileLink
+        }
+    } else if meta.is_dir() {
+        FileType::Dir
+    } else {
+        FileType::File
+    };
+    let mut entry = FileEntry {
+        name: get_file_name(path),
+        entry_type: entry_type.into(),
+        size: if entry_type == FileType::File {
+            meta.len()
+        } else {
+            0
+        },
+        modified_time: meta
+            .modified()
+            .map(|x| {
+                x.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                    .map(|x| x.as_secs())
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0),
+        ..Default::default()
+    };
+    set_entry_meta(&mut entry, path, &meta);
+    Ok(entry)
+}
+
+fn check_destination(from: &Path, to: &Path) -> ResultType<()> {
+    if from.is_dir() && to.starts_with(from) {
+        bail!("Cannot move or copy a directory into itself");
+    }
+    Ok(())
+}
+
+// The existence of the destination is checked by its creation, so that nothing
+// created there meanwhile is replaced.
+fn exists_error(err: std::io::Error, path: &Path) -> anyhow::Error {
+    if err.kind() == std::io::ErrorKind::AlreadyExists {
+        anyhow::anyhow!("{} already exists", get_string(path))
+    } else {
+        err.into()
+    }
+}
+
+// Links are copied as links, metadata is restored as on transfer.
+fn copy_path(from: &Path, to: &Path) -> ResultType<()> {
+    let root = get_entry(from)?;
+    if root.entry_type.enum_value() != Ok(FileType::Dir) {
+        if is_link(&root) {
+            create_link(&root, to).map_err(|err| exists_error(err, to))?;
+            restore_owner(&root, to);
+            return Ok(());
+        }
+        let mut src = std::fs::File::open(from)?;
+        let mut dst = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(to)
+            .map_err(|err| exists_error(err, to))?;
+        if let Err(err) = std::io::copy(&mut src, &mut dst) {
+            drop(dst);
+            std::fs::remove_file(to).ok();
+            return Err(err.into());
+        }
+        drop(dst);
+        restore_entry_meta(&root, to);
+        return Ok(());
+    }
+    std::fs::create_dir(to).map_err(|err| exists_error(err, to))?;
+    if let Err(err) = copy_dir(from, to) {
+        std::fs::remove_dir_all(to).ok();
+        return Err(err);
+    }
+    restore_entry_meta(&root, to);
+    Ok(())
+}
+
+// The content of `from` into the new directory `to`.
+fn copy_dir(from: &Path, to: &Path) -> ResultType<()> {
+    let entries = read_dir_recursive(&from.to_path_buf(), &PathBuf::new(), true, true)?;
+    for entry in entries.iter() {
+        let path = to.join(&entry.name);
+        match entry.entry_type.enum_value() {
+            Ok(FileType::Dir) => std::fs::create_dir(&path)?,
+            Ok(FileType::File) => {
+                std::fs::copy(from.join(&entry.name), &path)?;
+                restore_entry_meta(entry, &path);
+            }
+            _ if is_link(entry) => {
+                create_link(entry, &path)?;
+                restore_owner(entry, &path);
+            }
+            _ => {}
+        }
+    }
+    // children first, their creation touches the parent's mtime
+    for entry in entries.iter().rev() {
+        if entry.entry_type.enum_value() == Ok(FileType::Dir) {
+            restore_entry_meta(entry, &to.join(&entry.name));
+        }
+    }
+    Ok(())
+}
+
+// Like `std::fs::rename`, but failing if `to` exists rather than replacing it.
+fn rename_no_replace(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        const RENAME_NOREPLACE: libc::c_uint = 1;
+        let f = std::ffi::CString::new(from.as_os_str().as_bytes())?;
+        let t = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                libc::AT_FDCWD,
+                f.as_ptr(),
+                libc::AT_FDCWD,
+                t.as_ptr(),
+                RENAME_NOREPLACE,
+            )
+        };
+        if res == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        // older kernels and file systems without the flag
+        if !matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) {
+            return Err(err);
+        }
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let f = std::ffi::CString::new(from.as_os_str().as_bytes())?;
+        let t = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+        if unsafe { libc::renamex_np(f.as_ptr(), t.as_ptr(), libc::RENAME_EXCL) } == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ENOTSUP) {
+            return Err(err);
+        }
+    }
+    if !std::fs::symlink_metadata(from)?.is_dir() {
+        // a link is never made over an existing file
+        match std::fs::hard_link(from, to) {
+            Ok(_) => return std::fs::remove_file(from),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => return Err(err),
+            Err(_) => {}
+        }
+    }
+    // nothing left which checks it atomically
+    if std::fs::symlink_metadata(to).is_ok() {
+        return Err(std::io::ErrorKind::AlreadyExists.into());
+    }
+    std::fs::rename(from, to)
+}
+
+fn is_cross_device(err: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    return err.raw_os_error() == Some(libc::EXDEV);
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(windows)]
+    return err.raw_os_error() == Some(17);
+    #[cfg(not(any(unix, windows)))]
+    return false;
+}
+
+pub fn rename(from: &str, to: &str) -> ResultType<()> {
+    let from = get_path(from);
+    let to = get_path(to);
+    check_destination(&from, &to)?;
+    if let Err(err) = rename_no_replace(&from, &to) {
+        if !is_cross_device(&err) {
+            return Err(exists_error(err, &to));
+        }
+        // copy_path cleans up what it created
+        copy_path(&from, &to)?;
+        if get_entry(&from)?.entry_type.enum_value() == Ok(FileType::Dir) {
+            std::fs::remove_dir_all(&from)?;
+        } else {
+            std::fs::remove_file(&from)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn copy(from: &str, to: &str) -> ResultType<()> {
+    let from = get_path(from);
+    let to = get_path(to);
+    check_destination(&from, &to)?;
+    copy_path(&from, &to)
+}
+
+// Start of the last `lines` lines, not going further back than `max` bytes from the end,
+// in which case the first whole line in reach is the start.
+// A newline ending the file does not count as an empty last line.
+fn tail_offset(file: &mut std::fs::File, size: u64, lines: u32, max: u64) -> ResultType<u64> {
+    use std::io::{Read, Seek};
+    let start = size.saturating_sub(max);
+    let mut end = size;
+    let mut buf = vec![0u8; 8 * 1024];
+    let mut count = 0;
+    let mut line_start = None;
+    while end > start {
+        let n = (end - start).min(buf.len() as u64) as usize;
+        let pos = end - n as u64;
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut buf[..n])?;
+        for i in (0..n).rev() {
+            let next = pos + i as u64 + 1;
+            if buf[i] == b'\n' && next != size {
+                count += 1;
+                if count == lines {
+                    return Ok(next);
+                }
+                line_start = Some(next);
+            }
+        }
+        end = pos;
+    }
+    if start == 0 {
+        return Ok(0);
+    }
+    Ok(line_start.unwrap_or(start))
+}
+
+pub fn read_range(r: &FileReadRange) -> ResultType<FileRangeData> {
+    use std::io::{Read, Seek};
+    let mut file = std::fs::File::open(get_path(&r.path))?;
+    let file_size = file.metadata()?.len();
+    let length = if r.length == 0 || r.length > MAX_READ_RANGE {
+        MAX_READ_RANGE
+    } else {
+        r.length
+    } as u64;
+    let offset = if r.tail_lines > 0 {
+        tail_offset(&mut file, file_size, r.tail_lines, length)?
+    } else {
+        r.offset.min(file_size)
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(length).read_to_end(&mut data)?;
+    Ok(FileRangeData {
+        id: r.id,
+        path: r.path.clone(),
+        offset,
+        data: data.into(),
+        file_size,
+        ..Default::default()
+    })
+}
+
+#[inline]
+pub fn new_range_data(data: FileRangeData) -> Message {
+    let mut resp = FileResponse::new();
+    resp.set_range_data(data);
+    let mut msg_out = Message::new();
+    msg_out.set_file_response(resp);
+    msg_out
+}
+
 #[inline]
 pub fn transform_windows_path(entries: &mut Vec<FileEntry>) {
     for entry in entries {
@@ -806,6 +2363,227 @@ pub fn transform_windows_path(entries: &mut Vec<FileEntry>) {
     }
 }
 
+// Where an archive is extracted before it is verified.
+#[inline]
+fn archive_download_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.download", get_string(path)))
+}
+
+#[inline]
+fn delta_block_size(file_size: u64) -> u32 {
+    // about sqrt(size) as rsync does, aligned to 1k
+    let size = ((file_size as f64).sqrt() as u32 + 1023) & !1023;
+    size.clamp(DELTA_MIN_BLOCK_SIZE, DELTA_MAX_BLOCK_SIZE)
+}
+
+#[inline]
+fn strong_checksum(data: &[u8]) -> Vec<u8> {
+    sha256::hash(data).0[..DELTA_STRONG_LEN].to_vec()
+}
+
+fn block_checksums(path: &Path, block_size: u32) -> ResultType<Vec<FileTransferBlockChecksum>> {
+    use std::io::Read;
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut buf = vec![0; block_size as usize];
+    let mut blocks = Vec::new();
+    loop {
+        // the trailing partial block is always sent literally
+        match file.read_exact(&mut buf) {
+            Ok(()) => blocks.push(FileTransferBlockChecksum {
+                weak: RollingChecksum::new(&buf).digest(),
+                strong: strong_checksum(&buf).into(),
+                ..Default::default()
+            }),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(blocks)
+}
+
+/// Adler-32 like weak checksum which can be rolled forward byte by byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, x) in data.iter().enumerate() {
+            a = a.wrapping_add(*x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*x as u32));
+        }
+        Self { a, b, len }
+    }
+
+    #[inline]
+    fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    #[inline]
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+enum DeltaOp {
+    Literal(Vec<u8>),
+    Copy {
+        blk_id: u32,
+        copy_blocks: u32,
+        size: u64,
+    },
+    End,
+}
+
+/// Sender side of the delta transfer, it slides over the local file looking for blocks
+/// which the receiver already has.
+struct DeltaReader {
+    block_size: usize,
+    table: HashMap<u32, Vec<(u32, Vec<u8>)>>,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    // checksum of buf[pos..pos + block_size]
+    rolling: Option<RollingChecksum>,
+}
+
+impl DeltaReader {
+    fn new(checksums: FileTransferChecksums) -> Self {
+        let mut table: HashMap<u32, Vec<(u32, Vec<u8>)>> = HashMap::new();
+        for (i, blk) in checksums.blocks.into_iter().enumerate() {
+            table
+                .entry(blk.weak)
+                .or_default()
+                .push((i as u32, blk.strong.into()));
+        }
+        Self {
+            block_size: checksums.block_size as _,
+            table,
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+            rolling: None,
+        }
+    }
+
+    // make sure there are at least `block_size + 1` unconsumed bytes unless eof
+    async fn fill(&mut self, file: &mut File) -> ResultType<()> {
+        if self.pos >= BUF_SIZE {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        while !self.eof && self.buf.len() - self.pos <= self.block_size {
+            let len = self.buf.len();
+            self.buf.resize(len + BUF_SIZE, 0);
+            let n = file.read(&mut self.buf[len..]).await?;
+            self.buf.truncate(len + n);
+            if n == 0 {
+                self.eof = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn find(&self, weak: u32, data: &[u8]) -> Option<u32> {
+        let candidates = self.table.get(&weak)?;
+        let strong = strong_checksum(data);
+        candidates
+            .iter()
+            .find(|(_, s)| *s == strong)
+            .map(|(i, _)| *i)
+    }
+
+    fn matches(&self, blk_id: u32, data: &[u8]) -> bool {
+        match self.table.get(&RollingChecksum::new(data).digest()) {
+            Some(candidates) => {
+                let strong = strong_checksum(data);
+                candidates.iter().any(|(i, s)| *i == blk_id && *s == strong)
+            }
+            None => false,
+        }
+    }
+
+    // `hasher` is fed with all the data of the file covered by the returned op
+    async fn next_op(
+        &mut self,
+        file: &mut File,
+        hasher: &mut sha256::State,
+    ) -> ResultType<DeltaOp> {
+        let bs = self.block_size;
+        let mut literal = Vec::new();
+        loop {
+            self.fill(file).await?;
+            let avail = self.buf.len() - self.pos;
+            if avail < bs {
+                literal.extend_from_slice(&self.buf[self.pos..]);
+                self.pos = self.buf.len();
+                self.rolling = None;
+                if literal.is_empty() {
+                    return Ok(DeltaOp::End);
+                }
+                hasher.update(&literal);
+                return Ok(DeltaOp::Literal(literal));
+            }
+            let window = &self.buf[self.pos..self.pos + bs];
+            let mut sum = match self.rolling {
+                Some(sum) => sum,
+                None => RollingChecksum::new(window),
+            };
+            if let Some(blk_id) = self.find(sum.digest(), window) {
+                if !literal.is_empty() {
+                    // the match is picked up again by the next call
+                    self.rolling = Some(sum);
+                    hasher.update(&literal);
+                    return Ok(DeltaOp::Literal(literal));
+                }
+                hasher.update(window);
+                self.pos += bs;
+                self.rolling = None;
+                let mut copy_blocks: u32 = 1;
+                while (copy_blocks as u64 + 1) * (bs as u64) <= DELTA_MAX_COPY_SIZE {
+                    self.fill(file).await?;
+                    if self.buf.len() - self.pos < bs
+                        || !self.matches(blk_id + copy_blocks, &self.buf[self.pos..self.pos + bs])
+                    {
+                        break;
+                    }
+                    hasher.update(&self.buf[self.pos..self.pos + bs]);
+                    self.pos += bs;
+                    copy_blocks += 1;
+                }
+                return Ok(DeltaOp::Copy {
+                    blk_id,
+                    copy_blocks,
+                    size: copy_blocks as u64 * bs as u64,
+                });
+            }
+            literal.push(self.buf[self.pos]);
+            if self.pos + bs < self.buf.len() {
+                sum.roll(self.buf[self.pos], self.buf[self.pos + bs]);
+                self.rolling = Some(sum);
+            } else {
+                self.rolling = None;
+            }
+            self.pos += 1;
+            if literal.len() >= BUF_SIZE {
+                hasher.update(&literal);
+                return Ok(DeltaOp::Literal(literal));
+            }
+        }
+    }
+}
+
 pub enum DigestCheckResult {
     IsSame,
     NeedConfirm(FileTransferDigest),
@@ -842,3 +2620,481 @@ pub fn is_write_need_confirmation(
         Ok(DigestCheckResult::NoSuchFile)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data: Vec<u8> = (0..4096u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let len = 1024;
+        let mut sum = RollingChecksum::new(&data[..len]);
+        for i in 1..data.len() - len {
+            sum.roll(data[i - 1], data[i + len - 1]);
+            assert_eq!(sum, RollingChecksum::new(&data[i..i + len]));
+        }
+    }
+
+    #[test]
+    fn test_transfer_scheduler() {
+        let mut scheduler = TransferScheduler::new(0);
+        assert!(scheduler.can_send());
+        scheduler.set_session_limit(100);
+        assert_eq!(scheduler.limit(), 100 * 1024);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(scheduler.can_send());
+        scheduler.on_sent(BUF_SIZE);
+        assert!(!scheduler.can_send());
+
+        let mut scheduler = TransferScheduler::new(0);
+        scheduler.update_delay(20);
+        scheduler.on_sent(10 * 1024 * 1024);
+        std::thread::sleep(Duration::from_millis(10));
+        scheduler.update_delay(300);
+        let limit = scheduler.limit();
+        assert!(limit >= RATE_MIN_LIMIT);
+        scheduler.on_sent(limit as usize);
+        std::thread::sleep(Duration::from_millis(10));
+        scheduler.update_delay(20);
+        assert!(scheduler.limit() > limit);
+        scheduler.update_delay(20);
+        assert_eq!(scheduler.limit(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_delta_reader() {
+        let dir = std::env::temp_dir().join(format!("delta_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut seed: u32 = 1;
+        let old: Vec<u8> = (0..3_000_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut new = old.clone();
+        new[100_000..100_010].copy_from_slice(&[0; 10]);
+        new.splice(2_000_000..2_000_000, vec![1u8; 5000]);
+        new.truncate(2_900_000);
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        std::fs::write(&old_path, &old).unwrap();
+        std::fs::write(&new_path, &new).unwrap();
+
+        let block_size = delta_block_size(old.len() as _);
+        let blocks = block_checksums(&old_path, block_size).unwrap();
+        let mut reader = DeltaReader::new(FileTransferChecksums {
+            block_size,
+            blocks,
+            ..Default::default()
+        });
+        let mut file = File::open(&new_path).await.unwrap();
+        let mut hasher = sha256::State::new();
+        let mut out = Vec::new();
+        let mut literal_size = 0;
+        loop {
+            match reader.next_op(&mut file, &mut hasher).await.unwrap() {
+                DeltaOp::Literal(data) => {
+                    literal_size += data.len();
+                    out.extend(data);
+                }
+                DeltaOp::Copy {
+                    blk_id,
+                    copy_blocks,
+                    size,
+                } => {
+                    let start = blk_id as usize * block_size as usize;
+                    assert_eq!(size, copy_blocks as u64 * block_size as u64);
+                    out.extend_from_slice(&old[start..start + size as usize]);
+                }
+                DeltaOp::End => break,
+            }
+        }
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(out == new);
+        assert_eq!(hasher.finalize(), sha256::hash(&new));
+        assert!(literal_size < 4 * block_size as usize + 5000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_entry_meta() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("meta_test_{}", std::process::id()));
+        let src = dir.join("src");
+        let dst = dir.join("dst");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(&dst).unwrap();
+        std::fs::write(src.join("run.sh"), b"").unwrap();
+        std::fs::set_permissions(src.join("run.sh"), std::fs::Permissions::from_mode(0o750))
+            .unwrap();
+        std::os::unix::fs::symlink("run.sh", src.join("link")).unwrap();
+        let mut files = get_recursive_files(&get_string(&src), false).unwrap();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].link_target, "run.sh");
+        assert_eq!(files[1].mode, 0o750);
+        for entry in files.iter() {
+            let path = dst.join(&entry.name);
+            if !is_link(entry) {
+                std::fs::write(&path, b"").unwrap();
+            }
+            restore_entry_meta(entry, &path);
+        }
+        assert_eq!(
+            std::fs::read_link(dst.join("link")).unwrap(),
+            get_path("run.sh")
+        );
+        let mode = std::fs::metadata(dst.join("run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o750);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_links() {
+        let dir = std::env::temp_dir().join(format!("follow_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), b"abc").unwrap();
+        std::os::unix::fs::symlink("a", dir.join("link")).unwrap();
+        std::os::unix::fs::symlink("sub", dir.join("dir_link")).unwrap();
+        let mut job =
+            TransferJob::new_read(1, "".to_owned(), get_string(&dir), 0, false, false, false)
+                .unwrap();
+        assert_eq!(job.files().len(), 3);
+        job.follow_links();
+        let mut files = job.files().clone();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].name, "link");
+        assert_eq!(files[1].entry_type.enum_value(), Ok(FileType::File));
+        assert_eq!(files[1].size, 3);
+        assert!(files[1].link_target.is_empty());
+        assert_eq!(job.total_size(), 6);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_target() {
+        assert!(is_link_target_inside("link", "run.sh"));
+        assert!(is_link_target_inside("a/b/link", "../c/run.sh"));
+        assert!(is_link_target_inside("a/link", "./../run.sh"));
+        assert!(!is_link_target_inside("link", "../run.sh"));
+        assert!(!is_link_target_inside("a/link", "../../run.sh"));
+        assert!(!is_link_target_inside("a/link", "/etc/passwd"));
+        let dir = std::env::temp_dir().join(format!("link_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::os::unix::fs::symlink("a", dir.join("link")).unwrap();
+        assert_eq!(entry_path(&dir, "a/b").unwrap(), dir.join("a").join("b"));
+        assert!(entry_path(&dir, "link/b").is_err());
+        assert!(entry_path(&dir, "../b").is_err());
+        // a link leading out is not created
+        let entry = FileEntry {
+            name: "out".to_owned(),
+            entry_type: FileType::FileLink.into(),
+            link_target: "../out".to_owned(),
+            ..Default::default()
+        };
+        restore_entry_meta(&entry, &dir.join("out"));
+        assert!(std::fs::symlink_metadata(dir.join("out")).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rename_copy() {
+        let dir = std::env::temp_dir().join(format!("rename_test_{}", std::process::id()));
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub").join("a"), b"abc").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("sub", src.join("link")).unwrap();
+        let path = |p: &Path| get_string(p);
+        copy(&path(&src), &path(&dir.join("copy"))).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("copy").join("sub").join("a")).unwrap(),
+            b"abc"
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read_link(dir.join("copy").join("link")).unwrap(),
+            get_path("sub")
+        );
+        assert!(copy(&path(&src), &path(&dir.join("copy"))).is_err());
+        assert!(copy(&path(&src), &path(&src.join("sub").join("x"))).is_err());
+        rename(&path(&src.join("sub")), &path(&dir.join("moved"))).unwrap();
+        assert!(!src.join("sub").exists());
+        assert_eq!(std::fs::read(dir.join("moved").join("a")).unwrap(), b"abc");
+        assert!(rename(&path(&dir.join("moved")), &path(&dir.join("copy"))).is_err());
+        // an existing file is never replaced
+        std::fs::write(dir.join("file"), b"old").unwrap();
+        std::fs::write(dir.join("other"), b"new").unwrap();
+        assert!(rename(&path(&dir.join("other")), &path(&dir.join("file"))).is_err());
+        assert!(copy(&path(&dir.join("other")), &path(&dir.join("file"))).is_err());
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"old");
+        assert!(dir.join("other").exists());
+        rename(&path(&dir.join("other")), &path(&dir.join("renamed"))).unwrap();
+        assert_eq!(std::fs::read(dir.join("renamed")).unwrap(), b"new");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_read_range() {
+        let path = std::env::temp_dir().join(format!("range_test_{}", std::process::id()));
+        std::fs::write(&path, b"one\ntwo\nthree\n").unwrap();
+        let mut r = FileReadRange {
+            path: get_string(&path),
+            offset: 4,
+            length: 3,
+            ..Default::default()
+        };
+        let data = read_range(&r).unwrap();
+        assert_eq!(&data.data[..], b"two");
+        assert_eq!(data.file_size, 14);
+        r.tail_lines = 2;
+        r.length = 0;
+        let data = read_range(&r).unwrap();
+        assert_eq!((data.offset, &data.data[..]), (4, &b"two\nthree\n"[..]));
+        r.tail_lines = 10;
+        assert_eq!(read_range(&r).unwrap().offset, 0);
+        // bounded by length
+        r.length = 7;
+        assert_eq!(&read_range(&r).unwrap().data[..], b"three\n");
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_write_verify() {
+        let dir = std::env::temp_dir().join(format!("verify_test_{}", std::process::id()));
+        let files = vec![FileEntry {
+            name: "a".to_owned(),
+            size: 5,
+            ..Default::default()
+        }];
+        let mut job = TransferJob::new_write(
+            1,
+            "".to_owned(),
+            get_string(&dir),
+            0,
+            false,
+            false,
+            files,
+            true,
+        );
+        let block = |data: &[u8], sha256: &[u8]| FileTransferBlock {
+            id: 1,
+            data: data.to_vec().into(),
+            sha256: sha256.to_vec().into(),
+            ..Default::default()
+        };
+        let sha256 = sha256::hash(b"hello").0;
+        job.write(block(b"hallo", &[])).await.unwrap();
+        let v = job.write(block(b"", &sha256)).await.unwrap().unwrap();
+        assert!(!v.ok && v.retransmit);
+        assert_eq!(job.retransmitting(), Some(0));
+        assert!(!dir.join("a.download").exists());
+        job.write(block(b"hello", &[])).await.unwrap();
+        let v = job.write(block(b"", &sha256)).await.unwrap().unwrap();
+        assert!(v.ok);
+        assert_eq!(job.retransmitting(), None);
+        job.modify_time();
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"hello");
+        job.write(block(b"hallo", &[])).await.unwrap();
+        assert!(job.write(block(b"", &sha256)).await.is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_archive_transfer() {
+        let src = std::env::temp_dir().join(format!("archive_src_{}", std::process::id()));
+        let dst = std::env::temp_dir().join(format!("archive_dst_{}", std::process::id()));
+        let long = "d".repeat(60);
+        let nested = src.join(&long).join(&long);
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(src.join("empty")).unwrap();
+        let big: Vec<u8> = (0..300_000u32).map(|x| (x % 251) as u8).collect();
+        std::fs::write(nested.join("big"), &big).unwrap();
+        std::fs::write(src.join("small"), b"hello").unwrap();
+        std::fs::write(src.join("zero"), b"").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("small", src.join("link")).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = Stream::from(tcp, addr);
+
+        // merged with what is there already
+        std::fs::create_dir_all(dst.join(&long)).unwrap();
+        std::fs::write(dst.join("keep"), b"").unwrap();
+        let mut reader =
+            TransferJob::new_archive_read(1, "".to_owned(), get_string(&src), false, false)
+                .unwrap();
+        let mut writer = TransferJob::new_write(
+            1,
+            "".to_owned(),
+            get_string(&dst),
+            0,
+            false,
+            false,
+            reader.files().clone(),
+            false,
+        );
+        writer.set_archive(true);
+        while let Some(block) = reader.read(&mut stream).await.unwrap() {
+            writer.write(block).await.unwrap();
+        }
+        assert!(reader.job_completed());
+        assert_eq!(writer.finished_size(), big.len() as u64 + 5);
+        let nested = dst.join(&long).join(&long);
+        assert_eq!(std::fs::read(nested.join("big")).unwrap(), big);
+        assert_eq!(std::fs::read(dst.join("small")).unwrap(), b"hello");
+        assert!(dst.join("zero").is_file());
+        assert!(dst.join("empty").is_dir());
+        assert!(!dst.join("small.download").exists());
+        assert!(dst.join("keep").is_file());
+        assert!(!archive_download_path(&dst).exists());
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read_link(dst.join("link")).unwrap(),
+            Path::new("small")
+        );
+
+        // nothing is left in place by an archive which does not match its digest
+        std::fs::remove_dir_all(&dst).ok();
+        let mut reader =
+            TransferJob::new_archive_read(1, "".to_owned(), get_string(&src), false, false)
+                .unwrap();
+        let mut writer = TransferJob::new_write(
+            1,
+            "".to_owned(),
+            get_string(&dst),
+            0,
+            false,
+            false,
+            reader.files().clone(),
+            false,
+        );
+        writer.set_archive(true);
+        let mut res = Ok(None);
+        while let Some(mut block) = reader.read(&mut stream).await.unwrap() {
+            if !block.sha256.is_empty() {
+                block.sha256 = vec![0; 32].into();
+            }
+            res = writer.write(block).await;
+        }
+        assert!(res.is_err());
+        assert!(!dst.exists());
+        assert!(!archive_download_path(&dst).exists());
+        std::fs::remove_dir_all(&src).ok();
+    }
+
+    #[tokio::test]
+    async fn test_archive_overwrite() {
+        let src = std::env::temp_dir().join(format!("overwrite_src_{}", std::process::id()));
+        let dst = std::env::temp_dir().join(format!("overwrite_dst_{}", std::process::id()));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub").join("a"), b"new").unwrap();
+        std::fs::write(src.join("b"), b"new").unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = Stream::from(tcp, addr);
+        for strategy in [None, Some(true)] {
+            std::fs::create_dir_all(dst.join("sub")).unwrap();
+            std::fs::write(dst.join("sub").join("a"), b"old").unwrap();
+            std::fs::remove_file(dst.join("b")).ok();
+            let mut reader =
+                TransferJob::new_archive_read(1, "".to_owned(), get_string(&src), false, false)
+                    .unwrap();
+            let mut writer = TransferJob::new_write(
+                1,
+                "".to_owned(),
+                get_string(&dst),
+                0,
+                false,
+                false,
+                reader.files().clone(),
+                true,
+            );
+            writer.set_archive(true);
+            writer.set_overwrite_strategy(strategy);
+            while let Some(block) = reader.read(&mut stream).await.unwrap() {
+                writer.write(block).await.unwrap();
+            }
+            if strategy.is_none() {
+                // nothing is moved into place before the confirm
+                assert_eq!(
+                    writer.archive_conflict(),
+                    Some(get_string(&dst.join("sub").join("a")))
+                );
+                assert!(!dst.join("b").exists());
+                writer.confirm_archive(false).unwrap();
+                assert_eq!(std::fs::read(dst.join("sub").join("a")).unwrap(), b"old");
+            } else {
+                assert!(writer.archive_conflict().is_none());
+                assert_eq!(std::fs::read(dst.join("sub").join("a")).unwrap(), b"new");
+            }
+            assert_eq!(std::fs::read(dst.join("b")).unwrap(), b"new");
+            assert!(!archive_download_path(&dst).exists());
+        }
+        std::fs::remove_dir_all(&src).ok();
+        std::fs::remove_dir_all(&dst).ok();
+    }
+
+    #[tokio::test]
+    async fn test_parallel_transfer() {
+        let src = std::env::temp_dir().join(format!("parallel_src_{}", std::process::id()));
+        let dst = std::env::temp_dir().join(format!("parallel_dst_{}", std::process::id()));
+        std::fs::create_dir_all(&src).unwrap();
+        let contents: Vec<Vec<u8>> = (0..5)
+            .map(|i| {
+                (0..i * 200_000)
+                    .map(|x| (x * (i + 1) % 251) as u8)
+                    .collect()
+            })
+            .collect();
+        for (i, data) in contents.iter().enumerate() {
+            std::fs::write(src.join(format!("f{}", i)), data).unwrap();
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = Stream::from(tcp, addr);
+
+        let mut reader =
+            TransferJob::new_read(1, "".to_owned(), get_string(&src), 0, false, false, false)
+                .unwrap();
+        reader.set_parallel(3);
+        let mut writer = TransferJob::new_write(
+            1,
+            "".to_owned(),
+            get_string(&dst),
+            0,
+            false,
+            false,
+            reader.files().clone(),
+            false,
+        );
+        writer.set_parallel(3);
+        let mut file_nums = Vec::new();
+        while let Some(block) = reader.read(&mut stream).await.unwrap() {
+            file_nums.push(block.file_num);
+            writer.write(block).await.unwrap();
+        }
+        assert!(reader.job_completed());
+        writer.modify_time();
+        // the files took turns
+        assert!(file_nums.windows(2).any(|w| w[0] > w[1]));
+        for (i, data) in contents.iter().enumerate() {
+            let name = format!("f{}", i);
+            assert_eq!(&std::fs::read(dst.join(&name)).unwrap(), data);
+            assert!(!dst.join(format!("{}.download", name)).exists());
+        }
+        std::fs::remove_dir_all(&src).ok();
+        std::fs::remove_dir_all(&dst).ok();
+    }
+}
@@ -0,0 +1,504 @@
+// A minimal ustar codec, used to send a directory as one stream so that its files are
+// not listed and negotiated one by one. Long names and link targets use the GNU extension.
+use std::path::{Path, PathBuf};
+
+use tokio::{fs::File, io::*};
+
+use super::{entry_path, get_string, is_link, restore_entry_meta};
+use crate::{bail, message_proto::*, ResultType};
+
+const BLOCK_SIZE: usize = 512;
+const LONG_LINK_NAME: &str = "././@LongLink";
+const MAX_LONG_NAME: u64 = 64 * 1024;
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIR: u8 = b'5';
+const TYPE_LONG_NAME: u8 = b'L';
+const TYPE_LONG_LINK: u8 = b'K';
+
+#[inline]
+fn padding(size: u64) -> u64 {
+    (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64
+}
+
+// Octal with a trailing NUL, base-256 if the value does not fit.
+fn put_number(field: &mut [u8], v: u64) {
+    let digits = field.len() - 1;
+    if digits >= 22 || v < 1 << (3 * digits) {
+        let s = format!("{:0width$o}", v, width = digits);
+        field[..digits].copy_from_slice(s.as_bytes());
+        field[digits] = 0;
+    } else {
+        field.fill(0);
+        field[0] = 0x80;
+        let bytes = v.to_be_bytes();
+        let n = std::cmp::min(bytes.len(), field.len() - 1);
+        let len = field.len();
+        field[len - n..].copy_from_slice(&bytes[bytes.len() - n..]);
+    }
+}
+
+// `None` for an empty field.
+fn parse_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut v: u64 = (field[0] & 0x7f) as u64;
+        for b in &field[1..] {
+            v = (v << 8) | *b as u64;
+        }
+        return Some(v);
+    }
+    let s = parse_str(field);
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    u64::from_str_radix(s, 8).ok()
+}
+
+fn put_str(field: &mut [u8], s: &str) {
+    let n = std::cmp::min(field.len(), s.len());
+    field[..n].copy_from_slice(&s.as_bytes()[..n]);
+}
+
+fn parse_str(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn checksum(header: &[u8]) -> u64 {
+    // the checksum field itself counts as spaces
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            let b = if (148..156).contains(&i) { b' ' } else { *b };
+            b as u64
+        })
+        .sum()
+}
+
+fn new_header(name: &str, entry: &FileEntry, typeflag: u8, size: u64) -> [u8; BLOCK_SIZE] {
+    let mut h = [0u8; BLOCK_SIZE];
+    put_str(&mut h[0..100], name);
+    put_number(&mut h[100..108], (entry.mode & 0o7777) as _);
+    // left empty rather than 0 if the owner is unknown
+    if let Some(uid) = entry.uid {
+        put_number(&mut h[108..116], uid as _);
+    }
+    if let Some(gid) = entry.gid {
+        put_number(&mut h[116..124], gid as _);
+    }
+    put_number(&mut h[124..136], size);
+    put_number(&mut h[136..148], entry.modified_time);
+    h[156] = typeflag;
+    put_str(&mut h[157..257], &entry.link_target);
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    let sum = checksum(&h);
+    put_number(&mut h[148..155], sum);
+    h[155] = b' ';
+    h
+}
+
+fn push_long_name(buf: &mut Vec<u8>, typeflag: u8, name: &str) {
+    let size = name.len() as u64 + 1;
+    buf.extend_from_slice(&new_header(
+        LONG_LINK_NAME,
+        &FileEntry::default(),
+        typeflag,
+        size,
+    ));
+    buf.extend_from_slice(name.as_bytes());
+    buf.resize(buf.len() + 1 + padding(size) as usize, 0);
+}
+
+/// Produces the archive of the entries under `base`, as returned by `read_dir_recursive`.
+pub(super) struct ArchiveEncoder {
+    base: PathBuf,
+    entries: std::vec::IntoIter<FileEntry>,
+    file: Option<File>,
+    remaining: u64,
+    padding: u64,
+    finished: bool,
+    // bytes of file content read so far
+    content: u64,
+}
+
+impl ArchiveEncoder {
+    pub fn new(base: PathBuf, entries: Vec<FileEntry>) -> Self {
+        Self {
+            base,
+            entries: entries.into_iter(),
+            file: None,
+            remaining: 0,
+            padding: 0,
+            finished: false,
+            content: 0,
+        }
+    }
+
+    #[inline]
+    pub fn content(&self) -> u64 {
+        self.content
+    }
+
+    /// Append the next part of the archive to `buf`, until it holds at least `size` bytes.
+    /// Nothing is appended once the archive is complete.
+    pub async fn read(&mut self, buf: &mut Vec<u8>, size: usize) -> ResultType<()> {
+        while buf.len() < size {
+            if self.remaining > 0 {
+                let start = buf.len();
+                let n = std::cmp::min(self.remaining, (size - start) as u64) as usize;
+                buf.resize(start + n, 0);
+                let file = match self.file.as_mut() {
+                    Some(file) => file,
+                    None => bail!("No file"),
+                };
+                let mut read = file.read(&mut buf[start..]).await?;
+                if read == 0 {
+                    // the file shrank meanwhile, zeros keep the size announced in the header
+                    log::warn!("file in archive is shorter than expected");
+                    read = n;
+                }
+                buf.truncate(start + read);
+                self.remaining -= read as u64;
+                self.content += read as u64;
+                continue;
+            }
+            self.file = None;
+            if self.padding > 0 {
+                buf.resize(buf.len() + self.padding as usize, 0);
+                self.padding = 0;
+                continue;
+            }
+            match self.entries.next() {
+                Some(entry) => self.start_entry(entry, buf).await,
+                None => {
+                    if !self.finished {
+                        buf.resize(buf.len() + 2 * BLOCK_SIZE, 0);
+                        self.finished = true;
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn start_entry(&mut self, entry: FileEntry, buf: &mut Vec<u8>) {
+        let mut name = entry.name.replace('\\', "/");
+        let (typeflag, size) = if is_link(&entry) {
+            (TYPE_SYMLINK, 0)
+        } else if entry.entry_type.enum_value() == Ok(FileType::Dir) {
+            name.push('/');
+            (TYPE_DIR, 0)
+        } else {
+            let file = match File::open(self.base.join(&entry.name)).await {
+                Ok(file) => file,
+                Err(err) => {
+                    log::warn!("Failed to add {} to archive: {}", name, err);
+                    return;
+                }
+            };
+            let size = match file.metadata().await {
+                Ok(meta) => meta.len(),
+                Err(_) => entry.size,
+            };
+            self.file = Some(file);
+            (TYPE_FILE, size)
+        };
+        if name.len() > 100 {
+            push_long_name(buf, TYPE_LONG_NAME, &name);
+        }
+        if entry.link_target.len() > 100 {
+            push_long_name(buf, TYPE_LONG_LINK, &entry.link_target);
+        }
+        buf.extend_from_slice(&new_header(&name, &entry, typeflag, size));
+        self.remaining = size;
+        self.padding = padding(size);
+    }
+}
+
+enum State {
+    Header,
+    LongName {
+        link: bool,
+        size: u64,
+    },
+    Data {
+        entry: Box<FileEntry>,
+        path: PathBuf,
+        file: File,
+        remaining: u64,
+        padding: u64,
+    },
+    Skip(u64),
+    End,
+}
+
+/// Extracts an archive under `base` as its bytes arrive.
+pub(super) struct ArchiveExtractor {
+    base: PathBuf,
+    buf: Vec<u8>,
+    state: State,
+    long_name: Option<String>,
+    long_link: Option<String>,
+    // restored at the end, the files written inside change their mtime
+    dirs: Vec<(FileEntry, PathBuf)>,
+    // bytes of file content written so far
+    content: u64,
+}
+
+impl ArchiveExtractor {
+    pub fn new(base: PathBuf) -> Self {
+        Self {
+            base,
+            buf: Vec::new(),
+            state: State::Header,
+            long_name: None,
+            long_link: None,
+            dirs: Vec::new(),
+            content: 0,
+        }
+    }
+
+    #[inline]
+    pub fn content(&self) -> u64 {
+        self.content
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) -> ResultType<()> {
+        while !data.is_empty() {
+            match &mut self.state {
+                State::Header => {
+                    let n = std::cmp::min(BLOCK_SIZE - self.buf.len(), data.len());
+                    self.buf.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    if self.buf.len() == BLOCK_SIZE {
+                        let header = std::mem::take(&mut self.buf);
+                        self.on_header(&header).await?;
+                    }
+                }
+                State::LongName { link, size } => {
+                    let total = (*size + padding(*size)) as usize;
+                    let n = std::cmp::min(total - self.buf.len(), data.len());
+                    self.buf.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    if self.buf.len() == total {
+                        let name = parse_str(&self.buf[..*size as usize]);
+                        if *link {
+                            self.long_link = Some(name);
+                        } else {
+                            self.long_name = Some(name);
+                        }
+                        self.buf.clear();
+                        self.state = State::Header;
+                    }
+                }
+                State::Data {
+                    file,
+                    remaining,
+                    padding,
+                    ..
+                } => {
+                    if *remaining > 0 {
+                        let n = std::cmp::min(*remaining, data.len() as u64) as usize;
+                        file.write_all(&data[..n]).await?;
+                        *remaining -= n as u64;
+                        self.content += n as u64;
+                        data = &data[n..];
+                    } else {
+                        let n = std::cmp::min(*padding, data.len() as u64) as usize;
+                        *padding -= n as u64;
+                        data = &data[n..];
+                    }
+                    self.finish_data().await?;
+                }
+                State::Skip(n) => {
+                    let len = std::cmp::min(*n, data.len() as u64);
+                    *n -= len;
+                    data = &data[len as usize..];
+                    if *n == 0 {
+                        self.state = State::Header;
+                    }
+                }
+                State::End => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the archive is complete, and restore the metadata of the directories.
+    pub fn finish(&mut self) -> ResultType<()> {
+        if !matches!(self.state, State::End) {
+            bail!("Incomplete archive");
+        }
+        for (entry, path) in self.dirs.drain(..).rev() {
+            restore_entry_meta(&entry, &path);
+        }
+        Ok(())
+    }
+
+    /// Remove the file being written.
+    pub fn abort(&mut self) {
+        if let State::Data { path, .. } = std::mem::replace(&mut self.state, State::End) {
+            std::fs::remove_file(download_path(&path)).ok();
+        }
+    }
+
+    async fn finish_data(&mut self) -> ResultType<()> {
+        match &self.state {
+            State::Data {
+                remaining: 0,
+                padding: 0,
+                ..
+            } => {}
+            _ => return Ok(()),
+        }
+        if let State::Data {
+            entry,
+            path,
+            mut file,
+            ..
+        } = std::mem::replace(&mut self.state, State::Header)
+        {
+            // the pending writes must be done before the mtime is set
+            file.flush().await?;
+            drop(file);
+            if let Err(err) = std::fs::rename(download_path(&path), &path) {
+                log::error!("Failed to extract {:?}: {}", path, err);
+            }
+            restore_entry_meta(&entry, &path);
+        }
+        Ok(())
+    }
+
+    async fn on_header(&mut self, h: &[u8]) -> ResultType<()> {
+        if h.iter().all(|b| *b == 0) {
+            self.state = State::End;
+            return Ok(());
+        }
+        if parse_number(&h[148..156]) != Some(checksum(h)) {
+            bail!("Invalid archive header");
+        }
+        let typeflag = h[156];
+        let size = parse_number(&h[124..136]).unwrap_or(0);
+        let name = match self.long_name.take() {
+            Some(name) => name,
+            None => {
+                let prefix = parse_str(&h[345..500]);
+                let name = parse_str(&h[0..100]);
+                if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                }
+            }
+        };
+        let link_target = self
+            .long_link
+            .take()
+            .unwrap_or_else(|| parse_str(&h[157..257]));
+        let mut entry = FileEntry {
+            name: name.trim_end_matches('/').to_owned(),
+            size,
+            modified_time: parse_number(&h[136..148]).unwrap_or(0),
+            mode: parse_number(&h[100..108]).unwrap_or(0) as _,
+            uid: parse_number(&h[108..116]).map(|x| x as _),
+            gid: parse_number(&h[116..124]).map(|x| x as _),
+            ..Default::default()
+        };
+        match typeflag {
+            TYPE_LONG_NAME | TYPE_LONG_LINK => {
+                if size > MAX_LONG_NAME {
+                    bail!("Invalid archive header");
+                }
+                self.state = State::LongName {
+                    link: typeflag == TYPE_LONG_LINK,
+                    size,
+                };
+                return Ok(());
+            }
+            0 | TYPE_FILE | b'7' => {
+                let path = entry_path(&self.base, &entry.name)?;
+                if let Some(p) = path.parent() {
+                    std::fs::create_dir_all(p).ok();
+                }
+                entry.entry_type = FileType::File.into();
+                let file = File::create(download_path(&path)).await?;
+                self.state = State::Data {
+                    entry: Box::new(entry),
+                    path,
+                    file,
+                    remaining: size,
+                    padding: padding(size),
+                };
+                return self.finish_data().await;
+            }
+            TYPE_SYMLINK => {
+                let path = entry_path(&self.base, &entry.name)?;
+                if let Some(p) = path.parent() {
+                    std::fs::create_dir_all(p).ok();
+                    entry.entry_type = if p.join(&link_target).is_dir() {
+                        FileType::DirLink.into()
+                    } else {
+                        FileType::FileLink.into()
+                    };
+                }
+                entry.link_target = link_target;
+                restore_entry_meta(&entry, &path);
+            }
+            TYPE_DIR => {
+                let path = entry_path(&self.base, &entry.name)?;
+                std::fs::create_dir_all(&path).ok();
+                entry.entry_type = FileType::Dir.into();
+                self.dirs.push((entry, path));
+            }
+            _ => {
+                log::warn!("skip archive entry {} of type {}", name, typeflag);
+            }
+        }
+        let skip = size + padding(size);
+        self.state = if skip > 0 {
+            State::Skip(skip)
+        } else {
+            State::Header
+        };
+        Ok(())
+    }
+}
+
+#[inline]
+fn download_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.download", get_string(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_extract_outside() {
+        let dir = std::env::temp_dir().join(format!("extract_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["../escaped", "/escaped"] {
+            let mut extractor = ArchiveExtractor::new(dir.clone());
+            let header = new_header(name, &FileEntry::default(), TYPE_FILE, 0);
+            assert!(extractor.write(&header).await.is_err());
+        }
+        let mut extractor = ArchiveExtractor::new(dir.clone());
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&new_header("sub", &FileEntry::default(), TYPE_DIR, 0));
+        buf.extend_from_slice(&new_header("sub/a", &FileEntry::default(), TYPE_FILE, 3));
+        buf.extend_from_slice(b"abc");
+        buf.resize(buf.len() + padding(3) as usize + 2 * BLOCK_SIZE, 0);
+        // fed in small pieces, as they arrive
+        for chunk in buf.chunks(100) {
+            extractor.write(chunk).await.unwrap();
+        }
+        extractor.finish().unwrap();
+        assert_eq!(std::fs::read(dir.join("sub").join("a")).unwrap(), b"abc");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,208 @@
+// Recursive file search, walked a few directories per tick so that a large tree neither
+// holds up the connection nor has to be listed in full before the first results show up.
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use regex::Regex;
+
+use super::{get_path, get_string, read_dir};
+use crate::{bail, message_proto::*, ResultType, Stream};
+
+const PAGE_SIZE: usize = 100;
+const DIRS_PER_TICK: usize = 16;
+
+pub struct SearchJob {
+    id: i32,
+    path: PathBuf,
+    pattern: Option<Regex>,
+    min_size: u64,
+    max_size: u64,
+    min_modified_time: u64,
+    max_modified_time: u64,
+    include_hidden: bool,
+    // directories still to visit, relative to path
+    pending: VecDeque<PathBuf>,
+}
+
+// `*` and `?` do not cross a path separator, the rest is literal.
+fn glob_to_regex(pattern: &str) -> ResultType<Regex> {
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(r"[^/\\]*"),
+            '?' => re.push_str(r"[^/\\]"),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    #[cfg(windows)]
+    let re = format!("(?i){}", re);
+    Ok(Regex::new(&re)?)
+}
+
+impl SearchJob {
+    pub fn new(s: &FileSearch) -> ResultType<Self> {
+        let path = get_path(&s.path);
+        if !path.is_dir() {
+            bail!("Not a directory");
+        }
+        let pattern = if s.pattern.is_empty() {
+            None
+        } else if s.is_regex {
+            Some(Regex::new(&s.pattern)?)
+        } else {
+            Some(glob_to_regex(&s.pattern)?)
+        };
+        let mut pending = VecDeque::new();
+        pending.push_back(PathBuf::new());
+        Ok(Self {
+            id: s.id,
+            path,
+            pattern,
+            min_size: s.min_size,
+            max_size: s.max_size,
+            min_modified_time: s.min_modified_time,
+            max_modified_time: s.max_modified_time,
+            include_hidden: s.include_hidden,
+            pending,
+        })
+    }
+
+    #[inline]
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    #[inline]
+    pub fn done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // Directories only match on name and time, the size range is for files.
+    fn is_match(&self, entry: &FileEntry) -> bool {
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(&entry.name) {
+                return false;
+            }
+        }
+        if entry.modified_time < self.min_modified_time
+            || (self.max_modified_time > 0 && entry.modified_time > self.max_modified_time)
+        {
+            return false;
+        }
+        match entry.entry_type.enum_value() {
+            Ok(FileType::Dir) | Ok(FileType::DirLink) | Ok(FileType::DirDrive) => true,
+            _ => entry.size >= self.min_size && (self.max_size == 0 || entry.size <= self.max_size),
+        }
+    }
+
+    // Visits the next directories, stopping early once a page is filled.
+    // Unreadable directories are skipped, as in get_recursive_files.
+    pub fn step(&mut self) -> Vec<FileEntry> {
+        let mut found = Vec::new();
+        let mut visited = 0;
+        while found.len() < PAGE_SIZE && visited < DIRS_PER_TICK {
+            let prefix = match self.pending.pop_front() {
+                Some(prefix) => prefix,
+                None => break,
+            };
+            visited += 1;
+            let fd = match read_dir(&self.path.join(&prefix), self.include_hidden) {
+                Ok(fd) => fd,
+                Err(_) => continue,
+            };
+            for mut entry in fd.entries.into_iter() {
+                // links are listed, not followed
+                if entry.entry_type.enum_value() == Ok(FileType::Dir) {
+                    self.pending.push_back(prefix.join(&entry.name));
+                }
+                if self.is_match(&entry) {
+                    entry.name = get_string(&prefix.join(&entry.name));
+                    found.push(entry);
+                }
+            }
+        }
+        found
+    }
+}
+
+pub fn new_search_result(id: i32, path: String, entries: Vec<FileEntry>, done: bool) -> Message {
+    let mut resp = FileResponse::new();
+    resp.set_search_result(FileSearchResult {
+        id,
+        path,
+        entries,
+        done,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_response(resp);
+    msg_out
+}
+
+pub fn remove_search_job(id: i32, jobs: &mut Vec<SearchJob>) {
+    jobs.retain(|x| x.id() != id);
+}
+
+pub async fn handle_search_jobs(jobs: &mut Vec<SearchJob>, stream: &mut Stream) -> ResultType<()> {
+    for job in jobs.iter_mut() {
+        let mut found = job.step();
+        let done = job.done();
+        if found.is_empty() && !done {
+            continue;
+        }
+        let path = get_string(&job.path);
+        while found.len() > PAGE_SIZE {
+            let rest = found.split_off(PAGE_SIZE);
+            stream
+                .send(&new_search_result(job.id, path.clone(), found, false))
+                .await?;
+            found = rest;
+        }
+        stream
+            .send(&new_search_result(job.id, path, found, done))
+            .await?;
+    }
+    jobs.retain(|x| !x.done());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let dir = std::env::temp_dir().join(format!("search_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a").join("b")).unwrap();
+        std::fs::write(dir.join("x.log"), b"0123456789").unwrap();
+        std::fs::write(dir.join("a").join("y.log"), b"01").unwrap();
+        std::fs::write(dir.join("a").join("b").join("z.txt"), b"0123").unwrap();
+        let mut search = FileSearch {
+            path: get_string(&dir),
+            pattern: "*.log".to_owned(),
+            ..Default::default()
+        };
+        let names = |search: &FileSearch| {
+            let mut job = SearchJob::new(search).unwrap();
+            let mut names = Vec::new();
+            while !job.done() {
+                names.extend(job.step().into_iter().map(|e| PathBuf::from(e.name)));
+            }
+            names.sort();
+            names
+        };
+        assert_eq!(
+            names(&search),
+            vec![PathBuf::from("a").join("y.log"), PathBuf::from("x.log")]
+        );
+        search.min_size = 5;
+        assert_eq!(names(&search), vec![PathBuf::from("x.log")]);
+        search.pattern = r"^[yz]\.".to_owned();
+        search.is_regex = true;
+        search.min_size = 0;
+        search.max_size = 3;
+        assert_eq!(names(&search), vec![PathBuf::from("a").join("y.log")]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
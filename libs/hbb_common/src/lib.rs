@@ -16,6 +16,7 @@ use std::{
 };
 pub use tokio;
 pub use tokio_util;
+pub mod rekey;
 pub mod socket_client;
 pub mod tcp;
 pub mod udp;
@@ -24,10 +25,13 @@ pub use log;
 pub mod bytes_codec;
 #[cfg(feature = "quic")]
 pub mod quic;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 pub use anyhow::{self, bail};
 pub use futures_util;
 pub mod config;
 pub mod fs;
+pub mod tunnel;
 pub use lazy_static;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub use mac_address;
@@ -45,9 +49,6 @@ pub mod keyboard;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 pub use sysinfo;
 
-#[cfg(feature = "quic")]
-pub type Stream = quic::Connection;
-#[cfg(not(feature = "quic"))]
 pub type Stream = tcp::FramedStream;
 
 #[inline]
@@ -1,135 +1,263 @@
-use crate::{allow_err, anyhow::anyhow, ResultType};
-use protobuf::Message;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{self, stream::StreamExt, sync::mpsc};
+// QUIC over the udp hole punched next to the tcp one, a session runs over it as over a
+// FramedStream, with the channels on streams of their own.
+use crate::{
+    bail,
+    tcp::{Channels, FramedStream, CHANNELS},
+    timeout, ResultType,
+};
+use std::{
+    convert::TryFrom,
+    io,
+    net::{SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-const QUIC_HBB: &[&[u8]] = &[b"hbb"];
+const ALPN: &[u8] = b"hbb";
 const SERVER_NAME: &str = "hbb";
+const MAIN: u8 = 0;
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// what a connecting side spends on QUIC at most before going on with tcp
+pub const CONNECT_TIMEOUT: u64 = 3_000;
 
-type Sender = mpsc::UnboundedSender<Value>;
-type Receiver = mpsc::UnboundedReceiver<Value>;
-
-pub fn new_server(socket: std::net::UdpSocket) -> ResultType<(Server, SocketAddr)> {
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.stream_window_uni(0);
-    let mut server_config = quinn::ServerConfig::default();
-    server_config.transport = Arc::new(transport_config);
-    let mut server_config = quinn::ServerConfigBuilder::new(server_config);
-    server_config.protocols(QUIC_HBB);
-    // server_config.enable_keylog();
-    // server_config.use_stateless_retry(true);
-    let mut endpoint = quinn::Endpoint::builder();
-    endpoint.listen(server_config.build());
-    let (end, incoming) = endpoint.with_socket(socket)?;
-    Ok((Server { incoming }, end.local_addr()?))
+/// Bind the udp port of `local_addr`, the one of the tcp hole punching, punch it to `peer`
+/// and listen on it. This relies on the NAT mapping udp ports as tcp ones.
+pub fn listen(local_addr: SocketAddr, peer: SocketAddr) -> ResultType<quinn::Endpoint> {
+    let socket = UdpSocket::bind(local_addr)?;
+    for _ in 0..3 {
+        socket.send_to(&[MAIN], peer).ok();
+    }
+    new_endpoint(socket, Some(server_config()?))
+}
+
+/// Accept the connection of the peer `connect` is called by, with its channels.
+pub async fn accept(
+    endpoint: &quinn::Endpoint,
+    ms_timeout: u64,
+) -> ResultType<(FramedStream, SocketAddr)> {
+    let conn = match timeout(ms_timeout, endpoint.accept()).await? {
+        Some(connecting) => timeout(ms_timeout, connecting).await??,
+        None => bail!("QUIC endpoint closed"),
+    };
+    // one peer only
+    endpoint.set_server_config(None);
+    let addr = conn.remote_address();
+    let mut main = None;
+    let mut channels = Vec::new();
+    for _ in 0..=CHANNELS.len() {
+        let (send, mut recv) = timeout(ms_timeout, conn.accept_bi()).await??;
+        let mut id = [0u8];
+        timeout(ms_timeout, recv.read_exact(&mut id)).await??;
+        let stream = QuicStream::new(&conn, endpoint, send, recv);
+        if id[0] == MAIN {
+            main = Some(stream);
+        } else {
+            channels.push((id[0], stream));
+        }
+    }
+    let local_addr = endpoint.local_addr()?;
+    let mut stream = match main {
+        Some(main) => FramedStream::from(main, local_addr),
+        None => bail!("No main QUIC stream from {}", addr),
+    };
+    channels.sort_by_key(|(id, _)| *id);
+    stream.set_channels(Channels {
+        streams: channels
+            .into_iter()
+            .map(|(_, s)| FramedStream::from(s, local_addr))
+            .collect(),
+        accepted: true,
+    });
+    Ok((stream, addr))
 }
 
-pub async fn new_client(local_addr: &SocketAddr, peer: &SocketAddr) -> ResultType<Connection> {
-    let mut endpoint = quinn::Endpoint::builder();
-    let mut client_config = quinn::ClientConfigBuilder::default();
-    client_config.protocols(QUIC_HBB);
-    //client_config.enable_keylog();
-    endpoint.default_client_config(client_config.build());
-    let (endpoint, _) = endpoint.bind(local_addr)?;
-    let new_conn = endpoint.connect(peer, SERVER_NAME)?.await?;
-    Connection::new_for_client(new_conn.connection).await
+/// Connect from the udp port of `local_addr`, the one of the tcp hole punching, to `peer`
+/// listening with `listen`.
+pub async fn connect(
+    local_addr: SocketAddr,
+    peer: SocketAddr,
+    ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    let endpoint = new_endpoint(UdpSocket::bind(local_addr)?, None)?;
+    let mut config = quinn::ClientConfig::new(Arc::new(client_crypto()));
+    config.transport_config(Arc::new(transport_config()?));
+    let conn = timeout(
+        ms_timeout,
+        endpoint.connect_with(config, peer, SERVER_NAME)?,
+    )
+    .await??;
+    let local_addr = endpoint.local_addr()?;
+    // a stream shows up at the peer once written to, the session goes over QUIC from here
+    let mut streams = Vec::new();
+    for id in std::iter::once(MAIN).chain(CHANNELS.iter().map(|c| *c as u8)) {
+        let (mut send, recv) = conn.open_bi().await?;
+        send.write_all(&[id]).await?;
+        streams.push(FramedStream::from(
+            QuicStream::new(&conn, &endpoint, send, recv),
+            local_addr,
+        ));
+    }
+    let mut stream = streams.remove(0);
+    stream.set_channels(Channels {
+        streams,
+        accepted: false,
+    });
+    Ok(stream)
+}
+
+fn new_endpoint(
+    socket: UdpSocket,
+    server_config: Option<quinn::ServerConfig>,
+) -> ResultType<quinn::Endpoint> {
+    socket.set_nonblocking(true)?;
+    Ok(quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        server_config,
+        socket,
+        quinn::TokioRuntime,
+    )?)
 }
 
-pub struct Server {
-    incoming: quinn::Incoming,
+fn transport_config() -> ResultType<quinn::TransportConfig> {
+    let mut config = quinn::TransportConfig::default();
+    config.keep_alive_interval(Some(KEEP_ALIVE));
+    config.max_idle_timeout(Some(quinn::IdleTimeout::try_from(IDLE_TIMEOUT)?));
+    Ok(config)
 }
 
-impl Server {
-    #[inline]
-    pub async fn next(&mut self) -> ResultType<Option<Connection>> {
-        Connection::new_for_server(&mut self.incoming).await
+// The certificate is a throwaway one, peers are authenticated by the signed id and the
+// secretbox key of the session as over tcp.
+fn server_config() -> ResultType<quinn::ServerConfig> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let cert = rustls::Certificate(cert.serialize_der()?);
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport = Arc::new(transport_config()?);
+    Ok(config)
+}
+
+fn client_crypto() -> rustls::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    crypto
+}
+
+struct SkipServerVerification;
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
 
-pub struct Connection {
-    conn: quinn::Connection,
-    tx: quinn::SendStream,
-    rx: Receiver,
+struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    // the connection and its endpoint live as long as any of their streams
+    _conn: quinn::Connection,
+    _endpoint: quinn::Endpoint,
 }
 
-type Value = ResultType<Vec<u8>>;
-
-impl Connection {
-    async fn new_for_server(incoming: &mut quinn::Incoming) -> ResultType<Option<Self>> {
-        if let Some(conn) = incoming.next().await {
-            let quinn::NewConnection {
-                connection: conn,
-                // uni_streams,
-                mut bi_streams,
-                ..
-            } = conn.await?;
-            let (tx, rx) = mpsc::unbounded_channel::<Value>();
-            tokio::spawn(async move {
-                loop {
-                    let stream = bi_streams.next().await;
-                    if let Some(stream) = stream {
-                        let stream = match stream {
-                            Err(e) => {
-                                tx.send(Err(e.into())).ok();
-                                break;
-                            }
-                            Ok(s) => s,
-                        };
-                        let cloned = tx.clone();
-                        tokio::spawn(async move {
-                            allow_err!(handle_request(stream.1, cloned).await);
-                        });
-                    } else {
-                        tx.send(Err(anyhow!("Reset by the peer"))).ok();
-                        break;
-                    }
-                }
-                log::info!("Exit connection outer loop");
-            });
-            let tx = conn.open_uni().await?;
-            Ok(Some(Self { conn, tx, rx }))
-        } else {
-            Ok(None)
+impl QuicStream {
+    fn new(
+        conn: &quinn::Connection,
+        endpoint: &quinn::Endpoint,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+    ) -> Self {
+        Self {
+            send,
+            recv,
+            _conn: conn.clone(),
+            _endpoint: endpoint.clone(),
         }
     }
+}
 
-    async fn new_for_client(conn: quinn::Connection) -> ResultType<Self> {
-        let (tx, rx_quic) = conn.open_bi().await?;
-        let (tx_mpsc, rx) = mpsc::unbounded_channel::<Value>();
-        tokio::spawn(async move {
-            allow_err!(handle_request(rx_quic, tx_mpsc).await);
-        });
-        Ok(Self { conn, tx, rx })
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
     }
+}
 
-    #[inline]
-    pub async fn next(&mut self) -> Option<Value> {
-        // None is returned when all Sender halves have dropped,
-        // indicating that no further values can be sent on the channel.
-        self.rx.recv().await
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
     }
 
-    #[inline]
-    pub fn remote_address(&self) -> SocketAddr {
-        self.conn.remote_address()
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
     }
 
-    #[inline]
-    pub async fn send_raw(&mut self, bytes: &[u8]) -> ResultType<()> {
-        self.tx.write_all(bytes).await?;
-        Ok(())
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_proto::{Message, TestDelay},
+        tcp::Channel,
+    };
+    use protobuf::Message as _;
+    use sodiumoxide::crypto::secretbox;
 
-    #[inline]
-    pub async fn send(&mut self, msg: &dyn Message) -> ResultType<()> {
-        match msg.write_to_bytes() {
-            Ok(bytes) => self.send_raw(&bytes).await?,
-            err => allow_err!(err),
+    #[tokio::test]
+    async fn test_channels() {
+        let any = "127.0.0.1:0".parse().unwrap();
+        let endpoint = listen(any, "127.0.0.1:9".parse().unwrap()).unwrap();
+        let (a, b) = tokio::join!(
+            accept(&endpoint, 3_000),
+            connect(any, endpoint.local_addr().unwrap(), 3_000)
+        );
+        let (mut a, mut b) = (a.unwrap().0, b.unwrap());
+        let key = secretbox::gen_key();
+        a.set_key(key.clone());
+        b.set_key(key);
+        let mut delay = Message::new();
+        delay.set_test_delay(TestDelay {
+            time: 1,
+            ..Default::default()
+        });
+        let bytes = delay.write_to_bytes().unwrap();
+        // both sides send on the streams of the channels
+        for channel in CHANNELS {
+            a.send_on(channel, &delay).await.unwrap();
+            assert_eq!(&b.next().await.unwrap().unwrap()[..], &bytes[..]);
+            b.send_on(channel, &delay).await.unwrap();
+            assert_eq!(&a.next().await.unwrap().unwrap()[..], &bytes[..]);
         }
-        Ok(())
+        b.send_on(Channel::Audio, &delay).await.unwrap();
+        assert_eq!(&a.next().await.unwrap().unwrap()[..], &bytes[..]);
     }
 }
-
-async fn handle_request(rx: quinn::RecvStream, tx: Sender) -> ResultType<()> {
-    Ok(())
-}
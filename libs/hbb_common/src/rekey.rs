@@ -0,0 +1,282 @@
+// Keys of a FramedStream, one per direction, changed by an ephemeral X25519 key exchange right
+// after the handshake and then every REKEY_BYTES or REKEY_INTERVAL, so that a key got hold of
+// later does not open what was sent before.
+//
+// The connecting side sends REQUEST with its public key, the accepting side answers RESPONSE
+// with its own and a tag of its new tx key over both, then sends with that key. The connecting
+// side checks the tag, receives with its new rx key, answers CONFIRM with a tag of its new tx
+// key and sends with that one, the accepting side checks it and receives with its new rx key.
+// The tags and the frames are keyed with distinct keys derived from each new key, see `split`.
+use bytes::{BufMut, BytesMut};
+use sodiumoxide::crypto::{
+    auth, kdf, kx,
+    secretbox::{self, Key, Nonce},
+};
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    time::{Duration, Instant},
+};
+
+/// Version of the key exchange of a peer, in `IdPk` and `PublicKey`, 0 for none of this module.
+pub const KEY_EXCHANGE: u32 = 1;
+const REKEY_BYTES: u64 = 1 << 30;
+const REKEY_INTERVAL: Duration = Duration::from_secs(3600);
+// the first byte of a frame of the key exchange is its kind and the wire type 6, which protobuf
+// does not have, to tell it from a message
+pub(crate) const FLAG: u8 = 0x6;
+const REQUEST: u8 = FLAG;
+const RESPONSE: u8 = 1 << 3 | FLAG;
+const CONFIRM: u8 = 2 << 3 | FLAG;
+const KDF_CONTEXT: [u8; kdf::CONTEXTBYTES] = *b"rekey___";
+const KDF_TRAFFIC: u64 = 1;
+const KDF_CONFIRM: u64 = 2;
+/// The first byte of a frame of data of a raw stream kept framed, see `Keys::set_opaque`.
+pub(crate) const DATA: u8 = 0;
+
+pub(crate) struct Keys {
+    tx: (Key, u64),
+    rx: (Key, u64),
+    rekey: Option<Box<Rekey>>,
+}
+
+struct Rekey {
+    initiator: bool,
+    // the key pair of an exchange started by the initiator
+    pending: Option<(kx::PublicKey, kx::SecretKey)>,
+    // the rx key the initiator sends with after its CONFIRM, with the key of its tag and the
+    // public keys of both
+    next_rx: Option<(Key, auth::Key, Vec<u8>)>,
+    // frames to write before anything else, with the tx key to switch to once sealed
+    out: VecDeque<(Vec<u8>, Option<Key>)>,
+    bytes: u64,
+    since: Instant,
+    opaque: bool,
+}
+
+impl Keys {
+    pub(crate) fn new(key: Key, tx: u64, rx: u64) -> Self {
+        Self {
+            tx: (key.clone(), tx),
+            rx: (key, rx),
+            rekey: None,
+        }
+    }
+
+    pub(crate) fn set_rekey(&mut self, initiator: bool) {
+        let mut rekey = Rekey {
+            initiator,
+            pending: None,
+            next_rx: None,
+            out: Default::default(),
+            bytes: 0,
+            since: Instant::now(),
+            opaque: false,
+        };
+        if initiator {
+            rekey.start();
+        }
+        self.rekey = Some(Box::new(rekey));
+    }
+
+    #[inline]
+    pub(crate) fn is_rekeyed(&self) -> bool {
+        self.rekey.is_some()
+    }
+
+    // A raw stream being rekeyed stays framed and encrypted, its frames of data start with
+    // DATA to be told from the ones of the key exchange.
+    pub(crate) fn set_opaque(&mut self) -> bool {
+        match self.rekey.as_mut() {
+            Some(rekey) => {
+                rekey.opaque = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_opaque(&self) -> bool {
+        self.rekey.as_ref().map(|x| x.opaque).unwrap_or(false)
+    }
+
+    pub(crate) fn seal(&mut self, msg: &[u8]) -> Vec<u8> {
+        if let Some(rekey) = self.rekey.as_mut() {
+            rekey.count(msg.len());
+        }
+        self.tx.1 += 1;
+        secretbox::seal(msg, &get_nonce(self.tx.1), &self.tx.0)
+    }
+
+    pub(crate) fn open(&mut self, bytes: &mut BytesMut) -> Result<(), Error> {
+        self.rx.1 += 1;
+        match secretbox::open(bytes, &get_nonce(self.rx.1), &self.rx.0) {
+            Ok(res) => {
+                bytes.clear();
+                bytes.put_slice(&res);
+            }
+            Err(()) => {
+                return Err(Error::new(ErrorKind::Other, "decryption error"));
+            }
+        }
+        if let Some(rekey) = self.rekey.as_mut() {
+            rekey.count(bytes.len());
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn has_out(&self) -> bool {
+        self.rekey
+            .as_ref()
+            .map(|x| !x.out.is_empty())
+            .unwrap_or(false)
+    }
+
+    // The next frame of the key exchange to write, sealed.
+    pub(crate) fn next_out(&mut self) -> Option<Vec<u8>> {
+        let (frame, key) = self.rekey.as_mut()?.out.pop_front()?;
+        let frame = self.seal(&frame);
+        if let Some(key) = key {
+            self.tx = (key, 0);
+        }
+        Some(frame)
+    }
+
+    pub(crate) fn handle(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let rekey = match self.rekey.as_mut() {
+            Some(rekey) => rekey,
+            None => return Err(invalid("unexpected key exchange")),
+        };
+        match frame.first().copied() {
+            Some(REQUEST) if !rekey.initiator => {
+                let their_pk = kx::PublicKey::from_slice(&frame[1..])
+                    .ok_or_else(|| invalid("invalid key exchange"))?;
+                let (pk, sk) = kx::gen_keypair();
+                let (rx, tx) = kx::server_session_keys(&pk, &sk, &their_pk)
+                    .map_err(|_| invalid("invalid key exchange"))?;
+                let keys = [&their_pk.0[..], &pk.0[..]].concat();
+                let (rx, rx_confirm) = split(&rx)?;
+                let (tx, tx_confirm) = split(&tx)?;
+                let mut out = vec![RESPONSE];
+                out.extend_from_slice(&pk.0);
+                out.extend_from_slice(&tag(b"server", &keys, &tx_confirm).0);
+                rekey.out.push_back((out, Some(tx)));
+                rekey.next_rx = Some((rx, rx_confirm, keys));
+            }
+            Some(RESPONSE) if rekey.initiator => {
+                let (pk, sk) = match rekey.pending.take() {
+                    Some(pair) => pair,
+                    None => return Err(invalid("unexpected key exchange")),
+                };
+                if frame.len() != 1 + kx::PUBLICKEYBYTES + auth::TAGBYTES {
+                    return Err(invalid("invalid key exchange"));
+                }
+                let (their_pk, their_tag) = frame[1..].split_at(kx::PUBLICKEYBYTES);
+                let their_pk = kx::PublicKey::from_slice(their_pk)
+                    .ok_or_else(|| invalid("invalid key exchange"))?;
+                let (rx, tx) = kx::client_session_keys(&pk, &sk, &their_pk)
+                    .map_err(|_| invalid("invalid key exchange"))?;
+                let keys = [&pk.0[..], &their_pk.0[..]].concat();
+                let (rx, rx_confirm) = split(&rx)?;
+                let (tx, tx_confirm) = split(&tx)?;
+                if !verify(b"server", &keys, &rx_confirm, their_tag) {
+                    return Err(invalid("key confirmation failure"));
+                }
+                self.rx = (rx, 0);
+                let mut out = vec![CONFIRM];
+                out.extend_from_slice(&tag(b"client", &keys, &tx_confirm).0);
+                rekey.out.push_back((out, Some(tx)));
+                rekey.bytes = 0;
+                rekey.since = Instant::now();
+            }
+            Some(CONFIRM) if !rekey.initiator => {
+                let (key, confirm, keys) = match rekey.next_rx.take() {
+                    Some(next) => next,
+                    None => return Err(invalid("unexpected key exchange")),
+                };
+                if !verify(b"client", &keys, &confirm, &frame[1..]) {
+                    return Err(invalid("key confirmation failure"));
+                }
+                self.rx = (key, 0);
+            }
+            _ => return Err(invalid("unexpected key exchange")),
+        }
+        Ok(())
+    }
+}
+
+impl Rekey {
+    fn start(&mut self) {
+        let (pk, sk) = kx::gen_keypair();
+        let mut out = vec![REQUEST];
+        out.extend_from_slice(&pk.0);
+        self.out.push_back((out, None));
+        self.pending = Some((pk, sk));
+    }
+
+    fn count(&mut self, n: usize) {
+        self.bytes += n as u64;
+        if self.initiator
+            && self.pending.is_none()
+            && (self.bytes >= REKEY_BYTES || self.since.elapsed() >= REKEY_INTERVAL)
+        {
+            self.start();
+        }
+    }
+}
+
+// The key to seal frames with and the one to tag the key exchange with, derived from a new
+// session key so that no key is used by both secretbox and auth.
+fn split(key: &kx::SessionKey) -> Result<(Key, auth::Key), Error> {
+    let key = kdf::Key(key.0);
+    let mut traffic = Key([0u8; secretbox::KEYBYTES]);
+    let mut confirm = auth::Key([0u8; auth::KEYBYTES]);
+    kdf::derive_from_key(&mut traffic.0, KDF_TRAFFIC, KDF_CONTEXT, &key)
+        .and_then(|_| kdf::derive_from_key(&mut confirm.0, KDF_CONFIRM, KDF_CONTEXT, &key))
+        .map_err(|_| invalid("key derivation failure"))?;
+    Ok((traffic, confirm))
+}
+
+fn tag(side: &[u8], keys: &[u8], key: &auth::Key) -> auth::Tag {
+    auth::authenticate(&[side, keys].concat(), key)
+}
+
+fn verify(side: &[u8], keys: &[u8], key: &auth::Key, their_tag: &[u8]) -> bool {
+    match auth::Tag::from_slice(their_tag) {
+        Some(their_tag) => auth::verify(&their_tag, &[side, keys].concat(), key),
+        None => false,
+    }
+}
+
+fn invalid(err: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+pub(crate) fn get_nonce(seqnum: u64) -> Nonce {
+    let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
+    nonce.0[..std::mem::size_of_val(&seqnum)].copy_from_slice(&seqnum.to_le_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let (pk, sk) = kx::gen_keypair();
+        let (their_pk, their_sk) = kx::gen_keypair();
+        let (rx, tx) = kx::client_session_keys(&pk, &sk, &their_pk).unwrap();
+        let (their_rx, their_tx) = kx::server_session_keys(&their_pk, &their_sk, &pk).unwrap();
+        let (traffic, confirm) = split(&tx).unwrap();
+        assert_ne!(traffic.0, confirm.0);
+        assert_ne!(traffic.0, tx.0);
+        assert_ne!(confirm.0, tx.0);
+        let (their_traffic, their_confirm) = split(&their_rx).unwrap();
+        assert_eq!(traffic.0, their_traffic.0);
+        assert_eq!(confirm.0, their_confirm.0);
+        assert_eq!(split(&rx).unwrap().0 .0, split(&their_tx).unwrap().0 .0);
+    }
+}
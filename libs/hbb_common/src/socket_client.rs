@@ -50,6 +50,10 @@ pub fn increase_port<T: std::string::ToString>(host: T, offset: i32) -> String {
 }
 
 pub fn test_if_valid_server(host: &str) -> String {
+    // an HTTP proxy is set as http://host:port
+    let host = host.strip_prefix("http://").unwrap_or(host);
+    #[cfg(feature = "websocket")]
+    let host = crate::websocket::get_addr(host).unwrap_or(host.to_owned());
     let host = check_port(host, 0);
 
     use std::net::ToSocketAddrs;
@@ -58,7 +62,7 @@ pub fn test_if_valid_server(host: &str) -> String {
             Err(err) => err.to_string(),
             Ok(_) => "".to_owned(),
         },
-        NetworkType::ProxySocks => match &host.into_target_addr() {
+        NetworkType::ProxySocks | NetworkType::ProxyHttp => match &host.into_target_addr() {
             Err(err) => err.to_string(),
             Ok(_) => "".to_owned(),
         },
@@ -105,17 +109,49 @@ pub async fn connect_tcp_local<
     target: T,
     local: Option<SocketAddr>,
     ms_timeout: u64,
+) -> ResultType<FramedStream> {
+    #[cfg(feature = "websocket")]
+    {
+        let url = target.to_string();
+        if let Some(addr) = crate::websocket::get_addr(&url) {
+            let stream = connect_tcp_local_(addr, local, ms_timeout).await?;
+            return crate::websocket::connect(&url, stream, ms_timeout).await;
+        }
+    }
+    connect_tcp_local_(target, local, ms_timeout).await
+}
+
+async fn connect_tcp_local_<
+    't,
+    T: IntoTargetAddr<'t> + ToSocketAddrs + IsResolvedSocketAddr + std::fmt::Display,
+>(
+    target: T,
+    local: Option<SocketAddr>,
+    ms_timeout: u64,
 ) -> ResultType<FramedStream> {
     if let Some(conf) = Config::get_socks() {
-        return FramedStream::connect(
-            conf.proxy.as_str(),
-            target,
-            local,
-            conf.username.as_str(),
-            conf.password.as_str(),
-            ms_timeout,
-        )
-        .await;
+        if !is_no_proxy(&Config::get_option("no-proxy"), &target.to_string()) {
+            if let Some(proxy) = conf.http_proxy() {
+                return FramedStream::connect_http(
+                    proxy,
+                    target,
+                    local,
+                    conf.username.as_str(),
+                    conf.password.as_str(),
+                    ms_timeout,
+                )
+                .await;
+            }
+            return FramedStream::connect(
+                conf.proxy.as_str(),
+                target,
+                local,
+                conf.username.as_str(),
+                conf.password.as_str(),
+                ms_timeout,
+            )
+            .await;
+        }
     }
     if let Some(target) = target.resolve() {
         if let Some(local) = local {
@@ -128,6 +164,25 @@ pub async fn connect_tcp_local<
     FramedStream::new(target, local, ms_timeout).await
 }
 
+// Whether the host of `target` is in `no_proxy`, comma separated hosts also matching their
+// subdomains, or "*" for all.
+fn is_no_proxy(no_proxy: &str, target: &str) -> bool {
+    let host = if let Some(host) = target.strip_prefix('[') {
+        host.split(']').next().unwrap_or_default()
+    } else if target.matches(':').count() == 1 {
+        target.split(':').next().unwrap_or_default()
+    } else {
+        target
+    }
+    .to_lowercase();
+    no_proxy
+        .split(',')
+        .map(|x| x.trim().trim_start_matches("*.").trim_start_matches('.'))
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .any(|x| x == "*" || host == x || host.ends_with(&format!(".{x}")))
+}
+
 #[inline]
 pub fn is_ipv4(target: &TargetAddr<'_>) -> bool {
     match target {
@@ -171,7 +226,8 @@ pub async fn new_udp_for(
     target: &str,
     ms_timeout: u64,
 ) -> ResultType<(FramedSocket, TargetAddr<'static>)> {
-    let (ipv4, target) = if NetworkType::Direct == Config::get_network_type() {
+    // udp does not go through an HTTP proxy
+    let (ipv4, target) = if NetworkType::ProxySocks != Config::get_network_type() {
         let addr = test_target(target).await?;
         (addr.is_ipv4(), addr.into_target_addr()?)
     } else {
@@ -185,8 +241,7 @@ pub async fn new_udp_for(
 
 async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<FramedSocket> {
     match Config::get_socks() {
-        None => Ok(FramedSocket::new(local).await?),
-        Some(conf) => {
+        Some(conf) if conf.http_proxy().is_none() => {
             let socket = FramedSocket::new_proxy(
                 conf.proxy.as_str(),
                 local,
@@ -197,13 +252,14 @@ async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<Fram
             .await?;
             Ok(socket)
         }
+        _ => Ok(FramedSocket::new(local).await?),
     }
 }
 
 pub async fn rebind_udp_for(
     target: &str,
 ) -> ResultType<Option<(FramedSocket, TargetAddr<'static>)>> {
-    if Config::get_network_type() != NetworkType::Direct {
+    if Config::get_network_type() == NetworkType::ProxySocks {
         return Ok(None);
     }
     let addr = test_target(target).await?;
@@ -261,6 +317,21 @@ mod tests {
         assert!(test_if_valid_server("1.1.1.1:1").is_empty());
     }
 
+    #[test]
+    fn test_is_no_proxy() {
+        let no_proxy = "localhost, .corp.com,10.0.0.1,*.lan,::1";
+        assert!(is_no_proxy(no_proxy, "localhost:21116"));
+        assert!(is_no_proxy(no_proxy, "rs.corp.com:21117"));
+        assert!(is_no_proxy(no_proxy, "CORP.com"));
+        assert!(is_no_proxy(no_proxy, "10.0.0.1:21116"));
+        assert!(is_no_proxy(no_proxy, "nas.lan:21116"));
+        assert!(is_no_proxy(no_proxy, "[::1]:21116"));
+        assert!(!is_no_proxy(no_proxy, "notcorp.com:21116"));
+        assert!(!is_no_proxy(no_proxy, "10.0.0.11:21116"));
+        assert!(!is_no_proxy("", "localhost:21116"));
+        assert!(is_no_proxy("*", "rustdesk.com:21116"));
+    }
+
     #[test]
     fn test_check_port() {
         assert_eq!(check_port("[1:2]:12", 32), "[1:2]:12");
@@ -1,10 +1,16 @@
-use crate::{bail, bytes_codec::BytesCodec, ResultType};
+use crate::{
+    bail,
+    bytes_codec::BytesCodec,
+    rekey::{self, Keys},
+    ResultType,
+};
 use anyhow::Context as AnyhowCtx;
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use protobuf::Message;
-use sodiumoxide::crypto::secretbox::{self, Key, Nonce};
+use sodiumoxide::{base64, crypto::secretbox::Key};
 use std::{
+    collections::VecDeque,
     io::{self, Error, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::{Deref, DerefMut},
@@ -12,10 +18,10 @@ use std::{
     task::{Context, Poll},
 };
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{lookup_host, TcpListener, TcpSocket, ToSocketAddrs},
 };
-use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr, ToProxyAddrs};
+use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr, TargetAddr, ToProxyAddrs};
 use tokio_util::codec::Framed;
 
 pub trait TcpStreamTrait: AsyncRead + AsyncWrite + Unpin {}
@@ -24,10 +30,49 @@ pub struct DynTcpStream(Box<dyn TcpStreamTrait + Send + Sync>);
 pub struct FramedStream(
     Framed<DynTcpStream, BytesCodec>,
     SocketAddr,
-    Option<(Key, u64, u64)>,
+    Option<Keys>,
     u64,
+    Option<Box<Channels>>,
+    Option<Box<Mux>>,
 );
 
+/// Messages which go apart from input and control, so that e.g. video frames or file blocks
+/// do not hold them up, over streams of their own if the connection has several, e.g. over
+/// QUIC, else in chunks over the main stream if the peer takes them, see `set_chunked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Video = 1,
+    File = 2,
+    Audio = 3,
+}
+
+/// The channels with a stream of their own over QUIC.
+pub const CHANNELS: [Channel; 2] = [Channel::Video, Channel::File];
+// in the order their queued chunks are written, input and control go before all of them
+const PRIORITY: [Channel; 3] = [Channel::Audio, Channel::Video, Channel::File];
+const CHUNK_SIZE: usize = 16 * 1024;
+// a message of a channel with more queued waits for them to be written
+const MAX_QUEUED: usize = 1024 * 1024;
+// the first byte of a chunk is its channel, whether it is the last one of its message and
+// the wire type 7, which protobuf does not have, to tell it from a whole message
+const CHUNK_FLAG: u8 = 0x7;
+const CHUNK_LAST: u8 = 0x8;
+
+#[derive(Default)]
+pub(crate) struct Mux {
+    // the peer takes chunks
+    chunked: bool,
+    queued: [VecDeque<Vec<u8>>; 3],
+    partial: [BytesMut; 3],
+}
+
+// Both sides send and receive on the streams of the channels, a request creating a job goes
+// on the channel of what follows it, e.g. the blocks of an upload, to stay in order with it.
+pub(crate) struct Channels {
+    pub(crate) streams: Vec<FramedStream>,
+    pub(crate) accepted: bool,
+}
+
 impl Deref for FramedStream {
     type Target = Framed<DynTcpStream, BytesCodec>;
 
@@ -56,6 +101,25 @@ impl DerefMut for DynTcpStream {
     }
 }
 
+// Send the CONNECT request and read the response up to its blank line, what follows being the
+// tunnel.
+async fn http_connect(stream: &mut tokio::net::TcpStream, req: &str) -> ResultType<()> {
+    stream.write_all(req.as_bytes()).await?;
+    let mut res = Vec::new();
+    while !res.ends_with(b"\r\n\r\n") {
+        if res.len() >= 8192 {
+            bail!("HTTP proxy response too long");
+        }
+        res.push(stream.read_u8().await?);
+    }
+    let res = String::from_utf8_lossy(&res);
+    let status = res.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("HTTP proxy refused: {}", status);
+    }
+    Ok(())
+}
+
 fn new_socket(addr: std::net::SocketAddr, reuse: bool) -> Result<TcpSocket, std::io::Error> {
     let socket = match addr {
         std::net::SocketAddr::V4(..) => TcpSocket::new_v4()?,
@@ -91,12 +155,7 @@ impl FramedStream {
                 {
                     stream.set_nodelay(true).ok();
                     let addr = stream.local_addr()?;
-                    return Ok(Self(
-                        Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
-                        addr,
-                        None,
-                        0,
-                    ));
+                    return Ok(Self::from(stream, addr));
                 }
             }
         }
@@ -140,12 +199,47 @@ impl FramedStream {
                 .await??
             };
             let addr = stream.local_addr()?;
-            return Ok(Self(
-                Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
-                addr,
-                None,
-                0,
-            ));
+            return Ok(Self::from(stream, addr));
+        }
+        bail!("could not resolve to any address");
+    }
+
+    /// Connect to `target` through the HTTP proxy `proxy` with CONNECT, with basic auth if
+    /// `username` is not empty.
+    pub async fn connect_http<'a, 't, T>(
+        proxy: &'a str,
+        target: T,
+        local_addr: Option<SocketAddr>,
+        username: &'a str,
+        password: &'a str,
+        ms_timeout: u64,
+    ) -> ResultType<Self>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        let target = match target.into_target_addr()? {
+            TargetAddr::Ip(addr) => addr.to_string(),
+            TargetAddr::Domain(domain, port) => format!("{domain}:{port}"),
+        };
+        if let Some(proxy) = lookup_host(proxy).await?.next() {
+            let local = if let Some(addr) = local_addr {
+                addr
+            } else {
+                crate::config::Config::get_any_listen_addr(proxy.is_ipv4())
+            };
+            let mut stream =
+                super::timeout(ms_timeout, new_socket(local, true)?.connect(proxy)).await??;
+            stream.set_nodelay(true).ok();
+            let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+            if !username.trim().is_empty() {
+                let auth =
+                    base64::encode(format!("{username}:{password}"), base64::Variant::Original);
+                req += &format!("Proxy-Authorization: Basic {auth}\r\n");
+            }
+            req += "\r\n";
+            super::timeout(ms_timeout, http_connect(&mut stream, &req)).await??;
+            let addr = stream.local_addr()?;
+            return Ok(Self::from(stream, addr));
         }
         bail!("could not resolve to any address");
     }
@@ -154,8 +248,19 @@ impl FramedStream {
         self.1
     }
 
+    // The stream to run another protocol over, before any message.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn into_inner(self) -> DynTcpStream {
+        self.0.into_inner()
+    }
+
     pub fn set_send_timeout(&mut self, ms: u64) {
         self.3 = ms;
+        if let Some(channels) = self.4.as_mut() {
+            for s in channels.streams.iter_mut() {
+                s.3 = ms;
+            }
+        }
     }
 
     pub fn from(stream: impl TcpStreamTrait + Send + Sync + 'static, addr: SocketAddr) -> Self {
@@ -164,14 +269,33 @@ impl FramedStream {
             addr,
             None,
             0,
+            None,
+            None,
         )
     }
 
+    #[cfg(feature = "quic")]
+    pub(crate) fn set_channels(&mut self, channels: Channels) {
+        self.4 = Some(Box::new(channels));
+    }
+
     pub fn set_raw(&mut self) {
+        self.5 = None;
+        if let Some(keys) = self.2.as_mut() {
+            if keys.set_opaque() {
+                return;
+            }
+        }
         self.0.codec_mut().set_raw();
         self.2 = None;
     }
 
+    /// The peer takes the messages of channels in chunks, which are then queued and written
+    /// in order of priority while waiting for incoming ones, see `next`.
+    pub fn set_chunked(&mut self) {
+        self.5.get_or_insert_with(Default::default).chunked = true;
+    }
+
     pub fn is_secured(&self) -> bool {
         self.2.is_some()
     }
@@ -184,15 +308,99 @@ impl FramedStream {
     #[inline]
     pub async fn send_raw(&mut self, msg: Vec<u8>) -> ResultType<()> {
         let mut msg = msg;
-        if let Some(key) = self.2.as_mut() {
-            key.1 += 1;
-            let nonce = Self::get_nonce(key.1);
-            msg = secretbox::seal(&msg, &nonce, &key.0);
+        if self.2.as_ref().map(|x| x.is_opaque()) == Some(true) {
+            msg.insert(0, rekey::DATA);
         }
+        let msg = Self::seal(&mut self.2, msg);
         self.send_bytes(bytes::Bytes::from(msg)).await?;
         Ok(())
     }
 
+    /// Send over the stream of the channel if there is one, else queue in chunks if the peer
+    /// takes them, else send over this one.
+    #[inline]
+    pub async fn send_on(&mut self, channel: Channel, msg: &impl Message) -> ResultType<()> {
+        if let Some(s) = self
+            .4
+            .as_mut()
+            .and_then(|c| c.streams.get_mut(channel as usize - 1))
+        {
+            return s.send(msg).await;
+        }
+        let mux = match self.5.as_mut() {
+            Some(mux) if mux.chunked => mux,
+            _ => return self.send(msg).await,
+        };
+        let bytes = msg.write_to_bytes()?;
+        let queued = &mut mux.queued[channel as usize - 1];
+        let n = std::cmp::max(1, (bytes.len() + CHUNK_SIZE - 1) / CHUNK_SIZE);
+        for i in 0..n {
+            let piece = &bytes[i * CHUNK_SIZE..std::cmp::min((i + 1) * CHUNK_SIZE, bytes.len())];
+            let mut chunk = Vec::with_capacity(piece.len() + 1);
+            let last = if i + 1 == n { CHUNK_LAST } else { 0 };
+            chunk.push((channel as u8) << 4 | last | CHUNK_FLAG);
+            chunk.extend_from_slice(piece);
+            queued.push_back(chunk);
+        }
+        if queued.iter().map(|x| x.len()).sum::<usize>() > MAX_QUEUED {
+            let Self(framed, _, key, ms, _, mux) = self;
+            let write = futures::future::poll_fn(|cx| {
+                Self::poll_write_queued(framed, key, mux.as_deref_mut(), cx)
+            });
+            if *ms > 0 {
+                super::timeout(*ms, write).await??;
+            } else {
+                write.await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write the queued frames of the key exchange and chunks as long as the stream takes them.
+    fn poll_write_queued(
+        framed: &mut Framed<DynTcpStream, BytesCodec>,
+        key: &mut Option<Keys>,
+        mux: Option<&mut Mux>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(keys) = key.as_mut() {
+            while keys.has_out() {
+                match framed.poll_ready_unpin(cx) {
+                    Poll::Ready(Ok(())) => {}
+                    res => return res,
+                }
+                if let Some(frame) = keys.next_out() {
+                    framed.start_send_unpin(Bytes::from(frame))?;
+                }
+            }
+        }
+        let mux = match mux {
+            Some(mux) => mux,
+            None => return framed.poll_flush_unpin(cx),
+        };
+        while let Some(i) = PRIORITY
+            .iter()
+            .map(|c| *c as usize - 1)
+            .find(|i| !mux.queued[*i].is_empty())
+        {
+            match framed.poll_ready_unpin(cx) {
+                Poll::Ready(Ok(())) => {}
+                res => return res,
+            }
+            if let Some(chunk) = mux.queued[i].pop_front() {
+                framed.start_send_unpin(Bytes::from(Self::seal(key, chunk)))?;
+            }
+        }
+        framed.poll_flush_unpin(cx)
+    }
+
+    fn seal(key: &mut Option<Keys>, msg: Vec<u8>) -> Vec<u8> {
+        match key.as_mut() {
+            Some(keys) => keys.seal(&msg),
+            None => msg,
+        }
+    }
+
     #[inline]
     pub async fn send_bytes(&mut self, bytes: Bytes) -> ResultType<()> {
         if self.3 > 0 {
@@ -205,23 +413,83 @@ impl FramedStream {
 
     #[inline]
     pub async fn next(&mut self) -> Option<Result<BytesMut, Error>> {
-        let mut res = self.0.next().await;
-        if let Some(key) = self.2.as_mut() {
-            if let Some(Ok(bytes)) = res.as_mut() {
-                key.2 += 1;
-                let nonce = Self::get_nonce(key.2);
-                match secretbox::open(bytes, &nonce, &key.0) {
-                    Ok(res) => {
-                        bytes.clear();
-                        bytes.put_slice(&res);
+        match self.4.as_mut() {
+            Some(channels) if !channels.streams.is_empty() => {
+                let others = futures::future::select_all(
+                    channels
+                        .streams
+                        .iter_mut()
+                        .map(|s| Box::pin(Self::read(&mut s.0, &mut s.2, &mut s.5))),
+                );
+                tokio::select! {
+                    res = Self::read(&mut self.0, &mut self.2, &mut self.5) => res,
+                    (res, ..) = others => res,
+                }
+            }
+            _ => Self::read(&mut self.0, &mut self.2, &mut self.5).await,
+        }
+    }
+
+    // Read a message, writing queued frames meanwhile and handling the ones of the key exchange.
+    async fn read(
+        framed: &mut Framed<DynTcpStream, BytesCodec>,
+        key: &mut Option<Keys>,
+        mux: &mut Option<Box<Mux>>,
+    ) -> Option<Result<BytesMut, Error>> {
+        loop {
+            let mut res = futures::future::poll_fn(|cx| {
+                let res = Self::poll_write_queued(framed, key, mux.as_deref_mut(), cx);
+                if let Poll::Ready(Err(err)) = res {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                framed.poll_next_unpin(cx)
+            })
+            .await;
+            if let Some(keys) = key.as_mut() {
+                if let Some(Ok(bytes)) = res.as_mut() {
+                    if let Err(err) = keys.open(bytes) {
+                        return Some(Err(err));
+                    }
+                }
+            }
+            let head = match res.as_ref() {
+                Some(Ok(bytes)) if !framed.codec().is_raw() => bytes.first().copied(),
+                _ => None,
+            };
+            let (rekeyed, opaque) = key
+                .as_ref()
+                .map(|x| (x.is_rekeyed(), x.is_opaque()))
+                .unwrap_or_default();
+            match head {
+                Some(head) if rekeyed && head & CHUNK_FLAG == rekey::FLAG => {
+                    if let (Some(keys), Some(Ok(bytes))) = (key.as_mut(), res) {
+                        if let Err(err) = keys.handle(&bytes) {
+                            return Some(Err(err));
+                        }
+                    }
+                }
+                Some(rekey::DATA) if opaque => {
+                    if let Some(Ok(bytes)) = res.as_mut() {
+                        bytes.advance(1);
+                    }
+                    return res;
+                }
+                Some(head) if head & CHUNK_FLAG == CHUNK_FLAG => {
+                    let i = (head >> 4) as usize;
+                    if i == 0 || i > PRIORITY.len() {
+                        return Some(Err(Error::new(ErrorKind::Other, "invalid chunk")));
+                    }
+                    let partial = &mut mux.get_or_insert_with(Default::default).partial[i - 1];
+                    if let Some(Ok(bytes)) = res {
+                        partial.extend_from_slice(&bytes[1..]);
                     }
-                    Err(()) => {
-                        return Some(Err(Error::new(ErrorKind::Other, "decryption error")));
+                    if head & CHUNK_LAST != 0 {
+                        return Some(Ok(partial.split()));
                     }
                 }
+                _ => return res,
             }
         }
-        res
     }
 
     #[inline]
@@ -234,13 +502,30 @@ impl FramedStream {
     }
 
     pub fn set_key(&mut self, key: Key) {
-        self.2 = Some((key, 0, 0));
+        if let Some(channels) = self.4.as_mut() {
+            // the sequence numbers of a channel start at its id in the top byte, with the top
+            // bit set for what the accepting side sends, no nonce is used twice with the
+            // shared key
+            for (s, channel) in channels.streams.iter_mut().zip(CHANNELS) {
+                let seqnum = (channel as u64) << 56;
+                let (tx, rx) = if channels.accepted {
+                    (seqnum | 1 << 63, seqnum)
+                } else {
+                    (seqnum, seqnum | 1 << 63)
+                };
+                s.2 = Some(Keys::new(key.clone(), tx, rx));
+            }
+        }
+        self.2 = Some(Keys::new(key, 0, 0));
     }
 
-    fn get_nonce(seqnum: u64) -> Nonce {
-        let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
-        nonce.0[..std::mem::size_of_val(&seqnum)].copy_from_slice(&seqnum.to_le_bytes());
-        nonce
+    /// Change keys with ephemeral ones right away and then from time to time, the peer
+    /// supporting `rekey::KEY_EXCHANGE` and `initiator` being on the connecting side, see
+    /// `rekey`. The streams of channels over QUIC keep theirs.
+    pub fn set_rekey(&mut self, initiator: bool) {
+        if let Some(keys) = self.2.as_mut() {
+            keys.set_rekey(initiator);
+        }
     }
 }
 
@@ -323,3 +608,100 @@ impl AsyncWrite for DynTcpStream {
 }
 
 impl<R: AsyncRead + AsyncWrite + Unpin> TcpStreamTrait for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_proto::{FileTransferBlock, Message as Msg, TestDelay};
+    use sodiumoxide::crypto::secretbox;
+
+    #[tokio::test]
+    async fn test_chunked() {
+        let (a, b) = tokio::io::duplex(1 << 20);
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut a = FramedStream::from(a, addr);
+        let mut b = FramedStream::from(b, addr);
+        let key = secretbox::gen_key();
+        a.set_key(key.clone());
+        b.set_key(key);
+        a.set_chunked();
+        let block = crate::fs::new_block(FileTransferBlock {
+            id: 1,
+            data: vec![7u8; 3 * CHUNK_SIZE].into(),
+            ..Default::default()
+        });
+        a.send_on(Channel::File, &block).await.unwrap();
+        let mut delay = Msg::new();
+        delay.set_test_delay(TestDelay {
+            time: 1,
+            ..Default::default()
+        });
+        a.send(&delay).await.unwrap();
+        // the chunks are written while waiting for incoming messages
+        assert!(a.next_timeout(100).await.is_none());
+        let res = b.next().await.unwrap().unwrap();
+        assert_eq!(&res[..], &delay.write_to_bytes().unwrap()[..]);
+        let res = b.next().await.unwrap().unwrap();
+        assert_eq!(&res[..], &block.write_to_bytes().unwrap()[..]);
+    }
+
+    #[tokio::test]
+    async fn test_rekey() {
+        let (a, b) = tokio::io::duplex(1 << 20);
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut a = FramedStream::from(a, addr);
+        let mut b = FramedStream::from(b, addr);
+        let key = secretbox::gen_key();
+        a.set_key(key.clone());
+        b.set_key(key);
+        a.set_rekey(true);
+        b.set_rekey(false);
+        let mut delay = Msg::new();
+        delay.set_test_delay(TestDelay {
+            time: 1,
+            ..Default::default()
+        });
+        let delay = delay.write_to_bytes().unwrap();
+        a.send_raw(delay.clone()).await.unwrap();
+        // request, response and confirm are written while waiting for incoming messages
+        assert!(a.next_timeout(100).await.is_none());
+        assert_eq!(&b.next().await.unwrap().unwrap()[..], &delay[..]);
+        assert!(b.next_timeout(100).await.is_none());
+        assert!(a.next_timeout(100).await.is_none());
+        assert!(b.next_timeout(100).await.is_none());
+        b.send_raw(delay.clone()).await.unwrap();
+        assert_eq!(&a.next().await.unwrap().unwrap()[..], &delay[..]);
+        // raw data stays encrypted
+        a.set_raw();
+        b.set_raw();
+        a.send_raw(vec![rekey::DATA, 6, 7]).await.unwrap();
+        assert_eq!(&b.next().await.unwrap().unwrap()[..], &[rekey::DATA, 6, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let n = s.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]).to_string();
+            assert!(req.starts_with("CONNECT example.com:21117 HTTP/1.1\r\n"));
+            // "user:pass"
+            assert!(req.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+            s.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            let mut framed = Framed::new(s, BytesCodec::new());
+            let bytes = framed.next().await.unwrap().unwrap();
+            framed.send(bytes.freeze()).await.unwrap();
+        });
+        let mut s =
+            FramedStream::connect_http(&proxy, "example.com:21117", None, "user", "pass", 1_000)
+                .await
+                .unwrap();
+        s.send_raw(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(&s.next().await.unwrap().unwrap()[..], &[1, 2, 3]);
+    }
+}
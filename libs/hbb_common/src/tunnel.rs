@@ -0,0 +1,436 @@
+// TCP connections multiplexed over one session as TunnelOpen / TunnelData / TunnelClose,
+// for the forwards which can not have a session of their own per connection, i.e. the ones
+// accepted on the controlled side. Either end closing its socket closes the tunnel.
+// UDP sources are multiplexed likewise as UdpDatagram, and forgotten once idle.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    message_proto::*,
+    timeout,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+        sync::mpsc,
+        task::JoinHandle,
+    },
+};
+
+const BUF_SIZE: usize = 32 * 1024;
+const CONNECT_TIMEOUT: u64 = 3000;
+// sockets wait for the session once this many reads are queued,
+// and the session waits for a socket once this many writes are
+const QUEUE_SIZE: usize = 32;
+// a datagram can not be larger
+const UDP_BUF_SIZE: usize = 64 * 1024;
+// a socket is bound per source, the least recently active one makes room beyond this
+const MAX_UDP_SOURCES: usize = 256;
+/// UDP sources are forgotten by both sides after this long without a datagram.
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+enum Event {
+    Opened(i32),
+    Data(i32, Bytes),
+    Close(i32, String),
+}
+
+pub struct Tunnels {
+    sockets: HashMap<i32, mpsc::Sender<Bytes>>,
+    tx: mpsc::Sender<Event>,
+    rx: mpsc::Receiver<Event>,
+    last_id: i32,
+}
+
+impl Default for Tunnels {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        Self {
+            sockets: Default::default(),
+            tx,
+            rx,
+            last_id: 0,
+        }
+    }
+}
+
+impl Tunnels {
+    pub fn new_id(&mut self) -> i32 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    // Relays an accepted socket as tunnel `id`.
+    pub fn add(&mut self, id: i32, socket: TcpStream) {
+        let (tx_data, rx_data) = mpsc::channel(QUEUE_SIZE);
+        self.sockets.insert(id, tx_data);
+        tokio::spawn(relay(id, socket, rx_data, self.tx.clone(), false));
+    }
+
+    // Connects to `addr` and relays it as tunnel `id`, what the peer sends meanwhile is queued.
+    // The peer is told with a TunnelOpen once connected.
+    pub fn connect(&mut self, id: i32, addr: String) {
+        let (tx_data, rx_data) = mpsc::channel(QUEUE_SIZE);
+        self.sockets.insert(id, tx_data);
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let err = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+                Ok(Ok(socket)) => return relay(id, socket, rx_data, tx, true).await,
+                Ok(Err(err)) => err.to_string(),
+                Err(_) => "Timeout".to_owned(),
+            };
+            log::info!("Failed to connect {} for tunnel {}: {}", addr, id, err);
+            drop(rx_data);
+            tx.send(Event::Close(id, err)).await.ok();
+        });
+    }
+
+    // Waits while the socket of the tunnel has QUEUE_SIZE writes pending.
+    pub async fn send(&mut self, id: i32, data: Bytes) {
+        if let Some(tx) = self.sockets.get(&id) {
+            tx.send(data).await.ok();
+        }
+    }
+
+    // Closed by the peer, the socket is dropped once its queue is written.
+    pub fn close(&mut self, id: i32) {
+        self.sockets.remove(&id);
+    }
+
+    // The next message for the peer, pending while there is none.
+    pub async fn next(&mut self) -> Message {
+        loop {
+            match self.rx.recv().await {
+                Some(Event::Opened(id)) => {
+                    if self.sockets.contains_key(&id) {
+                        return new_tunnel_open(id, "".to_owned(), 0);
+                    }
+                }
+                Some(Event::Data(id, data)) => {
+                    if self.sockets.contains_key(&id) {
+                        return new_tunnel_data(id, data);
+                    }
+                }
+                Some(Event::Close(id, error)) => {
+                    if self.sockets.remove(&id).is_some() {
+                        return new_tunnel_close(id, error);
+                    }
+                }
+                // self.tx is never dropped
+                None => std::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+// Reads and writes do not wait for each other, a socket waiting for the session to take
+// its reads still writes what the session waits to give it. The peer is told with a
+// TunnelOpen first if `opened`.
+async fn relay(
+    id: i32,
+    socket: TcpStream,
+    mut rx: mpsc::Receiver<Bytes>,
+    tx: mpsc::Sender<Event>,
+    opened: bool,
+) {
+    let (mut reader, mut writer) = socket.into_split();
+    let error = {
+        let write = async {
+            while let Some(data) = rx.recv().await {
+                if let Err(err) = writer.write_all(&data).await {
+                    return Some(err.to_string());
+                }
+            }
+            // closed by the peer
+            None
+        };
+        let read = async {
+            if opened && tx.send(Event::Opened(id)).await.is_err() {
+                return None;
+            }
+            let mut buf = vec![0u8; BUF_SIZE];
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) => return Some("".to_owned()),
+                    Ok(n) => {
+                        let data = Bytes::copy_from_slice(&buf[..n]);
+                        if tx.send(Event::Data(id, data)).await.is_err() {
+                            return None;
+                        }
+                    }
+                    Err(err) => return Some(err.to_string()),
+                }
+            }
+        };
+        tokio::select! {
+            res = write => res,
+            res = read => res,
+        }
+    };
+    // the session may be waiting to write to it meanwhile
+    drop(rx);
+    if let Some(error) = error {
+        tx.send(Event::Close(id, error)).await.ok();
+    }
+}
+
+struct UdpSource {
+    socket: Arc<UdpSocket>,
+    last_active: Instant,
+    recv: JoinHandle<()>,
+}
+
+// Every source of a UDP forward, by the id the peer chose for it, sends from a socket of
+// its own to the target, so that the replies can be told apart.
+pub struct UdpTunnels {
+    target: SocketAddr,
+    sources: HashMap<i32, UdpSource>,
+    tx: mpsc::Sender<(i32, Bytes)>,
+    rx: mpsc::Receiver<(i32, Bytes)>,
+}
+
+impl UdpTunnels {
+    pub fn new(target: SocketAddr) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        Self {
+            target,
+            sources: Default::default(),
+            tx,
+            rx,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub async fn send(&mut self, id: i32, data: &[u8]) {
+        if !self.sources.contains_key(&id) {
+            if self.sources.len() >= MAX_UDP_SOURCES {
+                self.evict();
+            }
+            match self.bind().await {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    let recv = tokio::spawn(recv_udp(id, socket.clone(), self.tx.clone()));
+                    self.sources.insert(
+                        id,
+                        UdpSource {
+                            socket,
+                            last_active: Instant::now(),
+                            recv,
+                        },
+                    );
+                }
+                Err(err) => {
+                    log::error!("Failed to open udp socket to {}: {}", self.target, err);
+                    return;
+                }
+            }
+        }
+        if let Some(source) = self.sources.get_mut(&id) {
+            source.last_active = Instant::now();
+            // lost like any datagram
+            source.socket.send(data).await.ok();
+        }
+    }
+
+    async fn bind(&self) -> std::io::Result<UdpSocket> {
+        let socket = if self.target.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        socket.connect(self.target).await?;
+        Ok(socket)
+    }
+
+    fn evict(&mut self) {
+        let id = self
+            .sources
+            .iter()
+            .min_by_key(|(_, source)| source.last_active)
+            .map(|(id, _)| *id);
+        if let Some(source) = id.and_then(|id| self.sources.remove(&id)) {
+            log::info!("udp source {:?} evicted", id);
+            source.recv.abort();
+        }
+    }
+
+    // Forgets the sources idle for UDP_IDLE_TIMEOUT.
+    pub fn expire(&mut self) {
+        self.sources.retain(|id, source| {
+            if source.last_active.elapsed() < UDP_IDLE_TIMEOUT {
+                return true;
+            }
+            log::info!("udp source {} expired", id);
+            source.recv.abort();
+            false
+        });
+    }
+
+    // The next reply for the peer, pending while there is none.
+    pub async fn next(&mut self) -> Message {
+        loop {
+            match self.rx.recv().await {
+                Some((id, data)) => {
+                    if let Some(source) = self.sources.get_mut(&id) {
+                        source.last_active = Instant::now();
+                        return new_udp_datagram(id, data);
+                    }
+                }
+                // self.tx is never dropped
+                None => std::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+impl Drop for UdpTunnels {
+    fn drop(&mut self) {
+        for source in self.sources.values() {
+            source.recv.abort();
+        }
+    }
+}
+
+async fn recv_udp(id: i32, socket: Arc<UdpSocket>, tx: mpsc::Sender<(i32, Bytes)>) {
+    let mut buf = vec![0u8; UDP_BUF_SIZE];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(n) => {
+                if tx
+                    .send((id, Bytes::copy_from_slice(&buf[..n])))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            // an icmp unreachable of an earlier datagram, the next may be answered
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {}
+            Err(err) => {
+                log::info!("udp source {} stopped receiving: {}", id, err);
+                break;
+            }
+        }
+    }
+}
+
+pub fn new_tunnel_open(id: i32, host: String, port: i32) -> Message {
+    let mut msg_out = Message::new();
+    msg_out.set_tunnel_open(TunnelOpen {
+        id,
+        host,
+        port,
+        ..Default::default()
+    });
+    msg_out
+}
+
+pub fn new_tunnel_data(id: i32, data: Bytes) -> Message {
+    let mut msg_out = Message::new();
+    msg_out.set_tunnel_data(TunnelData {
+        id,
+        data,
+        ..Default::default()
+    });
+    msg_out
+}
+
+pub fn new_tunnel_close(id: i32, error: String) -> Message {
+    let mut msg_out = Message::new();
+    msg_out.set_tunnel_close(TunnelClose {
+        id,
+        error,
+        ..Default::default()
+    });
+    msg_out
+}
+
+pub fn new_udp_datagram(id: i32, data: Bytes) -> Message {
+    let mut msg_out = Message::new();
+    msg_out.set_udp_datagram(UdpDatagram {
+        id,
+        data,
+        ..Default::default()
+    });
+    msg_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_tunnels() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+        let mut tunnels = Tunnels::default();
+        let id = tunnels.new_id();
+        tunnels.connect(id, addr);
+        tunnels.send(id, Bytes::from_static(b"hello")).await;
+        let msg = tunnels.next().await;
+        assert_eq!(msg.tunnel_open().id, id);
+        let msg = tunnels.next().await;
+        assert_eq!(msg.tunnel_data().data.as_ref(), b"hello");
+        let msg = tunnels.next().await;
+        assert_eq!(msg.tunnel_close().id, id);
+        assert!(tunnels.is_empty());
+
+        let id = tunnels.new_id();
+        tunnels.connect(id, "127.0.0.1:1".to_owned());
+        let msg = tunnels.next().await;
+        assert!(!msg.tunnel_close().error.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_udp_tunnels() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tunnels = UdpTunnels::new(server.local_addr().unwrap());
+        tunnels.send(1, b"ping").await;
+        let mut buf = [0u8; 16];
+        let (n, addr) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        server.send_to(b"pong", addr).await.unwrap();
+        let msg = tunnels.next().await;
+        assert_eq!(msg.udp_datagram().id, 1);
+        assert_eq!(msg.udp_datagram().data.as_ref(), b"pong");
+
+        for id in 2..=MAX_UDP_SOURCES as i32 + 1 {
+            tunnels.send(id, b"ping").await;
+        }
+        assert_eq!(tunnels.sources.len(), MAX_UDP_SOURCES);
+        assert!(!tunnels.sources.contains_key(&1));
+
+        for source in tunnels.sources.values_mut() {
+            source.last_active -= UDP_IDLE_TIMEOUT;
+        }
+        tunnels.expire();
+        assert!(tunnels.is_empty());
+    }
+}
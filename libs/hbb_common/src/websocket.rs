@@ -0,0 +1,221 @@
+// WebSocket, over TLS for wss://, as a transport of the rendezvous and relay protocols where
+// only https goes out: a server set as a ws:// or wss:// url is connected to over tcp as any
+// other one, then each message of the stream goes in a binary message of the WebSocket, as
+// the WebSocket listeners of hbbs and hbbr take them. Raw data, see `FramedStream::set_raw`,
+// has no messages and can not go over it.
+use crate::{
+    bytes_codec::BytesCodec,
+    tcp::{DynTcpStream, FramedStream},
+    timeout, ResultType,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{ready, SinkExt, StreamExt};
+use std::{
+    io::{self, Error, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The host:port to connect to over tcp for a ws:// or wss:// url, none for other addresses.
+pub fn get_addr(url: &str) -> Option<String> {
+    let (rest, port) = if let Some(rest) = url.strip_prefix("wss://") {
+        (rest, 443)
+    } else if let Some(rest) = url.strip_prefix("ws://") {
+        (rest, 80)
+    } else {
+        return None;
+    };
+    let host = rest.split(&['/', '?'][..]).next().unwrap_or_default();
+    if host.is_empty() {
+        return None;
+    }
+    Some(crate::socket_client::check_port(host, port))
+}
+
+/// The url of the relay server next to the rendezvous one at `url`, at the path `/ws/relay`
+/// for `/ws/id`, else at the next port, none if neither, e.g. for the default port.
+pub fn get_relay_url(url: &str) -> Option<String> {
+    get_addr(url)?;
+    let i = url.find("://")? + 3;
+    let (scheme, rest) = url.split_at(i);
+    let j = rest.find(&['/', '?'][..]).unwrap_or(rest.len());
+    let (host, path) = rest.split_at(j);
+    if let Some(query) = path.strip_prefix("/ws/id") {
+        if query.is_empty() || query.starts_with('?') {
+            return Some(format!("{scheme}{host}/ws/relay{query}"));
+        }
+    }
+    let relay = crate::socket_client::increase_port(host, 1);
+    if relay == host {
+        return None;
+    }
+    Some(format!("{scheme}{relay}{path}"))
+}
+
+/// Open the WebSocket of `url` over `stream`, connected to its `get_addr`.
+pub async fn connect(url: &str, stream: FramedStream, ms_timeout: u64) -> ResultType<FramedStream> {
+    let addr = stream.local_addr();
+    let (ws, _) = timeout(
+        ms_timeout,
+        tokio_tungstenite::client_async_tls(url, stream.into_inner()),
+    )
+    .await??;
+    Ok(FramedStream::from(
+        WsStream {
+            ws,
+            codec: BytesCodec::new(),
+            read: BytesMut::new(),
+            write: BytesMut::new(),
+        },
+        addr,
+    ))
+}
+
+// Between the frames of `BytesCodec` written and read by `FramedStream` and the binary
+// messages of the WebSocket.
+struct WsStream {
+    ws: WebSocketStream<MaybeTlsStream<DynTcpStream>>,
+    codec: BytesCodec,
+    // what is left of the frames of the binary messages read
+    read: BytesMut,
+    // what is written and not sent yet, up to a part of a frame
+    write: BytesMut,
+}
+
+impl WsStream {
+    // Send each whole frame written in a binary message of its own.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.ws.poll_ready_unpin(cx))
+                .map_err(|err| Error::new(ErrorKind::Other, err))?;
+            match self.codec.decode(&mut self.write)? {
+                Some(msg) => self
+                    .ws
+                    .start_send_unpin(Message::Binary(msg.to_vec()))
+                    .map_err(|err| Error::new(ErrorKind::Other, err))?,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read.is_empty() {
+            match ready!(self.ws.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    let this = &mut *self;
+                    this.codec.encode(Bytes::from(data), &mut this.read)?;
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // pings are answered by tungstenite
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(Error::new(ErrorKind::Other, err))),
+            }
+        }
+        let n = std::cmp::min(buf.remaining(), self.read.len());
+        buf.put_slice(&self.read[..n]);
+        self.read.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_send(cx))?;
+        self.write.extend_from_slice(buf);
+        if let Poll::Ready(Err(err)) = self.poll_send(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send(cx))?;
+        self.ws
+            .poll_flush_unpin(cx)
+            .map_err(|err| Error::new(ErrorKind::Other, err))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.ws
+            .poll_close_unpin(cx)
+            .map_err(|err| Error::new(ErrorKind::Other, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_addr() {
+        assert_eq!(
+            get_addr("wss://rs.example.com").unwrap(),
+            "rs.example.com:443"
+        );
+        assert_eq!(
+            get_addr("wss://rs.example.com:8443/ws/id").unwrap(),
+            "rs.example.com:8443"
+        );
+        assert_eq!(get_addr("ws://1.2.3.4/ws/relay").unwrap(), "1.2.3.4:80");
+        assert_eq!(get_addr("ws://[::1]:8080?a=b").unwrap(), "[::1]:8080");
+        assert!(get_addr("rs.example.com:21116").is_none());
+        assert!(get_addr("wss://").is_none());
+    }
+
+    #[test]
+    fn test_get_relay_url() {
+        assert_eq!(
+            get_relay_url("wss://rs.example.com/ws/id").unwrap(),
+            "wss://rs.example.com/ws/relay"
+        );
+        assert_eq!(
+            get_relay_url("ws://1.2.3.4:21118").unwrap(),
+            "ws://1.2.3.4:21119"
+        );
+        assert_eq!(
+            get_relay_url("wss://rs.example.com:8443/ws?a=b").unwrap(),
+            "wss://rs.example.com:8444/ws?a=b"
+        );
+        assert!(get_relay_url("wss://rs.example.com").is_none());
+        assert!(get_relay_url("rs.example.com:21116").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(s).await.unwrap();
+            // one message, without the head of its frame, per binary message
+            for len in [3, 100_000] {
+                let msg = ws.next().await.unwrap().unwrap();
+                assert!(msg.is_binary());
+                assert_eq!(msg.len(), len);
+                ws.send(msg).await.unwrap();
+            }
+        });
+        let stream = FramedStream::new(addr, None, 1_000).await.unwrap();
+        let mut stream = connect(&format!("ws://{addr}/ws/id"), stream, 1_000)
+            .await
+            .unwrap();
+        stream.send_raw(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(&stream.next().await.unwrap().unwrap()[..], &[1, 2, 3]);
+        let big = vec![1u8; 100_000];
+        stream.send_raw(big.clone()).await.unwrap();
+        assert_eq!(&stream.next().await.unwrap().unwrap()[..], &big[..]);
+    }
+}
@@ -0,0 +1,133 @@
+// Time-based one-time codes of RFC 6238 as shown by authenticator apps, asked for after a
+// correct password once a secret is enrolled with --enable-2fa.
+use crate::common::hmac;
+use hbb_common::{bail, config::Config, get_time, lazy_static, rand, ResultType};
+use sha1::Sha1;
+use std::sync::Mutex;
+
+/// Login error asking for an [`hbb_common::message_proto::Auth2FA`].
+pub const REQUIRE_2FA: &str = "2FA Required";
+pub const WRONG_2FA_CODE: &str = "Wrong 2FA Code";
+
+// seconds
+const STEP: i64 = 30;
+const DIGITS: usize = 6;
+// the codes of the steps next to the current one are taken as well, for clocks a little off
+const SKEW: i64 = 1;
+const SECRET_LEN: usize = 20;
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+lazy_static::lazy_static! {
+    // the last step a code was taken for, a code is not taken twice
+    static ref LAST_STEP: Mutex<i64> = Default::default();
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    !Config::get_totp_secret().is_empty()
+}
+
+pub fn new_secret() -> String {
+    let secret: Vec<u8> = (0..SECRET_LEN).map(|_| rand::random()).collect();
+    base32_encode(&secret)
+}
+
+// The uri of the QR code an authenticator app is enrolled with.
+pub fn get_uri(secret: &str, id: &str) -> ResultType<String> {
+    let app = crate::get_app_name();
+    let mut uri = url::Url::parse("otpauth://totp")?;
+    uri.set_path(&format!("{}:{}", app, id));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", &app)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP.to_string());
+    Ok(uri.to_string())
+}
+
+pub fn verify(code: &str) -> ResultType<bool> {
+    let key = match base32_decode(&Config::get_totp_secret()) {
+        Some(key) if !key.is_empty() => key,
+        _ => bail!("Invalid 2FA secret"),
+    };
+    let now = get_time() / 1000 / STEP;
+    let mut last_step = LAST_STEP.lock().unwrap();
+    for step in (now - SKEW)..=(now + SKEW) {
+        if step > *last_step && get_code(&key, step) == code.trim() {
+            *last_step = step;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn get_code(key: &[u8], step: i64) -> String {
+    let hash = hmac::<Sha1>(key, &step.to_be_bytes());
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let n = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!("{:0width$}", n % 10u32.pow(DIGITS as _), width = DIGITS)
+}
+
+// RFC 4648 without padding, as authenticator apps take the secret.
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for b in data {
+        buf = buf << 8 | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[(buf >> bits & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[(buf << (5 - bits) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        if c == b'=' || c.is_ascii_whitespace() {
+            continue;
+        }
+        let v = BASE32_ALPHABET
+            .iter()
+            .position(|x| *x == c.to_ascii_uppercase())?;
+        buf = buf << 5 | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totp() {
+        // RFC 6238 appendix B, the last 6 of the 8 digits
+        let key = b"12345678901234567890";
+        assert_eq!(get_code(key, 59 / STEP), "287082");
+        assert_eq!(get_code(key, 1111111109 / STEP), "081804");
+        assert_eq!(get_code(key, 2000000000 / STEP), "279037");
+        let secret = base32_encode(key);
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&secret.to_lowercase()).unwrap(), key);
+        assert_eq!(base32_decode(&new_secret()).unwrap().len(), SECRET_LEN);
+    }
+}
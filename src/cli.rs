@@ -1,17 +1,51 @@
 use crate::client::*;
+#[cfg(unix)]
+use hbb_common::libc;
 use hbb_common::{
+    allow_err, bail,
     config::PeerConfig,
     config::READ_TIMEOUT,
+    fs::{self, can_enable_overwrite_detection, DigestCheckResult},
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
-    tokio::{self, sync::mpsc},
-    Stream,
+    tcp::Channel,
+    tokio::{
+        self,
+        io::AsyncReadExt,
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
+    ResultType, Stream,
 };
+use serde_derive::Deserialize;
+use std::future::Future;
+use std::io::Write;
 use std::sync::{Arc, RwLock};
 
+/// Environment variable holding the password of the peer, used if no password is stored.
+pub const PASSWORD_ENV: &str = "RUSTDESK_PASSWORD";
+/// Environment variable holding the account on the peer, if its auth provider asks for one.
+pub const ACCOUNT_ENV: &str = "RUSTDESK_ACCOUNT";
+/// Environment variable holding the one-time code, if the peer asks for one.
+pub const CODE_2FA_ENV: &str = "RUSTDESK_2FA_CODE";
+
+/// Exit status of `--send` / `--get`.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_TRANSFER_ERROR: i32 = 1;
+pub const EXIT_CONNECT_ERROR: i32 = 2;
+pub const EXIT_LOGIN_ERROR: i32 = 3;
+/// Exit status of `--exec` if the command could not be run, as ssh does.
+/// Otherwise it is the one of the remote command.
+pub const EXIT_EXEC_FAILED: i32 = 255;
+
+const JOB_ID: i32 = 1;
+const PROGRESS_INTERVAL: u128 = 500;
+const RESTART_DELAY_MIN: Duration = Duration::from_secs(1);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(60);
+
 #[derive(Clone)]
 pub struct Session {
     id: String,
@@ -21,9 +55,14 @@ pub struct Session {
 }
 
 impl Session {
-    pub fn new(id: &str, sender: mpsc::UnboundedSender<Data>) -> Self {
-        let mut password = "".to_owned();
-        if PeerConfig::load(id).password.is_empty() {
+    pub fn new(id: &str, conn_type: ConnType, sender: mpsc::UnboundedSender<Data>) -> Self {
+        let mut password = std::env::var(PASSWORD_ENV).unwrap_or_default();
+        // file transfer and exec are not interactive, the peer refuses the login if no password is given
+        if password.is_empty()
+            && conn_type != ConnType::FILE_TRANSFER
+            && conn_type != ConnType::REMOTE_EXEC
+            && PeerConfig::load(id).password.is_empty()
+        {
             password = rpassword::prompt_password("Enter password: ").unwrap();
         }
         let session = Self {
@@ -36,9 +75,37 @@ impl Session {
             .lc
             .write()
             .unwrap()
-            .initialize(id.to_owned(), ConnType::PORT_FORWARD, None);
+            .initialize(id.to_owned(), conn_type, None, false);
+        session.lc.write().unwrap().account = std::env::var(ACCOUNT_ENV).unwrap_or_default();
         session
     }
+
+    // The same peer and password with a login config and a channel of its own,
+    // so that concurrent sessions do not log in with the config of each other.
+    fn fork(&self) -> (Self, mpsc::UnboundedReceiver<Data>) {
+        let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+        let session = Self {
+            id: self.id.clone(),
+            sender,
+            password: self.password.clone(),
+            lc: Default::default(),
+        };
+        {
+            let lc = self.lc.read().unwrap();
+            let mut new_lc = session.lc.write().unwrap();
+            new_lc.initialize(self.id.clone(), lc.conn_type, None, false);
+            new_lc.account = lc.account.clone();
+        }
+        (session, receiver)
+    }
+}
+
+// The one-time code the peer asks for, the terminal is only asked without CODE_2FA_ENV.
+fn read_2fa_code() -> String {
+    match std::env::var(CODE_2FA_ENV) {
+        Ok(code) if !code.is_empty() => code,
+        _ => rpassword::prompt_password("Enter 2FA code: ").unwrap(),
+    }
 }
 
 #[async_trait]
@@ -49,13 +116,27 @@ impl Interface for Session {
 
     fn msgbox(&self, msgtype: &str, title: &str, text: &str, link: &str) {
         if msgtype == "input-password" {
-            self.sender
-                .send(Data::Login((self.password.clone(), true)))
-                .ok();
+            let lc = self.lc.read().unwrap();
+            // a password sent as is is only the preset one if confirmed in advance
+            let password = if lc.is_account_login() && !lc.is_account_login_confirmed() {
+                log::warn!("{}", text);
+                rpassword::prompt_password(format!("Password of {}: ", lc.get_account())).unwrap()
+            } else {
+                self.password.clone()
+            };
+            self.sender.send(Data::Login((password, true))).ok();
         } else if msgtype == "re-input-password" {
             log::error!("{}: {}", title, text);
             let pass = rpassword::prompt_password("Enter password: ").unwrap();
             self.sender.send(Data::Login((pass, true))).ok();
+        } else if msgtype == "input-2fa" {
+            self.sender
+                .send(Data::Message(new_auth_2fa(read_2fa_code())))
+                .ok();
+        } else if msgtype == "re-input-2fa" {
+            log::error!("{}: {}", title, text);
+            let code = rpassword::prompt_password("Enter 2FA code: ").unwrap();
+            self.sender.send(Data::Message(new_auth_2fa(code))).ok();
         } else if msgtype.contains("error") {
             log::error!("{}: {}: {}", msgtype, title, text);
         } else {
@@ -95,7 +176,7 @@ impl Interface for Session {
 #[tokio::main(flavor = "current_thread")]
 pub async fn connect_test(id: &str, key: String, token: String) {
     let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
-    let handler = Session::new(&id, sender);
+    let handler = Session::new(&id, ConnType::PORT_FORWARD, sender);
     match crate::client::Client::start(id, &key, &token, ConnType::PORT_FORWARD, handler).await {
         Err(err) => {
             log::error!("Failed to connect {}: {}", &id, err);
@@ -140,7 +221,7 @@ pub async fn start_one_port_forward(
     crate::common::test_rendezvous_server();
     crate::common::test_nat_type();
     let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
-    let handler = Session::new(&id, sender);
+    let handler = Session::new(&id, ConnType::PORT_FORWARD, sender);
     if let Err(err) = crate::port_forward::listen(
         handler.id.clone(),
         handler.password.clone(),
@@ -159,3 +240,933 @@ pub async fn start_one_port_forward(
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+/// A local port forwarded to `remote_host:remote_port` of the peer, one `[[forward]]`
+/// table of the file given to `--port-forwards`, or one element of `"forward"` if json.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PortForwardRule {
+    pub local_port: i32,
+    #[serde(default = "default_remote_host")]
+    pub remote_host: String,
+    pub remote_port: i32,
+    /// Datagrams of a UDP service instead of TCP connections.
+    #[serde(default)]
+    pub udp: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PortForwardFile {
+    #[serde(default)]
+    forward: Vec<PortForwardRule>,
+}
+
+fn default_remote_host() -> String {
+    "localhost".to_owned()
+}
+
+fn check_port_forwards(rules: Vec<PortForwardRule>) -> ResultType<Vec<PortForwardRule>> {
+    if rules.is_empty() {
+        bail!("No port forward");
+    }
+    let mut local_ports = std::collections::HashSet::new();
+    for r in rules.iter() {
+        if r.local_port <= 0 || r.local_port > 65535 || r.remote_port <= 0 || r.remote_port > 65535
+        {
+            bail!("Wrong port in {:?}", r);
+        }
+        if !local_ports.insert((r.local_port, r.udp)) {
+            bail!("Local port {} is forwarded twice", r.local_port);
+        }
+    }
+    Ok(rules)
+}
+
+fn parse_port_forwards(content: &str, is_json: bool) -> ResultType<Vec<PortForwardRule>> {
+    let file: PortForwardFile = if is_json {
+        serde_json::from_str(content)?
+    } else {
+        toml::from_str(content)?
+    };
+    check_port_forwards(file.forward)
+}
+
+/// Port forwards of a toml file, or of a json one if named so.
+pub fn load_port_forwards(path: &str) -> ResultType<Vec<PortForwardRule>> {
+    let content = std::fs::read_to_string(path)?;
+    parse_port_forwards(&content, path.to_lowercase().ends_with(".json"))
+}
+
+/// Port forwards saved for the peer in the gui.
+pub fn load_peer_port_forwards(id: &str) -> ResultType<Vec<PortForwardRule>> {
+    check_port_forwards(
+        PeerConfig::load(id)
+            .port_forwards
+            .into_iter()
+            .map(|(local_port, remote_host, remote_port)| PortForwardRule {
+                local_port,
+                remote_host: if remote_host.is_empty() {
+                    default_remote_host()
+                } else {
+                    remote_host
+                },
+                remote_port,
+                udp: false,
+            })
+            .collect(),
+    )
+}
+
+/// Forward every local port of `rules`, the password is asked at most once. The TCP ones
+/// are tunnelled over one session, each UDP one has a session of its own. A session which
+/// fails, its tunnels with it, is started again after a while.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_port_forwards(
+    id: String,
+    rules: Vec<PortForwardRule>,
+    key: String,
+    token: String,
+) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, ConnType::PORT_FORWARD, sender);
+    let (tcp, udp): (Vec<_>, Vec<_>) = rules.into_iter().partition(|r| !r.udp);
+    let mut groups = udp.into_iter().map(|r| vec![r]).collect::<Vec<_>>();
+    if !tcp.is_empty() {
+        groups.push(tcp);
+    }
+    let tasks = groups.into_iter().map(|rules| {
+        let handler = handler.clone();
+        let key = key.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            let ports = rules
+                .iter()
+                .map(|r| r.local_port.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let name = format!("port forward (:{})", ports);
+            restart_forward(&handler, &name, |handler, receiver| {
+                let rules = rules.clone();
+                let key = key.clone();
+                let token = token.clone();
+                async move {
+                    if rules[0].udp {
+                        let rule = &rules[0];
+                        crate::port_forward::listen_udp(
+                            &handler.id,
+                            &handler.password,
+                            rule.local_port,
+                            rule.remote_host.clone(),
+                            rule.remote_port,
+                            handler.clone(),
+                            receiver,
+                            &key,
+                            &token,
+                        )
+                        .await
+                    } else {
+                        crate::port_forward::listen_all(
+                            &handler.id,
+                            &handler.password,
+                            rules
+                                .iter()
+                                .map(|r| (r.local_port, r.remote_host.clone(), r.remote_port))
+                                .collect(),
+                            handler.clone(),
+                            receiver,
+                            &key,
+                            &token,
+                        )
+                        .await
+                    }
+                }
+            })
+            .await;
+        })
+    });
+    hbb_common::futures::future::join_all(tasks).await;
+}
+
+// Run a forward of `handler` with a session of its own until it stops without an error,
+// start it again after a failure, waiting twice as long as the previous time unless it
+// ran for a while.
+async fn restart_forward<F, R>(handler: &Session, name: &str, mut start: F)
+where
+    F: FnMut(Session, mpsc::UnboundedReceiver<Data>) -> R,
+    R: Future<Output = ResultType<()>>,
+{
+    let mut delay = RESTART_DELAY_MIN;
+    loop {
+        let (handler, receiver) = handler.fork();
+        let started = Instant::now();
+        match start(handler, receiver).await {
+            Ok(_) => break,
+            Err(err) => log::error!("{} failed: {}", name, err),
+        }
+        if started.elapsed() >= RESTART_DELAY_MAX {
+            delay = RESTART_DELAY_MIN;
+        }
+        log::info!("Restarting {} in {:?}", name, delay);
+        time::sleep(delay).await;
+        delay = std::cmp::min(delay * 2, RESTART_DELAY_MAX);
+    }
+    log::info!("{} exit", name);
+}
+
+/// Let the peer listen on `remote_port` of its loopback and forward the connections to
+/// `local_host:local_port` of this network. It is started again after a failure.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_reverse_forward(
+    id: String,
+    remote_port: i32,
+    local_host: String,
+    local_port: i32,
+    key: String,
+    token: String,
+) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, ConnType::PORT_FORWARD, sender);
+    let name = format!("reverse port forward (:{})", remote_port);
+    restart_forward(&handler, &name, |handler, receiver| {
+        let local_host = local_host.clone();
+        let key = key.clone();
+        let token = token.clone();
+        async move {
+            crate::port_forward::reverse(
+                &handler.id,
+                &handler.password,
+                remote_port,
+                local_host,
+                local_port,
+                handler.clone(),
+                receiver,
+                &key,
+                &token,
+            )
+            .await
+        }
+    })
+    .await;
+}
+
+/// SOCKS5 proxy on `port` of this machine, whose connections are opened by the peer.
+/// It is started again after a failure.
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_dynamic_forward(id: String, port: i32, key: String, token: String) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, ConnType::PORT_FORWARD, sender);
+    let name = format!("dynamic port forward (:{})", port);
+    restart_forward(&handler, &name, |handler, receiver| {
+        let key = key.clone();
+        let token = token.clone();
+        async move {
+            crate::port_forward::socks5(
+                &handler.id,
+                &handler.password,
+                port,
+                handler.clone(),
+                receiver,
+                &key,
+                &token,
+            )
+            .await
+        }
+    })
+    .await;
+}
+
+/// Copy `path` of this machine to `to` on the peer if `is_upload`, otherwise `path`
+/// of the peer to `to` of this machine. Progress goes to stderr, the returned value
+/// is the exit status of the process.
+#[tokio::main(flavor = "current_thread")]
+pub async fn transfer_files(
+    id: String,
+    path: String,
+    to: String,
+    is_upload: bool,
+    key: String,
+    token: String,
+) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
+    let mut handler = Session::new(&id, ConnType::FILE_TRANSFER, sender);
+    let mut stream = match Client::start(&id, &key, &token, ConnType::FILE_TRANSFER, handler.clone())
+        .await
+    {
+        Ok((stream, direct)) => {
+            log::info!("direct: {}", direct);
+            stream
+        }
+        Err(err) => {
+            eprintln!("Failed to connect {}: {}", id, err);
+            return EXIT_CONNECT_ERROR;
+        }
+    };
+    let mut read_jobs: Vec<fs::TransferJob> = Vec::new();
+    let mut scheduler = fs::TransferScheduler::default();
+    let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+    let mut timer = time::interval(Duration::from_millis(1));
+    let mut last_recv_time = Instant::now();
+    let mut last_progress_time = Instant::now();
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => {
+                        eprintln!("\nConnection error: {}", err);
+                        return EXIT_CONNECT_ERROR;
+                    }
+                    None => {
+                        eprintln!("\nConnection closed by peer");
+                        return EXIT_CONNECT_ERROR;
+                    }
+                };
+                last_recv_time = Instant::now();
+                let msg_in = match Message::parse_from_bytes(&bytes) {
+                    Ok(msg_in) => msg_in,
+                    Err(_) => continue,
+                };
+                match msg_in.union {
+                    Some(message::Union::Hash(hash)) => {
+                        let password = handler.password.clone();
+                        handler.handle_hash(&password, hash, &mut stream).await;
+                    }
+                    Some(message::Union::LoginResponse(lr)) => match lr.union {
+                        Some(login_response::Union::Error(err)) if err == crate::auth_2fa::REQUIRE_2FA => {
+                            allow_err!(stream.send(&new_auth_2fa(read_2fa_code())).await);
+                        }
+                        Some(login_response::Union::Error(err)) => {
+                            eprintln!("Login failed: {}", err);
+                            return EXIT_LOGIN_ERROR;
+                        }
+                        Some(login_response::Union::PeerInfo(pi)) => {
+                            handler.handle_peer_info(pi);
+                            let od = can_enable_overwrite_detection(handler.lc.read().unwrap().version);
+                            let parallel = handler.lc.read().unwrap().get_file_transfer_parallel();
+                            if is_upload {
+                                match fs::TransferJob::new_read(JOB_ID, to.clone(), path.clone(), 0, false, false, od) {
+                                    Err(err) => {
+                                        eprintln!("Failed to read {}: {}", path, err);
+                                        return EXIT_TRANSFER_ERROR;
+                                    }
+                                    Ok(mut job) => {
+                                        if !handler.lc.read().unwrap().is_file_links_supported() {
+                                            job.follow_links();
+                                        }
+                                        job.set_parallel(parallel);
+                                        #[cfg(not(windows))]
+                                        let (files, dirs) = (job.files().clone(), job.dirs().clone());
+                                        #[cfg(windows)]
+                                        let (mut files, mut dirs) = (job.files().clone(), job.dirs().clone());
+                                        #[cfg(windows)]
+                                        if handler.lc.read().unwrap().info.platform != "Windows" {
+                                            fs::transform_windows_path(&mut files);
+                                            fs::transform_windows_path(&mut dirs);
+                                        }
+                                        read_jobs.push(job);
+                                        allow_err!(stream.send_on(Channel::File, &fs::new_receive(JOB_ID, to.clone(), 0, files, dirs, parallel)).await);
+                                    }
+                                }
+                            } else {
+                                let mut job = fs::TransferJob::new_write(
+                                    JOB_ID,
+                                    path.clone(),
+                                    to.clone(),
+                                    0,
+                                    false,
+                                    false,
+                                    Vec::new(),
+                                    od,
+                                );
+                                let archive = handler.lc.read().unwrap().get_toggle_option(fs::OPTION_FILE_TRANSFER_ARCHIVE);
+                                job.set_parallel(parallel);
+                                job.set_archive(archive);
+                                write_jobs.push(job);
+                                allow_err!(stream.send(&fs::new_send(JOB_ID, path.clone(), 0, false, parallel, archive)).await);
+                            }
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::TestDelay(t)) => {
+                        if !t.from_client {
+                            scheduler.update_delay(t.last_delay);
+                        }
+                        handler.handle_test_delay(t, &mut stream).await;
+                    }
+                    Some(message::Union::FileAction(action)) => match action.union {
+                        Some(file_action::Union::SendConfirm(c)) => {
+                            if let Some(job) = fs::get_job(c.id, &mut read_jobs) {
+                                job.confirm(&c);
+                            }
+                        }
+                        Some(file_action::Union::Verified(v)) => {
+                            if let Some(job) = fs::get_job(v.id, &mut read_jobs) {
+                                if !v.ok && !v.retransmit {
+                                    eprintln!("\nDigest mismatch, transfer failed");
+                                    return EXIT_TRANSFER_ERROR;
+                                }
+                                job.verified(&v);
+                            }
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::FileResponse(fr)) => match fr.union {
+                        Some(file_response::Union::Dir(fd)) => {
+                            if let Some(job) = fs::get_job(fd.id, &mut write_jobs) {
+                                #[cfg(not(windows))]
+                                let (mut entries, mut dirs) = (fd.entries.to_vec(), fd.dirs.to_vec());
+                                #[cfg(windows)]
+                                let (entries, dirs) = (fd.entries.to_vec(), fd.dirs.to_vec());
+                                #[cfg(not(windows))]
+                                if handler.lc.read().unwrap().info.platform == "Windows" {
+                                    fs::transform_windows_path(&mut entries);
+                                    fs::transform_windows_path(&mut dirs);
+                                }
+                                job.set_files(entries);
+                                job.set_dirs(dirs);
+                                job.set_archive(fd.archive);
+                            }
+                        }
+                        Some(file_response::Union::Digest(digest)) => {
+                            let req = if digest.is_upload {
+                                match fs::get_job(digest.id, &mut read_jobs) {
+                                    Some(job) => {
+                                        job.set_peer_digest(&digest);
+                                        let req = new_confirm(&digest, digest.is_identical);
+                                        job.confirm(&req);
+                                        req
+                                    }
+                                    None => continue,
+                                }
+                            } else {
+                                match fs::get_job(digest.id, &mut write_jobs) {
+                                    Some(job) => {
+                                        job.set_peer_support_delta(digest.support_delta);
+                                        match job.retransmitting() {
+                                            Some(file_num) if file_num == digest.file_num => {}
+                                            Some(_) if job.parallel() <= 1 => continue,
+                                            _ => {}
+                                        }
+                                        let write_path = match job.files().get(digest.file_num as usize) {
+                                            Some(file) => fs::get_string(&job.join(&file.name)),
+                                            None => continue,
+                                        };
+                                        // identical files are skipped, the others are overwritten
+                                        let req = if job.retransmitting() == Some(digest.file_num) {
+                                            job.gen_write_confirm(digest.file_num).await
+                                        } else {
+                                            match fs::is_write_need_confirmation(&write_path, &digest) {
+                                                Ok(DigestCheckResult::IsSame) => new_confirm(&digest, true),
+                                                Ok(DigestCheckResult::NeedConfirm(d)) if d.is_identical => {
+                                                    new_confirm(&digest, true)
+                                                }
+                                                Ok(_) => job.gen_write_confirm(digest.file_num).await,
+                                                Err(err) => {
+                                                    eprintln!("\nFailed to check {}: {}", write_path, err);
+                                                    return EXIT_TRANSFER_ERROR;
+                                                }
+                                            }
+                                        };
+                                        job.confirm(&req);
+                                        req
+                                    }
+                                    None => continue,
+                                }
+                            };
+                            allow_err!(stream.send(&fs::new_send_confirm(req)).await);
+                        }
+                        Some(file_response::Union::Block(block)) => {
+                            if let Some(job) = fs::get_job(block.id, &mut write_jobs) {
+                                match job.write(block).await {
+                                    Ok(Some(verified)) => {
+                                        if verified.retransmit {
+                                            eprintln!("\nDigest mismatch, sending again");
+                                        }
+                                        allow_err!(stream.send(&fs::new_verified(verified)).await);
+                                    }
+                                    Ok(None) => {}
+                                    Err(err) => {
+                                        eprintln!("\nFailed to write {}: {}", to, err);
+                                        job.remove_download_file();
+                                        return EXIT_TRANSFER_ERROR;
+                                    }
+                                }
+                                if last_progress_time.elapsed().as_millis() >= PROGRESS_INTERVAL {
+                                    last_progress_time = Instant::now();
+                                    print_progress(job);
+                                }
+                            }
+                        }
+                        Some(file_response::Union::Done(d)) => {
+                            if d.id == JOB_ID {
+                                if let Some(job) = fs::get_job(d.id, &mut write_jobs) {
+                                    job.modify_time();
+                                    print_progress(job);
+                                }
+                                eprintln!("\nDone");
+                                return EXIT_OK;
+                            }
+                        }
+                        Some(file_response::Union::Error(e)) => {
+                            if e.id == JOB_ID {
+                                if let Some(job) = fs::get_job(e.id, &mut write_jobs) {
+                                    job.remove_download_file();
+                                }
+                                if e.error == "skipped" {
+                                    eprintln!("\nSkipped, the file is identical");
+                                    return EXIT_OK;
+                                }
+                                eprintln!("\nTransfer failed: {}", e.error);
+                                return EXIT_TRANSFER_ERROR;
+                            }
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::Misc(misc)) => {
+                        if let Some(misc::Union::CloseReason(c)) = misc.union {
+                            eprintln!("\nConnection closed: {}", c);
+                            return EXIT_CONNECT_ERROR;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = timer.tick() => {
+                if !read_jobs.is_empty() {
+                    let res = match fs::handle_read_jobs(&mut read_jobs, &mut stream, &mut scheduler).await {
+                        Ok(res) => res,
+                        Err(err) => {
+                            eprintln!("\nConnection error: {}", err);
+                            return EXIT_CONNECT_ERROR;
+                        }
+                    };
+                    // the peer sends no done for uploads, the job is over once removed
+                    for (_, err) in res {
+                        match err {
+                            Some(err) if err == "skipped" => {
+                                eprintln!("\nSkipped, the file is identical");
+                                return EXIT_OK;
+                            }
+                            Some(err) => {
+                                eprintln!("\nFailed to read {}: {}", path, err);
+                                return EXIT_TRANSFER_ERROR;
+                            }
+                            None if read_jobs.is_empty() => {
+                                eprintln!("\nDone");
+                                return EXIT_OK;
+                            }
+                            None => {}
+                        }
+                    }
+                    if let Some(job) = read_jobs.first() {
+                        if last_progress_time.elapsed().as_millis() >= PROGRESS_INTERVAL {
+                            last_progress_time = Instant::now();
+                            print_progress(job);
+                        }
+                    }
+                }
+                if last_recv_time.elapsed().as_millis() > READ_TIMEOUT as u128 {
+                    eprintln!("\nTimeout");
+                    return EXIT_CONNECT_ERROR;
+                }
+            }
+        }
+    }
+}
+
+/// Run `command` on the peer, relaying stdin, stdout and stderr. The returned value is
+/// the exit status of the remote command, or [`EXIT_EXEC_FAILED`].
+#[tokio::main(flavor = "current_thread")]
+pub async fn exec(id: String, command: Vec<String>, key: String, token: String) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
+    let mut handler = Session::new(&id, ConnType::REMOTE_EXEC, sender);
+    let mut command = command.into_iter();
+    handler.lc.write().unwrap().exec = (command.next().unwrap_or_default(), command.collect());
+    let mut stream =
+        match Client::start(&id, &key, &token, ConnType::REMOTE_EXEC, handler.clone()).await {
+            Ok((stream, direct)) => {
+                log::info!("direct: {}", direct);
+                stream
+            }
+            Err(err) => {
+                eprintln!("Failed to connect {}: {}", id, err);
+                return EXIT_EXEC_FAILED;
+            }
+        };
+    let mut stdin = None;
+    let mut buf = vec![0u8; 32 * 1024];
+    let mut logged_in = false;
+    let mut timer = time::interval(Duration::from_secs(1));
+    let mut last_recv_time = Instant::now();
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => {
+                        eprintln!("Connection error: {}", err);
+                        return EXIT_EXEC_FAILED;
+                    }
+                    None => {
+                        eprintln!("Connection closed by peer");
+                        return EXIT_EXEC_FAILED;
+                    }
+                };
+                last_recv_time = Instant::now();
+                let msg_in = match Message::parse_from_bytes(&bytes) {
+                    Ok(msg_in) => msg_in,
+                    Err(_) => continue,
+                };
+                match msg_in.union {
+                    Some(message::Union::Hash(hash)) => {
+                        let password = handler.password.clone();
+                        handler.handle_hash(&password, hash, &mut stream).await;
+                    }
+                    Some(message::Union::LoginResponse(lr)) => match lr.union {
+                        Some(login_response::Union::Error(err)) if err == crate::auth_2fa::REQUIRE_2FA => {
+                            allow_err!(stream.send(&new_auth_2fa(read_2fa_code())).await);
+                        }
+                        Some(login_response::Union::Error(err)) => {
+                            eprintln!("Login failed: {}", err);
+                            return EXIT_EXEC_FAILED;
+                        }
+                        Some(login_response::Union::PeerInfo(pi)) => {
+                            // an older peer takes it for a remote control login
+                            if !pi.features.remote_exec {
+                                eprintln!("The peer does not support remote exec");
+                                return EXIT_EXEC_FAILED;
+                            }
+                            handler.handle_peer_info(pi);
+                            logged_in = true;
+                            stdin = Some(tokio::io::stdin());
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::TestDelay(t)) => {
+                        handler.handle_test_delay(t, &mut stream).await;
+                    }
+                    Some(message::Union::ExecOutput(output)) => {
+                        let res = if output.is_stderr {
+                            let mut stderr = std::io::stderr();
+                            stderr.write_all(&output.data).and_then(|_| stderr.flush())
+                        } else {
+                            let mut stdout = std::io::stdout();
+                            stdout.write_all(&output.data).and_then(|_| stdout.flush())
+                        };
+                        if res.is_err() {
+                            // e.g. a closed pipe, the remote command is killed along with us
+                            return EXIT_EXEC_FAILED;
+                        }
+                    }
+                    Some(message::Union::ExecExit(exit)) => {
+                        if !exit.error.is_empty() {
+                            eprintln!("Remote exec failed: {}", exit.error);
+                        }
+                        return if exit.code < 0 { EXIT_EXEC_FAILED } else { exit.code };
+                    }
+                    Some(message::Union::Misc(misc)) => {
+                        if let Some(misc::Union::CloseReason(c)) = misc.union {
+                            eprintln!("Connection closed: {}", c);
+                            return EXIT_EXEC_FAILED;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            res = read_stdin(&mut stdin, &mut buf) => {
+                let eof = res.is_none();
+                if eof {
+                    stdin.take();
+                }
+                let mut msg_out = Message::new();
+                msg_out.set_exec_input(ExecInput {
+                    data: res.unwrap_or_default().into(),
+                    eof,
+                    ..Default::default()
+                });
+                allow_err!(stream.send(&msg_out).await);
+            }
+            _ = timer.tick() => {
+                // the command may be silent for as long as it likes once started
+                if !logged_in && last_recv_time.elapsed().as_millis() > READ_TIMEOUT as u128 {
+                    eprintln!("Timeout");
+                    return EXIT_EXEC_FAILED;
+                }
+            }
+        }
+    }
+}
+
+/// Interactive shell on the peer, with the local terminal in raw mode meanwhile. The
+/// returned value is the exit status of the remote shell, or [`EXIT_EXEC_FAILED`].
+#[cfg(unix)]
+#[tokio::main(flavor = "current_thread")]
+pub async fn terminal(id: String, key: String, token: String) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
+    let mut handler = Session::new(&id, ConnType::TERMINAL, sender);
+    let mut size = get_terminal_size();
+    handler.lc.write().unwrap().terminal = TerminalRequest {
+        rows: size.0,
+        cols: size.1,
+        term: std::env::var("TERM").unwrap_or_default(),
+        ..Default::default()
+    };
+    let mut stream =
+        match Client::start(&id, &key, &token, ConnType::TERMINAL, handler.clone()).await {
+            Ok((stream, direct)) => {
+                log::info!("direct: {}", direct);
+                stream
+            }
+            Err(err) => {
+                eprintln!("Failed to connect {}: {}", id, err);
+                return EXIT_EXEC_FAILED;
+            }
+        };
+    let mut stdin = None;
+    let mut raw_mode = None;
+    let mut logged_in = false;
+    let mut buf = vec![0u8; 32 * 1024];
+    let mut timer = time::interval(Duration::from_millis(500));
+    let mut last_recv_time = Instant::now();
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => {
+                        raw_mode.take();
+                        eprintln!("Connection error: {}", err);
+                        return EXIT_EXEC_FAILED;
+                    }
+                    None => {
+                        raw_mode.take();
+                        eprintln!("Connection closed by peer");
+                        return EXIT_EXEC_FAILED;
+                    }
+                };
+                last_recv_time = Instant::now();
+                let msg_in = match Message::parse_from_bytes(&bytes) {
+                    Ok(msg_in) => msg_in,
+                    Err(_) => continue,
+                };
+                match msg_in.union {
+                    Some(message::Union::Hash(hash)) => {
+                        let password = handler.password.clone();
+                        handler.handle_hash(&password, hash, &mut stream).await;
+                    }
+                    Some(message::Union::LoginResponse(lr)) => match lr.union {
+                        Some(login_response::Union::Error(err)) if err == crate::auth_2fa::REQUIRE_2FA => {
+                            allow_err!(stream.send(&new_auth_2fa(read_2fa_code())).await);
+                        }
+                        Some(login_response::Union::Error(err)) => {
+                            eprintln!("Login failed: {}", err);
+                            return EXIT_EXEC_FAILED;
+                        }
+                        Some(login_response::Union::PeerInfo(pi)) => {
+                            if !pi.features.terminal {
+                                eprintln!("The peer does not support terminal");
+                                return EXIT_EXEC_FAILED;
+                            }
+                            handler.handle_peer_info(pi);
+                            logged_in = true;
+                            raw_mode = RawMode::new();
+                            stdin = Some(tokio::io::stdin());
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::TestDelay(t)) => {
+                        handler.handle_test_delay(t, &mut stream).await;
+                    }
+                    Some(message::Union::TerminalData(d)) => {
+                        let mut stdout = std::io::stdout();
+                        stdout.write_all(&d.data).and_then(|_| stdout.flush()).ok();
+                    }
+                    Some(message::Union::ExecExit(exit)) => {
+                        raw_mode.take();
+                        if !exit.error.is_empty() {
+                            eprintln!("Terminal failed: {}", exit.error);
+                        }
+                        return if exit.code < 0 { EXIT_EXEC_FAILED } else { exit.code };
+                    }
+                    Some(message::Union::Misc(misc)) => {
+                        if let Some(misc::Union::CloseReason(c)) = misc.union {
+                            raw_mode.take();
+                            eprintln!("Connection closed: {}", c);
+                            return EXIT_EXEC_FAILED;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            res = read_stdin(&mut stdin, &mut buf) => {
+                match res {
+                    Some(data) => {
+                        let mut msg_out = Message::new();
+                        msg_out.set_terminal_data(TerminalData {
+                            data: data.into(),
+                            ..Default::default()
+                        });
+                        allow_err!(stream.send(&msg_out).await);
+                    }
+                    None => {
+                        stdin.take();
+                    }
+                }
+            }
+            _ = timer.tick() => {
+                if !logged_in {
+                    if last_recv_time.elapsed().as_millis() > READ_TIMEOUT as u128 {
+                        eprintln!("Timeout");
+                        return EXIT_EXEC_FAILED;
+                    }
+                    continue;
+                }
+                let new_size = get_terminal_size();
+                if new_size != size {
+                    size = new_size;
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_resize(TerminalResize {
+                        rows: size.0,
+                        cols: size.1,
+                        ..Default::default()
+                    });
+                    allow_err!(stream.send(&msg_out).await);
+                }
+            }
+        }
+    }
+}
+
+// Restores the saved mode of the local terminal on drop.
+#[cfg(unix)]
+struct RawMode(libc::termios);
+
+#[cfg(unix)]
+impl RawMode {
+    fn new() -> Option<Self> {
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return None;
+            }
+            let saved = termios;
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+            Some(Self(saved))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+// rows and columns, 24x80 if stdout is not a terminal
+#[cfg(unix)]
+fn get_terminal_size() -> (i32, i32) {
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) } != 0 || ws.ws_row == 0
+    {
+        return (24, 80);
+    }
+    (ws.ws_row as _, ws.ws_col as _)
+}
+
+// Pends forever until stdin is opened and after it is closed.
+async fn read_stdin(stdin: &mut Option<tokio::io::Stdin>, buf: &mut [u8]) -> Option<Vec<u8>> {
+    match stdin {
+        Some(stdin) => match stdin.read(buf).await {
+            Ok(n) if n > 0 => Some(buf[..n].to_vec()),
+            _ => None,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+fn new_confirm(digest: &FileTransferDigest, skip: bool) -> FileTransferSendConfirmRequest {
+    FileTransferSendConfirmRequest {
+        id: digest.id,
+        file_num: digest.file_num,
+        union: Some(if skip {
+            file_transfer_send_confirm_request::Union::Skip(true)
+        } else {
+            file_transfer_send_confirm_request::Union::OffsetBlk(0)
+        }),
+        ..Default::default()
+    }
+}
+
+fn print_progress(job: &fs::TransferJob) {
+    let total = job.total_size();
+    let finished = job.finished_size();
+    let percent = if total > 0 {
+        finished as f64 * 100. / total as f64
+    } else {
+        100.
+    };
+    let name = job
+        .files()
+        .get(job.file_num() as usize)
+        .map(|f| f.name.as_str())
+        .unwrap_or("");
+    eprint!(
+        "\r{}/{} files, {}/{} bytes ({:.1}%) {}\x1b[K",
+        std::cmp::min(job.file_num() + 1, job.files().len() as i32),
+        job.files().len(),
+        finished,
+        total,
+        percent,
+        name
+    );
+    std::io::stderr().flush().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_forwards() {
+        let rules = parse_port_forwards(
+            r#"
+[[forward]]
+local_port = 8080
+remote_port = 80
+
+[[forward]]
+local_port = 2222
+remote_host = "192.168.1.2"
+remote_port = 22
+"#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].remote_host, "localhost");
+        assert_eq!(rules[1].remote_host, "192.168.1.2");
+        let json = r#"{"forward": [{"local_port": 8080, "remote_port": 80}]}"#;
+        assert_eq!(
+            parse_port_forwards(json, true).unwrap(),
+            rules[..1].to_vec()
+        );
+        let twice = r#"{"forward": [{"local_port": 1, "remote_port": 1}, {"local_port": 1, "remote_port": 2}]}"#;
+        assert!(parse_port_forwards(twice, true).is_err());
+        let udp = r#"{"forward": [{"local_port": 53, "remote_port": 53}, {"local_port": 53, "remote_port": 53, "udp": true}]}"#;
+        assert!(parse_port_forwards(udp, true).unwrap()[1].udp);
+        assert!(parse_port_forwards("", false).is_err());
+    }
+}
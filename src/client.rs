@@ -28,7 +28,7 @@ use hbb_common::{
         Config, PeerConfig, PeerInfoSerde, CONNECT_TIMEOUT, READ_TIMEOUT, RELAY_PORT,
         RENDEZVOUS_TIMEOUT,
     },
-    get_version_number, log,
+    fs, get_version_number, log,
     message_proto::{option_message::BoolOption, *},
     protobuf::Message as _,
     rand,
@@ -231,6 +231,10 @@ impl Client {
         let mut peer_nat_type = NatType::UNKNOWN_NAT;
         let my_nat_type = crate::get_nat_type(100).await;
         let mut is_local = false;
+        // NOTICE: Socks5 is be used event in intranet. Which may be not a good way.
+        let can_quic =
+            cfg!(feature = "quic") && !interface.is_force_relay() && Config::get_socks().is_none();
+        let mut peer_quic = false;
         for i in 1..=3 {
             log::info!("#{} punch attempt with {}, id: {}", i, my_addr, peer);
             let mut msg_out = RendezvousMessage::new();
@@ -246,6 +250,7 @@ impl Client {
                 nat_type: nat_type.into(),
                 licence_key: key.to_owned(),
                 conn_type: conn_type.into(),
+                quic: can_quic,
                 ..Default::default()
             });
             socket.send(&msg_out).await?;
@@ -274,6 +279,7 @@ impl Client {
                             } else {
                                 peer_nat_type = ph.nat_type();
                                 is_local = ph.is_local();
+                                peer_quic = can_quic && ph.quic;
                                 signed_id_pk = ph.pk.into();
                                 relay_server = ph.relay_server;
                                 peer_addr = AddrMangle::decode(&ph.socket_addr);
@@ -343,6 +349,7 @@ impl Client {
             peer_nat_type,
             my_nat_type,
             is_local,
+            peer_quic,
             key,
             token,
             conn_type,
@@ -363,6 +370,7 @@ impl Client {
         peer_nat_type: NatType,
         my_nat_type: i32,
         is_local: bool,
+        peer_quic: bool,
         key: &str,
         token: &str,
         conn_type: ConnType,
@@ -402,9 +410,8 @@ impl Client {
         }
         log::info!("peer address: {}, timeout: {}", peer, connect_timeout);
         let start = std::time::Instant::now();
-        // NOTICE: Socks5 is be used event in intranet. Which may be not a good way.
-        let mut conn =
-            socket_client::connect_tcp_local(peer, Some(local_addr), connect_timeout).await;
+        let try_quic = peer_quic && (is_local || peer_nat_type != NatType::SYMMETRIC);
+        let mut conn = Self::connect_direct(peer, local_addr, connect_timeout, try_quic).await;
         let mut direct = !conn.is_err();
         if interface.is_force_relay() || conn.is_err() {
             if !relay_server.is_empty() {
@@ -441,6 +448,34 @@ impl Client {
         Ok((conn, direct))
     }
 
+    // QUIC over the udp port of the tcp hole punching first if the peer listens for it too,
+    // tcp with what is left of connect_timeout once given up on it. The peer accepts
+    // whichever comes.
+    async fn connect_direct(
+        peer: SocketAddr,
+        local_addr: SocketAddr,
+        connect_timeout: u64,
+        _try_quic: bool,
+    ) -> ResultType<Stream> {
+        #[allow(unused_mut)]
+        let mut connect_timeout = connect_timeout;
+        #[cfg(feature = "quic")]
+        if _try_quic {
+            let start = std::time::Instant::now();
+            // half of the time is left to tcp at least
+            let ms_timeout = std::cmp::min(connect_timeout / 2, hbb_common::quic::CONNECT_TIMEOUT);
+            match hbb_common::quic::connect(local_addr, peer, ms_timeout).await {
+                Ok(conn) => {
+                    log::info!("QUIC connection to {}", peer);
+                    return Ok(conn);
+                }
+                Err(err) => log::info!("QUIC connection to {} failed: {}", peer, err),
+            }
+            connect_timeout = connect_timeout.saturating_sub(start.elapsed().as_millis() as u64);
+        }
+        socket_client::connect_tcp_local(peer, Some(local_addr), connect_timeout).await
+    }
+
     /// Establish secure connection with the server.
     async fn secure_connection(
         peer_id: &str,
@@ -457,7 +492,7 @@ impl Client {
         });
         let mut sign_pk = None;
         if !signed_id_pk.is_empty() && rs_pk.is_some() {
-            if let Ok((id, pk)) = decode_id_pk(&signed_id_pk, &rs_pk.unwrap()) {
+            if let Ok((id, pk, _)) = decode_id_pk(&signed_id_pk, &rs_pk.unwrap()) {
                 if id == peer_id {
                     sign_pk = Some(sign::PublicKey(pk));
                 }
@@ -485,7 +520,7 @@ impl Client {
                 };
                 if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                     if let Some(message::Union::SignedId(si)) = msg_in.union {
-                        if let Ok((id, their_pk_b)) = decode_id_pk(&si.id, &sign_pk) {
+                        if let Ok((id, their_pk_b, key_exchange)) = decode_id_pk(&si.id, &sign_pk) {
                             if id == peer_id {
                                 let their_pk_b = box_::PublicKey(their_pk_b);
                                 let (our_pk_b, out_sk_b) = box_::gen_keypair();
@@ -496,10 +531,17 @@ impl Client {
                                 msg_out.set_public_key(PublicKey {
                                     asymmetric_value: Vec::from(our_pk_b.0).into(),
                                     symmetric_value: sealed_key.into(),
+                                    key_exchange: std::cmp::min(
+                                        key_exchange,
+                                        hbb_common::rekey::KEY_EXCHANGE,
+                                    ),
                                     ..Default::default()
                                 });
                                 timeout(CONNECT_TIMEOUT, conn.send(&msg_out)).await??;
                                 conn.set_key(key);
+                                if key_exchange > 0 {
+                                    conn.set_rekey(true);
+                                }
                             } else {
                                 log::error!("Handshake failed: sign failure");
                                 conn.send(&Message::new()).await?;
@@ -974,6 +1016,20 @@ pub struct LoginConfigHandler {
     pub remember: bool,
     config: PeerConfig,
     pub port_forward: (String, i32),
+    /// The peer listens on the port of `port_forward` and tunnels the connections back.
+    pub reverse_forward: bool,
+    /// Nothing is forwarded at login, the connections are opened with `TunnelOpen` later.
+    pub dynamic_forward: bool,
+    /// With `dynamic_forward`, the only `host:port` the connections may be opened to.
+    pub forward_targets: Vec<String>,
+    /// The port of `port_forward` is a UDP one.
+    pub udp_forward: bool,
+    /// Program and arguments of [`ConnType::REMOTE_EXEC`].
+    pub exec: (String, Vec<String>),
+    /// Size and TERM of [`ConnType::TERMINAL`].
+    pub terminal: TerminalRequest,
+    /// Account on the peer if its auth provider asks for one, see [`Self::get_account`].
+    pub account: String,
     pub version: i64,
     pub conn_id: i32,
     features: Option<Features>,
@@ -1262,7 +1318,18 @@ impl LoginConfigHandler {
     ///
     /// * `ignore_default` - If `true`, ignore the default value of the option.
     fn get_option_message(&self, ignore_default: bool) -> Option<OptionMessage> {
-        if self.conn_type.eq(&ConnType::FILE_TRANSFER) || self.conn_type.eq(&ConnType::PORT_FORWARD)
+        if self.conn_type.eq(&ConnType::FILE_TRANSFER) {
+            let limit = self.get_file_transfer_rate_limit();
+            if limit == 0 {
+                return None;
+            }
+            let mut msg = OptionMessage::new();
+            msg.file_transfer_rate_limit = Some(limit);
+            return Some(msg);
+        }
+        if self.conn_type.eq(&ConnType::PORT_FORWARD)
+            || self.conn_type.eq(&ConnType::REMOTE_EXEC)
+            || self.conn_type.eq(&ConnType::TERMINAL)
         {
             return None;
         }
@@ -1321,8 +1388,45 @@ impl LoginConfigHandler {
         }
     }
 
+    /// Get the file transfer rate limit of this session in KB/s, 0 means unlimited.
+    pub fn get_file_transfer_rate_limit(&self) -> u32 {
+        self.options
+            .get(fs::OPTION_FILE_TRANSFER_RATE_LIMIT)
+            .map(|v| fs::parse_rate_limit(v))
+            .unwrap_or(0)
+    }
+
+    /// Whether the peer recreates the links sent to it, else links are read as their files.
+    pub fn is_file_links_supported(&self) -> bool {
+        self.features
+            .as_ref()
+            .map(|f| f.file_links)
+            .unwrap_or(false)
+    }
+
+    /// Get the number of files a transfer job of this session sends at once,
+    /// 1 unless the peer supports it.
+    pub fn get_file_transfer_parallel(&self) -> u32 {
+        if !self
+            .features
+            .as_ref()
+            .map(|f| f.parallel_transfer)
+            .unwrap_or(false)
+        {
+            return 1;
+        }
+        let v = match self.options.get(fs::OPTION_FILE_TRANSFER_PARALLEL) {
+            Some(v) => v.clone(),
+            None => Config::get_option(fs::OPTION_FILE_TRANSFER_PARALLEL),
+        };
+        v.trim().parse().unwrap_or(1)
+    }
+
     pub fn get_option_message_after_login(&self) -> Option<OptionMessage> {
-        if self.conn_type.eq(&ConnType::FILE_TRANSFER) || self.conn_type.eq(&ConnType::PORT_FORWARD)
+        if self.conn_type.eq(&ConnType::FILE_TRANSFER)
+            || self.conn_type.eq(&ConnType::PORT_FORWARD)
+            || self.conn_type.eq(&ConnType::REMOTE_EXEC)
+            || self.conn_type.eq(&ConnType::TERMINAL)
         {
             return None;
         }
@@ -1473,6 +1577,26 @@ impl LoginConfigHandler {
         msg_out
     }
 
+    /// The account given, or the one of the peer options. The local user is never assumed.
+    pub fn get_account(&self) -> String {
+        if !self.account.is_empty() {
+            return self.account.clone();
+        }
+        self.get_option("account")
+    }
+
+    /// The peer checks the password of an account, which is sent as is.
+    pub fn is_account_login(&self) -> bool {
+        self.hash.account_login
+    }
+
+    /// Whether the password of an account may be sent as is without asking, because the
+    /// account is given for this session or the user marked the peer as checking it with
+    /// its auth provider.
+    pub fn is_account_login_confirmed(&self) -> bool {
+        !self.account.is_empty() || self.get_option("account-login") == "Y"
+    }
+
     pub fn get_option(&self, k: &str) -> String {
         if let Some(v) = self.config.options.get(k) {
             v.clone()
@@ -1570,6 +1694,23 @@ impl LoginConfigHandler {
     }
 
     /// Create a [`Message`] for login.
+    // The challenge signed with the key pair of this side, which `--public-key` shows.
+    fn get_key_login(&self) -> Option<KeyLogin> {
+        let (sk, pk) = Config::get_key_pair();
+        if sk.len() != sign::SECRETKEYBYTES {
+            return None;
+        }
+        let mut sk_ = [0u8; sign::SECRETKEYBYTES];
+        sk_[..].copy_from_slice(&sk);
+        let data = crate::common::get_key_login_data(&self.id, &self.hash);
+        let signature = sign::sign_detached(&data, &sign::SecretKey(sk_));
+        Some(KeyLogin {
+            public_key: pk.into(),
+            signature: signature.as_ref().to_vec().into(),
+            ..Default::default()
+        })
+    }
+
     fn create_login_msg(&self, password: Vec<u8>) -> Message {
         #[cfg(any(target_os = "android", target_os = "ios"))]
         let my_id = Config::get_id_or(crate::common::DEVICE_ID.lock().unwrap().clone());
@@ -1583,8 +1724,16 @@ impl LoginConfigHandler {
             option: self.get_option_message(true).into(),
             session_id: self.session_id,
             version: crate::VERSION.to_string(),
+            chunked: true,
+            file_links: true,
             ..Default::default()
         };
+        if self.hash.account_login {
+            lr.account = self.get_account();
+        }
+        if self.hash.key_login {
+            lr.key_login = self.get_key_login().into();
+        }
         match self.conn_type {
             ConnType::FILE_TRANSFER => lr.set_file_transfer(FileTransfer {
                 dir: self.get_remote_dir(),
@@ -1594,8 +1743,18 @@ impl LoginConfigHandler {
             ConnType::PORT_FORWARD => lr.set_port_forward(PortForward {
                 host: self.port_forward.0.clone(),
                 port: self.port_forward.1,
+                reverse: self.reverse_forward,
+                dynamic: self.dynamic_forward,
+                udp: self.udp_forward,
+                targets: self.forward_targets.clone(),
                 ..Default::default()
             }),
+            ConnType::REMOTE_EXEC => lr.set_exec(ExecRequest {
+                program: self.exec.0.clone(),
+                args: self.exec.1.clone(),
+                ..Default::default()
+            }),
+            ConnType::TERMINAL => lr.set_terminal(self.terminal.clone()),
             _ => {}
         }
 
@@ -1879,6 +2038,12 @@ pub fn handle_login_error(
         lc.write().unwrap().password = Default::default();
         interface.msgbox("re-input-password", err, "Do you want to enter again?", "");
         true
+    } else if err == crate::auth_2fa::REQUIRE_2FA {
+        interface.msgbox("input-2fa", err, "", "");
+        true
+    } else if err == crate::auth_2fa::WRONG_2FA_CODE {
+        interface.msgbox("re-input-2fa", err, "Do you want to enter again?", "");
+        true
     } else if err == "No Password Access" {
         lc.write().unwrap().password = Default::default();
         interface.msgbox(
@@ -1922,6 +2087,36 @@ pub async fn handle_hash(
             return;
         }
     }
+    if hash.account_login {
+        // the password is sent as is, which the peer only asks for on a secured connection
+        if !peer.is_secured() {
+            interface.msgbox(
+                "error",
+                "Login Error",
+                "The peer asks for a password on an insecure connection",
+                "",
+            );
+            return;
+        }
+        if lc.read().unwrap().get_account().is_empty() {
+            interface.msgbox(
+                "error",
+                "Login Error",
+                "The peer asks for the password of an account, which is not given",
+                "",
+            );
+            return;
+        }
+        // nothing preset or remembered is sent, the password is asked every time
+        send_login(lc.clone(), Vec::new(), peer).await;
+        let text = if lc.read().unwrap().is_account_login_confirmed() {
+            ""
+        } else {
+            "The peer checks the password with an account of its own, it is sent to it as is."
+        };
+        interface.msgbox("input-password", "Password Required", text, "");
+        return;
+    }
     let mut password = lc.read().unwrap().password.clone();
     if password.is_empty() {
         if !password_preset.is_empty() {
@@ -1974,6 +2169,11 @@ pub async fn handle_login_from_ui(
     remember: bool,
     peer: &mut Stream,
 ) {
+    if lc.read().unwrap().hash.account_login {
+        // sent as is to be checked with the account, not to be remembered
+        send_login(lc.clone(), password.into_bytes(), peer).await;
+        return;
+    }
     let mut hasher = Sha256::new();
     hasher.update(password);
     hasher.update(&lc.read().unwrap().hash.salt);
@@ -2047,6 +2247,8 @@ pub enum Data {
     RemoveDir((i32, String)),
     RemoveFile((i32, String, i32, bool)),
     CreateDir((i32, String, bool)),
+    RenameFile((i32, String, String, bool)),
+    CopyFile((i32, String, String, bool)),
     CancelJob(i32),
     RemovePortForward(i32),
     AddPortForward((i32, String, i32)),
@@ -2235,12 +2437,13 @@ fn get_rs_pk(str_base64: &str) -> Option<sign::PublicKey> {
     }
 }
 
-fn decode_id_pk(signed: &[u8], key: &sign::PublicKey) -> ResultType<(String, [u8; 32])> {
+// id, public key and version of the key exchange
+fn decode_id_pk(signed: &[u8], key: &sign::PublicKey) -> ResultType<(String, [u8; 32], u32)> {
     let res = IdPk::parse_from_bytes(
         &sign::verify(signed, key).map_err(|_| anyhow!("Signature mismatch"))?,
     )?;
     if let Some(pk) = get_pk(&res.pk) {
-        Ok((res.id, pk))
+        Ok((res.id, pk, res.key_exchange))
     } else {
         bail!("Wrong public length");
     }
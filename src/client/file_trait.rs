@@ -45,6 +45,30 @@ pub trait FileManager: Interface {
         self.send(Data::Message(msg_out));
     }
 
+    // `tail_lines` > 0 reads the end of the file instead of `offset`, see FileReadRange
+    fn read_remote_range(&self, id: i32, path: String, offset: u64, length: u32, tail_lines: u32) {
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_read_range(FileReadRange {
+            id,
+            path,
+            offset,
+            length,
+            tail_lines,
+            ..Default::default()
+        });
+        msg_out.set_file_action(file_action);
+        self.send(Data::Message(msg_out));
+    }
+
+    fn search_remote_files(&self, search: FileSearch) {
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_search(search);
+        msg_out.set_file_action(file_action);
+        self.send(Data::Message(msg_out));
+    }
+
     fn remove_file(&self, id: i32, path: String, file_num: i32, is_remote: bool) {
         self.send(Data::RemoveFile((id, path, file_num, is_remote)));
     }
@@ -73,6 +97,14 @@ pub trait FileManager: Interface {
         self.send(Data::CreateDir((id, path, is_remote)));
     }
 
+    fn rename_file(&self, id: i32, path: String, new_path: String, is_remote: bool) {
+        self.send(Data::RenameFile((id, path, new_path, is_remote)));
+    }
+
+    fn copy_file(&self, id: i32, path: String, new_path: String, is_remote: bool) {
+        self.send(Data::CopyFile((id, path, new_path, is_remote)));
+    }
+
     fn send_files(
         &self,
         id: i32,
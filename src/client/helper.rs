@@ -1,6 +1,8 @@
 use hbb_common::{
     get_time,
-    message_proto::{video_frame, Message, VideoFrame, VoiceCallRequest, VoiceCallResponse},
+    message_proto::{
+        video_frame, Auth2FA, Message, VideoFrame, VoiceCallRequest, VoiceCallResponse,
+    },
 };
 
 #[derive(PartialEq, Debug, Clone)]
@@ -62,3 +64,13 @@ pub fn new_voice_call_response(request_timestamp: i64, accepted: bool) -> Messag
     msg.set_voice_call_response(resp);
     msg
 }
+
+#[inline]
+pub fn new_auth_2fa(code: String) -> Message {
+    let mut msg = Message::new();
+    msg.set_auth_2fa(Auth2FA {
+        code,
+        ..Default::default()
+    });
+    msg
+}
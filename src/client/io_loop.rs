@@ -27,7 +27,7 @@ use hbb_common::tokio::{
     sync::mpsc,
     time::{self, Duration, Instant, Interval},
 };
-use hbb_common::{allow_err, fs, get_time, log, message_proto::*, Stream};
+use hbb_common::{allow_err, fs, get_time, log, message_proto::*, tcp::Channel, Stream};
 
 use crate::client::{
     new_voice_call_request, Client, CodecFormat, MediaData, MediaSender, QualityStatus, MILLI1,
@@ -52,6 +52,7 @@ pub struct Remote<T: InvokeUiSession> {
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     old_clipboard: Arc<Mutex<String>>,
     read_jobs: Vec<fs::TransferJob>,
+    file_scheduler: fs::TransferScheduler,
     write_jobs: Vec<fs::TransferJob>,
     remove_jobs: HashMap<i32, RemoveJob>,
     timer: Interval,
@@ -74,6 +75,9 @@ impl<T: InvokeUiSession> Remote<T> {
         sender: mpsc::UnboundedSender<Data>,
         frame_count: Arc<AtomicUsize>,
     ) -> Self {
+        let rate_limit = handler.lc.read().unwrap().get_file_transfer_rate_limit();
+        let mut file_scheduler = fs::TransferScheduler::default();
+        file_scheduler.set_session_limit(rate_limit);
         Self {
             handler,
             video_sender,
@@ -83,6 +87,7 @@ impl<T: InvokeUiSession> Remote<T> {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             old_clipboard: Default::default(),
             read_jobs: Vec::new(),
+            file_scheduler,
             write_jobs: Vec::new(),
             remove_jobs: Default::default(),
             timer: time::interval(SEC30),
@@ -210,7 +215,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 break;
                             }
                             if !self.read_jobs.is_empty() {
-                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
+                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer, &mut self.file_scheduler).await {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
                                     break;
                                 }
@@ -364,15 +369,23 @@ impl<T: InvokeUiSession> Remote<T> {
             Data::ToggleClipboardFile => {
                 self.check_clipboard_file_context();
             }
-            Data::Message(msg) => {
-                allow_err!(peer.send(&msg).await);
-            }
+            Data::Message(msg) => match crate::common::get_channel(&msg) {
+                Some(channel) => allow_err!(peer.send_on(channel, &msg).await),
+                None => allow_err!(peer.send(&msg).await),
+            },
             Data::SendFiles((id, path, to, file_num, include_hidden, is_remote)) => {
                 log::info!("send files, is remote {}", is_remote);
                 let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
+                let parallel = self.handler.lc.read().unwrap().get_file_transfer_parallel();
                 if is_remote {
                     log::debug!("New job {}, write to {} from remote {}", id, to, path);
-                    self.write_jobs.push(fs::TransferJob::new_write(
+                    let archive = self
+                        .handler
+                        .lc
+                        .read()
+                        .unwrap()
+                        .get_toggle_option(fs::OPTION_FILE_TRANSFER_ARCHIVE);
+                    let mut job = fs::TransferJob::new_write(
                         id,
                         path.clone(),
                         to,
@@ -381,10 +394,21 @@ impl<T: InvokeUiSession> Remote<T> {
                         is_remote,
                         Vec::new(),
                         od,
-                    ));
+                    );
+                    job.set_parallel(parallel);
+                    // confirmed by the peer along with the listing
+                    job.set_archive(archive);
+                    self.write_jobs.push(job);
                     allow_err!(
-                        peer.send(&fs::new_send(id, path, file_num, include_hidden))
-                            .await
+                        peer.send(&fs::new_send(
+                            id,
+                            path,
+                            file_num,
+                            include_hidden,
+                            parallel,
+                            archive
+                        ))
+                        .await
                     );
                 } else {
                     match fs::TransferJob::new_read(
@@ -399,7 +423,11 @@ impl<T: InvokeUiSession> Remote<T> {
                         Err(err) => {
                             self.handle_job_status(id, -1, Some(err.to_string()));
                         }
-                        Ok(job) => {
+                        Ok(mut job) => {
+                            if !self.handler.lc.read().unwrap().is_file_links_supported() {
+                                job.follow_links();
+                            }
+                            job.set_parallel(parallel);
                             log::debug!(
                                 "New job {}, read {} to remote {}, {} files",
                                 id,
@@ -415,23 +443,32 @@ impl<T: InvokeUiSession> Remote<T> {
                                 true,
                             );
                             #[cfg(not(windows))]
-                            let files = job.files().clone();
+                            let (files, dirs) = (job.files().clone(), job.dirs().clone());
                             #[cfg(windows)]
-                            let mut files = job.files().clone();
+                            let (mut files, mut dirs) = (job.files().clone(), job.dirs().clone());
                             #[cfg(windows)]
                             if self.handler.peer_platform() != "Windows" {
                                 // peer is not windows, need transform \ to /
                                 fs::transform_windows_path(&mut files);
+                                fs::transform_windows_path(&mut dirs);
                             }
                             self.read_jobs.push(job);
                             self.timer = time::interval(MILLI1);
-                            allow_err!(peer.send(&fs::new_receive(id, to, file_num, files)).await);
+                            // on the channel of the blocks, which must not overtake it
+                            allow_err!(
+                                peer.send_on(
+                                    Channel::File,
+                                    &fs::new_receive(id, to, file_num, files, dirs, parallel)
+                                )
+                                .await
+                            );
                         }
                     }
                 }
             }
             Data::AddJob((id, path, to, file_num, include_hidden, is_remote)) => {
                 let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
+                let parallel = self.handler.lc.read().unwrap().get_file_transfer_parallel();
                 if is_remote {
                     log::debug!(
                         "new write waiting job {}, write to {} from remote {}",
@@ -449,6 +486,14 @@ impl<T: InvokeUiSession> Remote<T> {
                         Vec::new(),
                         od,
                     );
+                    job.set_parallel(parallel);
+                    job.set_archive(
+                        self.handler
+                            .lc
+                            .read()
+                            .unwrap()
+                            .get_toggle_option(fs::OPTION_FILE_TRANSFER_ARCHIVE),
+                    );
                     job.is_last_job = true;
                     self.write_jobs.push(job);
                 } else {
@@ -465,6 +510,9 @@ impl<T: InvokeUiSession> Remote<T> {
                             self.handle_job_status(id, -1, Some(err.to_string()));
                         }
                         Ok(mut job) => {
+                            if !self.handler.lc.read().unwrap().is_file_links_supported() {
+                                job.follow_links();
+                            }
                             log::debug!(
                                 "new read waiting job {}, read {} to remote {}, {} files",
                                 id,
@@ -479,6 +527,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                 !is_remote,
                                 true,
                             );
+                            job.set_parallel(parallel);
                             job.is_last_job = true;
                             self.read_jobs.push(job);
                             self.timer = time::interval(MILLI1);
@@ -495,7 +544,9 @@ impl<T: InvokeUiSession> Remote<T> {
                                 id,
                                 job.remote.clone(),
                                 job.file_num,
-                                job.show_hidden
+                                job.show_hidden,
+                                job.parallel(),
+                                job.is_archive()
                             ))
                             .await
                         );
@@ -504,12 +555,17 @@ impl<T: InvokeUiSession> Remote<T> {
                     if let Some(job) = get_job(id, &mut self.read_jobs) {
                         job.is_last_job = false;
                         allow_err!(
-                            peer.send(&fs::new_receive(
-                                id,
-                                job.path.to_string_lossy().to_string(),
-                                job.file_num,
-                                job.files.clone()
-                            ))
+                            peer.send_on(
+                                Channel::File,
+                                &fs::new_receive(
+                                    id,
+                                    job.path.to_string_lossy().to_string(),
+                                    job.file_num,
+                                    job.files.clone(),
+                                    job.dirs().clone(),
+                                    job.parallel()
+                                )
+                            )
                             .await
                         );
                     }
@@ -554,17 +610,25 @@ impl<T: InvokeUiSession> Remote<T> {
                         if remember {
                             job.set_overwrite_strategy(Some(need_override));
                         }
+                        // the archive is received already, the peer is done with the job
+                        if job.archive_conflict().is_some() {
+                            let err = job.confirm_archive(need_override).err();
+                            job.modify_time();
+                            fs::remove_job(id, &mut self.write_jobs);
+                            self.handle_job_status(id, file_num, err.map(|e| e.to_string()));
+                            return true;
+                        }
                         let mut msg = Message::new();
                         let mut file_action = FileAction::new();
-                        let req = FileTransferSendConfirmRequest {
-                            id,
-                            file_num,
-                            union: if need_override {
-                                Some(file_transfer_send_confirm_request::Union::OffsetBlk(0))
-                            } else {
-                                Some(file_transfer_send_confirm_request::Union::Skip(true))
-                            },
-                            ..Default::default()
+                        let req = if need_override {
+                            job.gen_write_confirm(file_num).await
+                        } else {
+                            FileTransferSendConfirmRequest {
+                                id,
+                                file_num,
+                                union: Some(file_transfer_send_confirm_request::Union::Skip(true)),
+                                ..Default::default()
+                            }
                         };
                         job.confirm(&req);
                         file_action.set_send_confirm(req);
@@ -680,6 +744,54 @@ impl<T: InvokeUiSession> Remote<T> {
                     }
                 }
             }
+            Data::RenameFile((id, path, new_path, is_remote)) => {
+                if is_remote {
+                    let mut msg_out = Message::new();
+                    let mut file_action = FileAction::new();
+                    file_action.set_rename(FileRename {
+                        id,
+                        path,
+                        new_path,
+                        ..Default::default()
+                    });
+                    msg_out.set_file_action(file_action);
+                    allow_err!(peer.send(&msg_out).await);
+                } else {
+                    match fs::rename(&path, &new_path) {
+                        Err(err) => {
+                            self.handle_job_status(id, -1, Some(err.to_string()));
+                        }
+                        Ok(()) => {
+                            self.handle_job_status(id, -1, None);
+                        }
+                    }
+                }
+            }
+            Data::CopyFile((id, path, new_path, is_remote)) => {
+                if is_remote {
+                    let mut msg_out = Message::new();
+                    let mut file_action = FileAction::new();
+                    file_action.set_copy(FileCopy {
+                        id,
+                        path,
+                        new_path,
+                        ..Default::default()
+                    });
+                    msg_out.set_file_action(file_action);
+                    allow_err!(peer.send(&msg_out).await);
+                } else {
+                    // a tree may take a while, do not hold up the session
+                    let handler = self.handler.clone();
+                    tokio::spawn(async move {
+                        match tokio::task::spawn_blocking(move || fs::copy(&path, &new_path)).await
+                        {
+                            Ok(Ok(())) => handler.job_done(id, -1),
+                            Ok(Err(err)) => handler.job_error(id, err.to_string(), -1),
+                            Err(err) => handler.job_error(id, err.to_string(), -1),
+                        }
+                    });
+                }
+            }
             Data::RecordScreen(start, w, h, id) => {
                 let _ = self
                     .video_sender
@@ -844,6 +956,9 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                     }
                     Some(login_response::Union::PeerInfo(pi)) => {
+                        if pi.features.chunked {
+                            peer.set_chunked();
+                        }
                         self.handler.handle_peer_info(pi);
                         self.check_clipboard_file_context();
                         if !(self.handler.is_file_transfer() || self.handler.is_port_forward()) {
@@ -915,13 +1030,14 @@ impl<T: InvokeUiSession> Remote<T> {
                     match fr.union {
                         Some(file_response::Union::Dir(fd)) => {
                             #[cfg(windows)]
-                            let entries = fd.entries.to_vec();
+                            let (entries, dirs) = (fd.entries.to_vec(), fd.dirs.to_vec());
                             #[cfg(not(windows))]
-                            let mut entries = fd.entries.to_vec();
+                            let (mut entries, mut dirs) = (fd.entries.to_vec(), fd.dirs.to_vec());
                             #[cfg(not(windows))]
                             {
                                 if self.handler.peer_platform() == "Windows" {
                                     fs::transform_windows_path(&mut entries);
+                                    fs::transform_windows_path(&mut dirs);
                                 }
                             }
                             self.handler
@@ -929,15 +1045,41 @@ impl<T: InvokeUiSession> Remote<T> {
                             if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
                                 log::info!("job set_files: {:?}", entries);
                                 job.set_files(entries);
+                                job.set_dirs(dirs);
+                                job.set_archive(fd.archive);
                             } else if let Some(job) = self.remove_jobs.get_mut(&fd.id) {
                                 job.files = entries;
                             }
                         }
+                        Some(file_response::Union::SearchResult(r)) => {
+                            #[cfg(windows)]
+                            let entries = r.entries.to_vec();
+                            #[cfg(not(windows))]
+                            let mut entries = r.entries.to_vec();
+                            #[cfg(not(windows))]
+                            {
+                                if self.handler.peer_platform() == "Windows" {
+                                    fs::transform_windows_path(&mut entries);
+                                }
+                            }
+                            self.handler
+                                .update_search_result(r.id, &entries, r.path, r.done);
+                        }
+                        Some(file_response::Union::RangeData(r)) => {
+                            self.handler.update_file_preview(
+                                r.id,
+                                r.path,
+                                r.offset,
+                                &r.data,
+                                r.file_size,
+                            );
+                        }
                         Some(file_response::Union::Digest(digest)) => {
                             if digest.is_upload {
                                 if let Some(job) = fs::get_job(digest.id, &mut self.read_jobs) {
                                     if let Some(file) = job.files().get(digest.file_num as usize) {
                                         let read_path = get_string(&job.join(&file.name));
+                                        job.set_peer_digest(&digest);
                                         let overwrite_strategy = job.default_overwrite_strategy();
                                         if let Some(overwrite) = overwrite_strategy {
                                             let req = FileTransferSendConfirmRequest {
@@ -970,6 +1112,21 @@ impl<T: InvokeUiSession> Remote<T> {
                                 if let Some(job) = fs::get_job(digest.id, &mut self.write_jobs) {
                                     if let Some(file) = job.files().get(digest.file_num as usize) {
                                         let write_path = get_string(&job.join(&file.name));
+                                        job.set_peer_support_delta(digest.support_delta);
+                                        if let Some(file_num) = job.retransmitting() {
+                                            // the file sent again after a digest mismatch is overwritten without asking,
+                                            // the following files are confirmed once the peer moves on to them again
+                                            if file_num == digest.file_num {
+                                                let req = job.gen_write_confirm(file_num).await;
+                                                job.confirm(&req);
+                                                allow_err!(peer.send(&new_send_confirm(req)).await);
+                                                return true;
+                                            }
+                                            // unless the files are sent in parallel
+                                            if job.parallel() <= 1 {
+                                                return true;
+                                            }
+                                        }
                                         let overwrite_strategy = job.default_overwrite_strategy();
                                         match fs::is_write_need_confirmation(&write_path, &digest) {
                                             Ok(res) => match res {
@@ -986,15 +1143,15 @@ impl<T: InvokeUiSession> Remote<T> {
                                                 }
                                                 DigestCheckResult::NeedConfirm(digest) => {
                                                     if let Some(overwrite) = overwrite_strategy {
-                                                        let req = FileTransferSendConfirmRequest {
-                                                            id: digest.id,
-                                                            file_num: digest.file_num,
-                                                            union: Some(if overwrite {
-                                                                file_transfer_send_confirm_request::Union::OffsetBlk(0)
-                                                            } else {
-                                                                file_transfer_send_confirm_request::Union::Skip(true)
-                                                            }),
-                                                            ..Default::default()
+                                                        let req = if overwrite {
+                                                            job.gen_write_confirm(digest.file_num).await
+                                                        } else {
+                                                            FileTransferSendConfirmRequest {
+                                                                id: digest.id,
+                                                                file_num: digest.file_num,
+                                                                union: Some(file_transfer_send_confirm_request::Union::Skip(true)),
+                                                                ..Default::default()
+                                                            }
                                                         };
                                                         job.confirm(&req);
                                                         let msg = new_send_confirm(req);
@@ -1010,12 +1167,7 @@ impl<T: InvokeUiSession> Remote<T> {
                                                     }
                                                 }
                                                 DigestCheckResult::NoSuchFile => {
-                                                    let req = FileTransferSendConfirmRequest {
-                                                        id: digest.id,
-                                                        file_num: digest.file_num,
-                                                        union: Some(file_transfer_send_confirm_request::Union::OffsetBlk(0)),
-                                                        ..Default::default()
-                                                    };
+                                                    let req = job.gen_write_confirm(digest.file_num).await;
                                                     job.confirm(&req);
                                                     let msg = new_send_confirm(req);
                                                     allow_err!(peer.send(&msg).await);
@@ -1031,8 +1183,36 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                         Some(file_response::Union::Block(block)) => {
                             if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
-                                if let Err(_err) = job.write(block).await {
-                                    // to-do: add "skip" for writing job
+                                match job.write(block).await {
+                                    Ok(Some(verified)) => {
+                                        allow_err!(peer.send(&fs::new_verified(verified)).await);
+                                    }
+                                    Ok(None) => {
+                                        if let Some(path) = job.archive_conflict() {
+                                            self.handler.override_file_confirm(
+                                                job.id(),
+                                                job.file_num(),
+                                                path,
+                                                false,
+                                                false,
+                                            );
+                                        }
+                                    }
+                                    Err(err) => {
+                                        let (id, file_num) = (job.id(), job.file_num());
+                                        job.remove_download_file();
+                                        fs::remove_job(id, &mut self.write_jobs);
+                                        let mut msg_out = Message::new();
+                                        let mut file_action = FileAction::new();
+                                        file_action.set_cancel(FileTransferCancel {
+                                            id,
+                                            ..Default::default()
+                                        });
+                                        msg_out.set_file_action(file_action);
+                                        allow_err!(peer.send(&msg_out).await);
+                                        self.handle_job_status(id, file_num, Some(err.to_string()));
+                                        return true;
+                                    }
                                 }
                                 self.update_jobs_status();
                             }
@@ -1040,6 +1220,10 @@ impl<T: InvokeUiSession> Remote<T> {
                         Some(file_response::Union::Done(d)) => {
                             let mut err: Option<String> = None;
                             if let Some(job) = fs::get_job(d.id, &mut self.write_jobs) {
+                                // finished once the archive is confirmed
+                                if job.archive_conflict().is_some() {
+                                    return true;
+                                }
                                 job.modify_time();
                                 err = job.job_error();
                                 fs::remove_job(d.id, &mut self.write_jobs);
@@ -1213,6 +1397,9 @@ impl<T: InvokeUiSession> Remote<T> {
                     _ => {}
                 },
                 Some(message::Union::TestDelay(t)) => {
+                    if !t.from_client {
+                        self.file_scheduler.update_delay(t.last_delay);
+                    }
                     self.handler.handle_test_delay(t, peer).await;
                 }
                 Some(message::Union::AudioFrame(frame)) => {
@@ -1226,6 +1413,11 @@ impl<T: InvokeUiSession> Remote<T> {
                             job.confirm(&c);
                         }
                     }
+                    Some(file_action::Union::Verified(v)) => {
+                        if let Some(job) = fs::get_job(v.id, &mut self.read_jobs) {
+                            job.verified(&v);
+                        }
+                    }
                     _ => {}
                 },
                 Some(message::Union::MessageBox(msgbox)) => {
@@ -25,7 +25,9 @@ use hbb_common::{
     protobuf::Enum,
     protobuf::Message as _,
     rendezvous_proto::*,
-    sleep, socket_client, tokio, ResultType,
+    sleep, socket_client,
+    tcp::Channel,
+    tokio, ResultType,
 };
 // #[cfg(any(target_os = "android", target_os = "ios", feature = "cli"))]
 use hbb_common::{config::RENDEZVOUS_PORT, futures::future::join_all};
@@ -361,6 +363,12 @@ async fn test_nat_type_() -> ResultType<bool> {
     let start = std::time::Instant::now();
     let (rendezvous_server, _, _) = get_rendezvous_server(1_000).await;
     let server1 = rendezvous_server;
+    // the ports seen by a server behind WebSocket are not ours
+    #[cfg(feature = "websocket")]
+    if hbb_common::websocket::get_addr(&server1).is_some() {
+        Config::set_nat_type(NatType::SYMMETRIC as _);
+        return Ok(true);
+    }
     let server2 = crate::increase_port(&server1, -1);
     let mut msg_out = RendezvousMessage::new();
     let serial = Config::get_serial();
@@ -800,6 +808,46 @@ pub fn decode64<T: AsRef<[u8]>>(input: T) -> Result<Vec<u8>, base64::DecodeError
     base64::decode(input)
 }
 
+/// HMAC of RFC 2104 with the digest `D`, e.g. `sha2::Sha256`.
+pub fn hmac<D: sha2::Digest + sha2::digest::core_api::BlockSizeUser>(
+    key: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut block = vec![0u8; D::block_size()];
+    if key.len() > block.len() {
+        let key = D::digest(key);
+        block[..key.len()].copy_from_slice(&key);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |x: u8| block.iter().map(|b| b ^ x).collect::<Vec<u8>>();
+    let mut inner = D::new();
+    inner.update(pad(0x36));
+    inner.update(data);
+    let mut outer = D::new();
+    outer.update(pad(0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+/// What the controlling side signs for a [`KeyLogin`] to `username`, i.e. the id it connects to.
+pub fn get_key_login_data(username: &str, hash: &Hash) -> Vec<u8> {
+    format!("{}{}{}", username, hash.salt, hash.challenge).into_bytes()
+}
+
+/// The channel a message goes apart from input and control on, if any.
+pub fn get_channel(msg: &Message) -> Option<Channel> {
+    match &msg.union {
+        Some(message::Union::VideoFrame(_)) => Some(Channel::Video),
+        Some(message::Union::AudioFrame(_)) => Some(Channel::Audio),
+        Some(message::Union::FileResponse(res)) => match &res.union {
+            Some(file_response::Union::Block(_)) => Some(Channel::File),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 pub async fn get_key(sync: bool) -> String {
     let mut key = if sync {
         Config::get_option("key")
@@ -209,6 +209,45 @@ pub fn core_main() -> Option<Vec<String>> {
                 }
             }
             return None;
+        } else if args[0] == "--enable-2fa" {
+            if crate::platform::is_root() {
+                let secret = crate::auth_2fa::new_secret();
+                match crate::auth_2fa::get_uri(&secret, &crate::ipc::get_id()) {
+                    Ok(uri) => {
+                        crate::ipc::set_totp_secret(secret.clone()).unwrap();
+                        println!("Add this to your authenticator app, e.g. as a QR code:");
+                        println!("{}", uri);
+                        println!("Secret: {}", secret);
+                    }
+                    Err(err) => println!("Failed to enable 2FA: {}", err),
+                }
+            } else {
+                println!("Administrative privileges required!");
+            }
+            return None;
+        } else if args[0] == "--disable-2fa" {
+            if crate::platform::is_root() {
+                crate::ipc::set_totp_secret("".to_owned()).unwrap();
+            } else {
+                println!("Administrative privileges required!");
+            }
+            return None;
+        } else if args[0] == "--public-key" {
+            // to be authorized with --authorized-keys on the peers
+            println!(
+                "{}",
+                crate::encode64(hbb_common::config::Config::get_key_pair().1)
+            );
+            return None;
+        } else if args[0] == "--authorized-keys" {
+            if crate::platform::is_root() {
+                if let Err(err) = authorized_keys(&args[1..]) {
+                    println!("{}", err);
+                }
+            } else {
+                println!("Administrative privileges required!");
+            }
+            return None;
         } else if args[0] == "--get-id" {
             if crate::platform::is_root() {
                 println!("{}", crate::ipc::get_id());
@@ -259,6 +298,42 @@ fn import_config(path: &str) {
     }
 }
 
+// --authorized-keys [list | add <key> [comment] | remove <key or comment>]
+fn authorized_keys(args: &[String]) -> hbb_common::ResultType<()> {
+    use hbb_common::{bail, config::AuthorizedKey, sodiumoxide::crypto::sign};
+    let mut keys = crate::ipc::get_authorized_keys()?;
+    match args.get(0).map(|x| x.as_str()) {
+        None | Some("list") => {
+            for key in keys {
+                println!("{} {}", key.key, key.comment);
+            }
+            return Ok(());
+        }
+        Some("add") if args.len() >= 2 => {
+            let key = args[1].clone();
+            if !matches!(crate::decode64(&key), Ok(pk) if pk.len() == sign::PUBLICKEYBYTES) {
+                bail!("Invalid key {}, see --public-key", key);
+            }
+            keys.retain(|x| x.key != key);
+            keys.push(AuthorizedKey {
+                key,
+                comment: args[2..].join(" "),
+            });
+        }
+        Some("remove") if args.len() == 2 => {
+            let len = keys.len();
+            keys.retain(|x| x.key != args[1] && x.comment != args[1]);
+            if keys.len() == len {
+                bail!("No key {}", args[1]);
+            }
+        }
+        _ => {
+            bail!("Usage: --authorized-keys [list | add <key> [comment] | remove <key or comment>]")
+        }
+    }
+    crate::ipc::set_authorized_keys(keys)
+}
+
 /// invoke a new connection
 ///
 /// [Note]
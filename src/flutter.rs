@@ -414,6 +414,29 @@ impl InvokeUiSession for FlutterHandler {
         }
     }
 
+    fn update_search_result(&self, id: i32, entries: &Vec<FileEntry>, path: String, done: bool) {
+        self.push_event(
+            "search_result",
+            vec![
+                ("value", &crate::common::make_fd_to_json(id, path, entries)),
+                ("done", &done.to_string()),
+            ],
+        );
+    }
+
+    fn update_file_preview(&self, id: i32, path: String, offset: u64, data: &[u8], file_size: u64) {
+        self.push_event(
+            "file_preview",
+            vec![
+                ("id", &id.to_string()),
+                ("path", &path),
+                ("offset", &offset.to_string()),
+                ("data", &String::from_utf8_lossy(data)),
+                ("file_size", &file_size.to_string()),
+            ],
+        );
+    }
+
     // unused in flutter
     fn update_transfer_list(&self) {}
 
@@ -12,7 +12,7 @@ use flutter_rust_bridge::{StreamSink, SyncReturn};
 use hbb_common::{
     config::{self, LocalConfig, PeerConfig, PeerInfoSerde, ONLINE},
     fs, log,
-    message_proto::KeyboardMode,
+    message_proto::{FileSearch, KeyboardMode},
     ResultType,
 };
 use serde_json::json;
@@ -142,6 +142,12 @@ pub fn session_login(id: String, password: String, remember: bool) {
     }
 }
 
+pub fn session_send2fa(id: String, code: String) {
+    if let Some(session) = SESSIONS.read().unwrap().get(&id) {
+        session.send2fa(code);
+    }
+}
+
 pub fn session_close(id: String) {
     if let Some(mut session) = SESSIONS.write().unwrap().remove(&id) {
         session.close_event_stream();
@@ -403,6 +409,47 @@ pub fn session_read_remote_dir(id: String, path: String, include_hidden: bool) {
     }
 }
 
+pub fn session_read_remote_range(
+    id: String,
+    act_id: i32,
+    path: String,
+    offset: u64,
+    length: u32,
+    tail_lines: u32,
+) {
+    if let Some(session) = SESSIONS.read().unwrap().get(&id) {
+        session.read_remote_range(act_id, path, offset, length, tail_lines);
+    }
+}
+
+pub fn session_search_remote_files(
+    id: String,
+    act_id: i32,
+    path: String,
+    pattern: String,
+    is_regex: bool,
+    min_size: u64,
+    max_size: u64,
+    min_modified_time: u64,
+    max_modified_time: u64,
+    include_hidden: bool,
+) {
+    if let Some(session) = SESSIONS.read().unwrap().get(&id) {
+        session.search_remote_files(FileSearch {
+            id: act_id,
+            path,
+            pattern,
+            is_regex,
+            min_size,
+            max_size,
+            min_modified_time,
+            max_modified_time,
+            include_hidden,
+            ..Default::default()
+        });
+    }
+}
+
 pub fn session_send_files(
     id: String,
     act_id: i32,
@@ -436,6 +483,24 @@ pub fn session_remove_file(id: String, act_id: i32, path: String, file_num: i32,
     }
 }
 
+pub fn session_rename_file(
+    id: String,
+    act_id: i32,
+    path: String,
+    new_path: String,
+    is_remote: bool,
+) {
+    if let Some(session) = SESSIONS.read().unwrap().get(&id) {
+        session.rename_file(act_id, path, new_path, is_remote);
+    }
+}
+
+pub fn session_copy_file(id: String, act_id: i32, path: String, new_path: String, is_remote: bool) {
+    if let Some(session) = SESSIONS.read().unwrap().get(&id) {
+        session.copy_file(act_id, path, new_path, is_remote);
+    }
+}
+
 pub fn session_read_dir_recursive(
     id: String,
     act_id: i32,
@@ -13,7 +13,8 @@ pub use clipboard::ClipboardFile;
 use hbb_common::{
     allow_err, bail, bytes,
     bytes_codec::BytesCodec,
-    config::{self, Config, Config2},
+    config::{self, AuthorizedKey, AuthorizedKeys, Config, Config2},
+    fs,
     futures::StreamExt as _,
     futures_util::sink::SinkExt,
     log, password_security as password, timeout, tokio,
@@ -24,6 +25,8 @@ use hbb_common::{
 
 use crate::rendezvous_mediator::RendezvousMediator;
 
+pub mod control;
+
 // State with timestamp, because std::time::Instant cannot be serialized
 #[derive(Debug, Serialize, Deserialize, Copy, Clone)]
 #[serde(tag = "t", content = "c")]
@@ -55,12 +58,24 @@ pub enum FS {
         path: String,
         id: i32,
     },
+    Rename {
+        path: String,
+        new_path: String,
+        id: i32,
+    },
+    Copy {
+        path: String,
+        new_path: String,
+        id: i32,
+    },
     NewWrite {
         path: String,
         id: i32,
         file_num: i32,
-        files: Vec<(String, u64)>,
+        files: Vec<fs::FileEntryMeta>,
+        dirs: Vec<fs::FileEntryMeta>,
         overwrite_detection: bool,
+        parallel: u32,
     },
     CancelWrite {
         id: i32,
@@ -70,6 +85,9 @@ pub enum FS {
         file_num: i32,
         data: Bytes,
         compressed: bool,
+        blk_id: u32,
+        copy_blocks: u32,
+        sha256: Bytes,
     },
     WriteDone {
         id: i32,
@@ -91,6 +109,7 @@ pub enum FS {
         file_size: u64,
         last_modified: u64,
         is_upload: bool,
+        support_delta: bool,
     },
 }
 
@@ -159,6 +178,8 @@ pub enum Data {
         name: String,
         authorized: bool,
         port_forward: String,
+        exec_command: String,
+        is_terminal: bool,
         keyboard: bool,
         clipboard: bool,
         audio: bool,
@@ -166,6 +187,7 @@ pub enum Data {
         file_transfer_enabled: bool,
         restart: bool,
         recording: bool,
+        terminal: bool,
         from_switch: bool,
     },
     ChatMessage {
@@ -250,21 +272,35 @@ pub async fn start(postfix: &str) -> ResultType<()> {
 }
 
 pub async fn new_listener(postfix: &str) -> ResultType<Incoming> {
+    new_listener_(postfix, true).await
+}
+
+// Only for the account we are running as.
+pub async fn new_private_listener(postfix: &str) -> ResultType<Incoming> {
+    new_listener_(postfix, false).await
+}
+
+async fn new_listener_(postfix: &str, public: bool) -> ResultType<Incoming> {
     let path = Config::ipc_path(postfix);
     #[cfg(not(any(windows, target_os = "android", target_os = "ios")))]
     check_pid(postfix).await;
     let mut endpoint = Endpoint::new(path.clone());
-    match SecurityAttributes::allow_everyone_create() {
-        Ok(attr) => endpoint.set_security_attributes(attr),
-        Err(err) => log::error!("Failed to set ipc{} security: {}", postfix, err),
-    };
+    if public {
+        match SecurityAttributes::allow_everyone_create() {
+            Ok(attr) => endpoint.set_security_attributes(attr),
+            Err(err) => log::error!("Failed to set ipc{} security: {}", postfix, err),
+        };
+    } else {
+        endpoint.set_security_attributes(SecurityAttributes::empty());
+    }
     match endpoint.incoming() {
         Ok(incoming) => {
             log::info!("Started ipc{} server at path: {}", postfix, &path);
             #[cfg(not(windows))]
             {
                 use std::os::unix::fs::PermissionsExt;
-                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o0777)).ok();
+                let mode = if public { 0o0777 } else { 0o0600 };
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).ok();
                 write_pid(postfix);
             }
             Ok(incoming)
@@ -375,6 +411,8 @@ async fn handle(data: Data, stream: &mut Connection) {
                     value = Some(Config::get_permanent_password());
                 } else if name == "salt" {
                     value = Some(Config::get_salt());
+                } else if name == "authorized-keys" {
+                    value = serde_json::to_string(&AuthorizedKeys::load().keys).ok();
                 } else if name == "rendezvous_server" {
                     value = Some(format!(
                         "{},{}",
@@ -396,6 +434,16 @@ async fn handle(data: Data, stream: &mut Connection) {
                     password::update_temporary_password();
                 } else if name == "permanent-password" {
                     Config::set_permanent_password(&value);
+                } else if name == "totp-secret" {
+                    Config::set_totp_secret(&value);
+                } else if name == "authorized-keys" {
+                    match serde_json::from_str(&value) {
+                        Ok(keys) => AuthorizedKeys { keys }.store(),
+                        Err(err) => {
+                            log::error!("Invalid authorized keys: {}", err);
+                            return;
+                        }
+                    }
                 } else if name == "salt" {
                     Config::set_salt(&value);
                 } else {
@@ -695,6 +743,24 @@ pub fn set_permanent_password(v: String) -> ResultType<()> {
     set_config("permanent-password", v)
 }
 
+// Enrolls the secret of the one-time codes, none if empty. It can not be read back.
+pub fn set_totp_secret(v: String) -> ResultType<()> {
+    Config::set_totp_secret(&v);
+    set_config("totp-secret", v)
+}
+
+// The authorized keys of the service, which may have a config of its own.
+pub fn get_authorized_keys() -> ResultType<Vec<AuthorizedKey>> {
+    match get_config("authorized-keys")? {
+        Some(v) => Ok(serde_json::from_str(&v)?),
+        None => bail!("Failed to get authorized keys"),
+    }
+}
+
+pub fn set_authorized_keys(keys: Vec<AuthorizedKey>) -> ResultType<()> {
+    set_config("authorized-keys", serde_json::to_string(&keys)?)
+}
+
 pub fn get_id() -> String {
     if let Ok(Some(v)) = get_config("id") {
         // update salt also, so that next time reinstallation not causing first-time auto-login failure
@@ -0,0 +1,268 @@
+// Local control API of the running service, for tooling rather than our own processes.
+//
+// JSON-RPC 2.0 over the ipc socket with the `_control` postfix, each message framed as on
+// the other ipc sockets (hbb_common's BytesCodec). Unlike `ipc::Data`, which may change
+// with every release, the methods below only change along with API_VERSION:
+//
+// get_version                      {"api": 1, "version": "1.2.0"}
+// list_connections                 [ConnInfo]
+// disconnect {"id"}                true
+// get_options                      {key: value}
+// get_option {"key"}               value
+// set_option {"key", "value"}      true
+// get_id                           id
+// get_temporary_password           password
+// subscribe                        true, then "connection_event" notifications with ConnEvent
+//
+// Only the account of the service may connect.
+use std::{collections::HashMap, sync::Mutex};
+
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+
+use hbb_common::{
+    bail,
+    config::Config,
+    futures::StreamExt as _,
+    log, password_security as password, timeout,
+    tokio::{self, sync::broadcast},
+    ResultType,
+};
+
+pub const POSTFIX: &str = "_control";
+pub const API_VERSION: i64 = 1;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+lazy_static::lazy_static! {
+    static ref CONNS: Mutex<HashMap<i32, ConnInfo>> = Default::default();
+    static ref EVENTS: broadcast::Sender<ConnEvent> = broadcast::channel(32).0;
+    static ref DISCONNECT: broadcast::Sender<i32> = broadcast::channel(8).0;
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConnInfo {
+    pub id: i32,
+    pub peer_id: String,
+    pub name: String,
+    pub ip: String,
+    // "remote", "file-transfer", "port-forward", "remote-exec" or "terminal", empty before login
+    pub conn_type: String,
+    pub authorized: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ConnEvent {
+    Login(ConnInfo),
+    Close { id: i32 },
+}
+
+pub fn on_login(info: ConnInfo) {
+    CONNS.lock().unwrap().insert(info.id, info.clone());
+    EVENTS.send(ConnEvent::Login(info)).ok();
+}
+
+pub fn on_close(id: i32) {
+    CONNS.lock().unwrap().remove(&id);
+    EVENTS.send(ConnEvent::Close { id }).ok();
+}
+
+// ids of the connections to close
+pub fn disconnect_receiver() -> broadcast::Receiver<i32> {
+    DISCONNECT.subscribe()
+}
+
+fn list_connections() -> Vec<ConnInfo> {
+    let conns = CONNS.lock().unwrap();
+    crate::server::Connection::alive_conns()
+        .drain(..)
+        .map(|id| {
+            conns.get(&id).cloned().unwrap_or(ConnInfo {
+                id,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn get_param<'a>(params: &'a Value, name: &str) -> Result<&'a Value, (i64, String)> {
+    match params.get(name) {
+        Some(v) if !v.is_null() => Ok(v),
+        _ => Err((INVALID_PARAMS, format!("Missing param: {}", name))),
+    }
+}
+
+fn get_str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, (i64, String)> {
+    get_param(params, name)?
+        .as_str()
+        .ok_or_else(|| (INVALID_PARAMS, format!("{} is not a string", name)))
+}
+
+fn call(method: &str, params: &Value) -> Result<Value, (i64, String)> {
+    match method {
+        "get_version" => Ok(json!({"api": API_VERSION, "version": crate::VERSION})),
+        "list_connections" => Ok(json!(list_connections())),
+        "disconnect" => {
+            let id = get_param(params, "id")?
+                .as_i64()
+                .ok_or_else(|| (INVALID_PARAMS, "id is not a number".to_owned()))?;
+            if !crate::server::Connection::alive_conns().contains(&(id as i32)) {
+                return Err((INVALID_PARAMS, format!("No connection {}", id)));
+            }
+            DISCONNECT.send(id as _).ok();
+            Ok(json!(true))
+        }
+        "get_options" => Ok(json!(Config::get_options())),
+        "get_option" => Ok(json!(Config::get_option(get_str_param(params, "key")?))),
+        "set_option" => {
+            let key = get_str_param(params, "key")?.to_owned();
+            let value = get_str_param(params, "value")?.to_owned();
+            let _chk = super::CheckIfRestart::new();
+            Config::set_option(key, value);
+            Ok(json!(true))
+        }
+        "get_id" => Ok(json!(Config::get_id())),
+        "get_temporary_password" => Ok(json!(password::temporary_password())),
+        "subscribe" => Ok(json!(true)),
+        _ => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+    }
+}
+
+fn new_error(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message},
+    })
+}
+
+// Returns the response, none for a notification, and whether events were subscribed.
+fn handle_request(bytes: &[u8]) -> (Option<Value>, bool) {
+    let req = match serde_json::from_slice::<Value>(bytes) {
+        Ok(req) => req,
+        Err(err) => {
+            let err = new_error(Value::Null, PARSE_ERROR, err.to_string());
+            return (Some(err), false);
+        }
+    };
+    let method = match req.get("method").and_then(|m| m.as_str()) {
+        Some(method) if req["jsonrpc"] == "2.0" => method,
+        _ => {
+            let err = new_error(req["id"].clone(), INVALID_REQUEST, "Invalid request".into());
+            return (Some(err), false);
+        }
+    };
+    let res = call(method, &req["params"]);
+    let subscribe = method == "subscribe" && res.is_ok();
+    let id = match req.get("id") {
+        Some(id) => id.clone(),
+        None => return (None, subscribe),
+    };
+    let resp = match res {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err((code, message)) => new_error(id, code, message),
+    };
+    (Some(resp), subscribe)
+}
+
+async fn next_event(events: &mut Option<broadcast::Receiver<ConnEvent>>) -> Option<ConnEvent> {
+    match events {
+        Some(rx) => loop {
+            match rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("ipc{} subscriber missed {} events", POSTFIX, n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        },
+        None => std::future::pending().await,
+    }
+}
+
+async fn handle_connection(mut stream: super::Connection) {
+    let mut events = None;
+    loop {
+        tokio::select! {
+            res = stream.next_raw() => {
+                let bytes = match res {
+                    Err(err) => {
+                        log::trace!("ipc{} connection closed: {}", POSTFIX, err);
+                        break;
+                    }
+                    Ok(bytes) => bytes,
+                };
+                let (resp, subscribe) = handle_request(&bytes);
+                if subscribe && events.is_none() {
+                    events = Some(EVENTS.subscribe());
+                }
+                if let Some(resp) = resp {
+                    if stream.send_raw(resp.to_string().into()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Some(event) = next_event(&mut events) => {
+                let msg = json!({"jsonrpc": "2.0", "method": "connection_event", "params": event});
+                if stream.send_raw(msg.to_string().into()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn start() -> ResultType<()> {
+    let mut incoming = super::new_private_listener(POSTFIX).await?;
+    loop {
+        match incoming.next().await {
+            Some(Ok(stream)) => {
+                tokio::spawn(handle_connection(super::Connection::new(stream)));
+            }
+            Some(Err(err)) => {
+                log::error!("Couldn't get ipc{} client: {:?}", POSTFIX, err);
+            }
+            None => bail!("ipc{} listener closed", POSTFIX),
+        }
+    }
+}
+
+// A single call, as tooling written in rust would do it.
+pub async fn call_remote(method: &str, params: Value) -> ResultType<Value> {
+    let mut conn = super::connect(1000, POSTFIX).await?;
+    let req = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    conn.send_raw(req.to_string().into()).await?;
+    let bytes = timeout(1000, conn.next_raw()).await??;
+    let mut resp = serde_json::from_slice::<Value>(&bytes)?;
+    if let Some(err) = resp.get("error") {
+        bail!("{}", err["message"].as_str().unwrap_or_default());
+    }
+    Ok(resp["result"].take())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handle_request() {
+        let (resp, _) = handle_request(br#"{"jsonrpc": "2.0", "id": 3, "method": "get_version"}"#);
+        let resp = resp.unwrap();
+        assert_eq!(resp["id"], 3);
+        assert_eq!(resp["result"]["api"], API_VERSION);
+        let (resp, _) = handle_request(br#"{"jsonrpc": "2.0", "id": 4, "method": "nope"}"#);
+        assert_eq!(resp.unwrap()["error"]["code"], METHOD_NOT_FOUND);
+        let (resp, _) = handle_request(br#"{"jsonrpc": "2.0", "id": 5, "method": "get_option"}"#);
+        assert_eq!(resp.unwrap()["error"]["code"], INVALID_PARAMS);
+        let (resp, _) = handle_request(b"{");
+        assert_eq!(resp.unwrap()["error"]["code"], PARSE_ERROR);
+        // notification
+        let (resp, subscribe) = handle_request(br#"{"jsonrpc": "2.0", "method": "subscribe"}"#);
+        assert!(resp.is_none() && subscribe);
+    }
+}
@@ -9,6 +9,7 @@ pub use platform::{get_cursor, get_cursor_data, get_cursor_pos, start_os_service
 mod server;
 #[cfg(not(any(target_os = "ios")))]
 pub use self::server::*;
+mod auth_2fa;
 mod client;
 #[cfg(not(any(target_os = "ios")))]
 mod lan;
@@ -38,23 +38,101 @@ fn main() {
     if !common::global_init() {
         return;
     }
-    use clap::App;
+    use clap::{Arg, ArgAction, Command};
     use hbb_common::log;
-    let args = format!(
-        "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
-        -c, --connect=[REMOTE_ID] 'test only'
-        -k, --key=[KEY] ''
-       -s, --server=[] 'Start server'",
-    );
-    let matches = App::new("rustdesk")
+    let matches = Command::new("rustdesk")
         .version(crate::VERSION)
         .author("CarrieZ Studio<info@rustdesk.com>")
         .about("RustDesk command line tool")
-        .args_from_usage(&args)
+        .arg(
+            Arg::new("port-forward")
+                .short('p')
+                .long("port-forward")
+                .value_name("PORT-FORWARD-OPTIONS")
+                .help("Format: remote-id:local-port:remote-port[:remote-host]"),
+        )
+        .arg(
+            Arg::new("port-forwards")
+                .long("port-forwards")
+                .num_args(1..=2)
+                .value_names(["REMOTE_ID", "FILE"])
+                .help("Forward all ports of the toml or json file, or the ones saved for the peer"),
+        )
+        .arg(
+            Arg::new("udp-forward")
+                .long("udp-forward")
+                .value_name("UDP-FORWARD-OPTIONS")
+                .help("Format: remote-id:local-port:remote-port[:remote-host]"),
+        )
+        .arg(
+            Arg::new("reverse-forward")
+                .long("reverse-forward")
+                .value_name("REVERSE-FORWARD-OPTIONS")
+                .help("Format: remote-id:remote-port:local-port[:local-host]"),
+        )
+        .arg(
+            Arg::new("dynamic-forward")
+                .short('D')
+                .long("dynamic-forward")
+                .value_name("DYNAMIC-FORWARD-OPTIONS")
+                .help("SOCKS5 proxy through the peer, format: remote-id:local-port"),
+        )
+        .arg(
+            Arg::new("connect")
+                .short('c')
+                .long("connect")
+                .value_name("REMOTE_ID")
+                .help("test only"),
+        )
+        .arg(
+            Arg::new("send")
+                .long("send")
+                .num_args(3)
+                .value_names(["REMOTE_ID", "LOCAL_PATH", "REMOTE_PATH"])
+                .help("Send local file or directory to the peer"),
+        )
+        .arg(
+            Arg::new("get")
+                .long("get")
+                .num_args(3)
+                .value_names(["REMOTE_ID", "REMOTE_PATH", "LOCAL_PATH"])
+                .help("Get file or directory from the peer"),
+        )
+        .arg(
+            Arg::new("exec")
+                .long("exec")
+                .value_name("REMOTE_ID")
+                .requires("command")
+                .help("Run the command given after -- on the peer"),
+        )
+        .arg(
+            Arg::new("terminal")
+                .long("terminal")
+                .value_name("REMOTE_ID")
+                .help("Open a shell on the peer"),
+        )
+        .arg(
+            Arg::new("command")
+                .num_args(1..)
+                .last(true)
+                .value_name("COMMAND"),
+        )
+        .arg(Arg::new("key").short('k').long("key").value_name("KEY"))
+        .arg(
+            Arg::new("server")
+                .short('s')
+                .long("server")
+                .action(ArgAction::SetTrue)
+                .help("Start server"),
+        )
         .get_matches();
     use hbb_common::{config::LocalConfig, env_logger::*};
     init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
-    if let Some(p) = matches.value_of("port-forward") {
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .unwrap_or_default();
+    if let Some(p) = matches.get_one::<String>("port-forward") {
         let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
         if options.len() < 3 {
             log::error!("Wrong port-forward options");
@@ -80,7 +158,6 @@ fn main() {
         }
         common::test_rendezvous_server();
         common::test_nat_type();
-        let key = matches.value_of("key").unwrap_or("").to_owned();
         let token = LocalConfig::get_option("access_token");
         cli::start_one_port_forward(
             options[0].clone(),
@@ -90,13 +167,131 @@ fn main() {
             key,
             token,
         );
-    } else if let Some(p) = matches.value_of("connect") {
+    } else if let Some(mut values) = matches.get_many::<String>("port-forwards") {
+        let id = values.next().cloned().unwrap_or_default();
+        let rules = match values.next() {
+            Some(path) => cli::load_port_forwards(path),
+            None => cli::load_peer_port_forwards(&id),
+        };
+        match rules {
+            Ok(rules) => {
+                let token = LocalConfig::get_option("access_token");
+                cli::start_port_forwards(id, rules, key, token);
+            }
+            Err(err) => log::error!("Wrong port forwards: {}", err),
+        }
+    } else if let Some(p) = matches.get_one::<String>("udp-forward") {
+        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
+        if options.len() < 3 {
+            log::error!("Wrong udp-forward options");
+            return;
+        }
+        let is_port = |p: &i32| *p > 0 && *p <= 65535;
+        let (local_port, remote_port) = match (options[1].parse(), options[2].parse()) {
+            (Ok(local_port), Ok(remote_port)) if is_port(&local_port) && is_port(&remote_port) => {
+                (local_port, remote_port)
+            }
+            _ => {
+                log::error!("Wrong local-port or remote-port");
+                return;
+            }
+        };
+        let rule = cli::PortForwardRule {
+            local_port,
+            remote_host: options
+                .get(3)
+                .cloned()
+                .unwrap_or_else(|| "localhost".to_owned()),
+            remote_port,
+            udp: true,
+        };
+        let token = LocalConfig::get_option("access_token");
+        cli::start_port_forwards(options[0].clone(), vec![rule], key, token);
+    } else if let Some(p) = matches.get_one::<String>("reverse-forward") {
+        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
+        if options.len() < 3 {
+            log::error!("Wrong reverse-forward options");
+            return;
+        }
+        let remote_port = match options[1].parse::<i32>() {
+            Ok(v) if v > 0 && v <= 65535 => v,
+            _ => {
+                log::error!("Wrong remote-port");
+                return;
+            }
+        };
+        let local_port = match options[2].parse::<i32>() {
+            Ok(v) if v > 0 && v <= 65535 => v,
+            _ => {
+                log::error!("Wrong local-port");
+                return;
+            }
+        };
+        let local_host = options
+            .get(3)
+            .cloned()
+            .unwrap_or_else(|| "localhost".to_owned());
+        let token = LocalConfig::get_option("access_token");
+        cli::start_reverse_forward(
+            options[0].clone(),
+            remote_port,
+            local_host,
+            local_port,
+            key,
+            token,
+        );
+    } else if let Some(p) = matches.get_one::<String>("dynamic-forward") {
+        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
+        if options.len() != 2 {
+            log::error!("Wrong dynamic-forward options");
+            return;
+        }
+        let port = match options[1].parse::<i32>() {
+            Ok(v) if v > 0 && v <= 65535 => v,
+            _ => {
+                log::error!("Wrong local-port");
+                return;
+            }
+        };
+        let token = LocalConfig::get_option("access_token");
+        cli::start_dynamic_forward(options[0].clone(), port, key, token);
+    } else if let Some(p) = matches.get_one::<String>("connect") {
         common::test_rendezvous_server();
         common::test_nat_type();
-        let key = matches.value_of("key").unwrap_or("").to_owned();
         let token = LocalConfig::get_option("access_token");
         cli::connect_test(p, key, token);
-    } else if let Some(p) = matches.value_of("server") {
+    } else if let Some(mut values) = matches
+        .get_many::<String>("send")
+        .or_else(|| matches.get_many::<String>("get"))
+    {
+        let is_upload = matches.contains_id("send");
+        let id = values.next().cloned().unwrap_or_default();
+        let path = values.next().cloned().unwrap_or_default();
+        let to = values.next().cloned().unwrap_or_default();
+        let token = LocalConfig::get_option("access_token");
+        let code = cli::transfer_files(id, path, to, is_upload, key, token);
+        common::global_clean();
+        std::process::exit(code);
+    } else if let Some(id) = matches.get_one::<String>("exec") {
+        let command = matches
+            .get_many::<String>("command")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+        let token = LocalConfig::get_option("access_token");
+        let code = cli::exec(id.clone(), command, key, token);
+        common::global_clean();
+        std::process::exit(code);
+    } else if let Some(id) = matches.get_one::<String>("terminal") {
+        #[cfg(unix)]
+        {
+            let token = LocalConfig::get_option("access_token");
+            let code = cli::terminal(id.clone(), key, token);
+            common::global_clean();
+            std::process::exit(code);
+        }
+        #[cfg(not(unix))]
+        log::error!("Terminal is not supported on this platform: {}", id);
+    } else if matches.get_flag("server") {
         log::info!("id={}", hbb_common::config::Config::get_id());
         crate::start_server(true);
     }
@@ -614,6 +614,176 @@ pub fn run_as_user(arg: Vec<&str>) -> ResultType<Option<std::process::Child>> {
     Ok(None)
 }
 
+// A process started with the token of the active user and piped stdio, killed on drop.
+pub struct UserProcess {
+    handle: HANDLE,
+    pub stdin: Option<tokio::fs::File>,
+    pub stdout: Option<tokio::fs::File>,
+    pub stderr: Option<tokio::fs::File>,
+}
+
+unsafe impl Send for UserProcess {}
+
+impl UserProcess {
+    pub fn spawn(program: &str, args: &[String]) -> ResultType<Self> {
+        let session_id = unsafe { get_current_session(share_rdp()) };
+        if is_prelogin() {
+            bail!("No active user");
+        }
+        let token = get_user_token(session_id, true);
+        if token.is_null() {
+            bail!("No active user in session {}: {}", session_id, get_error());
+        }
+        let res = Self::spawn_with_token(token, program, args);
+        unsafe { CloseHandle(token) };
+        res
+    }
+
+    fn spawn_with_token(token: HANDLE, program: &str, args: &[String]) -> ResultType<Self> {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::{
+            processthreadsapi::CreateProcessAsUserW,
+            userenv::{CreateEnvironmentBlock, DestroyEnvironmentBlock},
+        };
+
+        let (child_stdin, stdin) = user_pipe(true)?;
+        let (child_stdout, stdout) = user_pipe(false)?;
+        let (child_stderr, stderr) = user_pipe(false)?;
+        let mut cmd = wide_string(
+            &std::iter::once(program)
+                .chain(args.iter().map(|x| x.as_str()))
+                .map(quote_arg)
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        let home = get_active_user_home().map(|x| wide_string(&x.to_string_lossy()));
+        unsafe {
+            let mut si: STARTUPINFOW = mem::zeroed();
+            si.cb = mem::size_of::<STARTUPINFOW>() as _;
+            si.dwFlags = STARTF_USESTDHANDLES;
+            si.hStdInput = child_stdin.as_raw_handle() as _;
+            si.hStdOutput = child_stdout.as_raw_handle() as _;
+            si.hStdError = child_stderr.as_raw_handle() as _;
+            let mut pi: PROCESS_INFORMATION = mem::zeroed();
+            let mut env = NULL;
+            let mut flags = CREATE_NO_WINDOW;
+            if CreateEnvironmentBlock(&mut env, token, FALSE) == TRUE {
+                flags |= CREATE_UNICODE_ENVIRONMENT;
+            }
+            let ok = CreateProcessAsUserW(
+                token,
+                std::ptr::null(),
+                cmd.as_mut_ptr(),
+                null_mut(),
+                null_mut(),
+                TRUE,
+                flags,
+                env,
+                home.as_ref()
+                    .map(|x| x.as_ptr())
+                    .unwrap_or(std::ptr::null()),
+                &mut si,
+                &mut pi,
+            );
+            if !env.is_null() {
+                DestroyEnvironmentBlock(env);
+            }
+            if ok == FALSE {
+                bail!("Failed to launch {}: {}", program, get_error());
+            }
+            CloseHandle(pi.hThread);
+            Ok(Self {
+                handle: pi.hProcess,
+                stdin: Some(tokio::fs::File::from_std(stdin)),
+                stdout: Some(tokio::fs::File::from_std(stdout)),
+                stderr: Some(tokio::fs::File::from_std(stderr)),
+            })
+        }
+    }
+
+    pub async fn wait(&self) -> io::Result<std::process::ExitStatus> {
+        use std::os::windows::process::ExitStatusExt;
+        loop {
+            let mut code = 0;
+            if unsafe { GetExitCodeProcess(self.handle, &mut code) } == FALSE {
+                return Err(io::Error::last_os_error());
+            }
+            if code != STILL_ACTIVE {
+                return Ok(std::process::ExitStatus::from_raw(code));
+            }
+            sleep(0.1).await;
+        }
+    }
+}
+
+impl Drop for UserProcess {
+    fn drop(&mut self) {
+        unsafe {
+            let mut code = 0;
+            if GetExitCodeProcess(self.handle, &mut code) == TRUE && code == STILL_ACTIVE {
+                winapi::um::processthreadsapi::TerminateProcess(self.handle, 1);
+            }
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+// A pipe for the stdio of a child, returned as (end of the child, own end),
+// only the end of the child is inherited.
+fn user_pipe(child_reads: bool) -> ResultType<(fs::File, fs::File)> {
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use winapi::um::{
+        handleapi::SetHandleInformation, minwinbase::SECURITY_ATTRIBUTES, namedpipeapi::CreatePipe,
+    };
+
+    let mut sa = SECURITY_ATTRIBUTES {
+        nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as _,
+        lpSecurityDescriptor: NULL,
+        bInheritHandle: TRUE,
+    };
+    let (mut r, mut w) = (NULL, NULL);
+    unsafe {
+        if CreatePipe(&mut r, &mut w, &mut sa, 0) == FALSE {
+            bail!("Failed to create pipe: {}", get_error());
+        }
+        let (r, w) = (
+            fs::File::from_raw_handle(r as _),
+            fs::File::from_raw_handle(w as _),
+        );
+        let own = if child_reads { &w } else { &r };
+        SetHandleInformation(own.as_raw_handle() as _, HANDLE_FLAG_INHERIT, 0);
+        Ok(if child_reads { (r, w) } else { (w, r) })
+    }
+}
+
+// Quotes an argument the way CommandLineToArgvW splits it.
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c == ' ' || c == '\t' || c == '"') {
+        return arg.to_owned();
+    }
+    let mut quoted = "\"".to_owned();
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+            }
+        }
+        if c != '\\' {
+            quoted.push(c);
+        }
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn send_close(postfix: &str) -> ResultType<()> {
     send_close_async(postfix).await
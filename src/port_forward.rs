@@ -1,20 +1,46 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
 
 use crate::client::*;
 use hbb_common::{
     allow_err, bail,
+    bytes::Bytes,
     config::READ_TIMEOUT,
-    futures::{SinkExt, StreamExt},
+    futures::{self, SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
+    tokio::{
+        self,
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
     tokio_util::codec::{BytesCodec, Framed},
+    tunnel::{new_tunnel_close, new_tunnel_open, new_udp_datagram, Tunnels, UDP_IDLE_TIMEOUT},
     ResultType, Stream,
 };
 
+// a datagram can not be larger
+const UDP_BUF_SIZE: usize = 64 * 1024;
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_NO_METHOD: u8 = 0xff;
+const SOCKS5_CONNECT: u8 = 1;
+const SOCKS5_IPV4: u8 = 1;
+const SOCKS5_DOMAIN: u8 = 3;
+const SOCKS5_IPV6: u8 = 4;
+const SOCKS5_SUCCEEDED: u8 = 0;
+const SOCKS5_FAILURE: u8 = 1;
+const SOCKS5_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS5_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
         .arg("/delete:localhost")
@@ -202,6 +228,9 @@ async fn connect_and_login_2(
                     Some(Data::Login((password, remember))) => {
                         interface.handle_login_from_ui(password, remember, &mut stream).await;
                     }
+                    Some(Data::Message(msg)) => {
+                        allow_err!(stream.send(&msg).await);
+                    }
                     _ => {}
                 }
             },
@@ -216,7 +245,7 @@ async fn connect_and_login_2(
     }
     stream.set_raw();
     if !buffer.is_empty() {
-        allow_err!(stream.send_bytes(buffer.into()).await);
+        allow_err!(stream.send_raw(buffer).await);
     }
     Ok(Some(stream))
 }
@@ -229,7 +258,7 @@ async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) ->
         tokio::select! {
             res = forward.next() => {
                 if let Some(Ok(bytes)) = res {
-                    allow_err!(stream.send_bytes(bytes.into()).await);
+                    allow_err!(stream.send_raw(bytes.to_vec()).await);
                 } else {
                     break;
                 }
@@ -245,3 +274,502 @@ async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) ->
     }
     Ok(())
 }
+
+/// Makes the peer listen on `remote_port` of its loopback, every connection to it is
+/// tunnelled back over this one session and forwarded to `local_host:local_port`.
+pub async fn reverse(
+    id: &str,
+    password: &str,
+    remote_port: i32,
+    local_host: String,
+    local_port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) -> ResultType<()> {
+    let addr = format!("{}:{}", local_host, local_port);
+    log::info!("Forwarding peer port {} to {}", remote_port, addr);
+    run_tunnels(
+        id,
+        password,
+        ("".to_owned(), remote_port),
+        TunnelEnd::Reverse(addr),
+        interface,
+        ui_receiver,
+        key,
+        token,
+    )
+    .await
+}
+
+/// SOCKS5 proxy on `port` of the loopback, the destination of every CONNECT is opened by
+/// the peer and tunnelled over this one session, as `ssh -D` does.
+pub async fn socks5(
+    id: &str,
+    password: &str,
+    port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) -> ResultType<()> {
+    // no authentication is asked, only local processes may use it
+    let listener = tcp::new_listener(format!("127.0.0.1:{}", port), true).await?;
+    log::info!("SOCKS5 listening on {:?}", listener.local_addr()?);
+    run_tunnels(
+        id,
+        password,
+        ("".to_owned(), 0),
+        TunnelEnd::Socks5(listener),
+        interface,
+        ui_receiver,
+        key,
+        token,
+    )
+    .await
+}
+
+/// Listens on the local port of every `(port, remote_host, remote_port)` and opens each
+/// connection accepted to the remote host and port of its listener on the peer, all tunnelled
+/// over this one session.
+pub async fn listen_all(
+    id: &str,
+    password: &str,
+    forwards: Vec<(i32, String, i32)>,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) -> ResultType<()> {
+    let mut listeners = Vec::new();
+    for (port, remote_host, remote_port) in forwards {
+        let listener = tcp::new_listener(format!("0.0.0.0:{}", port), true).await?;
+        log::info!("listening on port {:?}", listener.local_addr()?);
+        listeners.push((listener, remote_host, remote_port));
+    }
+    run_tunnels(
+        id,
+        password,
+        ("".to_owned(), 0),
+        TunnelEnd::Local(listeners),
+        interface,
+        ui_receiver,
+        key,
+        token,
+    )
+    .await
+}
+
+/// Relays the datagrams received on `port` to `remote_host:remote_port` of the peer, the
+/// replies go back to the address each datagram came from.
+pub async fn listen_udp(
+    id: &str,
+    password: &str,
+    port: i32,
+    remote_host: String,
+    remote_port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) -> ResultType<()> {
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+    log::info!("listening on udp port {:?}", socket.local_addr()?);
+    run_tunnels(
+        id,
+        password,
+        (remote_host, remote_port),
+        TunnelEnd::Udp(socket),
+        interface,
+        ui_receiver,
+        key,
+        token,
+    )
+    .await
+}
+
+// Where the tunnels of a session end on this side.
+enum TunnelEnd {
+    // the address the connections accepted by the peer are forwarded to
+    Reverse(String),
+    // the proxy whose connections are opened by the peer
+    Socks5(TcpListener),
+    // the socket whose datagrams are forwarded by the peer
+    Udp(UdpSocket),
+    // the listeners whose connections are opened by the peer to the host and port of each
+    Local(Vec<(TcpListener, String, i32)>),
+}
+
+// The source addresses of a UDP forward by the id told to the peer, forgotten once idle.
+#[derive(Default)]
+struct UdpSources {
+    ids: HashMap<SocketAddr, i32>,
+    addrs: HashMap<i32, (SocketAddr, Instant)>,
+    last_id: i32,
+}
+
+impl UdpSources {
+    fn id(&mut self, addr: SocketAddr) -> i32 {
+        let id = match self.ids.get(&addr) {
+            Some(id) => *id,
+            None => {
+                self.last_id += 1;
+                self.ids.insert(addr, self.last_id);
+                self.last_id
+            }
+        };
+        self.addrs.insert(id, (addr, Instant::now()));
+        id
+    }
+
+    fn addr(&mut self, id: i32) -> Option<SocketAddr> {
+        let (addr, last_active) = self.addrs.get_mut(&id)?;
+        *last_active = Instant::now();
+        Some(*addr)
+    }
+
+    fn expire(&mut self) {
+        let ids = &mut self.ids;
+        self.addrs.retain(|_, (addr, last_active)| {
+            if last_active.elapsed() < UDP_IDLE_TIMEOUT {
+                return true;
+            }
+            ids.remove(addr);
+            false
+        });
+    }
+}
+
+// The peer refuses a TunnelOpen of local listeners to anything else.
+fn set_login_config(
+    lc: &Arc<RwLock<LoginConfigHandler>>,
+    port_forward: (String, i32),
+    end: &TunnelEnd,
+) {
+    let mut lc = lc.write().unwrap();
+    lc.port_forward = port_forward;
+    lc.reverse_forward = matches!(end, TunnelEnd::Reverse(_));
+    lc.dynamic_forward = matches!(end, TunnelEnd::Socks5(_) | TunnelEnd::Local(_));
+    lc.udp_forward = matches!(end, TunnelEnd::Udp(_));
+    lc.forward_targets = match end {
+        TunnelEnd::Local(listeners) => listeners
+            .iter()
+            .map(|(_, host, port)| format!("{}:{}", host, port))
+            .collect(),
+        _ => Vec::new(),
+    };
+}
+
+async fn run_tunnels(
+    id: &str,
+    password: &str,
+    port_forward: (String, i32),
+    end: TunnelEnd,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) -> ResultType<()> {
+    set_login_config(&interface.get_login_config_handler(), port_forward, &end);
+    let (mut stream, direct) =
+        Client::start(id, key, token, ConnType::PORT_FORWARD, interface.clone()).await?;
+    let mut interface = interface;
+    let mut ui_receiver = ui_receiver;
+    let mut tunnels = Tunnels::default();
+    // connections waiting for the peer to open their destination
+    let mut pending = HashMap::new();
+    let (tx_open, mut rx_open) = mpsc::unbounded_channel();
+    let mut udp_sources = UdpSources::default();
+    let mut udp_buf = vec![0u8; UDP_BUF_SIZE];
+    let mut received = false;
+    let mut logged_in = false;
+    let mut timer = time::interval(Duration::from_secs(1));
+    let mut last_recv_time = Instant::now();
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                let bytes = match res {
+                    Some(Ok(bytes)) => bytes,
+                    Some(Err(err)) => {
+                        interface.set_force_relay(direct, received);
+                        bail!("Connection closed: {}", err);
+                    }
+                    None => bail!("Reset by the peer"),
+                };
+                received = true;
+                last_recv_time = Instant::now();
+                let msg_in = Message::parse_from_bytes(&bytes)?;
+                match msg_in.union {
+                    Some(message::Union::Hash(hash)) => {
+                        interface.handle_hash(password, hash, &mut stream).await;
+                    }
+                    Some(message::Union::LoginResponse(lr)) => match lr.union {
+                        Some(login_response::Union::Error(err)) => {
+                            // a wrong password is asked again
+                            if !interface.handle_login_error(&err) {
+                                bail!("Login failed: {}", err);
+                            }
+                        }
+                        Some(login_response::Union::PeerInfo(pi)) => {
+                            // an older peer takes it for a plain port forward
+                            match end {
+                                TunnelEnd::Reverse(_) if !pi.features.reverse_forward => {
+                                    bail!("The peer does not support reverse port forwarding");
+                                }
+                                TunnelEnd::Socks5(_) if !pi.features.dynamic_forward => {
+                                    bail!("The peer does not support dynamic port forwarding");
+                                }
+                                TunnelEnd::Local(_) if !pi.features.dynamic_forward => {
+                                    bail!("The peer does not support many port forwards in one session");
+                                }
+                                TunnelEnd::Udp(_) if !pi.features.udp_forward => {
+                                    bail!("The peer does not support udp port forwarding");
+                                }
+                                _ => {}
+                            }
+                            interface.handle_peer_info(pi);
+                            logged_in = true;
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::TestDelay(t)) => {
+                        interface.handle_test_delay(t, &mut stream).await;
+                    }
+                    Some(message::Union::UdpDatagram(d)) => {
+                        if let (TunnelEnd::Udp(socket), Some(addr)) = (&end, udp_sources.addr(d.id)) {
+                            // lost like any datagram
+                            socket.send_to(&d.data, addr).await.ok();
+                        }
+                    }
+                    Some(message::Union::TunnelOpen(t)) => match &end {
+                        TunnelEnd::Reverse(addr) => {
+                            log::info!("new reverse forwarding tunnel {}", t.id);
+                            tunnels.connect(t.id, addr.clone());
+                        }
+                        TunnelEnd::Socks5(_) => {
+                            if let Some(mut socket) = pending.remove(&t.id) {
+                                let reply = socks5_reply(SOCKS5_SUCCEEDED);
+                                if socket.write_all(&reply).await.is_ok() {
+                                    tunnels.add(t.id, socket);
+                                } else {
+                                    stream.send(&new_tunnel_close(t.id, "".to_owned())).await?;
+                                }
+                            }
+                        }
+                        TunnelEnd::Local(_) => {
+                            if let Some(socket) = pending.remove(&t.id) {
+                                tunnels.add(t.id, socket);
+                            }
+                        }
+                        TunnelEnd::Udp(_) => {}
+                    },
+                    Some(message::Union::TunnelData(d)) => {
+                        tunnels.send(d.id, d.data).await;
+                    }
+                    Some(message::Union::TunnelClose(c)) => {
+                        if let Some(mut socket) = pending.remove(&c.id) {
+                            log::info!("Peer failed to open tunnel {}: {}", c.id, c.error);
+                            if matches!(end, TunnelEnd::Socks5(_)) {
+                                socket.write_all(&socks5_reply(SOCKS5_FAILURE)).await.ok();
+                            }
+                        } else {
+                            tunnels.close(c.id);
+                        }
+                    }
+                    Some(message::Union::Misc(misc)) => {
+                        if let Some(misc::Union::CloseReason(c)) = misc.union {
+                            bail!("Connection closed: {}", c);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            res = accept_socks5(&end), if logged_in => {
+                match res {
+                    Ok((mut socket, addr)) => {
+                        let tx_open = tx_open.clone();
+                        tokio::spawn(async move {
+                            let res = timeout(READ_TIMEOUT, socks5_handshake(&mut socket)).await;
+                            match res {
+                                Ok(Ok((host, port))) => {
+                                    tx_open.send((socket, host, port)).ok();
+                                }
+                                Ok(Err(err)) => log::info!("SOCKS5 handshake from {} failed: {}", addr, err),
+                                Err(_) => log::info!("SOCKS5 handshake from {} timeout", addr),
+                            }
+                        });
+                    }
+                    Err(err) => log::error!("Failed to accept SOCKS5 connection: {}", err),
+                }
+            }
+            res = accept_local(&end), if logged_in => {
+                match res {
+                    Ok((socket, host, port)) => {
+                        tx_open.send((socket, host, port)).ok();
+                    }
+                    Err(err) => log::error!("Failed to accept connection: {}", err),
+                }
+            }
+            res = recv_udp(&end, &mut udp_buf), if logged_in => {
+                match res {
+                    Ok((n, addr)) => {
+                        let id = udp_sources.id(addr);
+                        let data = Bytes::copy_from_slice(&udp_buf[..n]);
+                        stream.send(&new_udp_datagram(id, data)).await?;
+                    }
+                    // e.g. an icmp unreachable of an earlier reply on windows
+                    Err(err) => log::debug!("Failed to receive udp datagram: {}", err),
+                }
+            }
+            Some((socket, host, port)) = rx_open.recv() => {
+                let id = tunnels.new_id();
+                log::info!("new forwarding tunnel {} to {}:{}", id, host, port);
+                pending.insert(id, socket);
+                stream.send(&new_tunnel_open(id, host, port)).await?;
+            }
+            msg = tunnels.next() => {
+                timeout(READ_TIMEOUT, stream.send(&msg)).await??;
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Login((password, remember))) => {
+                        interface.handle_login_from_ui(password, remember, &mut stream).await;
+                    }
+                    Some(Data::Message(msg)) => {
+                        timeout(READ_TIMEOUT, stream.send(&msg)).await??;
+                    }
+                    Some(Data::Close) | None => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            _ = timer.tick() => {
+                udp_sources.expire();
+                // the peer is silent as long as no tunnel is open
+                if !logged_in && last_recv_time.elapsed().as_millis() > READ_TIMEOUT as u128 {
+                    bail!("Timeout");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Pends forever if not a SOCKS5 proxy, so that it can stay in a select.
+async fn accept_socks5(end: &TunnelEnd) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match end {
+        TunnelEnd::Socks5(listener) => listener.accept().await,
+        _ => std::future::pending().await,
+    }
+}
+
+// Pends forever without local listeners, so that it can stay in a select.
+async fn accept_local(end: &TunnelEnd) -> std::io::Result<(TcpStream, String, i32)> {
+    match end {
+        TunnelEnd::Local(listeners) if !listeners.is_empty() => {
+            let (res, i, _) = futures::future::select_all(
+                listeners
+                    .iter()
+                    .map(|(listener, ..)| Box::pin(listener.accept())),
+            )
+            .await;
+            let (socket, addr) = res?;
+            log::info!("new connection from {:?}", addr);
+            let (_, host, port) = &listeners[i];
+            Ok((socket, host.clone(), *port))
+        }
+        _ => std::future::pending().await,
+    }
+}
+
+// Pends forever if not a UDP forward, so that it can stay in a select.
+async fn recv_udp(end: &TunnelEnd, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match end {
+        TunnelEnd::Udp(socket) => socket.recv_from(buf).await,
+        _ => std::future::pending().await,
+    }
+}
+
+// Negotiates no authentication and reads the CONNECT request of a SOCKS5 client,
+// the destination is replied to with `socks5_reply` once the peer has opened it.
+async fn socks5_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+) -> ResultType<(String, i32)> {
+    let mut head = [0u8; 2];
+    socket.read_exact(&mut head).await?;
+    if head[0] != SOCKS5_VERSION {
+        bail!("Wrong SOCKS version {}", head[0]);
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS5_NO_AUTH) {
+        socket
+            .write_all(&[SOCKS5_VERSION, SOCKS5_NO_METHOD])
+            .await?;
+        bail!("No supported SOCKS5 authentication method");
+    }
+    socket.write_all(&[SOCKS5_VERSION, SOCKS5_NO_AUTH]).await?;
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+    if request[1] != SOCKS5_CONNECT {
+        socket
+            .write_all(&socks5_reply(SOCKS5_COMMAND_NOT_SUPPORTED))
+            .await?;
+        bail!("Unsupported SOCKS5 command {}", request[1]);
+    }
+    let host = match request[3] {
+        SOCKS5_IPV4 => {
+            let mut ip = [0u8; 4];
+            socket.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS5_DOMAIN => {
+            let mut len = [0u8; 1];
+            socket.read_exact(&mut len).await?;
+            let mut domain = vec![0u8; len[0] as usize];
+            socket.read_exact(&mut domain).await?;
+            String::from_utf8(domain)?
+        }
+        SOCKS5_IPV6 => {
+            let mut ip = [0u8; 16];
+            socket.read_exact(&mut ip).await?;
+            std::net::Ipv6Addr::from(ip).to_string()
+        }
+        atyp => {
+            socket
+                .write_all(&socks5_reply(SOCKS5_ADDRESS_NOT_SUPPORTED))
+                .await?;
+            bail!("Unsupported SOCKS5 address type {}", atyp);
+        }
+    };
+    let mut port = [0u8; 2];
+    socket.read_exact(&mut port).await?;
+    Ok((host, u16::from_be_bytes(port) as _))
+}
+
+// The bound address is not known on this side, it is left unspecified.
+fn socks5_reply(rep: u8) -> [u8; 10] {
+    [SOCKS5_VERSION, rep, 0, SOCKS5_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_socks5_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 0, 5, 1, 0, 3, 9]).await.unwrap();
+        client.write_all(b"localhost\x00\x50").await.unwrap();
+        let dest = socks5_handshake(&mut server).await.unwrap();
+        assert_eq!(dest, ("localhost".to_owned(), 80));
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        assert!(socks5_handshake(&mut server).await.is_err());
+    }
+}
@@ -13,6 +13,7 @@ use hbb_common::tcp::FramedStream;
 use hbb_common::{
     allow_err,
     anyhow::bail,
+    bytes::BytesMut,
     config::{Config, REG_INTERVAL, RENDEZVOUS_PORT, RENDEZVOUS_TIMEOUT},
     futures::future::join_all,
     log,
@@ -24,6 +25,7 @@ use hbb_common::{
         self, select,
         time::{interval, Duration},
     },
+    tokio_socks::TargetAddr,
     udp::FramedSocket,
     AddrMangle, ResultType,
 };
@@ -39,7 +41,7 @@ static SHOULD_EXIT: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone)]
 pub struct RendezvousMediator {
-    addr: hbb_common::tokio_socks::TargetAddr<'static>,
+    addr: TargetAddr<'static>,
     host: String,
     host_prefix: String,
     last_id_pk_registry: String,
@@ -112,7 +114,7 @@ impl RendezvousMediator {
             })
             .unwrap_or(host.to_owned());
         let host = crate::check_port(&host, RENDEZVOUS_PORT);
-        let (mut socket, addr) = socket_client::new_udp_for(&host, RENDEZVOUS_TIMEOUT).await?;
+        let (mut socket, addr) = Sink::new(&host).await?;
         let mut rz = Self {
             addr: addr,
             host: host.clone(),
@@ -162,7 +164,7 @@ impl RendezvousMediator {
             select! {
                 n = socket.next() => {
                     match n {
-                        Some(Ok((bytes, _))) => {
+                        Some(Ok(bytes)) => {
                             if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                                 match msg_in.union {
                                     Some(rendezvous_message::Union::RegisterPeerResponse(rpr)) => {
@@ -248,11 +250,14 @@ impl RendezvousMediator {
                             if fails > MAX_FAILS2 {
                                 Config::update_latency(&host, -1);
                                 old_latency = 0;
+                                if let Sink::Tcp(_) = socket {
+                                    bail!("No response from {} over WebSocket", host);
+                                }
                                 if last_dns_check.elapsed().as_millis() as i64 > DNS_INTERVAL {
                                     // in some case of network reconnect (dial IP network),
                                     // old UDP socket not work any more after network recover
                                     if let Some((s, addr)) = socket_client::rebind_udp_for(&rz.host).await? {
-                                        socket = s;
+                                        socket = Sink::Udp(s);
                                         rz.addr = addr;
                                     }
                                     last_dns_check = Instant::now();
@@ -327,7 +332,7 @@ impl RendezvousMediator {
     }
 
     async fn handle_intranet(&self, fla: FetchLocalAddr, server: ServerPtr) -> ResultType<()> {
-        let relay_server = self.get_relay_server(fla.relay_server);
+        let relay_server = self.get_relay_server(fla.relay_server)?;
         if !is_ipv4(&self.addr) {
             // nat64, go relay directly, because current hbbs will crash if demangle ipv6 address
             let uuid = Uuid::new_v4().to_string();
@@ -348,6 +353,7 @@ impl RendezvousMediator {
         let local_addr = socket.local_addr();
         let local_addr: SocketAddr =
             format!("{}:{}", local_addr.ip(), local_addr.port()).parse()?;
+        let quic = cfg!(feature = "quic") && fla.quic;
         let mut msg_out = Message::new();
         msg_out.set_local_addr(LocalAddr {
             id: Config::get_id(),
@@ -355,16 +361,17 @@ impl RendezvousMediator {
             local_addr: AddrMangle::encode(local_addr).into(),
             relay_server,
             version: crate::VERSION.to_owned(),
+            quic,
             ..Default::default()
         });
         let bytes = msg_out.write_to_bytes()?;
         socket.send_raw(bytes).await?;
-        crate::accept_connection(server.clone(), socket, peer_addr, true).await;
+        crate::accept_connection(server.clone(), socket, peer_addr, true, quic).await;
         Ok(())
     }
 
     async fn handle_punch_hole(&self, ph: PunchHole, server: ServerPtr) -> ResultType<()> {
-        let relay_server = self.get_relay_server(ph.relay_server);
+        let relay_server = self.get_relay_server(ph.relay_server)?;
         if ph.nat_type.enum_value_or_default() == NatType::SYMMETRIC
             || Config::get_nat_type() == NatType::SYMMETRIC as i32
         {
@@ -393,21 +400,23 @@ impl RendezvousMediator {
         let mut msg_out = Message::new();
         use hbb_common::protobuf::Enum;
         let nat_type = NatType::from_i32(Config::get_nat_type()).unwrap_or(NatType::UNKNOWN_NAT);
+        let quic = cfg!(feature = "quic") && ph.quic;
         msg_out.set_punch_hole_sent(PunchHoleSent {
             socket_addr: ph.socket_addr,
             id: Config::get_id(),
             relay_server,
             nat_type: nat_type.into(),
             version: crate::VERSION.to_owned(),
+            quic,
             ..Default::default()
         });
         let bytes = msg_out.write_to_bytes()?;
         socket.send_raw(bytes).await?;
-        crate::accept_connection(server.clone(), socket, peer_addr, true).await;
+        crate::accept_connection(server.clone(), socket, peer_addr, true, quic).await;
         Ok(())
     }
 
-    async fn register_pk(&mut self, socket: &mut FramedSocket) -> ResultType<()> {
+    async fn register_pk(&mut self, socket: &mut Sink) -> ResultType<()> {
         let mut msg_out = Message::new();
         let pk = Config::get_key_pair().1;
         let uuid = hbb_common::get_uuid();
@@ -423,7 +432,7 @@ impl RendezvousMediator {
         Ok(())
     }
 
-    async fn handle_uuid_mismatch(&mut self, socket: &mut FramedSocket) -> ResultType<()> {
+    async fn handle_uuid_mismatch(&mut self, socket: &mut Sink) -> ResultType<()> {
         if self.last_id_pk_registry != Config::get_id() {
             return Ok(());
         }
@@ -441,7 +450,7 @@ impl RendezvousMediator {
         self.register_pk(socket).await
     }
 
-    async fn register_peer(&mut self, socket: &mut FramedSocket) -> ResultType<()> {
+    async fn register_peer(&mut self, socket: &mut Sink) -> ResultType<()> {
         if !SOLVING_PK_MISMATCH.lock().unwrap().is_empty() {
             return Ok(());
         }
@@ -469,15 +478,59 @@ impl RendezvousMediator {
         Ok(())
     }
 
-    fn get_relay_server(&self, provided_by_rendezvous_server: String) -> String {
+    fn get_relay_server(&self, provided_by_rendezvous_server: String) -> ResultType<String> {
         let mut relay_server = Config::get_option("relay-server");
         if relay_server.is_empty() {
             relay_server = provided_by_rendezvous_server;
         }
         if relay_server.is_empty() {
+            #[cfg(feature = "websocket")]
+            if hbb_common::websocket::get_addr(&self.host).is_some() {
+                return match hbb_common::websocket::get_relay_url(&self.host) {
+                    Some(url) => Ok(url),
+                    None => bail!(
+                        "Set the relay server, it can not be told from the url {}",
+                        self.host
+                    ),
+                };
+            }
             relay_server = crate::increase_port(&self.host, 1);
         }
-        relay_server
+        Ok(relay_server)
+    }
+}
+
+// Where registration goes, udp or, to a server behind WebSocket, which udp does not go
+// through, a tcp stream.
+enum Sink {
+    Udp(FramedSocket),
+    Tcp(FramedStream),
+}
+
+impl Sink {
+    async fn new(host: &str) -> ResultType<(Self, TargetAddr<'static>)> {
+        #[cfg(feature = "websocket")]
+        if let Some(addr) = hbb_common::websocket::get_addr(host) {
+            use hbb_common::tokio_socks::IntoTargetAddr;
+            let stream = socket_client::connect_tcp(host, RENDEZVOUS_TIMEOUT).await?;
+            return Ok((Self::Tcp(stream), addr.into_target_addr()?.to_owned()));
+        }
+        let (socket, addr) = socket_client::new_udp_for(host, RENDEZVOUS_TIMEOUT).await?;
+        Ok((Self::Udp(socket), addr))
+    }
+
+    async fn send(&mut self, msg: &Message, addr: TargetAddr<'static>) -> ResultType<()> {
+        match self {
+            Self::Udp(socket) => socket.send(msg, addr).await,
+            Self::Tcp(stream) => stream.send(msg).await,
+        }
+    }
+
+    async fn next(&mut self) -> Option<ResultType<BytesMut>> {
+        match self {
+            Self::Udp(socket) => Some(socket.next().await?.map(|(bytes, _)| bytes)),
+            Self::Tcp(stream) => Some(stream.next().await?.map_err(|err| err.into())),
+        }
     }
 }
 
@@ -52,10 +52,13 @@ pub const NAME_POS: &'static str = "";
 }
 }
 
+mod auth;
 mod connection;
 #[cfg(windows)]
 pub mod portable_service;
 mod service;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod terminal;
 mod video_qos;
 pub mod video_service;
 
@@ -102,7 +105,13 @@ pub fn new() -> ServerPtr {
     Arc::new(RwLock::new(server))
 }
 
-async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) -> ResultType<()> {
+async fn accept_connection_(
+    server: ServerPtr,
+    socket: Stream,
+    peer_addr: SocketAddr,
+    secure: bool,
+    _quic: bool,
+) -> ResultType<()> {
     let local_addr = socket.local_addr();
     drop(socket);
     // even we drop socket, below still may fail if not use reuse_addr,
@@ -110,11 +119,47 @@ async fn accept_connection_(server: ServerPtr, socket: Stream, secure: bool) ->
     // see “Only one usage of each socket address is normally permitted” on windows sometimes,
     let listener = new_listener(local_addr, true).await?;
     log::info!("Server listening on: {}", &listener.local_addr()?);
-    if let Ok((stream, addr)) = timeout(CONNECT_TIMEOUT, listener.accept()).await? {
+    let tcp = async {
+        let (stream, addr) = timeout(CONNECT_TIMEOUT, listener.accept()).await??;
         stream.set_nodelay(true).ok();
         let stream_addr = stream.local_addr()?;
-        create_tcp_connection(server, Stream::from(stream, stream_addr), addr, secure).await?;
-    }
+        ResultType::Ok((Stream::from(stream, stream_addr), addr))
+    };
+    // only listened for if told to the peer, which tries it first then
+    #[cfg(feature = "quic")]
+    let res = if !_quic {
+        tcp.await
+    } else {
+        match hbb_common::quic::listen(local_addr, peer_addr) {
+            Ok(endpoint) => {
+                // the peer goes on with tcp once it gave up on QUIC
+                let quic = async {
+                    match hbb_common::quic::accept(&endpoint, CONNECT_TIMEOUT).await {
+                        Ok(res) => res,
+                        Err(err) => {
+                            log::debug!("No QUIC connection from {}: {}", peer_addr, err);
+                            std::future::pending().await
+                        }
+                    }
+                };
+                tokio::select! {
+                    res = tcp => res,
+                    res = quic => {
+                        log::info!("QUIC connection from {}", res.1);
+                        Ok(res)
+                    }
+                }
+            }
+            Err(err) => {
+                log::warn!("Failed to listen QUIC on {}: {}", local_addr, err);
+                tcp.await
+            }
+        }
+    };
+    #[cfg(not(feature = "quic"))]
+    let res = tcp.await;
+    let (stream, addr) = res?;
+    create_tcp_connection(server, stream, addr, secure).await?;
     Ok(())
 }
 
@@ -153,6 +198,7 @@ pub async fn create_tcp_connection(
                 &IdPk {
                     id: Config::get_id(),
                     pk: Bytes::from(our_pk_b.0.to_vec()),
+                    key_exchange: hbb_common::rekey::KEY_EXCHANGE,
                     ..Default::default()
                 }
                 .write_to_bytes()
@@ -184,6 +230,9 @@ pub async fn create_tcp_connection(
                             let mut key = [0u8; secretbox::KEYBYTES];
                             key[..].copy_from_slice(&symmetric_key);
                             stream.set_key(secretbox::Key(key));
+                            if pk.key_exchange > 0 {
+                                stream.set_rekey(false);
+                            }
                         } else if pk.asymmetric_value.is_empty() {
                             Config::set_key_confirmed(false);
                             log::info!("Force to update pk");
@@ -222,8 +271,9 @@ pub async fn accept_connection(
     socket: Stream,
     peer_addr: SocketAddr,
     secure: bool,
+    quic: bool,
 ) {
-    if let Err(err) = accept_connection_(server, socket, secure).await {
+    if let Err(err) = accept_connection_(server, socket, peer_addr, secure, quic).await {
         log::error!("Failed to accept connection from {}: {}", peer_addr, err);
     }
 }
@@ -400,6 +450,11 @@ pub async fn start_server(is_server: bool) {
                 std::process::exit(-1);
             }
         });
+        std::thread::spawn(move || {
+            if let Err(err) = crate::ipc::control::start() {
+                log::error!("Failed to start control ipc: {}", err);
+            }
+        });
         #[cfg(windows)]
         crate::platform::windows::bootstrap();
         input_service::fix_key_down_timeout_loop();
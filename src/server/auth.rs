@@ -0,0 +1,346 @@
+// Authentication of incoming connections, the provider is chosen with the `auth-provider`
+// option: "password" (the default), "pam" or "hmac". Directories like LDAP are reached
+// through either of the latter, e.g. with sssd behind PAM.
+use crate::common::hmac;
+use async_trait::async_trait;
+use hbb_common::{
+    bail, config::Config, message_proto::Hash, password_security as password, ResultType,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+const HMAC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(12);
+const HMAC_SIGNATURE_HEADER: &str = "X-RustDesk-Signature";
+#[cfg(target_os = "linux")]
+const DEFAULT_PAM_SERVICE: &str = "rustdesk";
+
+pub struct Credentials<'a> {
+    pub peer_id: &'a str,
+    pub ip: &'a str,
+    // account on this side, only given to providers asking for the plain password
+    pub account: &'a str,
+    // sha256(sha256(password + salt) + challenge), or the password as is if asked for
+    pub password: &'a [u8],
+    pub hash: &'a Hash,
+}
+
+pub enum Decision {
+    Accept,
+    // accepted with the temporary password, which a recent session of the peer may reuse
+    AcceptTemporary(String),
+    Reject,
+}
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // The controlling side sends the password itself along with an account, instead of a
+    // proof of the password of this side. It is only done over a secured connection.
+    fn plain_password(&self) -> bool {
+        false
+    }
+
+    // An error is no decision, e.g. the provider is not reachable.
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> ResultType<Decision>;
+}
+
+pub fn get_provider() -> ResultType<Box<dyn AuthProvider>> {
+    Ok(match Config::get_option("auth-provider").as_str() {
+        "" | "password" => Box::new(PasswordAuth),
+        #[cfg(target_os = "linux")]
+        "pam" => Box::new(PamAuth {
+            service: match Config::get_option("auth-pam-service") {
+                service if service.is_empty() => DEFAULT_PAM_SERVICE.to_owned(),
+                service => service,
+            },
+        }),
+        "hmac" => Box::new(HmacAuth {
+            url: Config::get_option("auth-hmac-url"),
+            secret: Config::get_option("auth-hmac-secret"),
+        }),
+        // not falling back to passwords the administrator did not mean to allow
+        provider => bail!("Unknown auth provider {}", provider),
+    })
+}
+
+// Whether `proof` is sha256(sha256(password + salt) + challenge), as the peer sends it.
+pub fn check_password(hash: &Hash, proof: &[u8], password: &str) -> bool {
+    if password.is_empty() {
+        return false;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(password);
+    hasher.update(&hash.salt);
+    let mut hasher2 = Sha256::new();
+    hasher2.update(&hasher.finalize()[..]);
+    hasher2.update(&hash.challenge);
+    hasher2.finalize()[..] == proof[..]
+}
+
+// The temporary and permanent passwords of this side.
+struct PasswordAuth;
+
+#[async_trait]
+impl AuthProvider for PasswordAuth {
+    fn name(&self) -> &'static str {
+        "password"
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> ResultType<Decision> {
+        if password::temporary_enabled() {
+            let password = password::temporary_password();
+            if check_password(credentials.hash, credentials.password, &password) {
+                return Ok(Decision::AcceptTemporary(password));
+            }
+        }
+        if password::permanent_enabled()
+            && check_password(
+                credentials.hash,
+                credentials.password,
+                &Config::get_permanent_password(),
+            )
+        {
+            return Ok(Decision::Accept);
+        }
+        Ok(Decision::Reject)
+    }
+}
+
+// An account of the PAM service, which must be configured in /etc/pam.d.
+#[cfg(target_os = "linux")]
+struct PamAuth {
+    service: String,
+}
+
+#[cfg(target_os = "linux")]
+#[async_trait]
+impl AuthProvider for PamAuth {
+    fn name(&self) -> &'static str {
+        "pam"
+    }
+
+    fn plain_password(&self) -> bool {
+        true
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> ResultType<Decision> {
+        let service = self.service.clone();
+        let account = credentials.account.to_owned();
+        let password = String::from_utf8(credentials.password.to_vec())?;
+        // the modules may take their time, e.g. with a delay after a failure
+        let accepted = hbb_common::tokio::task::spawn_blocking(move || {
+            pam::authenticate(&service, &account, &password)
+        })
+        .await??;
+        Ok(if accepted {
+            Decision::Accept
+        } else {
+            Decision::Reject
+        })
+    }
+}
+
+// A service deciding on the account and password, the request and its answer are signed
+// with the shared secret:
+//   POST auth-hmac-url, X-RustDesk-Signature: hex(hmac_sha256(secret, body))
+//   {"id", "peer_id", "ip", "account", "password", "nonce"}
+//   -> {"accept": bool, "signature": hex(hmac_sha256(secret, "<nonce>:<accept>"))}
+struct HmacAuth {
+    url: String,
+    secret: String,
+}
+
+#[async_trait]
+impl AuthProvider for HmacAuth {
+    fn name(&self) -> &'static str {
+        "hmac"
+    }
+
+    fn plain_password(&self) -> bool {
+        true
+    }
+
+    async fn authenticate(&self, credentials: &Credentials<'_>) -> ResultType<Decision> {
+        if self.url.is_empty() || self.secret.is_empty() {
+            bail!("auth-hmac-url and auth-hmac-secret are required");
+        }
+        let nonce = Config::get_auto_password(16);
+        let body = json!({
+            "id": Config::get_id(),
+            "peer_id": credentials.peer_id,
+            "ip": credentials.ip,
+            "account": credentials.account,
+            "password": String::from_utf8_lossy(credentials.password),
+            "nonce": nonce,
+        })
+        .to_string();
+        let signature = hex::encode(hmac::<Sha256>(self.secret.as_bytes(), body.as_bytes()));
+        // not with crate::post_request, which puts the body on the command line of curl
+        let res = reqwest::Client::new()
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .header(HMAC_SIGNATURE_HEADER, signature)
+            .body(body)
+            .timeout(HMAC_TIMEOUT)
+            .send()
+            .await?
+            .text()
+            .await?;
+        let res: Value = serde_json::from_str(&res)?;
+        let accept = res["accept"].as_bool().unwrap_or(false);
+        let signature = hmac::<Sha256>(
+            self.secret.as_bytes(),
+            format!("{}:{}", nonce, accept).as_bytes(),
+        );
+        if res["signature"].as_str() != Some(hex::encode(signature).as_str()) {
+            bail!("Wrong signature of the auth service");
+        }
+        Ok(if accept {
+            Decision::Accept
+        } else {
+            Decision::Reject
+        })
+    }
+}
+
+// libpam is loaded once needed, it is no dependency of the package.
+#[cfg(target_os = "linux")]
+mod pam {
+    use dlopen::{symbor::Library, Error as LibError};
+    use hbb_common::{bail, lazy_static, libc, log, ResultType};
+    use std::{
+        ffi::{CStr, CString},
+        os::raw::{c_char, c_int, c_void},
+    };
+
+    const PAM_SUCCESS: c_int = 0;
+    const PAM_BUF_ERR: c_int = 5;
+    const PAM_PROMPT_ECHO_OFF: c_int = 1;
+    const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+    #[repr(C)]
+    struct PamMessage {
+        msg_style: c_int,
+        msg: *const c_char,
+    }
+
+    #[repr(C)]
+    struct PamResponse {
+        resp: *mut c_char,
+        resp_retcode: c_int,
+    }
+
+    #[repr(C)]
+    struct PamConv {
+        conv: extern "C" fn(
+            c_int,
+            *mut *const PamMessage,
+            *mut *mut PamResponse,
+            *mut c_void,
+        ) -> c_int,
+        appdata_ptr: *mut c_void,
+    }
+
+    type PamStart = unsafe extern "C" fn(
+        *const c_char,
+        *const c_char,
+        *const PamConv,
+        *mut *mut c_void,
+    ) -> c_int;
+    type PamCall = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+    type PamStrerror = unsafe extern "C" fn(*mut c_void, c_int) -> *const c_char;
+
+    lazy_static::lazy_static! {
+        static ref LIBPAM: Result<Library, LibError> = Library::open("libpam.so.0");
+    }
+
+    struct Login {
+        account: CString,
+        password: CString,
+    }
+
+    // Answers the prompts for the account and the password, the strings are freed by libpam.
+    extern "C" fn conversation(
+        num_msg: c_int,
+        msg: *mut *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *mut c_void,
+    ) -> c_int {
+        unsafe {
+            let login = &*(appdata_ptr as *const Login);
+            let num_msg = num_msg.max(0) as usize;
+            let responses =
+                libc::calloc(num_msg, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+            if responses.is_null() {
+                return PAM_BUF_ERR;
+            }
+            for i in 0..num_msg {
+                let answer = match (**msg.add(i)).msg_style {
+                    PAM_PROMPT_ECHO_OFF => &login.password,
+                    PAM_PROMPT_ECHO_ON => &login.account,
+                    _ => continue,
+                };
+                (*responses.add(i)).resp = libc::strdup(answer.as_ptr());
+            }
+            *resp = responses;
+        }
+        PAM_SUCCESS
+    }
+
+    pub fn authenticate(service: &str, account: &str, password: &str) -> ResultType<bool> {
+        let lib = match &*LIBPAM {
+            Ok(lib) => lib,
+            Err(err) => bail!("Failed to load libpam: {}", err),
+        };
+        let (start, authenticate, acct_mgmt, end, strerror) = unsafe {
+            (
+                lib.symbol::<PamStart>("pam_start")?,
+                lib.symbol::<PamCall>("pam_authenticate")?,
+                lib.symbol::<PamCall>("pam_acct_mgmt")?,
+                lib.symbol::<PamCall>("pam_end")?,
+                lib.symbol::<PamStrerror>("pam_strerror")?,
+            )
+        };
+        let service = CString::new(service)?;
+        let login = Login {
+            account: CString::new(account)?,
+            password: CString::new(password)?,
+        };
+        let conv = PamConv {
+            conv: conversation,
+            appdata_ptr: &login as *const Login as *mut c_void,
+        };
+        let mut handle = std::ptr::null_mut();
+        let mut ret =
+            unsafe { start(service.as_ptr(), login.account.as_ptr(), &conv, &mut handle) };
+        if ret != PAM_SUCCESS {
+            bail!("Failed to start pam: {}", ret);
+        }
+        ret = unsafe { authenticate(handle, 0) };
+        // e.g. an expired account is refused as well
+        if ret == PAM_SUCCESS {
+            ret = unsafe { acct_mgmt(handle, 0) };
+        }
+        if ret != PAM_SUCCESS {
+            let err = unsafe { CStr::from_ptr(strerror(handle, ret)) };
+            log::info!("pam refused {}: {}", account, err.to_string_lossy());
+        }
+        unsafe { end(handle, ret) };
+        Ok(ret == PAM_SUCCESS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            hex::encode(hmac::<Sha256>(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -6,6 +6,7 @@ use crate::common::update_clipboard;
 #[cfg(windows)]
 use crate::portable_service::client as portable_client;
 use crate::{
+    auth_2fa,
     client::{
         new_voice_call_request, new_voice_call_response, start_audio_thread, MediaData, MediaSender,
     },
@@ -17,25 +18,29 @@ use crate::{common::DEVICE_NAME, flutter::connection_manager::start_channel};
 use crate::{ipc, VERSION};
 use cidr_utils::cidr::IpCidr;
 use hbb_common::{
-    config::Config,
+    config::{AuthorizedKeys, Config},
     fs,
     fs::can_enable_overwrite_detection,
     futures::{SinkExt, StreamExt},
     get_time, get_version_number,
     message_proto::{option_message::BoolOption, permission_info::Permission},
     password_security::{self as password, ApproveMode},
-    sleep, timeout,
+    sleep,
+    sodiumoxide::crypto::sign,
+    tcp::Channel,
+    timeout,
     tokio::{
-        net::TcpStream,
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::{lookup_host, TcpListener, TcpStream},
         sync::mpsc,
         time::{self, Duration, Instant, Interval},
     },
     tokio_util::codec::{BytesCodec, Framed},
+    tunnel::{new_tunnel_close, new_tunnel_open, Tunnels, UdpTunnels},
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use scrap::android::call_main_service_mouse_input;
 use serde_json::{json, value::Value};
-use sha2::{Digest, Sha256};
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use std::sync::atomic::Ordering;
 use std::{
@@ -86,11 +91,23 @@ pub struct Connection {
     server: super::ServerPtrWeak,
     hash: Hash,
     read_jobs: Vec<fs::TransferJob>,
+    search_jobs: Vec<fs::SearchJob>,
+    file_scheduler: fs::TransferScheduler,
     timer: Interval,
     file_timer: Interval,
     file_transfer: Option<(String, bool)>,
     port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
+    reverse_forward_port: Option<i32>,
+    // the only "host:port" the controlling side may open, any if empty
+    dynamic_forward: Option<Vec<String>>,
+    udp_forward_target: Option<std::net::SocketAddr>,
     port_forward_address: String,
+    exec_request: Option<ExecRequest>,
+    terminal_request: Option<TerminalRequest>,
+    // the password is right, the one-time code is not there yet
+    require_2fa: bool,
+    // the temporary password logged in with, a recent session once the login is done
+    random_password: Option<String>,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
     keyboard: bool,
@@ -99,6 +116,8 @@ pub struct Connection {
     file: bool,
     restart: bool,
     recording: bool,
+    exec: bool,
+    terminal: bool,
     last_test_delay: i64,
     lock_after_session_end: bool,
     show_remote_cursor: bool,
@@ -170,6 +189,10 @@ const MILLI1: Duration = Duration::from_millis(1);
 const SEND_TIMEOUT_VIDEO: u64 = 12_000;
 const SEND_TIMEOUT_OTHER: u64 = SEND_TIMEOUT_VIDEO * 10;
 const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+const EXEC_BUF_SIZE: usize = 32 * 1024;
+// the peer is not read from while this much of its input waits for the command to take it
+const MAX_EXEC_INPUT: usize = 1024 * 1024;
+const REVERSE_FORWARD_HOST: &str = "127.0.0.1";
 
 impl Connection {
     pub async fn start(
@@ -181,6 +204,12 @@ impl Connection {
         let hash = Hash {
             salt: Config::get_salt(),
             challenge: Config::get_auto_password(6),
+            // the password is sent as is then
+            account_login: stream.is_secured()
+                && super::auth::get_provider()
+                    .map(|p| p.plain_password())
+                    .unwrap_or(false),
+            key_login: !AuthorizedKeys::load().keys.is_empty(),
             ..Default::default()
         };
         ALIVE_CONNS.lock().unwrap().push(id);
@@ -192,6 +221,7 @@ impl Connection {
         let (tx_video, mut rx_video) = mpsc::unbounded_channel::<(Instant, Arc<Message>)>();
         let (tx_input, _rx_input) = std_mpsc::channel();
         let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut control_rx = ipc::control::disconnect_receiver();
 
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         let tx_cloned = tx.clone();
@@ -205,11 +235,20 @@ impl Connection {
             server,
             hash,
             read_jobs: Vec::new(),
+            search_jobs: Vec::new(),
+            file_scheduler: Default::default(),
             timer: time::interval(SEC30),
             file_timer: time::interval(SEC30),
             file_transfer: None,
             port_forward_socket: None,
+            reverse_forward_port: None,
+            dynamic_forward: None,
+            udp_forward_target: None,
             port_forward_address: "".to_owned(),
+            exec_request: None,
+            terminal_request: None,
+            require_2fa: false,
+            random_password: None,
             tx_to_cm,
             authorized: false,
             keyboard: Connection::permission("enable-keyboard"),
@@ -218,6 +257,9 @@ impl Connection {
             file: Connection::permission("enable-file-transfer"),
             restart: Connection::permission("enable-remote-restart"),
             recording: Connection::permission("enable-record-session"),
+            // running commands is opt-in
+            exec: Config::get_option("allow-remote-exec") == "Y",
+            terminal: Config::get_option("allow-remote-terminal") == "Y",
             last_test_delay: 0,
             lock_after_session_end: false,
             show_remote_cursor: false,
@@ -279,7 +321,7 @@ impl Connection {
         let mut last_recv_time = Instant::now();
 
         conn.stream.set_send_timeout(
-            if conn.file_transfer.is_some() || conn.port_forward_socket.is_some() {
+            if conn.file_transfer.is_some() || conn.is_port_forward() || conn.runs_command() {
                 SEND_TIMEOUT_OTHER
             } else {
                 SEND_TIMEOUT_VIDEO
@@ -298,7 +340,7 @@ impl Connection {
                     match data {
                         ipc::Data::Authorize => {
                             conn.send_logon_response().await;
-                            if conn.port_forward_socket.is_some() {
+                            if conn.is_port_forward() || conn.runs_command() {
                                 break;
                             }
                         }
@@ -355,6 +397,12 @@ impl Connection {
                             } else if &name == "recording" {
                                 conn.recording = enabled;
                                 conn.send_permission(Permission::Recording, enabled).await;
+                            } else if &name == "exec" {
+                                conn.exec = enabled;
+                                conn.send_permission(Permission::Exec, enabled).await;
+                            } else if &name == "terminal" {
+                                conn.terminal = enabled;
+                                conn.send_permission(Permission::Terminal, enabled).await;
                             }
                         }
                         ipc::Data::RawMessage(bytes) => {
@@ -443,8 +491,12 @@ impl Connection {
                     }
                 },
                 _ = conn.file_timer.tick() => {
-                    if !conn.read_jobs.is_empty() {
-                        if let Err(err) = fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
+                    if !conn.read_jobs.is_empty() || !conn.search_jobs.is_empty() {
+                        if let Err(err) = fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream, &mut conn.file_scheduler).await {
+                            conn.on_close(&err.to_string(), false).await;
+                            break;
+                        }
+                        if let Err(err) = fs::handle_search_jobs(&mut conn.search_jobs, &mut conn.stream).await {
                             conn.on_close(&err.to_string(), false).await;
                             break;
                         }
@@ -459,11 +511,18 @@ impl Connection {
                         break;
                     }
                 }
+                Ok(conn_id) = control_rx.recv() => {
+                    if conn_id == id {
+                        conn.send_close_reason_no_retry("Closed manually by local control").await;
+                        conn.on_close("local control", true).await;
+                        break;
+                    }
+                }
                 Some((instant, value)) = rx_video.recv() => {
                     if !conn.video_ack_required {
                         video_service::notify_video_frame_fetched(id, Some(instant.into()));
                     }
-                    if let Err(err) = conn.stream.send(&value as &Message).await {
+                    if let Err(err) = conn.stream.send_on(Channel::Video, &value as &Message).await {
                         conn.on_close(&err.to_string(), false).await;
                         break;
                     }
@@ -494,7 +553,11 @@ impl Connection {
                         }
                         _ => {}
                     }
-                    if let Err(err) = conn.stream.send(msg).await {
+                    let res = match crate::common::get_channel(msg) {
+                        Some(channel) => conn.stream.send_on(channel, msg).await,
+                        None => conn.stream.send(msg).await,
+                    };
+                    if let Err(err) = res {
                         conn.on_close(&err.to_string(), false).await;
                         break;
                     }
@@ -541,6 +604,13 @@ impl Connection {
         if let Err(err) = conn.try_port_forward_loop(&mut rx_from_cm).await {
             conn.on_close(&err.to_string(), false).await;
         }
+        if let Err(err) = conn.try_exec_loop(&mut rx_from_cm).await {
+            conn.on_close(&err.to_string(), false).await;
+        }
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Err(err) = conn.try_terminal_loop(&mut rx_from_cm).await {
+            conn.on_close(&err.to_string(), false).await;
+        }
 
         conn.post_conn_audit(json!({
             "action": "close",
@@ -548,6 +618,7 @@ impl Connection {
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         conn.reset_resolution();
         ALIVE_CONNS.lock().unwrap().retain(|&c| c != id);
+        ipc::control::on_close(id);
         if let Some(s) = conn.server.upgrade() {
             let mut s = s.write().unwrap();
             s.remove_connection(&conn.inner);
@@ -630,6 +701,7 @@ impl Connection {
             log::info!("Running port forwarding loop");
             self.stream.set_raw();
             let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+            let mut control_rx = ipc::control::disconnect_receiver();
             loop {
                 tokio::select! {
                     Some(data) = rx_from_cm.recv() => {
@@ -643,7 +715,7 @@ impl Connection {
                     res = forward.next() => {
                         if let Some(res) = res {
                             last_recv_time = Instant::now();
-                            self.stream.send_bytes(res?.into()).await?;
+                            self.stream.send_raw(res?.to_vec()).await?;
                         } else {
                             bail!("Forward reset by the peer");
                         }
@@ -667,12 +739,448 @@ impl Connection {
                             bail!("Closed manually by the web console");
                         }
                     }
+                    Ok(conn_id) = control_rx.recv() => {
+                        if conn_id == self.inner.id {
+                            bail!("Closed manually by local control");
+                        }
+                    }
                 }
             }
+        } else if let Some(port) = self.reverse_forward_port.take() {
+            if self.authorized {
+                let listener = match TcpListener::bind((REVERSE_FORWARD_HOST, port as u16)).await {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        let reason = format!("Failed to listen on remote port {}: {}", port, err);
+                        self.send_close_reason_no_retry(&reason).await;
+                        bail!(reason);
+                    }
+                };
+                log::info!("Running reverse port forwarding loop on {}", port);
+                self.post_conn_audit(json!({"action": "reverse_forward", "port": port}));
+                self.tunnel_loop(Some(listener), Vec::new(), rx_from_cm)
+                    .await?;
+            }
+        } else if let Some(targets) = self.dynamic_forward.take() {
+            if self.authorized {
+                log::info!("Running dynamic port forwarding loop");
+                self.post_conn_audit(json!({"action": "dynamic_forward", "targets": targets}));
+                self.tunnel_loop(None, targets, rx_from_cm).await?;
+            }
+        } else if let Some(target) = self.udp_forward_target.take() {
+            if self.authorized {
+                log::info!("Running udp port forwarding loop to {}", target);
+                self.post_conn_audit(
+                    json!({"action": "udp_forward", "address": target.to_string()}),
+                );
+                self.udp_forward_loop(target, rx_from_cm).await?;
+            }
         }
         Ok(())
     }
 
+    // Relays the datagrams of every source forwarded by the controlling side to the target,
+    // and the replies back.
+    async fn udp_forward_loop(
+        &mut self,
+        target: std::net::SocketAddr,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        let mut udp = UdpTunnels::new(target);
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut control_rx = ipc::control::disconnect_receiver();
+        let mut last_recv_time = Instant::now();
+        loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        _ => {}
+                    }
+                }
+                msg = udp.next() => {
+                    last_recv_time = Instant::now();
+                    timeout(SEND_TIMEOUT_OTHER, self.stream.send(&msg)).await??;
+                }
+                res = self.stream.next() => {
+                    let bytes = match res {
+                        Some(res) => res?,
+                        None => bail!("Stream reset by the peer"),
+                    };
+                    last_recv_time = Instant::now();
+                    if let Ok(Message { union: Some(message::Union::UdpDatagram(d)), .. }) =
+                        Message::parse_from_bytes(&bytes)
+                    {
+                        udp.send(d.id, &d.data).await;
+                    }
+                }
+                _ = self.timer.tick() => {
+                    udp.expire();
+                    if udp.is_empty() && last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+                Ok(conn_id) = control_rx.recv() => {
+                    if conn_id == self.inner.id {
+                        bail!("Closed manually by local control");
+                    }
+                }
+            }
+        }
+    }
+
+    // Tunnels the connections accepted by the listener of a reverse forward to the controlling
+    // side, which connects to the target on its network for them. Without a listener, it is
+    // the controlling side opening them to targets of this network, as a dynamic forward,
+    // restricted to `targets` unless empty.
+    async fn tunnel_loop(
+        &mut self,
+        listener: Option<TcpListener>,
+        targets: Vec<String>,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        let mut tunnels = Tunnels::default();
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut control_rx = ipc::control::disconnect_receiver();
+        let mut last_recv_time = Instant::now();
+        loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        _ => {}
+                    }
+                }
+                res = accept_tunnel(&listener) => {
+                    let (socket, addr) = res?;
+                    let id = tunnels.new_id();
+                    log::info!("Reverse forward tunnel {} from {}", id, addr);
+                    self.send(new_tunnel_open(id, "".to_owned(), 0)).await;
+                    tunnels.add(id, socket);
+                }
+                msg = tunnels.next() => {
+                    last_recv_time = Instant::now();
+                    timeout(SEND_TIMEOUT_OTHER, self.stream.send(&msg)).await??;
+                }
+                res = self.stream.next() => {
+                    let bytes = match res {
+                        Some(res) => res?,
+                        None => bail!("Stream reset by the peer"),
+                    };
+                    last_recv_time = Instant::now();
+                    match Message::parse_from_bytes(&bytes).map(|msg| msg.union) {
+                        Ok(Some(message::Union::TunnelOpen(t))) if listener.is_none() => {
+                            if t.host.is_empty() || t.port <= 0 || t.port > 65535 {
+                                self.send(new_tunnel_close(t.id, "Wrong address".to_owned())).await;
+                            } else if !targets.is_empty() && !targets.contains(&format!("{}:{}", t.host, t.port)) {
+                                self.send(new_tunnel_close(t.id, "Not a forwarded address".to_owned())).await;
+                            } else {
+                                let addr = if t.host.contains(':') {
+                                    format!("[{}]:{}", t.host, t.port)
+                                } else {
+                                    format!("{}:{}", t.host, t.port)
+                                };
+                                log::info!("Dynamic forward tunnel {} to {}", t.id, addr);
+                                self.post_conn_audit(json!({"action": "dynamic_forward_open", "address": addr}));
+                                tunnels.connect(t.id, addr);
+                            }
+                        }
+                        Ok(Some(message::Union::TunnelData(d))) => {
+                            tunnels.send(d.id, d.data).await;
+                        }
+                        Ok(Some(message::Union::TunnelClose(c))) => {
+                            tunnels.close(c.id);
+                        }
+                        _ => {}
+                    }
+                }
+                _ = self.timer.tick() => {
+                    if tunnels.is_empty() && last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+                Ok(conn_id) = control_rx.recv() => {
+                    if conn_id == self.inner.id {
+                        bail!("Closed manually by local control");
+                    }
+                }
+            }
+        }
+    }
+
+    // Runs the command of an authorized exec login, relaying its stdio until it exits.
+    async fn try_exec_loop(
+        &mut self,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        let req = match self.exec_request.take() {
+            Some(req) if self.authorized => req,
+            _ => return Ok(()),
+        };
+        let command = exec_command_line(&req);
+        log::info!("Running remote exec: {}", command);
+        self.post_conn_audit(json!({"action": "exec", "command": command}));
+        let ExecChild {
+            mut stdin,
+            mut stdout,
+            mut stderr,
+            mut exit,
+        } = match spawn_exec(&req) {
+            Ok(child) => child,
+            Err(err) => {
+                self.post_conn_audit(json!({"action": "exec_exit", "error": err.to_string()}));
+                self.send(new_exec_exit(-1, err.to_string())).await;
+                self.on_close("Remote exec failed", false).await;
+                return Ok(());
+            }
+        };
+        let mut stdout_buf = vec![0u8; EXEC_BUF_SIZE];
+        let mut stderr_buf = vec![0u8; EXEC_BUF_SIZE];
+        // what the peer sent and the command has not read yet
+        let mut stdin_buf = Vec::new();
+        let mut stdin_eof = false;
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut control_rx = ipc::control::disconnect_receiver();
+        let mut last_recv_time = Instant::now();
+        let status = loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::SwitchPermission { name, enabled: false } if name == "exec" => {
+                            bail!("Remote exec disabled by connection manager");
+                        }
+                        _ => {}
+                    }
+                }
+                res = read_exec_output(&mut stdout, &mut stdout_buf) => {
+                    if let Some(data) = res {
+                        last_recv_time = Instant::now();
+                        self.send(new_exec_output(data, false)).await;
+                    } else {
+                        stdout.take();
+                    }
+                }
+                res = read_exec_output(&mut stderr, &mut stderr_buf) => {
+                    if let Some(data) = res {
+                        last_recv_time = Instant::now();
+                        self.send(new_exec_output(data, true)).await;
+                    } else {
+                        stderr.take();
+                    }
+                }
+                res = write_exec_input(&mut stdin, &stdin_buf), if !stdin_buf.is_empty() => {
+                    match res {
+                        Some(n) => {
+                            stdin_buf.drain(..n);
+                        }
+                        None => {
+                            stdin.take();
+                            stdin_buf.clear();
+                        }
+                    }
+                    if stdin_buf.is_empty() && stdin_eof {
+                        stdin.take();
+                    }
+                }
+                res = self.stream.next(), if stdin_buf.len() < MAX_EXEC_INPUT => {
+                    let bytes = match res {
+                        Some(res) => res?,
+                        None => bail!("Stream reset by the peer"),
+                    };
+                    last_recv_time = Instant::now();
+                    if let Ok(Message { union: Some(message::Union::ExecInput(input)), .. }) =
+                        Message::parse_from_bytes(&bytes)
+                    {
+                        if stdin.is_some() {
+                            stdin_buf.extend_from_slice(&input.data);
+                        }
+                        if input.eof {
+                            stdin_eof = true;
+                            if stdin_buf.is_empty() {
+                                stdin.take();
+                            }
+                        }
+                    }
+                }
+                // output is drained first, it may still be buffered when the child exits
+                res = &mut exit, if stdout.is_none() && stderr.is_none() => {
+                    break res?;
+                }
+                _ = self.timer.tick() => {
+                    if last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+                Ok(conn_id) = control_rx.recv() => {
+                    if conn_id == self.inner.id {
+                        bail!("Closed manually by local control");
+                    }
+                }
+            }
+        };
+        let code = status.code().unwrap_or(-1);
+        let error = if status.success() || status.code().is_some() {
+            "".to_owned()
+        } else {
+            status.to_string()
+        };
+        log::info!("Remote exec exited: {}", status);
+        self.post_conn_audit(json!({"action": "exec_exit", "code": code, "error": error}));
+        self.send(new_exec_exit(code, error)).await;
+        self.on_close("Remote exec finished", false).await;
+        Ok(())
+    }
+
+    // The login shell of the active user on a pty, for an authorized terminal login.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    async fn try_terminal_loop(
+        &mut self,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        let req = match self.terminal_request.take() {
+            Some(req) if self.authorized => req,
+            _ => return Ok(()),
+        };
+        self.post_conn_audit(json!({"action": "terminal"}));
+        let spawned = super::terminal::Pty::spawn(req.rows, req.cols, &req.term)
+            .and_then(|pty| Ok((pty.reader()?, pty.writer()?, pty)));
+        let (output, input, mut pty) = match spawned {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                log::error!("Failed to start terminal: {}", err);
+                self.send(new_exec_exit(-1, err.to_string())).await;
+                self.on_close("Terminal failed", false).await;
+                return Ok(());
+            }
+        };
+        let mut output = Some(output);
+        let mut input = Some(input);
+        let mut buf = vec![0u8; EXEC_BUF_SIZE];
+        // what the peer typed and the pty has not taken yet
+        let mut input_buf = Vec::new();
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut control_rx = ipc::control::disconnect_receiver();
+        let mut last_recv_time = Instant::now();
+        let status = loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::SwitchPermission { name, enabled: false } if name == "terminal" => {
+                            bail!("Terminal disabled by connection manager");
+                        }
+                        _ => {}
+                    }
+                }
+                res = read_exec_output(&mut output, &mut buf) => {
+                    if let Some(data) = res {
+                        last_recv_time = Instant::now();
+                        let mut msg_out = Message::new();
+                        msg_out.set_terminal_data(TerminalData {
+                            data: data.into(),
+                            ..Default::default()
+                        });
+                        self.send(msg_out).await;
+                    } else {
+                        output.take();
+                    }
+                }
+                res = write_exec_input(&mut input, &input_buf), if !input_buf.is_empty() => {
+                    match res {
+                        Some(n) => {
+                            input_buf.drain(..n);
+                        }
+                        None => {
+                            input.take();
+                            input_buf.clear();
+                        }
+                    }
+                }
+                res = self.stream.next(), if input_buf.len() < MAX_EXEC_INPUT => {
+                    let bytes = match res {
+                        Some(res) => res?,
+                        None => bail!("Stream reset by the peer"),
+                    };
+                    last_recv_time = Instant::now();
+                    match Message::parse_from_bytes(&bytes).map(|msg| msg.union) {
+                        Ok(Some(message::Union::TerminalData(d))) => {
+                            if input.is_some() {
+                                input_buf.extend_from_slice(&d.data);
+                            }
+                        }
+                        Ok(Some(message::Union::TerminalResize(r))) => {
+                            allow_err!(pty.resize(r.rows, r.cols));
+                        }
+                        _ => {}
+                    }
+                }
+                // the pty reads an error once the shell and whatever it left behind are gone
+                res = pty.child.wait(), if output.is_none() => {
+                    break res?;
+                }
+                _ = self.timer.tick() => {
+                    if last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+                Ok(conn_id) = control_rx.recv() => {
+                    if conn_id == self.inner.id {
+                        bail!("Closed manually by local control");
+                    }
+                }
+            }
+        };
+        let code = status.code().unwrap_or(-1);
+        log::info!("Terminal exited: {}", status);
+        self.post_conn_audit(json!({"action": "terminal_exit", "code": code}));
+        self.send(new_exec_exit(code, "".to_owned())).await;
+        self.on_close("Terminal closed", false).await;
+        Ok(())
+    }
+
+    #[inline]
+    fn is_port_forward(&self) -> bool {
+        self.port_forward_socket.is_some()
+            || self.reverse_forward_port.is_some()
+            || self.dynamic_forward.is_some()
+            || self.udp_forward_target.is_some()
+    }
+
+    // exec and terminal logins, which run a process instead of a session
+    #[inline]
+    fn runs_command(&self) -> bool {
+        self.exec_request.is_some() || self.terminal_request.is_some()
+    }
+
     async fn send_permission(&mut self, permission: Permission, enabled: bool) {
         let mut misc = Misc::new();
         misc.set_permission_info(PermissionInfo {
@@ -821,8 +1329,12 @@ impl Connection {
         }
         let conn_type = if self.file_transfer.is_some() {
             1
-        } else if self.port_forward_socket.is_some() {
+        } else if self.is_port_forward() {
             2
+        } else if self.exec_request.is_some() {
+            3
+        } else if self.terminal_request.is_some() {
+            4
         } else {
             0
         };
@@ -870,7 +1382,49 @@ impl Connection {
             .into();
         }
 
-        if self.port_forward_socket.is_some() {
+        let control_info = ipc::control::ConnInfo {
+            id: self.inner.id,
+            peer_id: self.lr.my_id.clone(),
+            name: self.lr.my_name.clone(),
+            ip: self.ip.clone(),
+            conn_type: [
+                "remote",
+                "file-transfer",
+                "port-forward",
+                "remote-exec",
+                "terminal",
+            ][conn_type]
+                .to_owned(),
+            authorized: true,
+        };
+        if self.is_port_forward() {
+            if self.port_forward_socket.is_none() {
+                // nothing is listened on or sent to before authorized
+                self.authorized = true;
+                ipc::control::on_login(control_info);
+                pi.features = Some(Features {
+                    reverse_forward: self.reverse_forward_port.is_some(),
+                    dynamic_forward: self.dynamic_forward.is_some(),
+                    udp_forward: self.udp_forward_target.is_some(),
+                    ..Default::default()
+                })
+                .into();
+            }
+            let mut msg_out = Message::new();
+            res.set_peer_info(pi);
+            msg_out.set_login_response(res);
+            self.send(msg_out).await;
+            return;
+        }
+        if self.runs_command() {
+            self.authorized = true;
+            ipc::control::on_login(control_info);
+            pi.features = Some(Features {
+                remote_exec: self.exec_request.is_some(),
+                terminal: self.terminal_request.is_some(),
+                ..Default::default()
+            })
+            .into();
             let mut msg_out = Message::new();
             res.set_peer_info(pi);
             msg_out.set_login_response(res);
@@ -878,7 +1432,7 @@ impl Connection {
             return;
         }
         #[cfg(target_os = "linux")]
-        if !self.file_transfer.is_some() && !self.port_forward_socket.is_some() {
+        if !self.file_transfer.is_some() && !self.is_port_forward() {
             let dtype = crate::platform::linux::get_display_server();
             if dtype != "x11" && dtype != "wayland" {
                 res.set_error(format!(
@@ -904,14 +1458,21 @@ impl Connection {
             }
         }
         self.authorized = true;
+        ipc::control::on_login(control_info);
 
         pi.username = username;
         pi.sas_enabled = sas_enabled;
         pi.features = Some(Features {
             privacy_mode: video_service::is_privacy_mode_supported(),
+            chunked: self.lr.chunked,
+            parallel_transfer: true,
+            file_links: true,
             ..Default::default()
         })
         .into();
+        if self.lr.chunked {
+            self.stream.set_chunked();
+        }
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         {
             pi.resolutions = Some(SupportedResolutions {
@@ -1003,6 +1564,12 @@ impl Connection {
             id: self.inner.id(),
             is_file_transfer: self.file_transfer.is_some(),
             port_forward: self.port_forward_address.clone(),
+            exec_command: self
+                .exec_request
+                .as_ref()
+                .map(exec_command_line)
+                .unwrap_or_default(),
+            is_terminal: self.terminal_request.is_some(),
             peer_id,
             name,
             authorized,
@@ -1013,6 +1580,7 @@ impl Connection {
             file_transfer_enabled: self.file_transfer_enabled(),
             restart: self.restart,
             recording: self.recording,
+            terminal: self.terminal,
             from_switch: self.from_switch,
         });
     }
@@ -1058,41 +1626,182 @@ impl Connection {
         self.tx_input.send(MessageInput::Key((msg, press))).ok();
     }
 
-    fn validate_one_password(&self, password: String) -> bool {
-        if password.len() == 0 {
+    // the passwords of this side, the accounts of the auth provider, or authorized keys
+    #[inline]
+    fn has_valid_password(&self) -> bool {
+        self.hash.account_login || self.hash.key_login || password::has_valid_password()
+    }
+
+    // Whether the challenge is signed with an authorized key, the attempt is audited.
+    fn validate_key(&self) -> bool {
+        let key_login = match self.lr.key_login.as_ref() {
+            Some(key_login) if self.hash.key_login => key_login,
+            _ => return false,
+        };
+        let key = crate::encode64(&key_login.public_key);
+        let keys = AuthorizedKeys::load();
+        let authorized = keys.get(&key);
+        let data = crate::common::get_key_login_data(&self.lr.username, &self.hash);
+        let accepted = authorized.is_some()
+            && match (
+                sign::PublicKey::from_slice(&key_login.public_key),
+                sign::Signature::from_bytes(&key_login.signature),
+            ) {
+                (Some(pk), Ok(signature)) => sign::verify_detached(&signature, &data, &pk),
+                _ => false,
+            };
+        log::info!("Key login of {} with {}: {}", self.lr.my_id, key, accepted);
+        self.post_conn_audit(json!({
+            "action": "key_login",
+            "ip": self.ip,
+            "key": key,
+            "comment": authorized.map(|x| x.comment.clone()).unwrap_or_default(),
+            "accepted": accepted,
+        }));
+        accepted
+    }
+
+    // Asks the provider of the `auth-provider` option, the decision is reported as an alarm.
+    async fn validate_password(&mut self) -> bool {
+        let provider = match super::auth::get_provider() {
+            Ok(provider) => provider,
+            Err(err) => {
+                log::error!("Failed to get auth provider: {}", err);
+                self.post_auth_audit("", false, &err.to_string());
+                return false;
+            }
+        };
+        if provider.plain_password() != self.hash.account_login {
+            // not asked for over an insecure connection, or the option changed since
+            self.post_auth_audit(provider.name(), false, "Password not sent as needed");
             return false;
         }
-        let mut hasher = Sha256::new();
-        hasher.update(password);
-        hasher.update(&self.hash.salt);
-        let mut hasher2 = Sha256::new();
-        hasher2.update(&hasher.finalize()[..]);
-        hasher2.update(&self.hash.challenge);
-        hasher2.finalize()[..] == self.lr.password[..]
+        let credentials = super::auth::Credentials {
+            peer_id: &self.lr.my_id,
+            ip: &self.ip,
+            account: &self.lr.account,
+            password: &self.lr.password,
+            hash: &self.hash,
+        };
+        let (accepted, error) = match provider.authenticate(&credentials).await {
+            Ok(super::auth::Decision::AcceptTemporary(password)) => {
+                self.random_password = Some(password);
+                (true, "".to_owned())
+            }
+            Ok(super::auth::Decision::Accept) => (true, "".to_owned()),
+            Ok(super::auth::Decision::Reject) => (false, "".to_owned()),
+            Err(err) => {
+                log::error!("Auth provider {} failed: {}", provider.name(), err);
+                (false, err.to_string())
+            }
+        };
+        self.post_auth_audit(provider.name(), accepted, &error);
+        accepted
     }
 
-    fn validate_password(&mut self) -> bool {
-        if password::temporary_enabled() {
-            let password = password::temporary_password();
-            if self.validate_one_password(password.clone()) {
-                SESSIONS.lock().unwrap().insert(
-                    self.lr.my_id.clone(),
-                    Session {
-                        name: self.lr.my_name.clone(),
-                        session_id: self.lr.session_id,
-                        last_recv_time: self.last_recv_time.clone(),
-                        random_password: password,
-                    },
-                );
-                return true;
-            }
+    fn post_auth_audit(&self, provider: &str, accepted: bool, error: &str) {
+        Self::post_alarm_audit(
+            AlarmAuditType::Authentication,
+            true,
+            json!({
+                "ip": self.ip,
+                "peer_id": self.lr.my_id,
+                "provider": provider,
+                "account": self.lr.account,
+                "accepted": accepted,
+                "error": error,
+            }),
+        );
+    }
+
+    // After a right password or key, the login is done unless a one-time code is asked for.
+    // Returns false if no more messages of the peer are handled, as with port forwards.
+    async fn accept_login(&mut self) -> bool {
+        if auth_2fa::enabled() {
+            self.require_2fa = true;
+            self.send_login_error(auth_2fa::REQUIRE_2FA).await;
+            return true;
         }
-        if password::permanent_enabled() {
-            if self.validate_one_password(Config::get_permanent_password()) {
-                return true;
+        self.logon().await
+    }
+
+    async fn logon(&mut self) -> bool {
+        self.add_recent_session();
+        self.try_start_cm(self.lr.my_id.clone(), self.lr.my_name.clone(), true);
+        self.send_logon_response().await;
+        !(self.is_port_forward() || self.runs_command())
+    }
+
+    // Lets the peer log in again with the temporary password it logged in with, for a while.
+    fn add_recent_session(&mut self) {
+        if let Some(password) = self.random_password.take() {
+            SESSIONS.lock().unwrap().insert(
+                self.lr.my_id.clone(),
+                Session {
+                    name: self.lr.my_name.clone(),
+                    session_id: self.lr.session_id,
+                    last_recv_time: self.last_recv_time.clone(),
+                    random_password: password,
+                },
+            );
+        }
+    }
+
+    // The wrong passwords and codes of the ip as (minute, count of the minute, total), None
+    // with the login refused if there were too many.
+    async fn check_failure(&mut self) -> Option<(i32, i32, i32)> {
+        let failure = LOGIN_FAILURES
+            .lock()
+            .unwrap()
+            .get(&self.ip)
+            .map(|x| x.clone())
+            .unwrap_or((0, 0, 0));
+        let time = (get_time() / 60_000) as i32;
+        if failure.2 > 30 {
+            self.send_login_error("Too many wrong password attempts")
+                .await;
+            Self::post_alarm_audit(
+                AlarmAuditType::ManyWrongPassword,
+                true,
+                json!({
+                            "ip":self.ip,
+                }),
+            );
+        } else if time == failure.0 && failure.1 > 6 {
+            self.send_login_error("Please try 1 minute later").await;
+            Self::post_alarm_audit(
+                AlarmAuditType::FrequentAttempt,
+                true,
+                json!({
+                            "ip":self.ip,
+                }),
+            );
+        } else {
+            return Some(failure);
+        }
+        None
+    }
+
+    fn update_failure(&self, mut failure: (i32, i32, i32), wrong: bool) {
+        if !wrong {
+            if failure.0 != 0 {
+                LOGIN_FAILURES.lock().unwrap().remove(&self.ip);
             }
+            return;
         }
-        false
+        let time = (get_time() / 60_000) as i32;
+        if failure.0 == time {
+            failure.1 += 1;
+            failure.2 += 1;
+        } else {
+            failure.0 = time;
+            failure.1 = 1;
+            failure.2 += 1;
+        }
+        LOGIN_FAILURES
+            .lock()
+            .unwrap()
+            .insert(self.ip.clone(), failure);
     }
 
     fn is_recent_session(&mut self) -> bool {
@@ -1108,8 +1817,11 @@ impl Connection {
         if let Some(session) = session {
             if session.name == self.lr.my_name
                 && session.session_id == self.lr.session_id
-                && !self.lr.password.is_empty()
-                && self.validate_one_password(session.random_password.clone())
+                && super::auth::check_password(
+                    &self.hash,
+                    &self.lr.password,
+                    &session.random_password,
+                )
             {
                 SESSIONS.lock().unwrap().insert(
                     self.lr.my_id.clone(),
@@ -1180,6 +1892,31 @@ impl Connection {
                     }
                     self.file_transfer = Some((ft.dir, ft.show_hidden));
                 }
+                Some(login_request::Union::Exec(e)) => {
+                    if !self.exec {
+                        self.send_login_error("No permission of remote exec").await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    if e.program.is_empty() {
+                        self.send_login_error("No command to run").await;
+                        return false;
+                    }
+                    self.exec_request = Some(e);
+                }
+                Some(login_request::Union::Terminal(t)) => {
+                    if !cfg!(any(target_os = "linux", target_os = "macos")) {
+                        self.send_login_error("Terminal is not supported on this platform")
+                            .await;
+                        return false;
+                    }
+                    if !self.terminal {
+                        self.send_login_error("No permission of terminal").await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    self.terminal_request = Some(t);
+                }
                 Some(login_request::Union::PortForward(mut pf)) => {
                     let mut is_rdp = false;
                     if pf.host == "RDP" && pf.port == 0 {
@@ -1198,26 +1935,81 @@ impl Connection {
                         sleep(1.).await;
                         return false;
                     }
-                    if pf.host.is_empty() {
-                        pf.host = "localhost".to_owned();
-                    }
-                    let mut addr = format!("{}:{}", pf.host, pf.port);
-                    self.port_forward_address = addr.clone();
-                    match timeout(3000, TcpStream::connect(&addr)).await {
-                        Ok(Ok(sock)) => {
-                            self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
+                    if pf.dynamic {
+                        // any destination of this network is opt-in, a list of them is
+                        // what as many plain port forwards could open
+                        if pf.targets.is_empty()
+                            && Config::get_option("allow-dynamic-forward") != "Y"
+                        {
+                            self.send_login_error("No permission of dynamic port forwarding")
+                                .await;
+                            sleep(1.).await;
+                            return false;
                         }
-                        _ => {
-                            if is_rdp {
-                                addr = "RDP".to_owned();
-                            }
-                            self.send_login_error(format!(
-                                "Failed to access remote {}, please make sure if it is open",
-                                addr
-                            ))
-                            .await;
+                        self.port_forward_address = if pf.targets.is_empty() {
+                            "(dynamic)".to_owned()
+                        } else {
+                            pf.targets.join(", ")
+                        };
+                        self.dynamic_forward = Some(pf.targets);
+                    } else if pf.reverse {
+                        // a listener on this machine is opt-in
+                        if Config::get_option("allow-reverse-forward") != "Y" {
+                            self.send_login_error("No permission of reverse port forwarding")
+                                .await;
+                            sleep(1.).await;
+                            return false;
+                        }
+                        if is_rdp || pf.port <= 0 || pf.port > 65535 {
+                            self.send_login_error("Wrong port of reverse forwarding")
+                                .await;
                             return false;
                         }
+                        self.port_forward_address =
+                            format!("{}:{} (reverse)", REVERSE_FORWARD_HOST, pf.port);
+                        self.reverse_forward_port = Some(pf.port);
+                    } else if pf.udp && !is_rdp {
+                        if pf.host.is_empty() {
+                            pf.host = "localhost".to_owned();
+                        }
+                        let addr = format!("{}:{}", pf.host, pf.port);
+                        self.port_forward_address = format!("{} (udp)", addr);
+                        let addrs = match timeout(3000, lookup_host(&addr)).await {
+                            Ok(Ok(addrs)) => addrs.collect::<Vec<_>>(),
+                            _ => vec![],
+                        };
+                        // localhost is rather served on ipv4
+                        match addrs.iter().find(|a| a.is_ipv4()).or(addrs.first()) {
+                            Some(target) => self.udp_forward_target = Some(*target),
+                            None => {
+                                self.send_login_error(format!("Failed to resolve remote {}", addr))
+                                    .await;
+                                return false;
+                            }
+                        }
+                    } else {
+                        if pf.host.is_empty() {
+                            pf.host = "localhost".to_owned();
+                        }
+                        let mut addr = format!("{}:{}", pf.host, pf.port);
+                        self.port_forward_address = addr.clone();
+                        match timeout(3000, TcpStream::connect(&addr)).await {
+                            Ok(Ok(sock)) => {
+                                self.port_forward_socket =
+                                    Some(Framed::new(sock, BytesCodec::new()));
+                            }
+                            _ => {
+                                if is_rdp {
+                                    addr = "RDP".to_owned();
+                                }
+                                self.send_login_error(format!(
+                                    "Failed to access remote {}, please make sure if it is open",
+                                    addr
+                                ))
+                                .await;
+                                return false;
+                            }
+                        }
                     }
                 }
                 _ => {}
@@ -1225,7 +2017,7 @@ impl Connection {
             if !hbb_common::is_ipv4_str(&lr.username) && lr.username != Config::get_id() {
                 self.send_login_error("Offline").await;
             } else if password::approve_mode() == ApproveMode::Click
-                || password::approve_mode() == ApproveMode::Both && !password::has_valid_password()
+                || password::approve_mode() == ApproveMode::Both && !self.has_valid_password()
             {
                 self.try_start_cm(lr.my_id, lr.my_name, false);
                 if hbb_common::get_version_number(&lr.version)
@@ -1235,71 +2027,60 @@ impl Connection {
                 }
                 return true;
             } else if password::approve_mode() == ApproveMode::Password
-                && !password::has_valid_password()
+                && !self.has_valid_password()
             {
                 self.send_login_error("Connection not allowed").await;
                 return false;
             } else if self.is_recent_session() {
                 self.try_start_cm(lr.my_id, lr.my_name, true);
                 self.send_logon_response().await;
-                if self.port_forward_socket.is_some() {
+                if self.is_port_forward() || self.runs_command() {
+                    return false;
+                }
+            } else if self.validate_key() {
+                if !self.accept_login().await {
                     return false;
                 }
             } else if lr.password.is_empty() {
                 self.try_start_cm(lr.my_id, lr.my_name, false);
-            } else {
-                let mut failure = LOGIN_FAILURES
-                    .lock()
-                    .unwrap()
-                    .get(&self.ip)
-                    .map(|x| x.clone())
-                    .unwrap_or((0, 0, 0));
-                let time = (get_time() / 60_000) as i32;
-                if failure.2 > 30 {
-                    self.send_login_error("Too many wrong password attempts")
-                        .await;
-                    Self::post_alarm_audit(
-                        AlarmAuditType::ManyWrongPassword,
-                        true,
-                        json!({
-                                    "ip":self.ip,
-                        }),
-                    );
-                } else if time == failure.0 && failure.1 > 6 {
-                    self.send_login_error("Please try 1 minute later").await;
-                    Self::post_alarm_audit(
-                        AlarmAuditType::FrequentAttempt,
-                        true,
-                        json!({
-                                    "ip":self.ip,
-                        }),
-                    );
-                } else if !self.validate_password() {
-                    if failure.0 == time {
-                        failure.1 += 1;
-                        failure.2 += 1;
-                    } else {
-                        failure.0 = time;
-                        failure.1 = 1;
-                        failure.2 += 1;
-                    }
-                    LOGIN_FAILURES
-                        .lock()
-                        .unwrap()
-                        .insert(self.ip.clone(), failure);
+            } else if let Some(failure) = self.check_failure().await {
+                if !self.validate_password().await {
+                    self.update_failure(failure, true);
                     self.send_login_error("Wrong Password").await;
                     self.try_start_cm(lr.my_id, lr.my_name, false);
                 } else {
-                    if failure.0 != 0 {
-                        LOGIN_FAILURES.lock().unwrap().remove(&self.ip);
+                    // failures are only forgotten with the right code if one is asked for
+                    if !auth_2fa::enabled() {
+                        self.update_failure(failure, false);
                     }
-                    self.try_start_cm(lr.my_id, lr.my_name, true);
-                    self.send_logon_response().await;
-                    if self.port_forward_socket.is_some() {
+                    if !self.accept_login().await {
                         return false;
                     }
                 }
             }
+        } else if let Some(message::Union::Auth2fa(auth)) = msg.union {
+            if !self.require_2fa {
+                return true;
+            }
+            if let Some(failure) = self.check_failure().await {
+                match auth_2fa::verify(&auth.code) {
+                    Ok(true) => {
+                        self.require_2fa = false;
+                        self.update_failure(failure, false);
+                        if !self.logon().await {
+                            return false;
+                        }
+                    }
+                    Ok(false) => {
+                        self.update_failure(failure, true);
+                        self.send_login_error(auth_2fa::WRONG_2FA_CODE).await;
+                    }
+                    Err(err) => {
+                        log::error!("Failed to verify 2FA code: {}", err);
+                        self.send_login_error(err.to_string()).await;
+                    }
+                }
+            }
         } else if let Some(message::Union::TestDelay(t)) = msg.union {
             if t.from_client {
                 let mut msg_out = Message::new();
@@ -1308,6 +2089,7 @@ impl Connection {
             } else {
                 self.last_test_delay = 0;
                 let new_delay = (get_time() - t.time) as u32;
+                self.file_scheduler.update_delay(new_delay);
                 video_service::VIDEO_QOS
                     .lock()
                     .unwrap()
@@ -1407,7 +2189,34 @@ impl Connection {
                                         self.send(fs::new_error(f.id, err, -1)).await;
                                     }
                                     Ok(files) => {
-                                        self.send(fs::new_dir(f.id, f.path, files)).await;
+                                        self.send(fs::new_dir(f.id, f.path, files, Vec::new()))
+                                            .await;
+                                    }
+                                }
+                            }
+                            Some(file_action::Union::ReadRange(r)) => {
+                                let (id, path) = (r.id, r.path.clone());
+                                // the file may be slow to open or seek
+                                let task = tokio::task::spawn_blocking(move || fs::read_range(&r));
+                                let res = match task.await {
+                                    Ok(res) => res,
+                                    Err(err) => Err(err.into()),
+                                };
+                                match res {
+                                    Err(err) => {
+                                        self.send(fs::new_error(id, err, -1)).await;
+                                    }
+                                    Ok(data) => {
+                                        self.post_file_audit(
+                                            FileAuditType::RemoteReadRange,
+                                            &path,
+                                            vec![("".to_owned(), data.data.len() as _)],
+                                            json!({
+                                                "offset": data.offset,
+                                                "file_size": data.file_size,
+                                            }),
+                                        );
+                                        self.send(fs::new_range_data(data)).await;
                                     }
                                 }
                             }
@@ -1417,21 +2226,41 @@ impl Connection {
                                     &self.lr.version,
                                 ));
                                 let path = s.path.clone();
-                                match fs::TransferJob::new_read(
-                                    id,
-                                    "".to_string(),
-                                    path.clone(),
-                                    s.file_num,
-                                    s.include_hidden,
-                                    false,
-                                    od,
-                                ) {
+                                let res = if s.archive && std::path::Path::new(&path).is_dir() {
+                                    fs::TransferJob::new_archive_read(
+                                        id,
+                                        "".to_string(),
+                                        path.clone(),
+                                        s.include_hidden,
+                                        false,
+                                    )
+                                } else {
+                                    fs::TransferJob::new_read(
+                                        id,
+                                        "".to_string(),
+                                        path.clone(),
+                                        s.file_num,
+                                        s.include_hidden,
+                                        false,
+                                        od,
+                                    )
+                                };
+                                match res {
                                     Err(err) => {
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
-                                    Ok(job) => {
-                                        self.send(fs::new_dir(id, path, job.files().to_vec()))
-                                            .await;
+                                    Ok(mut job) => {
+                                        if !self.lr.file_links {
+                                            job.follow_links();
+                                        }
+                                        job.set_parallel(s.parallel);
+                                        let files = job.files().to_vec();
+                                        if job.is_archive() {
+                                            self.send(fs::new_archive_dir(id, path, files)).await;
+                                        } else {
+                                            let dirs = job.dirs().to_vec();
+                                            self.send(fs::new_dir(id, path, files, dirs)).await;
+                                        }
                                         let mut files = job.files().to_owned();
                                         self.read_jobs.push(job);
                                         self.file_timer = time::interval(MILLI1);
@@ -1457,13 +2286,10 @@ impl Connection {
                                     path: r.path.clone(),
                                     id: r.id,
                                     file_num: r.file_num,
-                                    files: r
-                                        .files
-                                        .to_vec()
-                                        .drain(..)
-                                        .map(|f| (f.name, f.modified_time))
-                                        .collect(),
+                                    files: r.files.to_vec().drain(..).map(|f| f.into()).collect(),
+                                    dirs: r.dirs.to_vec().drain(..).map(|f| f.into()).collect(),
                                     overwrite_detection: od,
+                                    parallel: r.parallel,
                                 });
                                 self.post_file_audit(
                                     FileAuditType::RemoteReceive,
@@ -1496,15 +2322,56 @@ impl Connection {
                                     id: c.id,
                                 });
                             }
+                            Some(file_action::Union::Rename(r)) => {
+                                self.post_file_audit(
+                                    FileAuditType::RemoteRename,
+                                    &r.path,
+                                    Vec::new(),
+                                    json!({ "to": r.new_path }),
+                                );
+                                self.send_fs(ipc::FS::Rename {
+                                    path: r.path,
+                                    new_path: r.new_path,
+                                    id: r.id,
+                                });
+                            }
+                            Some(file_action::Union::Copy(c)) => {
+                                self.post_file_audit(
+                                    FileAuditType::RemoteCopy,
+                                    &c.path,
+                                    Vec::new(),
+                                    json!({ "to": c.new_path }),
+                                );
+                                self.send_fs(ipc::FS::Copy {
+                                    path: c.path,
+                                    new_path: c.new_path,
+                                    id: c.id,
+                                });
+                            }
                             Some(file_action::Union::Cancel(c)) => {
                                 self.send_fs(ipc::FS::CancelWrite { id: c.id });
                                 fs::remove_job(c.id, &mut self.read_jobs);
+                                fs::remove_search_job(c.id, &mut self.search_jobs);
                             }
+                            Some(file_action::Union::Search(s)) => match fs::SearchJob::new(&s) {
+                                Err(err) => {
+                                    self.send(fs::new_error(s.id, err, -1)).await;
+                                }
+                                Ok(job) => {
+                                    self.search_jobs.push(job);
+                                    self.file_timer = time::interval(MILLI1);
+                                }
+                            },
                             Some(file_action::Union::SendConfirm(r)) => {
                                 if let Some(job) = fs::get_job(r.id, &mut self.read_jobs) {
                                     job.confirm(&r);
                                 }
                             }
+                            Some(file_action::Union::Verified(v)) => {
+                                if let Some(job) = fs::get_job(v.id, &mut self.read_jobs) {
+                                    job.verified(&v);
+                                }
+                            }
                             _ => {}
                         }
                     }
@@ -1516,6 +2383,9 @@ impl Connection {
                             file_num: block.file_num,
                             data: block.data,
                             compressed: block.compressed,
+                            blk_id: block.blk_id,
+                            copy_blocks: block.copy_blocks,
+                            sha256: block.sha256,
                         });
                     }
                     Some(file_response::Union::Done(d)) => {
@@ -1530,6 +2400,7 @@ impl Connection {
                         file_size: d.file_size,
                         last_modified: d.last_modified,
                         is_upload: true,
+                        support_delta: d.support_delta,
                     }),
                     Some(file_response::Union::Error(e)) => {
                         self.send_fs(ipc::FS::WriteError {
@@ -1733,6 +2604,9 @@ impl Connection {
 
     async fn update_options(&mut self, o: &OptionMessage) {
         log::info!("Option update: {:?}", o);
+        if let Some(limit) = o.file_transfer_rate_limit {
+            self.file_scheduler.set_session_limit(limit);
+        }
         if let Ok(q) = o.image_quality.enum_value() {
             let image_quality;
             if let ImageQuality::NotSet = q {
@@ -1925,6 +2799,11 @@ impl Connection {
         let data = ipc::Data::Close;
         self.tx_to_cm.send(data).ok();
         self.port_forward_socket.take();
+        self.reverse_forward_port.take();
+        self.dynamic_forward.take();
+        self.udp_forward_target.take();
+        self.exec_request.take();
+        self.terminal_request.take();
     }
 
     // The `reason` should be consistent with `check_if_retry` if not empty
@@ -1951,7 +2830,10 @@ impl Connection {
 
     #[inline]
     async fn send(&mut self, msg: Message) {
-        allow_err!(self.stream.send(&msg).await);
+        match crate::common::get_channel(&msg) {
+            Some(channel) => allow_err!(self.stream.send_on(channel, &msg).await),
+            None => allow_err!(self.stream.send(&msg).await),
+        }
     }
 
     pub fn alive_conns() -> Vec<i32> {
@@ -1962,7 +2844,8 @@ impl Connection {
     fn portable_check(&mut self) {
         if self.portable.is_installed
             || self.file_transfer.is_some()
-            || self.port_forward_socket.is_some()
+            || self.is_port_forward()
+            || self.runs_command()
         {
             return;
         }
@@ -2123,8 +3006,11 @@ async fn start_ipc(
                         if let Data::FS(ipc::FS::WriteBlock{id,
                             file_num,
                             data,
-                            compressed}) = data {
-                                stream.send(&Data::FS(ipc::FS::WriteBlock{id, file_num, data: Bytes::new(), compressed})).await?;
+                            compressed,
+                            blk_id,
+                            copy_blocks,
+                            sha256}) = data {
+                                stream.send(&Data::FS(ipc::FS::WriteBlock{id, file_num, data: Bytes::new(), compressed, blk_id, copy_blocks, sha256})).await?;
                                 stream.send_raw(data).await?;
                         } else {
                             stream.send(&data).await?;
@@ -2139,6 +3025,114 @@ async fn start_ipc(
     }
 }
 
+// Pends forever without a listener, so that it can stay in a select.
+async fn accept_tunnel(
+    listener: &Option<TcpListener>,
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+type ExecReader = Box<dyn AsyncRead + Send + Unpin>;
+type ExecWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+// The piped stdio of a remote exec command and its exit status, the command is killed on drop.
+struct ExecChild {
+    stdin: Option<ExecWriter>,
+    stdout: Option<ExecReader>,
+    stderr: Option<ExecReader>,
+    exit: std::pin::Pin<
+        Box<dyn std::future::Future<Output = std::io::Result<std::process::ExitStatus>> + Send>,
+    >,
+}
+
+fn exec_command_line(req: &ExecRequest) -> String {
+    std::iter::once(&req.program)
+        .chain(req.args.iter())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The command runs as the logged-in user, never as the account of the service.
+fn spawn_exec(req: &ExecRequest) -> ResultType<ExecChild> {
+    #[cfg(windows)]
+    if crate::platform::is_root() {
+        let mut child = crate::platform::windows::UserProcess::spawn(&req.program, &req.args)?;
+        return Ok(ExecChild {
+            stdin: child.stdin.take().map(|x| Box::new(x) as ExecWriter),
+            stdout: child.stdout.take().map(|x| Box::new(x) as ExecReader),
+            stderr: child.stderr.take().map(|x| Box::new(x) as ExecReader),
+            exit: Box::pin(async move { child.wait().await }),
+        });
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let mut cmd = super::terminal::exec_command(&req.program)?;
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    let mut cmd = tokio::process::Command::new(&req.program);
+    let mut child = cmd
+        .args(&req.args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    Ok(ExecChild {
+        stdin: child.stdin.take().map(|x| Box::new(x) as ExecWriter),
+        stdout: child.stdout.take().map(|x| Box::new(x) as ExecReader),
+        stderr: child.stderr.take().map(|x| Box::new(x) as ExecReader),
+        exit: Box::pin(async move { child.wait().await }),
+    })
+}
+
+// Pends forever once the pipe is closed, so that it can stay in a select.
+async fn read_exec_output<R: AsyncRead + Unpin>(
+    r: &mut Option<R>,
+    buf: &mut [u8],
+) -> Option<Vec<u8>> {
+    match r {
+        Some(r) => match r.read(buf).await {
+            Ok(n) if n > 0 => Some(buf[..n].to_vec()),
+            _ => None,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+// Pends forever once the pipe is closed, so that it can stay in a select. A single write,
+// unlike write_all, loses nothing when another branch of the select goes first.
+async fn write_exec_input<W: AsyncWrite + Unpin>(w: &mut Option<W>, buf: &[u8]) -> Option<usize> {
+    match w {
+        Some(w) => match w.write(buf).await {
+            Ok(n) if n > 0 => Some(n),
+            _ => None,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+fn new_exec_output(data: Vec<u8>, is_stderr: bool) -> Message {
+    let mut msg_out = Message::new();
+    msg_out.set_exec_output(ExecOutput {
+        data: data.into(),
+        is_stderr,
+        ..Default::default()
+    });
+    msg_out
+}
+
+fn new_exec_exit(code: i32, error: String) -> Message {
+    let mut msg_out = Message::new();
+    msg_out.set_exec_exit(ExecExit {
+        code,
+        error,
+        ..Default::default()
+    });
+    msg_out
+}
+
 // in case screen is sleep and blank, here to activate it
 fn try_activate_screen() {
     #[cfg(windows)]
@@ -2193,11 +3187,15 @@ pub enum AlarmAuditType {
     IpWhitelist = 0,
     ManyWrongPassword = 1,
     FrequentAttempt = 2,
+    Authentication = 3,
 }
 
 pub enum FileAuditType {
     RemoteSend = 0,
     RemoteReceive = 1,
+    RemoteRename = 2,
+    RemoteCopy = 3,
+    RemoteReadRange = 4,
 }
 
 #[cfg(windows)]
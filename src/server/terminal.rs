@@ -0,0 +1,229 @@
+// Pseudo terminal running the login shell of the active user for a terminal session.
+use std::{
+    ffi::{CStr, CString},
+    os::unix::io::{AsRawFd, FromRawFd},
+    process::Stdio,
+};
+
+use hbb_common::{
+    bail, libc, log,
+    tokio::{
+        fs::File,
+        process::{Child, Command},
+    },
+    ResultType,
+};
+
+const DEFAULT_TERM: &str = "xterm-256color";
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+struct User {
+    name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    home: String,
+    shell: String,
+}
+
+pub struct Pty {
+    master: std::fs::File,
+    pub child: Child,
+}
+
+fn get_user(name: Option<&str>) -> ResultType<User> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut res = std::ptr::null_mut();
+    let name = name.map(CString::new).transpose()?;
+    let err = unsafe {
+        match &name {
+            Some(name) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut res,
+            ),
+            None => libc::getpwuid_r(
+                libc::getuid(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut res,
+            ),
+        }
+    };
+    if err != 0 || res.is_null() {
+        bail!("Failed to look up user {:?}", name);
+    }
+    let to_string = |s: *const libc::c_char| unsafe { CStr::from_ptr(s) }.to_string_lossy();
+    let shell = to_string(pwd.pw_shell);
+    Ok(User {
+        name: to_string(pwd.pw_name).into_owned(),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: to_string(pwd.pw_dir).into_owned(),
+        shell: if shell.is_empty() {
+            "/bin/sh".to_owned()
+        } else {
+            shell.into_owned()
+        },
+    })
+}
+
+// The logged-in user if the service runs as root, the service user otherwise.
+fn get_active_user() -> ResultType<User> {
+    if crate::platform::is_root() {
+        #[cfg(target_os = "linux")]
+        let (_, name) = crate::platform::linux::get_active_user_id_name();
+        #[cfg(target_os = "macos")]
+        let name = crate::platform::macos::get_active_username();
+        if name.is_empty() || crate::platform::is_prelogin() {
+            bail!("No active user");
+        }
+        return get_user(Some(&name));
+    }
+    get_user(None)
+}
+
+// Runs the command in the environment of the user and switches to the user in the child,
+// optionally as the leader of a new session on its stdin which is a pty then.
+fn set_user(cmd: &mut Command, user: &User, new_session: bool) -> ResultType<()> {
+    cmd.env_clear()
+        .env("HOME", &user.home)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("SHELL", &user.shell)
+        .env("PATH", DEFAULT_PATH);
+    if std::path::Path::new(&user.home).is_dir() {
+        cmd.current_dir(&user.home);
+    }
+    if let Ok(lang) = std::env::var("LANG") {
+        cmd.env("LANG", lang);
+    }
+    let switch_user = unsafe { libc::getuid() } != user.uid;
+    // Command::uid would drop the supplementary groups of the user, which are looked up here
+    // as initgroups is not safe between fork and exec
+    let groups = if switch_user {
+        get_groups(user)?
+    } else {
+        Vec::new()
+    };
+    let (uid, gid) = (user.uid, user.gid);
+    unsafe {
+        cmd.pre_exec(move || {
+            if new_session && (libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0)
+                || switch_user
+                    && (libc::setgroups(groups.len() as _, groups.as_ptr()) < 0
+                        || libc::setgid(gid) < 0
+                        || libc::setuid(uid) < 0)
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+// The groups of the user, its primary one included.
+fn get_groups(user: &User) -> ResultType<Vec<libc::gid_t>> {
+    let name = CString::new(user.name.clone())?;
+    let mut n: libc::c_int = 64;
+    loop {
+        let mut groups: Vec<libc::gid_t> = vec![0; n as usize];
+        let res = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                user.gid as _,
+                groups.as_mut_ptr() as _,
+                &mut n,
+            )
+        };
+        if res >= 0 {
+            groups.truncate(n as usize);
+            return Ok(groups);
+        }
+        // linux tells how many there are, macos does not
+        n = std::cmp::max(n, groups.len() as libc::c_int * 2);
+        if n > 65536 {
+            bail!("Failed to get the groups of {}", user.name);
+        }
+    }
+}
+
+// A command of remote exec, run as the logged-in user and refused if there is none.
+pub fn exec_command(program: &str) -> ResultType<Command> {
+    let user = get_active_user()?;
+    let mut cmd = Command::new(program);
+    set_user(&mut cmd, &user, false)?;
+    log::info!("Remote exec runs as {}", user.name);
+    Ok(cmd)
+}
+
+fn new_winsize(rows: i32, cols: i32) -> libc::winsize {
+    libc::winsize {
+        ws_row: rows.max(1) as _,
+        ws_col: cols.max(1) as _,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+impl Pty {
+    pub fn spawn(rows: i32, cols: i32, term: &str) -> ResultType<Self> {
+        let user = get_active_user()?;
+        let mut master = 0;
+        let mut slave = 0;
+        let mut ws = new_winsize(rows, cols);
+        if unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut ws,
+            )
+        } != 0
+        {
+            bail!("Failed to open pty: {}", std::io::Error::last_os_error());
+        }
+        let master = unsafe { std::fs::File::from_raw_fd(master) };
+        let slave = unsafe { std::fs::File::from_raw_fd(slave) };
+        // the shell must not inherit the master side
+        unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+
+        let shell_name = std::path::Path::new(&user.shell)
+            .file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut cmd = Command::new(&user.shell);
+        cmd.arg0(format!("-{}", shell_name))
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .kill_on_drop(true);
+        set_user(&mut cmd, &user, true)?;
+        cmd.env("TERM", if term.is_empty() { DEFAULT_TERM } else { term });
+        let child = cmd.spawn()?;
+        log::info!("Terminal started for {}: {}", user.name, user.shell);
+        Ok(Self { master, child })
+    }
+
+    // Output of the shell, ends with an error once the shell and its children are gone.
+    pub fn reader(&self) -> ResultType<File> {
+        Ok(File::from_std(self.master.try_clone()?))
+    }
+
+    pub fn writer(&self) -> ResultType<File> {
+        Ok(File::from_std(self.master.try_clone()?))
+    }
+
+    pub fn resize(&self, rows: i32, cols: i32) -> ResultType<()> {
+        let ws = new_winsize(rows, cols);
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &ws) } < 0 {
+            bail!("Failed to resize pty: {}", std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
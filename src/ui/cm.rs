@@ -28,7 +28,10 @@ impl InvokeUiCM for SciterHandler {
                 client.audio,
                 client.file,
                 client.restart,
-                client.recording
+                client.recording,
+                client.exec_command.clone(),
+                client.is_terminal,
+                client.terminal
             ),
         );
     }
@@ -189,6 +189,27 @@ impl InvokeUiSession for SciterHandler {
         self.call("updateFolderFiles", &make_args!(m));
     }
 
+    // unused in sciter
+    fn update_file_preview(
+        &self,
+        _id: i32,
+        _path: String,
+        _offset: u64,
+        _data: &[u8],
+        _file_size: u64,
+    ) {
+    }
+
+    // unused in sciter
+    fn update_search_result(
+        &self,
+        _id: i32,
+        _entries: &Vec<FileEntry>,
+        _path: String,
+        _done: bool,
+    ) {
+    }
+
     fn update_transfer_list(&self) {
         self.call("updateTransferList", &make_args!());
     }
@@ -246,6 +267,8 @@ impl InvokeUiSession for SciterHandler {
             ConnType::RDP => {}
             ConnType::PORT_FORWARD => {}
             ConnType::FILE_TRANSFER => {}
+            ConnType::REMOTE_EXEC => {}
+            ConnType::TERMINAL => {}
             ConnType::DEFAULT_CONN => {
                 crate::keyboard::client::start_grab_loop();
             }
@@ -399,6 +422,7 @@ impl sciter::EventHandler for SciterSession {
         fn is_port_forward();
         fn is_rdp();
         fn login(String, bool);
+        fn send2fa(String);
         fn new_rdp();
         fn send_mouse(i32, i32, i32, bool, bool, bool, bool);
         fn enter();
@@ -415,6 +439,8 @@ impl sciter::EventHandler for SciterSession {
         fn remove_dir(i32, String, bool);
         fn create_dir(i32, String, bool);
         fn remove_file(i32, String, i32, bool);
+        fn rename_file(i32, String, String, bool);
+        fn copy_file(i32, String, String, bool);
         fn read_remote_dir(String, bool);
         fn send_chat(String);
         fn switch_display(i32);
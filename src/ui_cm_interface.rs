@@ -29,7 +29,7 @@ use hbb_common::{
     fs::{self, get_string, new_send_confirm, DigestCheckResult},
     log,
     message_proto::*,
-    protobuf::Message as _,
+    protobuf::{Message as _, MessageField},
     tokio::{
         self,
         sync::mpsc::{self, UnboundedSender},
@@ -44,6 +44,8 @@ pub struct Client {
     pub disconnected: bool,
     pub is_file_transfer: bool,
     pub port_forward: String,
+    pub exec_command: String,
+    pub is_terminal: bool,
     pub name: String,
     pub peer_id: String,
     pub keyboard: bool,
@@ -52,6 +54,7 @@ pub struct Client {
     pub file: bool,
     pub restart: bool,
     pub recording: bool,
+    pub terminal: bool,
     pub from_switch: bool,
     pub in_voice_call: bool,
     pub incoming_voice_call: bool,
@@ -119,6 +122,8 @@ impl<T: InvokeUiCM> ConnectionManager<T> {
         id: i32,
         is_file_transfer: bool,
         port_forward: String,
+        exec_command: String,
+        is_terminal: bool,
         peer_id: String,
         name: String,
         authorized: bool,
@@ -128,6 +133,7 @@ impl<T: InvokeUiCM> ConnectionManager<T> {
         file: bool,
         restart: bool,
         recording: bool,
+        terminal: bool,
         from_switch: bool,
         tx: mpsc::UnboundedSender<Data>,
     ) {
@@ -137,6 +143,8 @@ impl<T: InvokeUiCM> ConnectionManager<T> {
             disconnected: false,
             is_file_transfer,
             port_forward,
+            exec_command,
+            is_terminal,
             name: name.clone(),
             peer_id: peer_id.clone(),
             keyboard,
@@ -145,6 +153,7 @@ impl<T: InvokeUiCM> ConnectionManager<T> {
             file,
             restart,
             recording,
+            terminal,
             from_switch,
             tx,
             in_voice_call: false,
@@ -359,9 +368,9 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                         }
                         Ok(Some(data)) => {
                             match data {
-                                Data::Login{id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, file_transfer_enabled: _file_transfer_enabled, restart, recording, from_switch} => {
+                                Data::Login{id, is_file_transfer, port_forward, exec_command, is_terminal, peer_id, name, authorized, keyboard, clipboard, audio, file, file_transfer_enabled: _file_transfer_enabled, restart, recording, terminal, from_switch} => {
                                     log::debug!("conn_id: {}", id);
-                                    self.cm.add_connection(id, is_file_transfer, port_forward, peer_id, name, authorized, keyboard, clipboard, audio, file, restart, recording, from_switch,self.tx.clone());
+                                    self.cm.add_connection(id, is_file_transfer, port_forward, exec_command, is_terminal, peer_id, name, authorized, keyboard, clipboard, audio, file, restart, recording, terminal, from_switch,self.tx.clone());
                                     self.authorized = authorized;
                                     self.conn_id = id;
                                     #[cfg(windows)]
@@ -395,9 +404,9 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                     self.cm.new_message(self.conn_id, text);
                                 }
                                 Data::FS(mut fs) => {
-                                    if let ipc::FS::WriteBlock { id, file_num, data: _, compressed } = fs {
+                                    if let ipc::FS::WriteBlock { id, file_num, data: _, compressed, blk_id, copy_blocks, sha256 } = fs {
                                         if let Ok(bytes) = self.stream.next_raw().await {
-                                            fs = ipc::FS::WriteBlock{id, file_num, data:bytes.into(), compressed};
+                                            fs = ipc::FS::WriteBlock{id, file_num, data:bytes.into(), compressed, blk_id, copy_blocks, sha256};
                                             handle_fs(fs, &mut write_jobs, &self.tx).await;
                                         }
                                     } else {
@@ -546,6 +555,8 @@ pub async fn start_listen<T: InvokeUiCM>(
                 id,
                 is_file_transfer,
                 port_forward,
+                exec_command,
+                is_terminal,
                 peer_id,
                 name,
                 authorized,
@@ -555,6 +566,7 @@ pub async fn start_listen<T: InvokeUiCM>(
                 file,
                 restart,
                 recording,
+                terminal,
                 from_switch,
                 ..
             }) => {
@@ -563,6 +575,8 @@ pub async fn start_listen<T: InvokeUiCM>(
                     id,
                     is_file_transfer,
                     port_forward,
+                    exec_command,
+                    is_terminal,
                     peer_id,
                     name,
                     authorized,
@@ -572,6 +586,7 @@ pub async fn start_listen<T: InvokeUiCM>(
                     file,
                     restart,
                     recording,
+                    terminal,
                     from_switch,
                     tx.clone(),
                 );
@@ -615,32 +630,36 @@ async fn handle_fs(fs: ipc::FS, write_jobs: &mut Vec<fs::TransferJob>, tx: &Unbo
         ipc::FS::CreateDir { path, id } => {
             create_dir(path, id, tx).await;
         }
+        ipc::FS::Rename { path, new_path, id } => {
+            rename(path, new_path, id, tx).await;
+        }
+        ipc::FS::Copy { path, new_path, id } => {
+            copy(path, new_path, id, tx).await;
+        }
         ipc::FS::NewWrite {
             path,
             id,
             file_num,
             mut files,
+            mut dirs,
             overwrite_detection,
+            parallel,
         } => {
             // cm has no show_hidden context
             // dummy remote, show_hidden, is_remote
-            write_jobs.push(fs::TransferJob::new_write(
+            let mut job = fs::TransferJob::new_write(
                 id,
                 "".to_string(),
                 path,
                 file_num,
                 false,
                 false,
-                files
-                    .drain(..)
-                    .map(|f| FileEntry {
-                        name: f.0,
-                        modified_time: f.1,
-                        ..Default::default()
-                    })
-                    .collect(),
+                files.drain(..).map(|f| f.into()).collect(),
                 overwrite_detection,
-            ));
+            );
+            job.set_parallel(parallel);
+            job.set_dirs(dirs.drain(..).map(|f| f.into()).collect());
+            write_jobs.push(job);
         }
         ipc::FS::CancelWrite { id } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
@@ -666,19 +685,33 @@ async fn handle_fs(fs: ipc::FS, write_jobs: &mut Vec<fs::TransferJob>, tx: &Unbo
             file_num,
             data,
             compressed,
+            blk_id,
+            copy_blocks,
+            sha256,
         } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
-                if let Err(err) = job
+                match job
                     .write(FileTransferBlock {
                         id,
                         file_num,
                         data,
                         compressed,
+                        blk_id,
+                        copy_blocks,
+                        sha256,
                         ..Default::default()
                     })
                     .await
                 {
-                    send_raw(fs::new_error(id, err, file_num), &tx);
+                    Ok(Some(verified)) => {
+                        send_raw(fs::new_verified(verified), &tx);
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        job.remove_download_file();
+                        fs::remove_job(id, write_jobs);
+                        send_raw(fs::new_error(id, err, file_num), &tx);
+                    }
                 }
             }
         }
@@ -688,8 +721,21 @@ async fn handle_fs(fs: ipc::FS, write_jobs: &mut Vec<fs::TransferJob>, tx: &Unbo
             file_size,
             last_modified,
             is_upload,
+            support_delta,
         } => {
             if let Some(job) = fs::get_job(id, write_jobs) {
+                job.set_peer_support_delta(support_delta);
+                if let Some(n) = job.retransmitting() {
+                    // the file sent again after a digest mismatch is overwritten without asking
+                    if n == file_num {
+                        send_raw(new_send_confirm(job.gen_write_confirm(n).await), &tx);
+                        return;
+                    }
+                    // the other files in flight go on when sent in parallel
+                    if job.parallel() <= 1 {
+                        return;
+                    }
+                }
                 let mut req = FileTransferSendConfirmRequest {
                     id,
                     file_num,
@@ -716,6 +762,9 @@ async fn handle_fs(fs: ipc::FS, write_jobs: &mut Vec<fs::TransferJob>, tx: &Unbo
                                 DigestCheckResult::NeedConfirm(mut digest) => {
                                     // upload to server, but server has the same file, request
                                     digest.is_upload = is_upload;
+                                    digest.verify_hash = true;
+                                    digest.checksums =
+                                        MessageField::from_option(job.gen_checksums(file_num).await);
                                     let mut msg_out = Message::new();
                                     let mut fr = FileResponse::new();
                                     fr.set_digest(digest);
@@ -723,6 +772,7 @@ async fn handle_fs(fs: ipc::FS, write_jobs: &mut Vec<fs::TransferJob>, tx: &Unbo
                                     send_raw(msg_out, &tx);
                                 }
                                 DigestCheckResult::NoSuchFile => {
+                                    let req = job.gen_write_confirm(file_num).await;
                                     let msg_out = new_send_confirm(req);
                                     send_raw(msg_out, &tx);
                                 }
@@ -795,6 +845,26 @@ async fn create_dir(path: String, id: i32, tx: &UnboundedSender<Data>) {
     .await;
 }
 
+async fn rename(path: String, new_path: String, id: i32, tx: &UnboundedSender<Data>) {
+    handle_result(
+        spawn_blocking(move || fs::rename(&path, &new_path)).await,
+        id,
+        0,
+        tx,
+    )
+    .await;
+}
+
+async fn copy(path: String, new_path: String, id: i32, tx: &UnboundedSender<Data>) {
+    handle_result(
+        spawn_blocking(move || fs::copy(&path, &new_path)).await,
+        id,
+        0,
+        tx,
+    )
+    .await;
+}
+
 async fn remove_dir(path: String, id: i32, recursive: bool, tx: &UnboundedSender<Data>) {
     let path = fs::get_path(&path);
     handle_result(
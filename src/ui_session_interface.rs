@@ -715,6 +715,10 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Login((password, remember)));
     }
 
+    pub fn send2fa(&self, code: String) {
+        self.send(Data::Message(crate::client::new_auth_2fa(code)));
+    }
+
     pub fn new_rdp(&self) {
         self.send(Data::NewRDP);
     }
@@ -872,6 +876,8 @@ pub trait InvokeUiSession: Send + Sync + Clone + 'static + Sized + Default {
         is_local: bool,
         only_count: bool,
     );
+    fn update_search_result(&self, id: i32, entries: &Vec<FileEntry>, path: String, done: bool);
+    fn update_file_preview(&self, id: i32, path: String, offset: u64, data: &[u8], file_size: u64);
     fn confirm_delete_files(&self, id: i32, i: i32, name: String);
     fn override_file_confirm(
         &self,